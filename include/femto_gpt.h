@@ -0,0 +1,58 @@
+#ifndef FEMTO_GPT_H
+#define FEMTO_GPT_H
+
+/* C bindings of femtoGPT, built with:
+ *   cargo build --release --lib --features cdylib
+ * Functions returning null or -1 have failed, femto_gpt_last_error() then describes the
+ * error of the calling thread. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct FemtoGptModel FemtoGptModel;
+
+/* Zeros disable top_k, top_p, the penalties, the seed and the stop tokens */
+typedef struct {
+    size_t max_tokens;
+    float temperature; /* 0 always picks the most likely token */
+    size_t top_k;
+    float top_p;
+    float repetition_penalty;
+    float presence_penalty;
+    float frequency_penalty;
+    uint64_t seed; /* 0 seeds every call randomly */
+    const uint32_t *stop_tokens; /* num_stop_tokens ids, generation stops before any of them */
+    size_t num_stop_tokens;
+} FemtoGptSamplingOptions;
+
+/* checkpoint: checkpoint_len bytes of a file written by GPT::save_checkpoint. config_json:
+ * a GPTConfig as JSON, or NULL for the config stored in the checkpoint. tokenizer_text: the
+ * text the SimpleTokenizer was built from, or NULL when generating from token ids only. */
+FemtoGptModel *femto_gpt_model_new(const char *config_json, const uint8_t *checkpoint,
+                                   size_t checkpoint_len, const char *tokenizer_text);
+void femto_gpt_model_free(FemtoGptModel *model);
+
+/* Writes up to options->max_tokens tokens generated after the prompt to out, returning their
+ * count. out_capacity must be at least options->max_tokens. */
+ptrdiff_t femto_gpt_generate(FemtoGptModel *model, const uint32_t *prompt, size_t prompt_len,
+                             const FemtoGptSamplingOptions *options, uint32_t *out,
+                             size_t out_capacity);
+
+/* Returns the text generated after the prompt, to be released with femto_gpt_string_free.
+ * Requires a tokenizer. */
+char *femto_gpt_generate_text(FemtoGptModel *model, const char *prompt,
+                              const FemtoGptSamplingOptions *options);
+void femto_gpt_string_free(char *s);
+
+/* Valid until the next error on the calling thread */
+const char *femto_gpt_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
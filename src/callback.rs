@@ -0,0 +1,199 @@
+use crate::gpt::{Evaluation, GPT};
+use crate::graph::{Graph, GraphError};
+use crate::tensor::Float;
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepInfo {
+    // Optimizer step, counted across training runs
+    pub step: usize,
+    pub loss: Float,
+    pub learning_rate: Float,
+    // Global L2 norm of the gradients, when known (The GPU training loop only computes
+    // it when the gradients are clipped)
+    pub grad_norm: Option<Float>,
+    // Number of tokens in the batch
+    pub tokens: usize,
+    // Duration of the step
+    pub elapsed: Duration,
+    // Time since the start of the training run
+    pub wall_time: Duration,
+}
+
+impl StepInfo {
+    pub fn tokens_per_sec(&self) -> Float {
+        self.tokens as Float / self.elapsed.as_secs_f64().max(1e-9) as Float
+    }
+}
+
+// Summary of the values of a tensor. The statistics are computed over its finite values,
+// the others are only counted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorStats {
+    pub name: String,
+    pub mean: Float,
+    pub variance: Float,
+    // L2 norm
+    pub norm: Float,
+    // Number of NaN and infinite values
+    pub non_finite: usize,
+}
+
+impl TensorStats {
+    pub fn new(name: String, values: &[Float]) -> Self {
+        let finite = values.iter().filter(|v| v.is_finite());
+        let (count, sum, sum_sq) = finite.fold((0, 0., 0.), |(n, s, sq), v| {
+            (n + 1, s + *v as f64, sq + (*v as f64) * (*v as f64))
+        });
+        let mean = sum / (count as f64).max(1.);
+        Self {
+            name,
+            mean: mean as Float,
+            variance: (sum_sq / (count as f64).max(1.) - mean * mean).max(0.) as Float,
+            norm: sum_sq.sqrt() as Float,
+            non_finite: values.len() - count,
+        }
+    }
+}
+
+// Gradients of the trainable parameters (Before clipping) and outputs of the blocks of the
+// model (Named `block_<layer>`, then `hidden` and `logits`) on a step, see
+// `TrainingOptions::monitor_interval`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainingStats {
+    pub gradients: Vec<TensorStats>,
+    pub activations: Vec<TensorStats>,
+}
+
+impl TrainingStats {
+    // Tensors with NaN or infinite values
+    pub fn non_finite(&self) -> impl Iterator<Item = &TensorStats> {
+        self.gradients
+            .iter()
+            .chain(self.activations.iter())
+            .filter(|s| s.non_finite > 0)
+    }
+}
+
+// Hooks of the training loops. The default implementations log the progress (Through
+// `tracing`, at the info level).
+// Returning `ControlFlow::Break` stops the training.
+pub trait TrainCallback<G: Graph> {
+    fn on_step_end(
+        &mut self,
+        _gpt: &mut GPT<G>,
+        info: &StepInfo,
+    ) -> Result<ControlFlow<()>, GraphError> {
+        tracing::info!(
+            step = info.step,
+            loss = info.loss,
+            elapsed_ms = info.elapsed.as_millis() as u64,
+            "training step"
+        );
+        Ok(ControlFlow::Continue(()))
+    }
+
+    // After the validation dataset is evaluated, see `TrainingOptions::eval_interval`
+    fn on_eval(
+        &mut self,
+        _gpt: &mut GPT<G>,
+        info: &StepInfo,
+        eval: &Evaluation,
+    ) -> Result<ControlFlow<()>, GraphError> {
+        tracing::info!(
+            step = info.step,
+            loss = eval.loss,
+            perplexity = eval.perplexity,
+            training_loss = info.loss,
+            "validation"
+        );
+        Ok(ControlFlow::Continue(()))
+    }
+
+    // Called periodically with the parameters synced to the CPU, for saving the model
+    fn on_checkpoint(&mut self, _gpt: &mut GPT<G>) -> Result<(), GraphError> {
+        Ok(())
+    }
+
+    // After the steps whose statistics are monitored, see `TrainingStats`. Tensors with NaN
+    // or infinite values are reported at the warn level, the largest gradient norm at the
+    // debug level.
+    fn on_stats(
+        &mut self,
+        _gpt: &mut GPT<G>,
+        info: &StepInfo,
+        stats: &TrainingStats,
+    ) -> Result<(), GraphError> {
+        for s in stats.non_finite() {
+            tracing::warn!(
+                step = info.step,
+                tensor = s.name.as_str(),
+                non_finite = s.non_finite,
+                "non-finite values"
+            );
+        }
+        if let Some(s) = stats
+            .gradients
+            .iter()
+            .max_by(|a, b| a.norm.total_cmp(&b.norm))
+        {
+            tracing::debug!(
+                step = info.step,
+                tensor = s.name.as_str(),
+                grad_norm = s.norm,
+                "largest gradient"
+            );
+        }
+        Ok(())
+    }
+}
+
+// Logs the progress and nothing else
+impl<G: Graph> TrainCallback<G> for () {}
+
+// Both callbacks are called, the training stops if either of them asks to
+impl<G: Graph, A: TrainCallback<G>, B: TrainCallback<G>> TrainCallback<G> for (A, B) {
+    fn on_step_end(
+        &mut self,
+        gpt: &mut GPT<G>,
+        info: &StepInfo,
+    ) -> Result<ControlFlow<()>, GraphError> {
+        let a = self.0.on_step_end(gpt, info)?;
+        let b = self.1.on_step_end(gpt, info)?;
+        Ok(if a.is_break() { a } else { b })
+    }
+
+    fn on_eval(
+        &mut self,
+        gpt: &mut GPT<G>,
+        info: &StepInfo,
+        eval: &Evaluation,
+    ) -> Result<ControlFlow<()>, GraphError> {
+        let a = self.0.on_eval(gpt, info, eval)?;
+        let b = self.1.on_eval(gpt, info, eval)?;
+        Ok(if a.is_break() { a } else { b })
+    }
+
+    fn on_checkpoint(&mut self, gpt: &mut GPT<G>) -> Result<(), GraphError> {
+        self.0.on_checkpoint(gpt)?;
+        self.1.on_checkpoint(gpt)
+    }
+
+    fn on_stats(
+        &mut self,
+        gpt: &mut GPT<G>,
+        info: &StepInfo,
+        stats: &TrainingStats,
+    ) -> Result<(), GraphError> {
+        self.0.on_stats(gpt, info, stats)?;
+        self.1.on_stats(gpt, info, stats)
+    }
+}
+
+// Closures are called on checkpoints
+impl<G: Graph, F: FnMut(&mut GPT<G>) -> Result<(), GraphError>> TrainCallback<G> for F {
+    fn on_checkpoint(&mut self, gpt: &mut GPT<G>) -> Result<(), GraphError> {
+        self(gpt)
+    }
+}
@@ -0,0 +1,155 @@
+use crate::gpt::GPT;
+use crate::graph::{Graph, GraphError};
+use crate::sampling::SamplingParams;
+use crate::tokenizer::Tokenizer;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// Multi-turn conversations, laid out as the token sequences a model is trained on and
+// prompted with. Every message is the header of its role (E.g. "User: "), tokenized along
+// with its content, followed by the end-of-turn token. The end-of-turn token is a token id
+// rather than a text, so that it can be a token the conversations never contain.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+}
+
+impl Message {
+    pub fn new(role: Role, content: &str) -> Self {
+        Self {
+            role,
+            content: content.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatTemplate {
+    pub system: String,
+    pub user: String,
+    pub assistant: String,
+    // Tokens the conversation starts with (E.g. a BOS token)
+    pub prefix: Vec<usize>,
+    pub end_of_turn: usize,
+}
+
+impl ChatTemplate {
+    // "System: ", "User: " and "Assistant: " headers
+    pub fn new(end_of_turn: usize) -> Self {
+        Self {
+            system: "System: ".into(),
+            user: "User: ".into(),
+            assistant: "Assistant: ".into(),
+            prefix: Vec::new(),
+            end_of_turn,
+        }
+    }
+
+    pub fn header(&self, role: Role) -> &str {
+        match role {
+            Role::System => &self.system,
+            Role::User => &self.user,
+            Role::Assistant => &self.assistant,
+        }
+    }
+
+    // Tokens of the whole conversation, every message terminated by the end-of-turn token
+    pub fn format<T: Tokenizer>(
+        &self,
+        tokenizer: &T,
+        messages: &[Message],
+    ) -> Result<Vec<usize>, GraphError> {
+        let vocab_size = tokenizer.vocab_size();
+        if let Some(t) = self
+            .prefix
+            .iter()
+            .chain([&self.end_of_turn])
+            .find(|t| **t >= vocab_size)
+        {
+            return Err(GraphError::InvalidConfig(format!(
+                "token {} of the chat template out of a vocabulary of {}",
+                t, vocab_size
+            )));
+        }
+        let mut tokens = self.prefix.clone();
+        for message in messages {
+            let text = format!("{}{}", self.header(message.role), message.content);
+            tokens.extend(tokenizer.tokenize(&text));
+            tokens.push(self.end_of_turn);
+        }
+        Ok(tokens)
+    }
+
+    // Tokens of the conversation followed by the header of the assistant, which the model
+    // completes with its answer
+    pub fn prompt<T: Tokenizer>(
+        &self,
+        tokenizer: &T,
+        messages: &[Message],
+    ) -> Result<Vec<usize>, GraphError> {
+        let mut tokens = self.format(tokenizer, messages)?;
+        tokens.extend(tokenizer.tokenize(&self.assistant));
+        Ok(tokens)
+    }
+}
+
+// Answer of the model to the conversation, generated until the end-of-turn token (Which is
+// added to the stop tokens of `params`) or `params.max_tokens`
+pub fn generate_chat<G: Graph, T: Tokenizer, R: Rng>(
+    gpt: &mut GPT<G>,
+    rng: &mut R,
+    tokenizer: &T,
+    template: &ChatTemplate,
+    messages: &[Message],
+    params: &SamplingParams,
+) -> Result<Message, GraphError> {
+    let prompt = template.prompt(tokenizer, messages)?;
+    let mut params = params.clone();
+    if !params.stop_tokens.contains(&template.end_of_turn) {
+        params.stop_tokens.push(template.end_of_turn);
+    }
+    let tokens = gpt.infer(rng, &prompt, &params, |_| {})?;
+    Ok(Message {
+        role: Role::Assistant,
+        content: tokenizer.untokenize(&tokens[prompt.len()..]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::SimpleTokenizer;
+
+    #[test]
+    fn test_chat_template() {
+        let tokenizer = SimpleTokenizer::new("SystemUserAssistant: hi\n");
+        let eot = tokenizer.tokenize("\n")[0];
+        let template = ChatTemplate::new(eot);
+        let messages = [
+            Message::new(Role::System, "hi"),
+            Message::new(Role::User, "hi"),
+        ];
+        let tokens = template.format(&tokenizer, &messages).unwrap();
+        assert_eq!(tokenizer.untokenize(&tokens), "System: hi\nUser: hi\n");
+        let prompt = template.prompt(&tokenizer, &messages[..1]).unwrap();
+        assert_eq!(tokenizer.untokenize(&prompt), "System: hi\nAssistant: ");
+
+        let template = ChatTemplate {
+            prefix: vec![tokenizer.vocab_size()],
+            ..template
+        };
+        assert!(template.format(&tokenizer, &messages).is_err());
+        let message: Message = serde_json::from_str(r#"{"role":"user","content":"hi"}"#).unwrap();
+        assert_eq!(message, Message::new(Role::User, "hi"));
+    }
+}
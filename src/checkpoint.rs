@@ -0,0 +1,483 @@
+use crate::gpt::{GPTConfig, TrainingState};
+use crate::graph::GraphError;
+use crate::optimizer::SwaState;
+use crate::tensor::{Float, Tensor, TensorOps};
+use crate::tokenizer::{SavedTokenizer, Tokenizer};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+// Checkpoints split across multiple files, for models too large for a single one. A sharded
+// checkpoint is a directory holding:
+//
+// - `shard_<i>.dat`: Tensors of the training state (Parameters, their averages and
+//   optimizer state), at most `max_shard_bytes` of them per shard (Unless a single tensor
+//   is larger)
+// - `state.dat`: The rest of the training state (Config, schedule, progress...)
+// - `index.json`: The shard and the shape of every tensor, written last
+//
+// Tensors are read lazily, one shard at a time, and checked against the checksums of the
+// manifest of the training state.
+
+// Training states are written after a header with the version of their format, bumped
+// whenever the layout of `TrainingState` (Or of a struct in it) changes. States of another
+// version are rejected, `migrate` converts them.
+const MAGIC: &[u8; 8] = b"femtoGPT";
+pub const FORMAT_VERSION: u32 = 1;
+
+// Version of the format of an encoded training state, `None` for states without a header
+// (Those of the first version of femtoGPT)
+pub fn format_version(bytes: &[u8]) -> Option<u32> {
+    match bytes.split_at_checked(MAGIC.len() + 4) {
+        Some((header, _)) if header.starts_with(MAGIC) => Some(u32::from_le_bytes(
+            header[MAGIC.len()..].try_into().unwrap(),
+        )),
+        _ => None,
+    }
+}
+
+impl TrainingState {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, GraphError> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(FORMAT_VERSION.to_le_bytes());
+        bytes.extend(bincode::serialize(self)?);
+        Ok(bytes)
+    }
+
+    // Decodes a training state of the current format
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, GraphError> {
+        match format_version(bytes) {
+            Some(FORMAT_VERSION) => Ok(bincode::deserialize(&bytes[MAGIC.len() + 4..])?),
+            Some(version) if version > FORMAT_VERSION => {
+                Err(GraphError::DeserializationError(format!(
+                    "the checkpoint was saved by a later version of femtoGPT (Format {})",
+                    version
+                )))
+            }
+            _ => Err(GraphError::DeserializationError(
+                "the checkpoint was saved by an earlier version of femtoGPT, convert it with \
+                 `migrate::migrate` (Or `-- migrate`)"
+                    .into(),
+            )),
+        }
+    }
+}
+
+const INDEX: &str = "index.json";
+const STATE: &str = "state.dat";
+
+// Tensors are named after the part of the training state they belong to
+const PARAMS: &str = "params/";
+const EMA: &str = "ema/";
+const OPTIMIZER: &str = "optimizer/";
+const SWA: &str = "swa/";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    shard: usize,
+    shape: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Index {
+    shards: Vec<String>,
+    tensors: BTreeMap<String, Entry>,
+}
+
+// Integrity metadata of a training state, checked when it is loaded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    // Version of femtoGPT the state was saved with
+    pub version: String,
+    pub step: usize,
+    // `Tokenizer::fingerprint` of the tokenizer the model was trained with, if known
+    pub tokenizer: Option<u64>,
+    // Checksum of every tensor, named after the part of the state it belongs to
+    // (E.g. `params/token_embedding` or `optimizer/token_embedding_m`)
+    pub checksums: BTreeMap<String, u64>,
+}
+
+// FNV-1a, a hash that is stable across platforms and releases
+pub(crate) fn fnv1a<I: IntoIterator<Item = u8>>(bytes: I) -> u64 {
+    bytes.into_iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub fn checksum(t: &Tensor<Float>) -> u64 {
+    let shape = t.shape().iter().flat_map(|d| (*d as u64).to_le_bytes());
+    fnv1a(shape.chain(t.blob().iter().flat_map(|v| v.to_le_bytes())))
+}
+
+// All the tensors of the state, named after the part of the state they belong to
+fn named_tensors(state: &TrainingState) -> BTreeMap<String, &Tensor<Float>> {
+    [
+        (PARAMS, Some(&state.tensors)),
+        (EMA, Some(&state.ema)),
+        (OPTIMIZER, Some(&state.optimizer.state)),
+        (SWA, state.swa.as_ref().map(|swa| &swa.tensors)),
+    ]
+    .into_iter()
+    .flat_map(|(prefix, tensors)| {
+        tensors
+            .into_iter()
+            .flatten()
+            .map(move |(k, t)| (prefix.to_string() + k, t))
+    })
+    .collect()
+}
+
+impl Manifest {
+    pub fn new(state: &TrainingState, tokenizer: Option<u64>) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").into(),
+            step: state.optimizer.step,
+            tokenizer,
+            checksums: named_tensors(state)
+                .into_iter()
+                .map(|(k, t)| (k, checksum(t)))
+                .collect(),
+        }
+    }
+
+    fn check(&self, name: &str, t: &Tensor<Float>) -> Result<(), GraphError> {
+        match self.checksums.get(name) {
+            Some(expected) if *expected == checksum(t) => Ok(()),
+            Some(_) => Err(GraphError::CorruptCheckpoint(format!(
+                "checksum mismatch on tensor {}",
+                name
+            ))),
+            None => Err(GraphError::CorruptCheckpoint(format!(
+                "tensor {} is not in the manifest",
+                name
+            ))),
+        }
+    }
+
+    // Checks that the tensors of the state are exactly those of the manifest
+    pub fn verify(&self, state: &TrainingState) -> Result<(), GraphError> {
+        if self.version != env!("CARGO_PKG_VERSION") {
+            tracing::warn!(
+                "checkpoint saved with femtoGPT {}, loaded with {}",
+                self.version,
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+        if self.step != state.optimizer.step {
+            return Err(GraphError::CorruptCheckpoint(format!(
+                "saved at step {}, but the optimizer is at step {}",
+                self.step, state.optimizer.step
+            )));
+        }
+        if let (Some(fingerprint), Some(tokenizer)) = (self.tokenizer, &state.tokenizer) {
+            if fingerprint != tokenizer.fingerprint() {
+                return Err(GraphError::CorruptCheckpoint(
+                    "the saved tokenizer doesn't match the manifest".into(),
+                ));
+            }
+        }
+        let tensors = named_tensors(state);
+        for (name, t) in tensors.iter() {
+            self.check(name, t)?;
+        }
+        if let Some(missing) = self.checksums.keys().find(|k| !tensors.contains_key(*k)) {
+            return Err(GraphError::CorruptCheckpoint(format!(
+                "tensor {} is missing",
+                missing
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn shard_name(i: usize) -> String {
+    format!("shard_{:05}.dat", i)
+}
+
+pub fn save_sharded<P: AsRef<Path>>(
+    state: &TrainingState,
+    dir: P,
+    max_shard_bytes: usize,
+) -> Result<(), GraphError> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir)?;
+    let tensors = named_tensors(state);
+
+    let mut index = Index {
+        shards: Vec::new(),
+        tensors: BTreeMap::new(),
+    };
+    let mut shard = HashMap::new();
+    let mut shard_bytes = 0;
+    let mut write_shard = |shard: &mut HashMap<String, &Tensor<Float>>| -> Result<(), GraphError> {
+        if !shard.is_empty() {
+            let name = shard_name(index.shards.len());
+            std::fs::write(dir.join(&name), bincode::serialize(&shard)?)?;
+            for (k, t) in shard.drain() {
+                let entry = Entry {
+                    shard: index.shards.len(),
+                    shape: t.shape().to_vec(),
+                };
+                index.tensors.insert(k, entry);
+            }
+            index.shards.push(name);
+        }
+        Ok(())
+    };
+    for (name, t) in tensors {
+        let bytes = t.size() * std::mem::size_of::<Float>();
+        if shard_bytes + bytes > max_shard_bytes {
+            write_shard(&mut shard)?;
+            shard_bytes = 0;
+        }
+        shard.insert(name, t);
+        shard_bytes += bytes;
+    }
+    write_shard(&mut shard)?;
+
+    let skeleton = TrainingState {
+        tensors: HashMap::new(),
+        ema: HashMap::new(),
+        optimizer: crate::optimizer::OptimizerState {
+            step: state.optimizer.step,
+            state: HashMap::new(),
+        },
+        // The number of samples of the average is kept with the rest of the state
+        swa: state.swa.as_ref().map(|swa| SwaState {
+            count: swa.count,
+            tensors: HashMap::new(),
+        }),
+        ..state.clone()
+    };
+    std::fs::write(dir.join(STATE), skeleton.to_bytes()?)?;
+    let index_json = serde_json::to_string_pretty(&index)
+        .map_err(|e| GraphError::DeserializationError(e.to_string()))?;
+    std::fs::write(dir.join(INDEX), index_json)?;
+
+    // Shards of a previous, larger, checkpoint
+    for i in index.shards.len().. {
+        let path = dir.join(shard_name(i));
+        if !path.is_file() {
+            break;
+        }
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// Reads a checkpoint, sharded (A directory) or not, and checks it against its manifest
+pub fn read_training_state<P: AsRef<Path>>(path: P) -> Result<TrainingState, GraphError> {
+    let path = path.as_ref();
+    if path.is_dir() {
+        return ShardedCheckpoint::open(path)?.training_state();
+    }
+    let state = TrainingState::from_bytes(&std::fs::read(path)?)?;
+    if let Some(manifest) = &state.manifest {
+        manifest.verify(&state)?;
+    }
+    Ok(state)
+}
+
+pub struct ShardedCheckpoint {
+    dir: PathBuf,
+    index: Index,
+    // Training state without its tensors
+    skeleton: TrainingState,
+}
+
+impl ShardedCheckpoint {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, GraphError> {
+        let dir = dir.as_ref().to_path_buf();
+        let index = serde_json::from_str(&std::fs::read_to_string(dir.join(INDEX))?)
+            .map_err(|e| GraphError::DeserializationError(e.to_string()))?;
+        let skeleton = TrainingState::from_bytes(&std::fs::read(dir.join(STATE))?)?;
+        Ok(Self {
+            dir,
+            index,
+            skeleton,
+        })
+    }
+
+    pub fn config(&self) -> Option<&GPTConfig> {
+        self.skeleton.config.as_ref()
+    }
+
+    pub fn manifest(&self) -> Option<&Manifest> {
+        self.skeleton.manifest.as_ref()
+    }
+
+    pub fn tokenizer(&self) -> Option<&SavedTokenizer> {
+        self.skeleton.tokenizer.as_ref()
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.index.shards.len()
+    }
+
+    // Names and shapes of the parameters
+    pub fn params(&self) -> impl Iterator<Item = (&str, &[usize])> {
+        self.index.tensors.iter().filter_map(|(k, e)| {
+            k.strip_prefix(PARAMS)
+                .map(|name| (name, e.shape.as_slice()))
+        })
+    }
+
+    fn read_shard(&self, i: usize) -> Result<HashMap<String, Tensor<Float>>, GraphError> {
+        let path = self.dir.join(&self.index.shards[i]);
+        let tensors: HashMap<String, Tensor<Float>> = bincode::deserialize(&std::fs::read(path)?)?;
+        if let Some(manifest) = &self.skeleton.manifest {
+            for (name, t) in tensors.iter() {
+                manifest.check(name, t)?;
+            }
+        }
+        Ok(tensors)
+    }
+
+    // Parameters stored in the `i`th shard (Shards without any are not read)
+    pub fn load_shard(&self, i: usize) -> Result<HashMap<String, Tensor<Float>>, GraphError> {
+        let has_params = self
+            .index
+            .tensors
+            .iter()
+            .any(|(k, e)| e.shard == i && k.starts_with(PARAMS));
+        if !has_params {
+            return Ok(HashMap::new());
+        }
+        Ok(self
+            .read_shard(i)?
+            .into_iter()
+            .filter_map(|(k, t)| k.strip_prefix(PARAMS).map(|name| (name.to_string(), t)))
+            .collect())
+    }
+
+    // Reads the given parameters, and only the shards they are stored in
+    pub fn load(&self, names: &[&str]) -> Result<HashMap<String, Tensor<Float>>, GraphError> {
+        let mut shards = BTreeMap::<usize, Vec<String>>::new();
+        for name in names {
+            let key = PARAMS.to_string() + name;
+            let entry = self
+                .index
+                .tensors
+                .get(&key)
+                .ok_or_else(|| GraphError::MissingTensor(name.to_string()))?;
+            shards.entry(entry.shard).or_default().push(key);
+        }
+        let mut tensors = HashMap::new();
+        for (shard, keys) in shards {
+            let mut stored = self.read_shard(shard)?;
+            for key in keys {
+                let t = stored.remove(&key).ok_or_else(|| {
+                    GraphError::DeserializationError(format!("{} is not in its shard", key))
+                })?;
+                tensors.insert(key[PARAMS.len()..].to_string(), t);
+            }
+        }
+        Ok(tensors)
+    }
+
+    // The training state, without its parameters unless `with_params`
+    pub fn training_state_with(&self, with_params: bool) -> Result<TrainingState, GraphError> {
+        let mut state = self.skeleton.clone();
+        for i in 0..self.num_shards() {
+            let is_needed = self
+                .index
+                .tensors
+                .iter()
+                .any(|(k, e)| e.shard == i && (with_params || !k.starts_with(PARAMS)));
+            if !is_needed {
+                continue;
+            }
+            for (k, t) in self.read_shard(i)? {
+                if let Some(name) = k.strip_prefix(PARAMS) {
+                    if with_params {
+                        state.tensors.insert(name.to_string(), t);
+                    }
+                } else if let Some(name) = k.strip_prefix(EMA) {
+                    state.ema.insert(name.to_string(), t);
+                } else if let Some(name) = k.strip_prefix(OPTIMIZER) {
+                    state.optimizer.state.insert(name.to_string(), t);
+                } else if let (Some(name), Some(swa)) = (k.strip_prefix(SWA), &mut state.swa) {
+                    swa.tensors.insert(name.to_string(), t);
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    pub fn training_state(&self) -> Result<TrainingState, GraphError> {
+        self.training_state_with(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sharded() {
+        let mut state = TrainingState {
+            tensors: HashMap::new(),
+            optimizer: Default::default(),
+            schedule: None,
+            ema: HashMap::new(),
+            progress: None,
+            config: None,
+            manifest: None,
+            swa: None,
+            tokenizer: None,
+        };
+        for i in 0..5 {
+            state
+                .tensors
+                .insert(format!("p{}", i), Tensor::constant(&[4, 4], i as Float));
+        }
+        state.tensors.insert("big".into(), Tensor::zeros(&[40]));
+        state
+            .optimizer
+            .state
+            .insert("p0_m".into(), Tensor::constant(&[4, 4], 0.5));
+        state.optimizer.step = 3;
+        state.manifest = Some(Manifest::new(&state, None));
+
+        let dir = std::env::temp_dir().join(format!("femto_gpt_sharded_{}", std::process::id()));
+        // Tensors sorted by name, at most two 4x4 ones per shard: [p0_m], [big], [p0, p1],
+        // [p2, p3], [p4]
+        save_sharded(&state, &dir, 2 * 16 * std::mem::size_of::<Float>()).unwrap();
+        let checkpoint = ShardedCheckpoint::open(&dir).unwrap();
+        assert_eq!(checkpoint.num_shards(), 5);
+        assert_eq!(checkpoint.params().count(), 6);
+
+        let loaded = checkpoint.load(&["p3"]).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded["p3"].blob(), state.tensors["p3"].blob());
+        assert!(matches!(
+            checkpoint.load(&["p9"]),
+            Err(GraphError::MissingTensor(_))
+        ));
+
+        let read = read_training_state(&dir).unwrap();
+        assert_eq!(read.optimizer.step, 3);
+        assert_eq!(read.optimizer.state.len(), 1);
+        for (name, t) in state.tensors.iter() {
+            assert_eq!(read.tensors[name].blob(), t.blob());
+        }
+        let without_params = checkpoint.training_state_with(false).unwrap();
+        assert!(without_params.tensors.is_empty());
+        assert_eq!(without_params.optimizer.state.len(), 1);
+
+        // Shards whose tensors don't match the manifest are rejected
+        let mut shard = checkpoint.read_shard(2).unwrap();
+        shard.insert("params/p0".into(), Tensor::constant(&[4, 4], 1.));
+        std::fs::write(dir.join(shard_name(2)), bincode::serialize(&shard).unwrap()).unwrap();
+        assert!(matches!(
+            checkpoint.load(&["p0"]),
+            Err(GraphError::CorruptCheckpoint(_))
+        ));
+
+        // Saving fewer shards removes the stale ones
+        save_sharded(&state, &dir, usize::MAX).unwrap();
+        assert_eq!(ShardedCheckpoint::open(&dir).unwrap().num_shards(), 1);
+        assert!(!dir.join(shard_name(1)).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use crate::graph::GraphError;
+use rayon::ThreadPool;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+// Threads the CPU computations of the crate run on. By default they use rayon's global pool,
+// `ComputeConfig::apply` replaces it with a dedicated one for the whole process.
+
+// How the work is split between the threads: the samples of a batch are processed by separate
+// workers during training (`Batch`), the big operations split their rows between the threads
+// (`Ops`, see `tensor::for_each_row`), or both. Splitting the operations is what makes a
+// single sequence (E.g. during inference) use several cores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Parallelism {
+    Batch,
+    Ops,
+    #[default]
+    Both,
+}
+
+impl Parallelism {
+    pub fn across_batch(self) -> bool {
+        self != Parallelism::Ops
+    }
+
+    pub fn within_ops(self) -> bool {
+        self != Parallelism::Batch
+    }
+}
+
+impl FromStr for Parallelism {
+    type Err = GraphError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "batch" => Ok(Parallelism::Batch),
+            "ops" => Ok(Parallelism::Ops),
+            "both" => Ok(Parallelism::Both),
+            _ => Err(GraphError::InvalidConfig(format!(
+                "unknown parallelism {}, expected batch, ops or both",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ComputeConfig {
+    // Size of the pool, defaults to the number of cores (Or `RAYON_NUM_THREADS`)
+    pub threads: Option<usize>,
+    pub parallelism: Parallelism,
+    // Pins every thread of the pool to its own core (Linux only), so that the scheduler does
+    // not move them around and their caches stay warm
+    pub pin_threads: bool,
+}
+
+struct Compute {
+    config: ComputeConfig,
+    pool: Arc<ThreadPool>,
+}
+
+static COMPUTE: RwLock<Option<Compute>> = RwLock::new(None);
+
+impl ComputeConfig {
+    // Runs the computations started afterwards on a new pool configured accordingly
+    pub fn apply(&self) -> Result<(), GraphError> {
+        let compute = self.build()?;
+        *COMPUTE.write().unwrap_or_else(|e| e.into_inner()) = Some(compute);
+        Ok(())
+    }
+
+    // The pool of the configuration, without installing it
+    fn build(&self) -> Result<Compute, GraphError> {
+        if self.threads == Some(0) {
+            return Err(GraphError::InvalidConfig(
+                "the number of threads should be positive".into(),
+            ));
+        }
+        let pin = self.pin_threads;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads.unwrap_or(0))
+            .thread_name(|i| format!("femtogpt-{}", i))
+            .start_handler(move |i| {
+                if pin {
+                    pin_thread(i);
+                }
+            })
+            .build()
+            .map_err(|e| GraphError::InvalidConfig(e.to_string()))?;
+        tracing::info!(
+            threads = pool.current_num_threads(),
+            parallelism = ?self.parallelism,
+            pinned = pin,
+            "compute pool ready"
+        );
+        Ok(Compute {
+            config: self.clone(),
+            pool: Arc::new(pool),
+        })
+    }
+
+    // The configuration applied last, the default one if none was
+    pub fn current() -> ComputeConfig {
+        COMPUTE
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map(|c| c.config.clone())
+            .unwrap_or_default()
+    }
+}
+
+// Runs `op` on the pool of the applied `ComputeConfig`, so that the parallel iterators it
+// uses share its threads. (On rayon's global pool when none was applied)
+pub fn install<R: Send, F: FnOnce() -> R + Send>(op: F) -> R {
+    let pool = COMPUTE
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|c| c.pool.clone());
+    match pool {
+        Some(pool) => pool.install(op),
+        None => op(),
+    }
+}
+
+pub fn num_threads() -> usize {
+    install(rayon::current_num_threads)
+}
+
+pub fn parallelism() -> Parallelism {
+    COMPUTE
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|c| c.config.parallelism)
+        .unwrap_or_default()
+}
+
+// Pins the i-th thread of the pool to the i-th core the process may run on
+#[cfg(target_os = "linux")]
+fn pin_thread(index: usize) {
+    // Safety: the CPU sets are plain bitmasks, only accessed through the libc helpers
+    unsafe {
+        let mut allowed: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut allowed) != 0 {
+            tracing::warn!(
+                "can not read the CPU affinity, thread {} is not pinned",
+                index
+            );
+            return;
+        }
+        let cores = (0..libc::CPU_SETSIZE as usize)
+            .filter(|c| libc::CPU_ISSET(*c, &allowed))
+            .collect::<Vec<_>>();
+        if cores.is_empty() {
+            return;
+        }
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cores[index % cores.len()], &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            tracing::warn!(
+                "can not set the CPU affinity, thread {} is not pinned",
+                index
+            );
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_thread(index: usize) {
+    tracing::warn!(
+        "pinning threads is only supported on Linux, thread {} is not pinned",
+        index
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_config() {
+        assert_eq!("ops".parse::<Parallelism>().unwrap(), Parallelism::Ops);
+        assert!("rows".parse::<Parallelism>().is_err());
+        assert!(!Parallelism::Batch.within_ops() && Parallelism::Both.within_ops());
+        assert!(ComputeConfig {
+            threads: Some(0),
+            ..Default::default()
+        }
+        .apply()
+        .is_err());
+
+        let config = ComputeConfig {
+            threads: Some(3),
+            parallelism: Parallelism::Both,
+            pin_threads: true,
+        };
+        // The pool is not installed, the other tests of the process keep the global one
+        let compute = config.build().unwrap();
+        assert_eq!(compute.config, config);
+        assert_eq!(compute.pool.current_num_threads(), 3);
+        assert!(compute
+            .pool
+            .install(|| rayon::current_thread_index().is_some()));
+        assert_eq!(ComputeConfig::current(), ComputeConfig::default());
+    }
+}
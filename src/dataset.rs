@@ -0,0 +1,1075 @@
+use crate::graph::GraphError;
+use crate::tensor::{Float, Tensor, TensorOps};
+use crate::tokenizer::Tokenizer;
+use memmap2::Mmap;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Mutex;
+use std::thread::Scope;
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub xs: Tensor<usize>,
+    // Expected outputs, usually the inputs shifted by one
+    pub ys: Tensor<usize>,
+    // Per-position weights of the loss, all positions count equally when `None`. Weights are
+    // normalized by the training loops, so that the loss is a weighted mean.
+    pub weights: Option<Tensor<Float>>,
+    // Which inputs are real tokens (1) and which are padding (0), no padding when `None`.
+    // Padding positions are not attended to and do not count in the loss.
+    pub attention_mask: Option<Tensor<Float>>,
+    // Which inputs belong to the prefix of their sequence (1), attended to bidirectionally by
+    // the models with `GPTConfig::prefix_lm`. Fully causal when `None`.
+    pub prefix: Option<Tensor<Float>>,
+}
+
+// A corpus of tokens the training loops sample their batches from
+pub trait Dataset {
+    // Number of tokens
+    fn len(&self) -> usize;
+
+    // `len` tokens starting at `start` (With `start + len <= self.len()`)
+    fn read(&self, start: usize, len: usize) -> Result<Vec<usize>, GraphError>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Windows of `context_size` tokens starting at random positions, and the tokens that
+    // follow them (The windows shifted by one). The corpus is treated as an infinite loop,
+    // so windows may wrap around its end.
+    fn sample<R: Rng>(
+        &self,
+        rng: &mut R,
+        batch_size: usize,
+        context_size: usize,
+    ) -> Result<Batch, GraphError> {
+        let len = self.len();
+        if len == 0 {
+            return Err(GraphError::InvalidConfig("the dataset is empty".into()));
+        }
+        let mut xs = Vec::with_capacity(batch_size * context_size);
+        let mut ys = Vec::with_capacity(batch_size * context_size);
+        for _ in 0..batch_size {
+            let start = rng.gen_range(0..len);
+            let mut all = Vec::with_capacity(context_size + 1);
+            while all.len() < context_size + 1 {
+                let pos = (start + all.len()) % len;
+                let count = usize::min(len - pos, context_size + 1 - all.len());
+                all.extend(self.read(pos, count)?);
+            }
+            xs.extend(&all[0..context_size]);
+            ys.extend(&all[1..context_size + 1]);
+        }
+        Ok(Batch {
+            xs: Tensor::raw(&[batch_size, context_size], xs)?,
+            ys: Tensor::raw(&[batch_size, context_size], ys)?,
+            weights: None,
+            attention_mask: None,
+            prefix: None,
+        })
+    }
+}
+
+impl Dataset for [usize] {
+    fn len(&self) -> usize {
+        <[usize]>::len(self)
+    }
+    fn read(&self, start: usize, len: usize) -> Result<Vec<usize>, GraphError> {
+        Ok(self[start..start + len].to_vec())
+    }
+}
+
+impl Dataset for Vec<usize> {
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+    fn read(&self, start: usize, len: usize) -> Result<Vec<usize>, GraphError> {
+        Ok(self[start..start + len].to_vec())
+    }
+}
+
+// Token file (See `write_token_file`) read on demand, through plain reads instead of a
+// memory mapping
+pub struct FileDataset {
+    file: Mutex<BufReader<File>>,
+    width: usize,
+    len: usize,
+}
+
+impl FileDataset {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, GraphError> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len() as usize;
+        let mut header = [0u8; TOKEN_FILE_HEADER_SIZE];
+        file.read_exact(&mut header)
+            .map_err(|_| GraphError::DeserializationError("token file: bad magic bytes".into()))?;
+        let width = token_width(&header, size)?;
+        Ok(Self {
+            file: Mutex::new(BufReader::new(file)),
+            width,
+            len: (size - TOKEN_FILE_HEADER_SIZE) / width,
+        })
+    }
+}
+
+impl Dataset for FileDataset {
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn read(&self, start: usize, len: usize) -> Result<Vec<usize>, GraphError> {
+        let mut bytes = vec![0u8; len * self.width];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(
+            (TOKEN_FILE_HEADER_SIZE + start * self.width) as u64,
+        ))?;
+        file.read_exact(&mut bytes)?;
+        Ok(decode_tokens(&bytes, self.width))
+    }
+}
+
+// Documents separated by an end-of-sequence token, packed into the rows of the batches.
+// Rows start at random positions and continue with the following documents. When the end of
+// the corpus is reached, an EOS token is inserted and a random document follows, so no row
+// wraps around the corpus and every document boundary is marked by an EOS token (The targets
+// never continue one document with the start of another).
+pub struct PackedDataset<D: Dataset> {
+    inner: D,
+    eos: usize,
+    doc_starts: Vec<usize>,
+}
+
+impl<D: Dataset> PackedDataset<D> {
+    pub fn new(inner: D, eos: usize) -> Result<Self, GraphError> {
+        const CHUNK: usize = 1 << 20;
+        let mut doc_starts = vec![0];
+        let mut pos = 0;
+        while pos < inner.len() {
+            let chunk = inner.read(pos, usize::min(CHUNK, inner.len() - pos))?;
+            for (i, t) in chunk.iter().enumerate() {
+                if *t == eos && pos + i + 1 < inner.len() {
+                    doc_starts.push(pos + i + 1);
+                }
+            }
+            pos += chunk.len();
+        }
+        Ok(Self {
+            inner,
+            eos,
+            doc_starts,
+        })
+    }
+
+    pub fn num_documents(&self) -> usize {
+        self.doc_starts.len()
+    }
+}
+
+impl<D: Dataset> Dataset for PackedDataset<D> {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+    fn read(&self, start: usize, len: usize) -> Result<Vec<usize>, GraphError> {
+        self.inner.read(start, len)
+    }
+    fn sample<R: Rng>(
+        &self,
+        rng: &mut R,
+        batch_size: usize,
+        context_size: usize,
+    ) -> Result<Batch, GraphError> {
+        let len = self.len();
+        if len == 0 {
+            return Err(GraphError::InvalidConfig("the dataset is empty".into()));
+        }
+        let mut xs = Vec::with_capacity(batch_size * context_size);
+        let mut ys = Vec::with_capacity(batch_size * context_size);
+        for _ in 0..batch_size {
+            let mut pos = rng.gen_range(0..len);
+            let mut all = Vec::with_capacity(context_size + 1);
+            while all.len() < context_size + 1 {
+                let count = usize::min(len - pos, context_size + 1 - all.len());
+                all.extend(self.read(pos, count)?);
+                if all.len() < context_size + 1 {
+                    if all.last() != Some(&self.eos) {
+                        all.push(self.eos);
+                    }
+                    pos = self.doc_starts[rng.gen_range(0..self.doc_starts.len())];
+                }
+            }
+            all.truncate(context_size + 1);
+            xs.extend(&all[0..context_size]);
+            ys.extend(&all[1..context_size + 1]);
+        }
+        Ok(Batch {
+            xs: Tensor::raw(&[batch_size, context_size], xs)?,
+            ys: Tensor::raw(&[batch_size, context_size], ys)?,
+            weights: None,
+            attention_mask: None,
+            prefix: None,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct SftExample {
+    prompt: String,
+    completion: String,
+}
+
+// (Prompt, completion) pairs for supervised fine-tuning. Each row of a batch holds a single
+// example, padded on the right, and only the completion tokens contribute to the loss.
+// Examples longer than the context lose the beginning of their prompts first, then the end
+// of their completions.
+// Read as a plain dataset, the examples are concatenated.
+pub struct SftDataset {
+    tokens: Vec<usize>,
+    // Offset, prompt length and total length of each example
+    examples: Vec<(usize, usize, usize)>,
+    pad: usize,
+}
+
+impl SftDataset {
+    pub fn new(examples: &[(Vec<usize>, Vec<usize>)], pad: usize) -> Self {
+        let mut tokens = Vec::new();
+        let mut offsets = Vec::new();
+        for (prompt, completion) in examples {
+            offsets.push((tokens.len(), prompt.len(), prompt.len() + completion.len()));
+            tokens.extend(prompt);
+            tokens.extend(completion);
+        }
+        Self {
+            tokens,
+            examples: offsets,
+            pad,
+        }
+    }
+
+    // Reads a JSON Lines file of `{"prompt": ..., "completion": ...}` objects. Completions
+    // are terminated with `eos`, which is also used for padding.
+    pub fn from_jsonl<P: AsRef<Path>, T: Tokenizer>(
+        path: P,
+        tokenizer: &T,
+        eos: usize,
+    ) -> Result<Self, GraphError> {
+        let mut examples = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let example: SftExample = serde_json::from_str(&line)
+                .map_err(|e| GraphError::DeserializationError(format!("bad SFT example: {}", e)))?;
+            let mut completion = tokenizer.tokenize(&example.completion);
+            completion.push(eos);
+            examples.push((tokenizer.tokenize(&example.prompt), completion));
+        }
+        Ok(Self::new(&examples, eos))
+    }
+
+    pub fn num_examples(&self) -> usize {
+        self.examples.len()
+    }
+}
+
+impl Dataset for SftDataset {
+    fn len(&self) -> usize {
+        self.tokens.len()
+    }
+    fn read(&self, start: usize, len: usize) -> Result<Vec<usize>, GraphError> {
+        Ok(self.tokens[start..start + len].to_vec())
+    }
+    fn sample<R: Rng>(
+        &self,
+        rng: &mut R,
+        batch_size: usize,
+        context_size: usize,
+    ) -> Result<Batch, GraphError> {
+        if self.examples.is_empty() {
+            return Err(GraphError::InvalidConfig("the dataset is empty".into()));
+        }
+        let mut xs = Vec::with_capacity(batch_size * context_size);
+        let mut ys = Vec::with_capacity(batch_size * context_size);
+        let mut weights = Vec::with_capacity(batch_size * context_size);
+        let mut mask = Vec::with_capacity(batch_size * context_size);
+        let mut prefix = Vec::with_capacity(batch_size * context_size);
+        for _ in 0..batch_size {
+            let (offset, prompt_len, len) = self.examples[rng.gen_range(0..self.examples.len())];
+            let tokens = &self.tokens[offset..offset + len];
+            let row = CompletionRow::new(tokens, prompt_len, context_size, self.pad);
+            xs.extend(row.xs);
+            ys.extend(row.ys);
+            weights.extend(row.weights);
+            mask.extend(row.mask);
+            prefix.extend(row.prefix);
+        }
+        let shape = [batch_size, context_size];
+        Ok(Batch {
+            xs: Tensor::raw(&shape, xs)?,
+            ys: Tensor::raw(&shape, ys)?,
+            weights: Some(Tensor::raw(&shape, weights)?),
+            attention_mask: Some(Tensor::raw(&shape, mask)?),
+            prefix: Some(Tensor::raw(&shape, prefix)?),
+        })
+    }
+}
+
+// A prompt followed by its completion as a row of a batch, padded to `context_size` tokens.
+// Only the completion tokens have a loss weight, and the prompt is the prefix of the row.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CompletionRow {
+    pub xs: Vec<usize>,
+    pub ys: Vec<usize>,
+    pub weights: Vec<Float>,
+    pub mask: Vec<Float>,
+    pub prefix: Vec<Float>,
+}
+
+impl CompletionRow {
+    pub fn new(tokens: &[usize], prompt_len: usize, context_size: usize, pad: usize) -> Self {
+        let mut row = Self::default();
+        // The last prompt token is kept, it predicts the first completion token
+        let num_pairs = tokens.len().saturating_sub(1);
+        let start = num_pairs
+            .saturating_sub(context_size)
+            .min(prompt_len.saturating_sub(1));
+        let end = usize::min(num_pairs, start + context_size);
+        for i in start..end {
+            row.xs.push(tokens[i]);
+            row.ys.push(tokens[i + 1]);
+            row.weights.push(if i + 1 >= prompt_len { 1. } else { 0. });
+            row.mask.push(1.);
+            row.prefix.push(if i < prompt_len { 1. } else { 0. });
+        }
+        for _ in end - start..context_size {
+            row.xs.push(pad);
+            row.ys.push(pad);
+            row.weights.push(0.);
+            row.mask.push(0.);
+            row.prefix.push(0.);
+        }
+        row
+    }
+}
+
+#[derive(Deserialize)]
+struct PreferenceExample {
+    prompt: String,
+    chosen: String,
+    rejected: String,
+}
+
+// Two completions of a prompt, the chosen one being preferred to the rejected one, for
+// `GPT::train_dpo`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreferencePair {
+    pub prompt: Vec<usize>,
+    pub chosen: Vec<usize>,
+    pub rejected: Vec<usize>,
+}
+
+impl PreferencePair {
+    // Reads a JSON Lines file of `{"prompt": ..., "chosen": ..., "rejected": ...}` objects.
+    // Completions are terminated with `eos`.
+    pub fn from_jsonl<P: AsRef<Path>, T: Tokenizer>(
+        path: P,
+        tokenizer: &T,
+        eos: usize,
+    ) -> Result<Vec<Self>, GraphError> {
+        let mut pairs = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let example: PreferenceExample = serde_json::from_str(&line).map_err(|e| {
+                GraphError::DeserializationError(format!("bad preference pair: {}", e))
+            })?;
+            let completion = |text: &str| {
+                let mut tokens = tokenizer.tokenize(text);
+                tokens.push(eos);
+                tokens
+            };
+            pairs.push(Self {
+                prompt: tokenizer.tokenize(&example.prompt),
+                chosen: completion(&example.chosen),
+                rejected: completion(&example.rejected),
+            });
+        }
+        Ok(pairs)
+    }
+}
+
+// Token files start with a 16 bytes header: the magic bytes, the version of the format, the
+// number of bytes per token (2 or 4) and a reserved word, all little-endian. The tokens
+// follow, with the given width.
+const TOKEN_FILE_MAGIC: &[u8; 4] = b"FGTK";
+const TOKEN_FILE_VERSION: u32 = 1;
+const TOKEN_FILE_HEADER_SIZE: usize = 16;
+
+// Writes tokens as a token file, using 16-bit tokens when the ids fit
+pub fn write_token_file<P: AsRef<Path>>(path: P, tokens: &[usize]) -> Result<(), GraphError> {
+    let max = tokens.iter().cloned().max().unwrap_or_default();
+    let mut writer = TokenWriter::create(path, max)?;
+    writer.write(tokens)?;
+    writer.finish()
+}
+
+// Writes a token file a few tokens at a time. The width of the tokens is chosen up front,
+// from the largest id the file may hold.
+pub struct TokenWriter {
+    file: BufWriter<File>,
+    width: u32,
+    max: usize,
+}
+
+impl TokenWriter {
+    pub fn create<P: AsRef<Path>>(path: P, max: usize) -> Result<Self, GraphError> {
+        if max > u32::MAX as usize {
+            return Err(GraphError::InvalidConfig(
+                "token ids should fit in 32 bits".into(),
+            ));
+        }
+        let width: u32 = if max <= u16::MAX as usize { 2 } else { 4 };
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(TOKEN_FILE_MAGIC)?;
+        file.write_all(&TOKEN_FILE_VERSION.to_le_bytes())?;
+        file.write_all(&width.to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?;
+        Ok(Self { file, width, max })
+    }
+
+    pub fn write(&mut self, tokens: &[usize]) -> Result<(), GraphError> {
+        for t in tokens {
+            if *t > self.max {
+                return Err(GraphError::InvalidConfig(format!(
+                    "token id {} is larger than {}",
+                    t, self.max
+                )));
+            }
+            match self.width {
+                2 => self.file.write_all(&(*t as u16).to_le_bytes())?,
+                _ => self.file.write_all(&(*t as u32).to_le_bytes())?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), GraphError> {
+        Ok(self.file.flush()?)
+    }
+}
+
+// Width of the tokens of a token file, checked against its header and its size in bytes
+fn token_width(header: &[u8], size: usize) -> Result<usize, GraphError> {
+    let invalid = |msg: &str| {
+        Err(GraphError::DeserializationError(format!(
+            "token file: {}",
+            msg
+        )))
+    };
+    if size < TOKEN_FILE_HEADER_SIZE || &header[0..4] != TOKEN_FILE_MAGIC {
+        return invalid("bad magic bytes");
+    }
+    let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+    if word(4) != TOKEN_FILE_VERSION {
+        return invalid(&format!("unsupported version {}", word(4)));
+    }
+    let width = word(8) as usize;
+    if width != 2 && width != 4 {
+        return invalid(&format!("unsupported token width {}", width));
+    }
+    if !(size - TOKEN_FILE_HEADER_SIZE).is_multiple_of(width) {
+        return invalid("truncated tokens");
+    }
+    Ok(width)
+}
+
+fn decode_tokens(bytes: &[u8], width: usize) -> Vec<usize> {
+    match width {
+        2 => bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+            .collect(),
+        _ => bytes
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .collect(),
+    }
+}
+
+// Token file mapped in memory, so that only the pages that are sampled get loaded
+pub struct MmapDataset {
+    mmap: Mmap,
+    width: usize,
+    len: usize,
+}
+
+impl MmapDataset {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, GraphError> {
+        let file = File::open(path)?;
+        // The file must not be modified while it's mapped
+        let mmap = unsafe { Mmap::map(&file)? };
+        let width = token_width(&mmap, mmap.len())?;
+        let len = (mmap.len() - TOKEN_FILE_HEADER_SIZE) / width;
+        Ok(Self { mmap, width, len })
+    }
+}
+
+impl Dataset for MmapDataset {
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn read(&self, start: usize, len: usize) -> Result<Vec<usize>, GraphError> {
+        let bytes = &self.mmap[TOKEN_FILE_HEADER_SIZE + start * self.width
+            ..TOKEN_FILE_HEADER_SIZE + (start + len) * self.width];
+        Ok(decode_tokens(bytes, self.width))
+    }
+}
+
+// Number of bytes of text tokenized at once by `TextDataset` (Rounded up to whole lines)
+const TEXT_CHUNK_SIZE: usize = 1 << 20;
+
+// What a token cache of `TextDataset` was built from
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct TextCacheKey {
+    // `Tokenizer::fingerprint`
+    tokenizer: u64,
+    // Path, size and modification time (In nanoseconds) of each file
+    files: Vec<(PathBuf, u64, Option<u128>)>,
+}
+
+impl TextCacheKey {
+    fn new<P: AsRef<Path>, T: Tokenizer>(files: &[P], tokenizer: &T) -> Result<Self, GraphError> {
+        let mut key = Self {
+            tokenizer: tokenizer.fingerprint(),
+            files: Vec::new(),
+        };
+        for path in files {
+            let metadata = std::fs::metadata(path)?;
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_nanos());
+            key.files
+                .push((path.as_ref().to_path_buf(), metadata.len(), modified));
+        }
+        Ok(key)
+    }
+}
+
+// Raw text files, tokenized on the fly into a token file cached on disk, which is then mapped
+// in memory (See `MmapDataset`). The files are read and tokenized a chunk of lines at a time,
+// so they don't have to fit in memory. The cache is reused by later runs, as long as the files
+// and the tokenizer don't change.
+pub struct TextDataset {
+    tokens: MmapDataset,
+}
+
+impl TextDataset {
+    // The cache is written at `cache`, along with `<cache>.json` describing what it was built
+    // from (Written last, so that an interrupted tokenization starts over)
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>, T: Tokenizer>(
+        files: &[P],
+        tokenizer: &T,
+        cache: Q,
+    ) -> Result<Self, GraphError> {
+        let cache = cache.as_ref();
+        let mut key_path = cache.as_os_str().to_owned();
+        key_path.push(".json");
+        let key = TextCacheKey::new(files, tokenizer)?;
+        let cached = std::fs::read_to_string(&key_path)
+            .ok()
+            .and_then(|json| serde_json::from_str::<TextCacheKey>(&json).ok());
+        if cached.as_ref() != Some(&key) || !cache.is_file() {
+            if cached.is_some() {
+                std::fs::remove_file(&key_path)?;
+            }
+            let mut writer = TokenWriter::create(cache, tokenizer.vocab_size().saturating_sub(1))?;
+            for path in files {
+                let mut file = BufReader::new(File::open(path)?);
+                let mut chunk = String::new();
+                while file.read_line(&mut chunk)? > 0 {
+                    if chunk.len() >= TEXT_CHUNK_SIZE {
+                        writer.write(&tokenizer.tokenize(&chunk))?;
+                        chunk.clear();
+                    }
+                }
+                writer.write(&tokenizer.tokenize(&chunk))?;
+            }
+            writer.finish()?;
+            let json = serde_json::to_string_pretty(&key)
+                .map_err(|e| GraphError::DeserializationError(e.to_string()))?;
+            std::fs::write(&key_path, json)?;
+        }
+        Ok(Self {
+            tokens: MmapDataset::open(cache)?,
+        })
+    }
+}
+
+impl Dataset for TextDataset {
+    fn len(&self) -> usize {
+        self.tokens.len()
+    }
+    fn read(&self, start: usize, len: usize) -> Result<Vec<usize>, GraphError> {
+        self.tokens.read(start, len)
+    }
+}
+
+// The object-safe part of `Dataset`, so that datasets of different types can be mixed
+trait DynDataset: Sync {
+    fn dyn_len(&self) -> usize;
+    fn dyn_read(&self, start: usize, len: usize) -> Result<Vec<usize>, GraphError>;
+    fn dyn_sample(
+        &self,
+        rng: &mut dyn RngCore,
+        batch_size: usize,
+        context_size: usize,
+    ) -> Result<Batch, GraphError>;
+}
+
+impl<D: Dataset + Sync> DynDataset for D {
+    fn dyn_len(&self) -> usize {
+        self.len()
+    }
+    fn dyn_read(&self, start: usize, len: usize) -> Result<Vec<usize>, GraphError> {
+        self.read(start, len)
+    }
+    fn dyn_sample(
+        &self,
+        mut rng: &mut dyn RngCore,
+        batch_size: usize,
+        context_size: usize,
+    ) -> Result<Batch, GraphError> {
+        self.sample(&mut rng, batch_size, context_size)
+    }
+}
+
+struct MixedSource<'a> {
+    name: String,
+    dataset: Box<dyn DynDataset + 'a>,
+    weight: Float,
+    // Number of sequences sampled from the source so far
+    sampled: AtomicUsize,
+}
+
+// Several datasets sampled at once, e.g. 80% of the sequences from one corpus and 20% from
+// another. The source of each sequence of a batch is drawn according to the weights, and the
+// number of sequences drawn from each source is logged (At the debug level) and kept in
+// `sampled`. Read as a whole, the dataset is the concatenation of its sources.
+#[derive(Default)]
+pub struct MixedDataset<'a> {
+    sources: Vec<MixedSource<'a>>,
+    index: Option<WeightedIndex<Float>>,
+}
+
+impl<'a> MixedDataset<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Weights are relative, they don't have to sum up to one
+    pub fn add<D: Dataset + Sync + 'a>(
+        &mut self,
+        name: &str,
+        dataset: D,
+        weight: Float,
+    ) -> Result<(), GraphError> {
+        if !(weight > 0. && weight.is_finite()) {
+            return Err(GraphError::InvalidConfig(format!(
+                "the weight of dataset {} should be positive",
+                name
+            )));
+        }
+        if dataset.is_empty() {
+            return Err(GraphError::InvalidConfig(format!(
+                "dataset {} is empty",
+                name
+            )));
+        }
+        self.sources.push(MixedSource {
+            name: name.into(),
+            dataset: Box::new(dataset),
+            weight,
+            sampled: AtomicUsize::new(0),
+        });
+        self.index = WeightedIndex::new(self.sources.iter().map(|s| s.weight)).ok();
+        Ok(())
+    }
+
+    // Names of the sources, and the number of sequences sampled from each of them
+    pub fn sampled(&self) -> Vec<(&str, usize)> {
+        self.sources
+            .iter()
+            .map(|s| (s.name.as_str(), s.sampled.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+impl Dataset for MixedDataset<'_> {
+    fn len(&self) -> usize {
+        self.sources.iter().map(|s| s.dataset.dyn_len()).sum()
+    }
+
+    fn read(&self, mut start: usize, mut len: usize) -> Result<Vec<usize>, GraphError> {
+        let mut tokens = Vec::with_capacity(len);
+        for source in self.sources.iter() {
+            let source_len = source.dataset.dyn_len();
+            if start >= source_len {
+                start -= source_len;
+                continue;
+            }
+            let count = usize::min(len, source_len - start);
+            tokens.extend(source.dataset.dyn_read(start, count)?);
+            len -= count;
+            start = 0;
+            if len == 0 {
+                break;
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn sample<R: Rng>(
+        &self,
+        rng: &mut R,
+        batch_size: usize,
+        context_size: usize,
+    ) -> Result<Batch, GraphError> {
+        let index = self
+            .index
+            .as_ref()
+            .ok_or_else(|| GraphError::InvalidConfig("the dataset is empty".into()))?;
+        let mut counts = vec![0; self.sources.len()];
+        let mut rows = Vec::with_capacity(batch_size);
+        for _ in 0..batch_size {
+            let i = index.sample(rng);
+            counts[i] += 1;
+            rows.push(self.sources[i].dataset.dyn_sample(rng, 1, context_size)?);
+        }
+        for (source, count) in self.sources.iter().zip(counts.iter()) {
+            source.sampled.fetch_add(*count, Ordering::Relaxed);
+        }
+        tracing::debug!(
+            mix = ?self.sources.iter().map(|s| &s.name).zip(counts).collect::<Vec<_>>(),
+            "sampled a mixed batch"
+        );
+        concat_rows(rows, context_size)
+    }
+}
+
+// Stacks batches of the same context size into one. Weights and attention masks default to
+// ones for the rows without them, prefixes to zeros.
+fn concat_rows(rows: Vec<Batch>, context_size: usize) -> Result<Batch, GraphError> {
+    let batch_size = rows.iter().map(|b| b.xs.shape()[0]).sum::<usize>();
+    let shape = [batch_size, context_size];
+    let optional =
+        |f: fn(&Batch) -> &Option<Tensor<Float>>, default: Float| -> Result<_, GraphError> {
+            if rows.iter().all(|b| f(b).is_none()) {
+                return Ok(None);
+            }
+            let values = rows
+                .iter()
+                .flat_map(|b| match f(b) {
+                    Some(t) => t.blob().to_vec(),
+                    None => vec![default; b.xs.size()],
+                })
+                .collect();
+            Ok(Some(Tensor::raw(&shape, values)?))
+        };
+    Ok(Batch {
+        weights: optional(|b| &b.weights, 1.)?,
+        attention_mask: optional(|b| &b.attention_mask, 1.)?,
+        prefix: optional(|b| &b.prefix, 0.)?,
+        xs: Tensor::raw(
+            &shape,
+            rows.iter().flat_map(|b| b.xs.blob().to_vec()).collect(),
+        )?,
+        ys: Tensor::raw(
+            &shape,
+            rows.iter().flat_map(|b| b.ys.blob().to_vec()).collect(),
+        )?,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PrefetchConfig {
+    // Number of threads sampling the batches
+    pub workers: usize,
+    // Number of batches sampled ahead of the training loop
+    pub depth: usize,
+}
+
+// Batches of a fixed size, sampled on worker threads ahead of the training loop that consumes
+// them. Batch `i` is sampled with stream `i` of a ChaCha RNG seeded with `seed`, and worker
+// `w` samples the batches `w`, `w + workers`..., so the batches don't depend on the
+// scheduling of the workers. The workers stop once the `Prefetcher` is dropped.
+pub struct Prefetcher {
+    receivers: Vec<Receiver<Result<Batch, GraphError>>>,
+    next: usize,
+}
+
+impl Prefetcher {
+    pub fn spawn<'scope, 'env, D: Dataset + Sync + ?Sized>(
+        scope: &'scope Scope<'scope, 'env>,
+        dataset: &'env D,
+        config: &PrefetchConfig,
+        batch_size: usize,
+        context_size: usize,
+        seed: [u8; 32],
+    ) -> Result<Self, GraphError> {
+        let workers = config.workers;
+        if workers == 0 {
+            return Err(GraphError::InvalidConfig(
+                "prefetching needs at least one worker".into(),
+            ));
+        }
+        let capacity = config.depth.div_ceil(workers).max(1);
+        let receivers = (0..workers)
+            .map(|w| {
+                let (sender, receiver) = sync_channel(capacity);
+                scope.spawn(move || {
+                    for i in (w..).step_by(workers) {
+                        let mut rng = ChaCha8Rng::from_seed(seed);
+                        rng.set_stream(i as u64);
+                        let batch = dataset.sample(&mut rng, batch_size, context_size);
+                        let failed = batch.is_err();
+                        // Fails once the receiver is dropped
+                        if sender.send(batch).is_err() || failed {
+                            break;
+                        }
+                    }
+                });
+                receiver
+            })
+            .collect();
+        Ok(Self { receivers, next: 0 })
+    }
+
+    pub fn next_batch(&mut self) -> Result<Batch, GraphError> {
+        let receiver = &self.receivers[self.next % self.receivers.len()];
+        self.next += 1;
+        receiver.recv().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "a prefetching worker stopped",
+            )
+        })?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tensor::TensorOps;
+
+    #[test]
+    fn test_file_dataset() {
+        let path = std::env::temp_dir().join(format!("femto_gpt_tokens_{}", std::process::id()));
+        let tokens = (0..10).collect::<Vec<usize>>();
+        write_token_file(&path, &tokens).unwrap();
+        let dataset = FileDataset::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // Raw tokens without a header are not a token file
+        let raw = path.with_extension("raw");
+        std::fs::write(&raw, [0u8; 40]).unwrap();
+        assert!(matches!(
+            FileDataset::open(&raw),
+            Err(GraphError::DeserializationError(_))
+        ));
+        std::fs::remove_file(&raw).unwrap();
+        assert_eq!(dataset.len(), 10);
+        assert_eq!(dataset.read(3, 4).unwrap(), [3, 4, 5, 6]);
+
+        // Targets are the inputs shifted by one, wrapping around the end
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let batch = dataset.sample(&mut rng, 2, 12).unwrap();
+            assert_eq!(batch.xs.shape(), [2, 12]);
+            for (x, y) in batch.xs.blob().iter().zip(batch.ys.blob()) {
+                assert_eq!((x + 1) % 10, *y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mmap_dataset() {
+        let path = std::env::temp_dir().join(format!("femto_gpt_mmap_{}", std::process::id()));
+        for tokens in [vec![1, 2, 65535, 4], vec![1, 2, 65536, 4]] {
+            write_token_file(&path, &tokens).unwrap();
+            let dataset = MmapDataset::open(&path).unwrap();
+            assert_eq!(dataset.len(), 4);
+            assert_eq!(dataset.read(0, 4).unwrap(), tokens);
+            assert_eq!(dataset.read(1, 2).unwrap(), tokens[1..3]);
+        }
+        std::fs::write(&path, b"not a token file").unwrap();
+        assert!(matches!(
+            MmapDataset::open(&path),
+            Err(GraphError::DeserializationError(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_prefetcher() {
+        let dataset = (0..1000).collect::<Vec<usize>>();
+        let sample = |workers: usize| {
+            std::thread::scope(|scope| {
+                let config = PrefetchConfig { workers, depth: 4 };
+                let mut batches =
+                    Prefetcher::spawn(scope, &dataset, &config, 2, 8, [7; 32]).unwrap();
+                (0..10)
+                    .map(|_| batches.next_batch().unwrap().xs.blob().to_vec())
+                    .collect::<Vec<_>>()
+            })
+        };
+        // The batches don't depend on the number of workers
+        let batches = sample(1);
+        assert_eq!(batches, sample(3));
+        let mut rng = ChaCha8Rng::from_seed([7; 32]);
+        rng.set_stream(9);
+        assert_eq!(
+            batches[9],
+            dataset.sample(&mut rng, 2, 8).unwrap().xs.blob()
+        );
+
+        let config = PrefetchConfig {
+            workers: 0,
+            depth: 4,
+        };
+        let empty = Vec::<usize>::new();
+        std::thread::scope(|scope| {
+            assert!(Prefetcher::spawn(scope, &dataset, &config, 2, 8, [7; 32]).is_err());
+            let config = PrefetchConfig {
+                workers: 2,
+                ..config
+            };
+            let mut batches = Prefetcher::spawn(scope, &empty, &config, 2, 8, [7; 32]).unwrap();
+            assert!(matches!(
+                batches.next_batch(),
+                Err(GraphError::InvalidConfig(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_text_dataset() {
+        use crate::tokenizer::SimpleTokenizer;
+        let dir = std::env::temp_dir().join(format!("femto_gpt_text_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [dir.join("a.txt"), dir.join("b.txt")];
+        std::fs::write(&files[0], "hello\nworld\n").unwrap();
+        std::fs::write(&files[1], "hold").unwrap();
+        let tokenizer = SimpleTokenizer::new("hello\nworld\nhold");
+        let cache = dir.join("tokens.bin");
+        let dataset = TextDataset::open(&files, &tokenizer, &cache).unwrap();
+        let expected = tokenizer.tokenize("hello\nworld\nhold");
+        assert_eq!(dataset.read(0, dataset.len()).unwrap(), expected);
+        drop(dataset);
+
+        // The cache is reused until a file changes
+        write_token_file(&cache, &[1, 2, 3]).unwrap();
+        let dataset = TextDataset::open(&files, &tokenizer, &cache).unwrap();
+        assert_eq!(dataset.len(), 3);
+        drop(dataset);
+        std::fs::write(&files[1], "hold\nhold").unwrap();
+        let dataset = TextDataset::open(&files, &tokenizer, &cache).unwrap();
+        assert_eq!(dataset.len(), expected.len() + 5);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mixed_dataset() {
+        let mut dataset = MixedDataset::new();
+        dataset.add("ones", vec![1; 50], 4.).unwrap();
+        dataset.add("twos", vec![2; 10], 1.).unwrap();
+        assert!(dataset.add("empty", Vec::new(), 1.).is_err());
+        assert!(dataset.add("zero", vec![3; 10], 0.).is_err());
+        assert_eq!(dataset.len(), 60);
+        assert_eq!(dataset.read(48, 4).unwrap(), [1, 1, 2, 2]);
+
+        let mut rng = rand::thread_rng();
+        let batch = dataset.sample(&mut rng, 1000, 4).unwrap();
+        assert_eq!(batch.xs.shape(), [1000, 4]);
+        assert!(batch.weights.is_none() && batch.attention_mask.is_none());
+        let ones = batch.xs.blob().iter().filter(|t| **t == 1).count() / 4;
+        assert!((700..900).contains(&ones));
+        assert_eq!(dataset.sampled(), [("ones", ones), ("twos", 1000 - ones)]);
+
+        // Rows of plain datasets are unmasked when mixed with padded examples
+        let mut dataset = MixedDataset::new();
+        dataset.add("plain", vec![1; 10], 1.).unwrap();
+        dataset
+            .add("sft", SftDataset::new(&[(vec![3], vec![4])], 0), 1.)
+            .unwrap();
+        let batch = dataset.sample(&mut rng, 100, 4).unwrap();
+        let mask = batch.attention_mask.unwrap();
+        for (x, m) in batch.xs.blob().iter().zip(mask.blob()) {
+            assert_eq!(*m == 0., *x == 0);
+        }
+        assert!(batch.weights.is_some());
+    }
+
+    #[test]
+    fn test_packed_dataset() {
+        const EOS: usize = 0;
+        // The last document is not terminated
+        let dataset = PackedDataset::new(vec![1, 2, EOS, 3, 4, 5, EOS, 6], EOS).unwrap();
+        assert_eq!(dataset.num_documents(), 3);
+        let allowed = |x: usize, y: usize| match x {
+            EOS => [1, 3, 6].contains(&y),
+            2 | 5 | 6 => y == EOS,
+            _ => y == x + 1,
+        };
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let batch = dataset.sample(&mut rng, 4, 10).unwrap();
+            assert_eq!(batch.xs.shape(), [4, 10]);
+            let rows = batch.xs.blob().chunks(10).zip(batch.ys.blob().chunks(10));
+            for (row_xs, row_ys) in rows {
+                assert_eq!(row_xs[1..], row_ys[..9]);
+                for (x, y) in row_xs.iter().zip(row_ys) {
+                    assert!(allowed(*x, *y), "{} -> {}", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sft_dataset() {
+        let dataset = SftDataset::new(&[(vec![1, 2, 3], vec![4, 5])], 0);
+        assert_eq!(dataset.read(0, 5).unwrap(), [1, 2, 3, 4, 5]);
+        let mut rng = rand::thread_rng();
+
+        // Padded on the right, only completion tokens are predicted
+        let batch = dataset.sample(&mut rng, 1, 6).unwrap();
+        assert_eq!(batch.xs.blob(), &[1, 2, 3, 4, 0, 0]);
+        assert_eq!(batch.ys.blob(), &[2, 3, 4, 5, 0, 0]);
+        assert_eq!(batch.weights.unwrap().blob(), &[0., 0., 1., 1., 0., 0.]);
+        assert_eq!(
+            batch.attention_mask.unwrap().blob(),
+            &[1., 1., 1., 1., 0., 0.]
+        );
+
+        // Truncated from the start of the prompt
+        let batch = dataset.sample(&mut rng, 1, 3).unwrap();
+        assert_eq!(batch.xs.blob(), &[2, 3, 4]);
+        assert_eq!(batch.ys.blob(), &[3, 4, 5]);
+        assert_eq!(batch.weights.unwrap().blob(), &[0., 1., 1.]);
+
+        // Then from the end of the completion
+        let batch = dataset.sample(&mut rng, 1, 1).unwrap();
+        assert_eq!(batch.xs.blob(), &[3]);
+        assert_eq!(batch.ys.blob(), &[4]);
+    }
+}
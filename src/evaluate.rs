@@ -0,0 +1,123 @@
+use crate::gpt::GPT;
+use crate::graph::{Graph, GraphError};
+use crate::tensor::Float;
+use crate::tokenizer::Tokenizer;
+use std::path::Path;
+
+// Evaluation of a model on held-out texts, for comparing training runs.
+//
+// Texts are scored with `GPT::score`, in windows of the context size overlapping by half,
+// so texts of any length can be evaluated. Losses are in nats per token, bits per character
+// normalize them by the length of the text instead, so that they can be compared between
+// models using different tokenizers.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextEvaluation {
+    pub name: String,
+    // Predicted tokens (All of them but the first), and the characters they decode to
+    pub num_tokens: usize,
+    pub num_chars: usize,
+    // Sum of the negative log-probabilities of the predicted tokens
+    pub nll: f64,
+}
+
+impl TextEvaluation {
+    // Mean cross-entropy per token
+    pub fn loss(&self) -> Float {
+        (self.nll / self.num_tokens.max(1) as f64) as Float
+    }
+
+    pub fn perplexity(&self) -> Float {
+        self.loss().exp()
+    }
+
+    pub fn bits_per_char(&self) -> Float {
+        (self.nll / std::f64::consts::LN_2 / self.num_chars.max(1) as f64) as Float
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvaluationReport {
+    pub files: Vec<TextEvaluation>,
+}
+
+impl EvaluationReport {
+    // All the files together, weighted by their number of tokens
+    pub fn total(&self) -> TextEvaluation {
+        TextEvaluation {
+            name: "Total".into(),
+            num_tokens: self.files.iter().map(|e| e.num_tokens).sum(),
+            num_chars: self.files.iter().map(|e| e.num_chars).sum(),
+            nll: self.files.iter().map(|e| e.nll).sum(),
+        }
+    }
+}
+
+impl std::fmt::Display for EvaluationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let row = |f: &mut std::fmt::Formatter<'_>, e: &TextEvaluation| {
+            writeln!(
+                f,
+                "{}: {} tokens, {} chars, loss {:.4}, perplexity {:.3}, {:.4} bits per char",
+                e.name,
+                e.num_tokens,
+                e.num_chars,
+                e.loss(),
+                e.perplexity(),
+                e.bits_per_char()
+            )
+        };
+        for file in self.files.iter() {
+            row(f, file)?;
+        }
+        if self.files.len() > 1 {
+            row(f, &self.total())?;
+        }
+        Ok(())
+    }
+}
+
+pub fn evaluate_text<G: Graph, T: Tokenizer>(
+    gpt: &mut GPT<G>,
+    tokenizer: &T,
+    name: &str,
+    text: &str,
+) -> Result<TextEvaluation, GraphError> {
+    let tokens = tokenizer.tokenize(text);
+    if tokens.len() < 2 {
+        return Err(GraphError::InvalidConfig(format!(
+            "{} has less than two tokens",
+            name
+        )));
+    }
+    let scores = gpt.score(&tokens)?;
+    let first = tokenizer.untokenize(&tokens[..1]).chars().count();
+    Ok(TextEvaluation {
+        name: name.into(),
+        num_tokens: scores.len(),
+        num_chars: text.chars().count().saturating_sub(first),
+        nll: -scores.iter().map(|s| *s as f64).sum::<f64>(),
+    })
+}
+
+// Evaluates the model on every file, named by its path in the report
+pub fn evaluate_files<G: Graph, T: Tokenizer, P: AsRef<Path>>(
+    gpt: &mut GPT<G>,
+    tokenizer: &T,
+    files: &[P],
+) -> Result<EvaluationReport, GraphError> {
+    let mut report = EvaluationReport { files: Vec::new() };
+    for path in files {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        let eval = evaluate_text(gpt, tokenizer, &path.display().to_string(), &text)?;
+        tracing::info!(
+            "Evaluated {}: loss {:.4}, {:.4} bits per char",
+            eval.name,
+            eval.loss(),
+            eval.bits_per_char()
+        );
+        report.files.push(eval);
+    }
+    Ok(report)
+}
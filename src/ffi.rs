@@ -0,0 +1,352 @@
+// C ABI for embedding femtoGPT in other languages, declared in `include/femto_gpt.h`. Pointer
+// arguments must be valid for the documented lengths there, and objects returned by these
+// functions must be released with the matching `_free` function.
+#![allow(clippy::missing_safety_doc)]
+
+use crate::gpt::{GPTConfig, TrainingState, GPT};
+use crate::graph::{CpuGraph, GraphError};
+use crate::sampling::SamplingParams;
+use crate::tensor::Float;
+use crate::tokenizer::{SavedTokenizer, SimpleTokenizer, Tokenizer};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+pub struct FemtoGptModel {
+    gpt: GPT<CpuGraph>,
+    tokenizer: Option<SavedTokenizer>,
+}
+
+// C counterpart of `SamplingParams`, where zeros disable the optional fields
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FemtoGptSamplingOptions {
+    pub max_tokens: usize,
+    // 0 always picks the most likely token
+    pub temperature: f32,
+    pub top_k: usize,
+    pub top_p: f32,
+    pub repetition_penalty: f32,
+    pub presence_penalty: f32,
+    pub frequency_penalty: f32,
+    // 0 seeds every call randomly
+    pub seed: u64,
+    pub stop_tokens: *const u32,
+    pub num_stop_tokens: usize,
+}
+
+unsafe fn sampling_params(
+    options: *const FemtoGptSamplingOptions,
+) -> Result<SamplingParams, GraphError> {
+    let options = options.as_ref().ok_or_else(|| null_error("options"))?;
+    let stop_tokens = if options.stop_tokens.is_null() {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(options.stop_tokens, options.num_stop_tokens)
+            .iter()
+            .map(|t| *t as usize)
+            .collect()
+    };
+    let disabled_if_zero = |v: f32| (v != 0.).then_some(v as Float);
+    Ok(SamplingParams {
+        max_tokens: options.max_tokens,
+        temperature: options.temperature as Float,
+        top_k: (options.top_k != 0).then_some(options.top_k),
+        top_p: disabled_if_zero(options.top_p),
+        repetition_penalty: disabled_if_zero(options.repetition_penalty).unwrap_or(1.),
+        presence_penalty: options.presence_penalty as Float,
+        frequency_penalty: options.frequency_penalty as Float,
+        seed: (options.seed != 0).then_some(options.seed),
+        stop_tokens,
+    })
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+// Runs `f`, recording its error as the last error of the thread and returning `failed`
+// instead. Panics are caught, as they must not unwind into the caller.
+fn guard<T, F: FnOnce() -> Result<T, GraphError>>(failed: T, f: F) -> T {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            set_error(e.to_string());
+            failed
+        }
+        Err(_) => {
+            set_error("femtoGPT panicked".into());
+            failed
+        }
+    }
+}
+
+fn null_error(name: &str) -> GraphError {
+    GraphError::InvalidConfig(format!("{} is null", name))
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str, GraphError> {
+    if s.is_null() {
+        return Err(null_error(name));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|e| GraphError::InvalidConfig(format!("{} is not UTF-8: {}", name, e)))
+}
+
+unsafe fn generate(
+    gpt: &mut GPT<CpuGraph>,
+    prompt: &[usize],
+    options: *const FemtoGptSamplingOptions,
+) -> Result<Vec<usize>, GraphError> {
+    // Unless the options have a seed
+    let mut rng = StdRng::from_entropy();
+    let tokens = gpt.infer(&mut rng, prompt, &sampling_params(options)?, |_| {})?;
+    Ok(tokens[prompt.len()..].to_vec())
+}
+
+// Creates a model from the bytes of a checkpoint, with its stored config unless a JSON
+// `GPTConfig` is given. The text the `SimpleTokenizer` was built from may be given (Or
+// null) for generating from text, with checkpoints saved without their tokenizer.
+#[no_mangle]
+pub unsafe extern "C" fn femto_gpt_model_new(
+    config_json: *const c_char,
+    checkpoint: *const u8,
+    checkpoint_len: usize,
+    tokenizer_text: *const c_char,
+) -> *mut FemtoGptModel {
+    guard(ptr::null_mut(), || {
+        if checkpoint.is_null() {
+            return Err(null_error("checkpoint"));
+        }
+        let mut state =
+            TrainingState::from_bytes(std::slice::from_raw_parts(checkpoint, checkpoint_len))?;
+        if let Some(manifest) = &state.manifest {
+            manifest.verify(&state)?;
+        }
+        if !config_json.is_null() {
+            let config = serde_json::from_str(str_arg(config_json, "config")?)
+                .map_err(|e| GraphError::DeserializationError(e.to_string()))?;
+            state.config = Some(config);
+        }
+        let config: GPTConfig = state.config.clone().ok_or_else(|| null_error("config"))?;
+        let tokenizer = if tokenizer_text.is_null() {
+            state.tokenizer.clone()
+        } else {
+            let tokenizer = SimpleTokenizer::new(str_arg(tokenizer_text, "tokenizer")?);
+            if tokenizer.vocab_size() != config.vocab_size {
+                return Err(GraphError::InvalidConfig(format!(
+                    "the tokenizer has {} tokens, the model {}",
+                    tokenizer.vocab_size(),
+                    config.vocab_size
+                )));
+            }
+            Some(SavedTokenizer::Simple(tokenizer))
+        };
+        let gpt = GPT::from_training_state(CpuGraph::new(), None, state)?;
+        if let Some(tokenizer) = &tokenizer {
+            gpt.check_tokenizer(tokenizer)?;
+        }
+        Ok(Box::into_raw(Box::new(FemtoGptModel { gpt, tokenizer })))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn femto_gpt_model_free(model: *mut FemtoGptModel) {
+    if !model.is_null() {
+        drop(Box::from_raw(model));
+    }
+}
+
+// Writes up to `options.max_tokens` generated tokens to `out`, returning their count, or -1
+// on errors
+#[no_mangle]
+pub unsafe extern "C" fn femto_gpt_generate(
+    model: *mut FemtoGptModel,
+    prompt: *const u32,
+    prompt_len: usize,
+    options: *const FemtoGptSamplingOptions,
+    out: *mut u32,
+    out_capacity: usize,
+) -> isize {
+    guard(-1, || {
+        if prompt.is_null() || out.is_null() {
+            return Err(null_error("prompt or out"));
+        }
+        let max_tokens = options.as_ref().map(|o| o.max_tokens).unwrap_or_default();
+        if out_capacity < max_tokens {
+            return Err(GraphError::InvalidConfig(format!(
+                "out can not hold {} tokens",
+                max_tokens
+            )));
+        }
+        let prompt = std::slice::from_raw_parts(prompt, prompt_len)
+            .iter()
+            .map(|t| *t as usize)
+            .collect::<Vec<_>>();
+        let model = model.as_mut().ok_or_else(|| null_error("model"))?;
+        let tokens = generate(&mut model.gpt, &prompt, options)?;
+        for (i, t) in tokens.iter().enumerate() {
+            *out.add(i) = *t as u32;
+        }
+        Ok(tokens.len() as isize)
+    })
+}
+
+// Returns the text generated after `prompt` (Which requires a tokenizer), to be released with
+// `femto_gpt_string_free`, or null on errors
+#[no_mangle]
+pub unsafe extern "C" fn femto_gpt_generate_text(
+    model: *mut FemtoGptModel,
+    prompt: *const c_char,
+    options: *const FemtoGptSamplingOptions,
+) -> *mut c_char {
+    guard(ptr::null_mut(), || {
+        let model = model.as_mut().ok_or_else(|| null_error("model"))?;
+        let tokenizer = model
+            .tokenizer
+            .as_ref()
+            .ok_or_else(|| GraphError::InvalidConfig("the model has no tokenizer".into()))?;
+        let prompt = tokenizer.tokenize(str_arg(prompt, "prompt")?);
+        let text = tokenizer.untokenize(&generate(&mut model.gpt, &prompt, options)?);
+        Ok(CString::new(text)
+            .map_err(|e| GraphError::InvalidConfig(e.to_string()))?
+            .into_raw())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn femto_gpt_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+// The message of the last error on this thread, or null. It remains valid until the next
+// error on the thread.
+#[no_mangle]
+pub extern "C" fn femto_gpt_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::*;
+
+    #[test]
+    fn test_ffi() {
+        let text = "hello world";
+        let tokenizer = SimpleTokenizer::new(text);
+        let config = GPTConfig {
+            vocab_size: tokenizer.vocab_size(),
+            num_tokens: 4,
+            positional_encoding: PositionalEncoding::Learned,
+            bias: true,
+            ..GPTConfig::tiny()
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, config.clone()).unwrap();
+        let checkpoint = gpt.get_training_state().unwrap().to_bytes().unwrap();
+        let config_json = CString::new(serde_json::to_string(&config).unwrap()).unwrap();
+        let tokenizer_text = CString::new(text).unwrap();
+        let options = FemtoGptSamplingOptions {
+            max_tokens: 5,
+            temperature: 0.,
+            top_k: 0,
+            top_p: 0.,
+            repetition_penalty: 0.,
+            presence_penalty: 0.,
+            frequency_penalty: 0.,
+            seed: 0,
+            stop_tokens: ptr::null(),
+            num_stop_tokens: 0,
+        };
+        let params = SamplingParams {
+            max_tokens: 5,
+            temperature: 0.,
+            ..Default::default()
+        };
+
+        unsafe {
+            let model = femto_gpt_model_new(
+                config_json.as_ptr(),
+                checkpoint.as_ptr(),
+                checkpoint.len(),
+                tokenizer_text.as_ptr(),
+            );
+            assert!(!model.is_null());
+            assert_eq!(sampling_params(&options).unwrap().seed, None);
+            let seeded = FemtoGptSamplingOptions { seed: 3, ..options };
+            assert_eq!(sampling_params(&seeded).unwrap().seed, Some(3));
+
+            // Same tokens as the model the checkpoint was saved from
+            let prompt = [1u32, 2];
+            let mut out = [0u32; 5];
+            let count =
+                femto_gpt_generate(model, prompt.as_ptr(), 2, &options, out.as_mut_ptr(), 5);
+            assert_eq!(count, 5);
+            let expected = gpt.infer(&mut rng, &[1, 2], &params, |_| {}).unwrap();
+            assert_eq!(
+                out.iter().map(|t| *t as usize).collect::<Vec<_>>(),
+                expected[2..]
+            );
+
+            // Generation stops before the first stop token
+            let stop = [expected[3] as u32];
+            let stopping = FemtoGptSamplingOptions {
+                stop_tokens: stop.as_ptr(),
+                num_stop_tokens: 1,
+                ..options
+            };
+            let count =
+                femto_gpt_generate(model, prompt.as_ptr(), 2, &stopping, out.as_mut_ptr(), 5);
+            let first = expected[2..]
+                .iter()
+                .position(|t| *t == expected[3])
+                .unwrap();
+            assert_eq!(count, first as isize);
+
+            let prompt = CString::new("he").unwrap();
+            let generated = femto_gpt_generate_text(model, prompt.as_ptr(), &options);
+            assert_eq!(
+                CStr::from_ptr(generated).to_str().unwrap().chars().count(),
+                5
+            );
+            femto_gpt_string_free(generated);
+
+            let count = femto_gpt_generate(
+                model,
+                prompt.as_ptr() as _,
+                1,
+                &options,
+                out.as_mut_ptr(),
+                4,
+            );
+            assert_eq!(count, -1);
+            femto_gpt_model_free(model);
+
+            // Checkpoints store their config
+            let model = femto_gpt_model_new(
+                ptr::null(),
+                checkpoint.as_ptr(),
+                checkpoint.len(),
+                ptr::null(),
+            );
+            assert!(!model.is_null());
+            femto_gpt_model_free(model);
+
+            let model = femto_gpt_model_new(c"{}".as_ptr(), checkpoint.as_ptr(), 1, ptr::null());
+            assert!(model.is_null());
+            assert!(!femto_gpt_last_error().is_null());
+        }
+    }
+}
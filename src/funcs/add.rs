@@ -13,25 +13,48 @@ impl Add {
 }
 impl Function for Add {
     fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        let mut out = Tensor::scalar(0.);
+        self.run_into(inps, training, &mut out)?;
+        Ok(out)
+    }
+    fn run_into(
         &mut self,
         inps: &[&GeneralTensor],
         _training: bool,
-    ) -> Result<Tensor<f32>, TensorError> {
-        inps[0].as_float()? + inps[1].as_float()?
+        out: &mut Tensor<Float>,
+    ) -> Result<(), TensorError> {
+        let (a, b) = (inps[0].as_float()?, inps[1].as_float()?);
+        // The smaller tensor is broadcast over the bigger one, see `broadcast_shape`
+        let (a, b) = if a.dim() >= b.dim() { (a, b) } else { (b, a) };
+        if !a.shape().ends_with(b.shape()) {
+            *out = binary(a, b, |a, b| a + b)?;
+            return Ok(());
+        }
+        let out = out.resize(a.shape());
+        for (a, out) in a.blob().chunks(b.size()).zip(out.chunks_mut(b.size())) {
+            simd::add(a, b.blob(), out);
+        }
+        Ok(())
     }
     fn grad(
         &self,
-        _inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError> {
-        Ok(vec![out_grad.clone(), out_grad.clone()])
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        inps.iter()
+            .map(|inp| unbroadcast(out_grad, inp.shape()))
+            .collect()
     }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::add::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::add::gpu_impl(out_id, inps))
     }
 }
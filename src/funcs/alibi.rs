@@ -0,0 +1,60 @@
+use super::Function;
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+
+// Attention with Linear Biases (https://arxiv.org/abs/2108.12409)
+// Penalizes the attention score between positions i and j by `slope * (i - j)`. As with
+// `TrilMask`, the rows of `[..., n, m]` scores are the last `n` positions.
+#[derive(Debug, Clone)]
+pub struct Alibi {
+    pub(crate) slope: Float,
+}
+impl Alibi {
+    pub fn new(slope: Float) -> Box<dyn Function> {
+        Box::new(Self { slope })
+    }
+
+    // Geometric sequence of slopes, starting at 2^(-8/num_heads)
+    pub fn slope(head: usize, num_heads: usize) -> Float {
+        (2. as Float).powf(-8. * (head + 1) as Float / num_heads as Float)
+    }
+}
+
+impl Function for Alibi {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        inps[0].as_float()?.map(2, |t| {
+            let (n, m) = (t.shape()[0], t.shape()[1]);
+            let offset = m.checked_sub(n).ok_or(TensorError::UnexpectedShape)?;
+            let t_blob = t.blob();
+            let mut dat = Vec::with_capacity(n * m);
+            for i in 0..n {
+                for j in 0..m {
+                    let distance = (i + offset).abs_diff(j) as Float;
+                    dat.push(t_blob[i * m + j] - self.slope * distance);
+                }
+            }
+            Tensor::raw(&[n, m], dat)
+        })
+    }
+    fn grad(
+        &self,
+        _inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        Ok(vec![out_grad.clone()])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::alibi::gpu_impl(out_id, inps, self.slope))
+    }
+}
@@ -17,7 +17,7 @@ impl Function for Cat {
         &mut self,
         inps: &[&GeneralTensor],
         _training: bool,
-    ) -> Result<Tensor<f32>, TensorError> {
+    ) -> Result<Tensor<Float>, TensorError> {
         let inps = inps
             .iter()
             .map(|t| t.as_float())
@@ -46,8 +46,8 @@ impl Function for Cat {
     fn grad(
         &self,
         inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
         let inps = inps
             .iter()
             .map(|t| t.as_float())
@@ -82,7 +82,7 @@ impl Function for Cat {
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::cat::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::cat::gpu_impl(out_id, inps))
     }
 }
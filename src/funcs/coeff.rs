@@ -6,10 +6,10 @@ use super::{gpu, GpuFunction, TensorId};
 
 #[derive(Debug, Clone)]
 pub struct Coeff {
-    coeff: f32,
+    pub(crate) coeff: Float,
 }
 impl Coeff {
-    pub fn new(coeff: f32) -> Box<dyn Function> {
+    pub fn new(coeff: Float) -> Box<dyn Function> {
         Box::new(Self { coeff })
     }
 }
@@ -18,14 +18,26 @@ impl Function for Coeff {
         &mut self,
         inps: &[&GeneralTensor],
         _training: bool,
-    ) -> Result<Tensor<f32>, TensorError> {
+    ) -> Result<Tensor<Float>, TensorError> {
         Ok(inps[0].as_float()?.map_values(|f| f * self.coeff))
     }
+    fn run_into(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+        out: &mut Tensor<Float>,
+    ) -> Result<(), TensorError> {
+        let inp = inps[0].as_float()?;
+        for (o, f) in out.resize(inp.shape()).iter_mut().zip(inp.blob()) {
+            *o = f * self.coeff;
+        }
+        Ok(())
+    }
     fn grad(
         &self,
         _inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
         Ok(vec![out_grad.map_values(|d| d * self.coeff)])
     }
     fn clone_box(&self) -> Box<dyn Function> {
@@ -33,7 +45,7 @@ impl Function for Coeff {
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::coeff::gpu_impl(out_id, inps, self.coeff)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::coeff::gpu_impl(out_id, inps, self.coeff))
     }
 }
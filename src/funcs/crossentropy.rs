@@ -8,7 +8,7 @@ use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct CrossEntropy {
-    exp_output: Arc<Tensor<f32>>,
+    exp_output: Arc<Tensor<Float>>,
 }
 impl CrossEntropy {
     pub fn new() -> Box<dyn Function> {
@@ -22,11 +22,11 @@ impl Function for CrossEntropy {
         &mut self,
         inps: &[&GeneralTensor],
         _training: bool,
-    ) -> Result<Tensor<f32>, TensorError> {
+    ) -> Result<Tensor<Float>, TensorError> {
         let inp = inps[0].as_float()?;
         let target = inps[1].as_usize()?;
 
-        self.exp_output = Arc::new(inp.map(1, |o| Ok(o.map_values(|f| f.exp())))?);
+        self.exp_output = Arc::new(inp.map_values(|f| f.exp()));
 
         Tensor::raw(
             target.shape(),
@@ -36,7 +36,7 @@ impl Function for CrossEntropy {
                 .zip(target.blob().iter())
                 .zip(self.exp_output.keep_right(1)?.inners().iter())
                 .map(|((o, t), o_exps)| {
-                    let sum = o_exps.blob().iter().sum::<f32>();
+                    let sum = o_exps.blob().iter().sum::<Float>();
                     let loss = sum.ln() - o.blob()[*t];
                     loss
                 })
@@ -46,8 +46,8 @@ impl Function for CrossEntropy {
     fn grad(
         &self,
         inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
         let inp = inps[0].as_float()?;
         let target = inps[1].as_usize()?;
 
@@ -63,7 +63,7 @@ impl Function for CrossEntropy {
                 .zip(out_grad.blob().iter())
                 .map(|((o_exps, t), g)| {
                     let o_exps = o_exps.blob();
-                    let sum = o_exps.iter().sum::<f32>();
+                    let sum = o_exps.iter().sum::<Float>();
                     let sum_inv = 1. / sum;
 
                     let grad = (0..classes)
@@ -88,7 +88,7 @@ impl Function for CrossEntropy {
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::crossentropy::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::crossentropy::gpu_impl(out_id, inps))
     }
 }
@@ -3,30 +3,43 @@ use crate::tensor::*;
 
 #[cfg(feature = "gpu")]
 use super::{gpu, GpuFunction, TensorId};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
 use std::sync::Arc;
 
+// Zeroes each element of its first input with the probability given by its second (A
+// single-element tensor, so that the rate can change during training), and scales the
+// others up to compensate. Does nothing outside of training.
 #[derive(Debug, Clone)]
 pub struct Dropout {
-    mask: Arc<Tensor<f32>>,
-    rate: f32,
+    mask: Arc<Tensor<Float>>,
+    // Masks are drawn from the thread's RNG until the function is seeded
+    rng: Option<ChaCha8Rng>,
 }
 impl Dropout {
-    pub fn new(rate: f32) -> Box<dyn Function> {
+    pub fn new() -> Box<dyn Function> {
         Box::new(Self {
-            rate,
             mask: Arc::new(Tensor::scalar(1.)),
+            rng: None,
         })
     }
 }
 
 impl Function for Dropout {
-    fn run(&mut self, inps: &[&GeneralTensor], training: bool) -> Result<Tensor<f32>, TensorError> {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
         let inp = inps[0].as_float()?;
-        Ok(if training {
-            let mut rng = rand::thread_rng();
-            let rnd = Tensor::<f32>::rand_range(&mut rng, 0., 1.0, inp.shape());
-            let scale = 1. / (1. - self.rate);
-            self.mask = Arc::new(rnd.map_values(|v| if v > self.rate { scale } else { 0. }));
+        let rate = inps[1].as_float()?.blob()[0];
+        Ok(if training && rate > 0. {
+            let rnd = match &mut self.rng {
+                Some(rng) => Tensor::<Float>::rand_range(rng, 0., 1.0, inp.shape()),
+                None => Tensor::<Float>::rand_range(&mut rand::thread_rng(), 0., 1.0, inp.shape()),
+            };
+            let scale = 1. / (1. - rate);
+            self.mask = Arc::new(rnd.map_values(|v| if v > rate { scale } else { 0. }));
             (inp * &self.mask.view())?
         } else {
             self.mask = Arc::new(Tensor::scalar(1.));
@@ -35,17 +48,23 @@ impl Function for Dropout {
     }
     fn grad(
         &self,
-        _inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError> {
-        Ok(vec![(out_grad * &self.mask.view())?])
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        Ok(vec![
+            (out_grad * &self.mask.view())?,
+            Tensor::zeros(inps[1].shape()),
+        ])
     }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
+    fn reseed(&mut self, seed: u64) {
+        self.rng = Some(ChaCha8Rng::seed_from_u64(seed));
+    }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::dropout::gpu_impl(out_id, inps, self.rate)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::dropout::gpu_impl(out_id, inps))
     }
 }
@@ -4,6 +4,8 @@ use crate::tensor::*;
 #[cfg(feature = "gpu")]
 use super::{gpu, GpuFunction, TensorId};
 
+// Looks up the rows of an embedding table (Second input) by the token indices (First input).
+// The gradient of the table is scattered (And accumulated) back into the rows that were looked up.
 #[derive(Debug, Clone)]
 pub struct Embedding;
 impl Embedding {
@@ -16,7 +18,7 @@ impl Function for Embedding {
         &mut self,
         inps: &[&GeneralTensor],
         _training: bool,
-    ) -> Result<Tensor<f32>, TensorError> {
+    ) -> Result<Tensor<Float>, TensorError> {
         let inp = inps[0].as_usize()?;
         let emb = inps[1].as_float()?;
         if emb.dim() != 2 {
@@ -27,10 +29,10 @@ impl Function for Embedding {
     fn grad(
         &self,
         inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
         let inp = inps[0].as_usize()?;
-        let mut grad = Tensor::<f32>::zeros(inps[1].as_float()?.shape());
+        let mut grad = Tensor::<Float>::zeros(inps[1].as_float()?.shape());
         for (ch, embed) in inp
             .blob()
             .iter()
@@ -46,7 +48,30 @@ impl Function for Embedding {
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::embedding::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::embedding::gpu_impl(out_id, inps))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grad_accumulates_repeated_indices() {
+        let table = Tensor::<Float>::raw(&[3, 2], vec![1., 2., 3., 4., 5., 6.]).unwrap();
+        let inp = Tensor::<usize>::raw(&[2, 2], vec![2, 0, 2, 2]).unwrap();
+        let inps = [&GeneralTensor::Usize(inp), &GeneralTensor::Float(table)];
+
+        let mut emb = Embedding::new();
+        let out = emb.run(&inps, false).unwrap();
+        assert_eq!(out.shape(), &[2, 2, 2]);
+        assert_eq!(out.blob(), &[5., 6., 1., 2., 5., 6., 5., 6.]);
+
+        let out_grad =
+            Tensor::<Float>::raw(&[2, 2, 2], vec![1., 1., 2., 2., 3., 3., 4., 4.]).unwrap();
+        let grads = emb.grad(&inps, &out_grad).unwrap();
+        assert_eq!(grads[1].shape(), &[3, 2]);
+        assert_eq!(grads[1].blob(), &[2., 2., 0., 0., 8., 8.]);
     }
 }
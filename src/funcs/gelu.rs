@@ -4,14 +4,14 @@ use crate::tensor::*;
 #[cfg(feature = "gpu")]
 use super::{gpu, GpuFunction, TensorId};
 
-const SQRT_2_OVER_PI: f32 = 0.7978845608;
-const GELU_CONST: f32 = 0.044715;
+pub(crate) const SQRT_2_OVER_PI: Float = 0.7978845608;
+pub(crate) const GELU_CONST: Float = 0.044715;
 
-fn gelu(x: f32) -> f32 {
+fn gelu(x: Float) -> Float {
     0.5 * x * ((SQRT_2_OVER_PI * (x + GELU_CONST * x.powi(3))).tanh() + 1.)
 }
 
-fn gelu_prime(x: f32) -> f32 {
+fn gelu_prime(x: Float) -> Float {
     let x2 = x * x;
     let x3 = x2 * x;
     let v = SQRT_2_OVER_PI * x + SQRT_2_OVER_PI * GELU_CONST * x3;
@@ -32,14 +32,26 @@ impl Function for Gelu {
         &mut self,
         inps: &[&GeneralTensor],
         _training: bool,
-    ) -> Result<Tensor<f32>, TensorError> {
+    ) -> Result<Tensor<Float>, TensorError> {
         Ok(inps[0].as_float()?.map_values(gelu))
     }
+    fn run_into(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+        out: &mut Tensor<Float>,
+    ) -> Result<(), TensorError> {
+        let inp = inps[0].as_float()?;
+        for (o, f) in out.resize(inp.shape()).iter_mut().zip(inp.blob()) {
+            *o = gelu(*f);
+        }
+        Ok(())
+    }
     fn grad(
         &self,
         inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
         let der = inps[0].as_float()?.map_values(gelu_prime);
         Ok(vec![(&der * out_grad)?])
     }
@@ -48,8 +60,8 @@ impl Function for Gelu {
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::gelu::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::gelu::gpu_impl(out_id, inps))
     }
 }
 
@@ -70,7 +82,7 @@ mod tests {
         while x < 5. {
             let numeric = ((gelu_f64(x as f64 + EPSILON) - gelu_f64(x as f64 - EPSILON))
                 / EPSILON
-                / 2.) as f32;
+                / 2.) as Float;
             let symbolic = gelu_prime(x);
             let diff = numeric / symbolic;
             x += 0.01;
@@ -3,7 +3,8 @@ use super::*;
 pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
     let a_size = inps[0].iter().fold(1, |a, b| a * b);
     let b_size = inps[1].iter().fold(1, |a, b| a * b);
-    assert!(b_size <= a_size);
+    // The OpenCL kernel only broadcasts a tensor whose shape ends the shape of the other one
+    assert!(inps[0].ends_with(&inps[1]));
     let repeats = a_size / b_size;
     let works = std::cmp::max(a_size, b_size);
 
@@ -1,19 +1,19 @@
 use super::*;
 
-pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>], rate: f32) -> GpuFunction {
+pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
     let works = inps[0].iter().fold(1, |a, b| a * b);
     const M: usize = 2147483647;
-    let threshold = (rate as f64) * (M as f64);
-    let gain = 1.0 / (1.0 - rate);
 
     let forward_source_code = format!(
         "__kernel void calc_{out_id}(
                         __global float* out,
                         __global ulong* seeds,
-                        __global float* a) {{
+                        __global float* a,
+                        __global float* rate) {{
         uint id = get_global_id(0);
         ulong A = 16807;
         ulong M = {M};
+        double threshold = (double)rate[0] * {M}.0;
 
         if(id < {works}) {{
             if(seeds[id] == 0) {{
@@ -21,7 +21,7 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>], rate: f32) -> GpuFunction
             }}
             seeds[id] = (seeds[id] * A) % M;
 
-            if(seeds[id] < {threshold}) {{
+            if(seeds[id] < threshold) {{
                 out[id] = 0.0;
             }} else {{
                 out[id] = a[id];
@@ -36,12 +36,14 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>], rate: f32) -> GpuFunction
                         __global float* out_grad,
                         __global ulong* seeds,
                         __global float* a,
-                        __global float* a_grad) {{
+                        __global float* a_grad,
+                        __global float* rate,
+                        __global float* rate_grad) {{
         uint id = get_global_id(0);
         if(id < {works}) {{
-            bool dropped = seeds[id] < {threshold};
+            bool dropped = seeds[id] < (double)rate[0] * {M}.0;
             if(!dropped) {{
-                a_grad[id] += out_grad[id] * {gain};
+                a_grad[id] += out_grad[id] / (1.0f - rate[0]);
             }}
         }}
     }}"
@@ -4,6 +4,26 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
     let n = inps[0][inps[0].len() - 1];
     let works = inps[0][..inps[0].len() - 1].iter().fold(1, |a, b| a * b);
 
+    // Bias is optional
+    let has_bias = inps.len() > 2;
+    let bias_arg = if has_bias {
+        ",\n                        __global float* bias"
+    } else {
+        ""
+    };
+    let bias_grad_args = if has_bias {
+        ",\n                        __global float* bias,\n                        __global float* bias_grad"
+    } else {
+        ""
+    };
+    let plus_bias = if has_bias { " + bias[i]" } else { "" };
+    let minus_bias = if has_bias { " - bias[ii]" } else { "" };
+    let bias_grad = if has_bias {
+        "bias_grad[id] += bias_sum;"
+    } else {
+        ""
+    };
+
     let forward_source_code = format!(
         "__kernel void calc_{out_id}(
                         __global float* out,
@@ -11,8 +31,7 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
                         __global float* avg_buff,
                         __global float* sigma2_buff,
                         __global float* a,
-                        __global float* coeff,
-                        __global float* bias) {{
+                        __global float* coeff{bias_arg}) {{
         uint id = get_global_id(0);
         if(id < {works}) {{
             a += id * {n};
@@ -32,7 +51,7 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
             sigma2_buff[id] = var;
             float var_inv = 1. / sqrt(var + 1e-5);
             for(uint i = 0; i < {n}; i++) {{
-                out[i] = (a[i] - avg) * var_inv * coeff[i] + bias[i];
+                out[i] = (a[i] - avg) * var_inv * coeff[i]{plus_bias};
             }}
         }}
     }}"
@@ -48,9 +67,7 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
                         __global float* inp,
                         __global float* inp_grad,
                         __global float* coeff,
-                        __global float* coeff_grad,
-                        __global float* bias,
-                        __global float* bias_grad) {{
+                        __global float* coeff_grad{bias_grad_args}) {{
         uint wid = get_global_id(0);
         uint id = wid / {n};
         uint i = wid % {n};
@@ -63,7 +80,7 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
 
         if(wid < {works} * {n}) {{
             for(uint ii = 0; ii < {n}; ii++) {{
-                coeff_grad_temp[ii] = (out[ii] - bias[ii]) * out_grad[ii] / coeff[ii];
+                coeff_grad_temp[ii] = (out[ii]{minus_bias}) * out_grad[ii] / coeff[ii];
             }}
 
             float n_inv = 1.0 / {n};
@@ -101,9 +118,7 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
                         __global float* inp,
                         __global float* inp_grad,
                         __global float* coeff,
-                        __global float* coeff_grad,
-                        __global float* bias,
-                        __global float* bias_grad) {{
+                        __global float* coeff_grad{bias_grad_args}) {{
         uint id = get_global_id(0);
         if(id < {n}) {{
             float coeff_sum = 0.0;
@@ -113,7 +128,7 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
                 bias_sum += out_grad[i * {n} + id];
             }}
             coeff_grad[id] += coeff_sum;
-            bias_grad[id] += bias_sum;
+            {bias_grad}
         }}
     }}"
     );
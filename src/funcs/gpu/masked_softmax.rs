@@ -0,0 +1,73 @@
+use super::*;
+
+pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>], coeff: f32, n: usize) -> GpuFunction {
+    let works = inps[0][..inps[0].len() - 1].iter().fold(1, |a, b| a * b);
+
+    let forward_source_code = format!(
+        "__kernel void calc_{out_id}(
+                        __global float* out,
+                        __global float* a) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            uint row = id % {n};
+            a += id * {n};
+            out += id * {n};
+            float mx = a[0] * {coeff};
+            for(uint i = 1; i <= row; i++) {{
+                if(a[i] * {coeff} > mx) {{
+                    mx = a[i] * {coeff};
+                }}
+            }}
+            float sum = 0.;
+            for(uint i = 0; i <= row; i++) {{
+                sum += exp(a[i] * {coeff} - mx);
+            }}
+            for(uint i = 0; i < {n}; i++) {{
+                if(i <= row) {{
+                    out[i] = exp(a[i] * {coeff} - mx) / sum;
+                }} else {{
+                    out[i] = 0.;
+                }}
+            }}
+        }}
+    }}"
+    );
+
+    let backward_source_code = format!(
+        "__kernel void grad_{out_id}(
+                        __global float* out,
+                        __global float* out_grad,
+                        __global float* a,
+                        __global float* a_grad) {{
+        uint wid = get_global_id(0);
+        uint id = wid / {n};
+        uint i = wid % {n};
+        if(wid < {works} * {n}) {{
+            out += id * {n};
+            out_grad += id * {n};
+            a_grad += id * {n};
+            float dot = 0.0;
+            for(uint j = 0; j < {n}; j++) {{
+                dot += out[j] * out_grad[j];
+            }}
+            a_grad[i] += out[i] * (out_grad[i] - dot) * {coeff};
+        }}
+    }}"
+    );
+
+    GpuFunction {
+        forward_funcs: vec![KernelCall {
+            source_code: forward_source_code,
+            kernel_name: format!("calc_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        backward_funcs: vec![KernelCall {
+            source_code: backward_source_code,
+            kernel_name: format!("grad_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works * n,
+        }],
+        shared_buffers: vec![],
+    }
+}
@@ -1,4 +1,5 @@
 pub mod add;
+pub mod alibi;
 pub mod cat;
 pub mod coeff;
 pub mod crossentropy;
@@ -6,11 +7,21 @@ pub mod dropout;
 pub mod embedding;
 pub mod gelu;
 pub mod layer_norm;
+pub mod masked_softmax;
 pub mod matmul;
+pub mod matmul_add;
+pub mod mul;
+pub mod padmask;
+pub mod positional;
+pub mod prefixmask;
 pub mod relu;
+pub mod rope;
+pub mod silu;
+pub mod sinusoidal;
 pub mod softmax;
 pub mod transpose;
 pub mod trilmask;
+pub mod zloss;
 use crate::graph::TensorId;
 
 #[derive(Clone, Debug)]
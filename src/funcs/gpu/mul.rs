@@ -0,0 +1,51 @@
+use super::*;
+
+pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
+    // The OpenCL kernel does not broadcast
+    assert_eq!(inps[0], inps[1]);
+    let works = inps[0].iter().fold(1, |a, b| a * b);
+
+    let forward_source_code = format!(
+        "__kernel void calc_{out_id}(
+                        __global float* out,
+                        __global float* a,
+                        __global float* b) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            out[id] = a[id] * b[id];
+        }}
+    }}"
+    );
+
+    let backward_source_code = format!(
+        "__kernel void grad_{out_id}(
+                        __global float* out,
+                        __global float* out_grad,
+                        __global float* a,
+                        __global float* a_grad,
+                        __global float* b,
+                        __global float* b_grad) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            a_grad[id] += out_grad[id] * b[id];
+            b_grad[id] += out_grad[id] * a[id];
+        }}
+    }}"
+    );
+
+    GpuFunction {
+        forward_funcs: vec![KernelCall {
+            source_code: forward_source_code,
+            kernel_name: format!("calc_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        backward_funcs: vec![KernelCall {
+            source_code: backward_source_code,
+            kernel_name: format!("grad_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        shared_buffers: vec![],
+    }
+}
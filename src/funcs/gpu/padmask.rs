@@ -0,0 +1,54 @@
+use super::*;
+
+pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
+    let works = inps[0].iter().fold(1, |a, b| a * b);
+    let n = inps[0].last().unwrap();
+    let masked = crate::funcs::padmask::MASKED_SCORE;
+
+    let forward_source_code = format!(
+        "__kernel void calc_{out_id}(
+                        __global float* out,
+                        __global float* a,
+                        __global float* mask) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            uint col = id / ({n} * {n}) * {n} + id % {n};
+            out[id] = mask[col] > 0.0 ? a[id] : {masked:.1}f;
+        }}
+    }}"
+    );
+
+    let backward_source_code = format!(
+        "__kernel void grad_{out_id}(
+                        __global float* out,
+                        __global float* out_grad,
+                        __global float* a,
+                        __global float* a_grad,
+                        __global float* mask,
+                        __global float* mask_grad) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            uint col = id / ({n} * {n}) * {n} + id % {n};
+            if(mask[col] > 0.0) {{
+                a_grad[id] += out_grad[id];
+            }}
+        }}
+    }}"
+    );
+
+    GpuFunction {
+        shared_buffers: vec![],
+        forward_funcs: vec![KernelCall {
+            source_code: forward_source_code,
+            kernel_name: format!("calc_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        backward_funcs: vec![KernelCall {
+            source_code: backward_source_code,
+            kernel_name: format!("grad_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+    }
+}
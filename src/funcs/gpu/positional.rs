@@ -0,0 +1,78 @@
+use super::*;
+
+pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
+    let works = inps[0].iter().fold(1, |a, b| a * b);
+    // Rows of the table that are used
+    let rows_size = inps[0][inps[0].len() - 2] * inps[0][inps[0].len() - 1];
+    let repeats = works / rows_size;
+
+    let forward_source_code = format!(
+        "__kernel void calc_{out_id}(
+                        __global float* out,
+                        __global float* a,
+                        __global float* table) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            out[id] = a[id] + table[id % {rows_size}];
+        }}
+    }}"
+    );
+
+    let backward_source_code_part_1 = format!(
+        "__kernel void grad_{out_id}_1(
+                        __global float* out,
+                        __global float* out_grad,
+                        __global float* a,
+                        __global float* a_grad,
+                        __global float* table,
+                        __global float* table_grad) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            a_grad[id] += out_grad[id];
+        }}
+    }}"
+    );
+
+    let backward_source_code_part_2 = format!(
+        "__kernel void grad_{out_id}_2(
+                        __global float* out,
+                        __global float* out_grad,
+                        __global float* a,
+                        __global float* a_grad,
+                        __global float* table,
+                        __global float* table_grad) {{
+        uint id = get_global_id(0);
+        if(id < {rows_size}) {{
+            float sum = 0.0;
+            for(uint i = 0; i < {repeats}; i++) {{
+                sum += out_grad[i * {rows_size} + id];
+            }}
+            table_grad[id] += sum;
+        }}
+    }}"
+    );
+
+    GpuFunction {
+        shared_buffers: vec![],
+        forward_funcs: vec![KernelCall {
+            source_code: forward_source_code,
+            kernel_name: format!("calc_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        backward_funcs: vec![
+            KernelCall {
+                source_code: backward_source_code_part_1,
+                kernel_name: format!("grad_{}_1", out_id),
+                local_work_size: 32,
+                global_work_size: works,
+            },
+            KernelCall {
+                source_code: backward_source_code_part_2,
+                kernel_name: format!("grad_{}_2", out_id),
+                local_work_size: 32,
+                global_work_size: rows_size,
+            },
+        ],
+    }
+}
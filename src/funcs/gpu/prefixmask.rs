@@ -0,0 +1,61 @@
+use super::*;
+
+pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
+    let works = inps[0].iter().fold(1, |a, b| a * b);
+    let n = inps[0].last().unwrap();
+
+    let forward_source_code = format!(
+        "__kernel void calc_{out_id}(
+                        __global float* out,
+                        __global float* a,
+                        __global float* prefix) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            uint row = id / {n} % {n};
+            uint col = id % {n};
+            uint instance = id / ({n} * {n}) * {n};
+            if(col <= row || (prefix[instance + row] > 0.0 && prefix[instance + col] > 0.0)) {{
+                out[id] = a[id];
+            }} else {{
+                out[id] = -INFINITY;
+            }}
+        }}
+    }}"
+    );
+
+    let backward_source_code = format!(
+        "__kernel void grad_{out_id}(
+                        __global float* out,
+                        __global float* out_grad,
+                        __global float* a,
+                        __global float* a_grad,
+                        __global float* prefix,
+                        __global float* prefix_grad) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            uint row = id / {n} % {n};
+            uint col = id % {n};
+            uint instance = id / ({n} * {n}) * {n};
+            if(col <= row || (prefix[instance + row] > 0.0 && prefix[instance + col] > 0.0)) {{
+                a_grad[id] += out_grad[id];
+            }}
+        }}
+    }}"
+    );
+
+    GpuFunction {
+        shared_buffers: vec![],
+        forward_funcs: vec![KernelCall {
+            source_code: forward_source_code,
+            kernel_name: format!("calc_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        backward_funcs: vec![KernelCall {
+            source_code: backward_source_code,
+            kernel_name: format!("grad_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+    }
+}
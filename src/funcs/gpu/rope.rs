@@ -0,0 +1,74 @@
+use super::*;
+
+pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>], base: f32) -> GpuFunction {
+    let d = inps[0][inps[0].len() - 1];
+    let n = inps[0][inps[0].len() - 2];
+    let half = d / 2;
+    let works = inps[0].iter().fold(1, |a, b| a * b) / d * half;
+
+    let forward_source_code = format!(
+        "__kernel void calc_{out_id}(
+                        __global float* out,
+                        __global float* a) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            uint i = id % {half};
+            uint row = id / {half};
+            float p = row % {n};
+            float theta = p * pow({base:.1}f, -2.0f * i / {d});
+            float s = sin(theta);
+            float c = cos(theta);
+            uint offset = row * {d} + 2 * i;
+            float x0 = a[offset];
+            float x1 = a[offset + 1];
+            out[offset] = x0 * c - x1 * s;
+            out[offset + 1] = x0 * s + x1 * c;
+            if({d} % 2 == 1 && i == 0) {{
+                out[row * {d} + {d} - 1] = a[row * {d} + {d} - 1];
+            }}
+        }}
+    }}"
+    );
+
+    let backward_source_code = format!(
+        "__kernel void grad_{out_id}(
+                        __global float* out,
+                        __global float* out_grad,
+                        __global float* a,
+                        __global float* a_grad) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            uint i = id % {half};
+            uint row = id / {half};
+            float p = row % {n};
+            float theta = p * pow({base:.1}f, -2.0f * i / {d});
+            float s = sin(theta);
+            float c = cos(theta);
+            uint offset = row * {d} + 2 * i;
+            float g0 = out_grad[offset];
+            float g1 = out_grad[offset + 1];
+            a_grad[offset] += g0 * c + g1 * s;
+            a_grad[offset + 1] += -g0 * s + g1 * c;
+            if({d} % 2 == 1 && i == 0) {{
+                a_grad[row * {d} + {d} - 1] += out_grad[row * {d} + {d} - 1];
+            }}
+        }}
+    }}"
+    );
+
+    GpuFunction {
+        forward_funcs: vec![KernelCall {
+            source_code: forward_source_code,
+            kernel_name: format!("calc_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        backward_funcs: vec![KernelCall {
+            source_code: backward_source_code,
+            kernel_name: format!("grad_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        shared_buffers: vec![],
+    }
+}
@@ -0,0 +1,54 @@
+use super::*;
+
+pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
+    let works = inps[0].iter().fold(1, |a, b| a * b);
+    let degree = inps[0][inps[0].len() - 1];
+    let num_tokens = inps[0][inps[0].len() - 2];
+
+    let forward_source_code = format!(
+        "__kernel void calc_{out_id}(
+                        __global float* out,
+                        __global float* a) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            uint col = id % {degree};
+            float k = (id / {degree}) % {num_tokens};
+            float factor = pow(10000.0f, 2.0f * (col / 2) / {degree});
+            if(col % 2 == 0) {{
+                out[id] = a[id] + sin(k / factor);
+            }} else {{
+                out[id] = a[id] + cos(k / factor);
+            }}
+        }}
+    }}"
+    );
+
+    let backward_source_code = format!(
+        "__kernel void grad_{out_id}(
+                        __global float* out,
+                        __global float* out_grad,
+                        __global float* a,
+                        __global float* a_grad) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            a_grad[id] += out_grad[id];
+        }}
+    }}"
+    );
+
+    GpuFunction {
+        forward_funcs: vec![KernelCall {
+            source_code: forward_source_code,
+            kernel_name: format!("calc_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        backward_funcs: vec![KernelCall {
+            source_code: backward_source_code,
+            kernel_name: format!("grad_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        shared_buffers: vec![],
+    }
+}
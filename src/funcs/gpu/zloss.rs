@@ -0,0 +1,64 @@
+use super::*;
+
+pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
+    let works = inps[1].iter().fold(1, |a, b| a * b);
+    let classes = inps[0].last().unwrap();
+
+    let forward_source_code = format!(
+        "__kernel void calc_{out_id}(
+                        __global float* out,
+                        __global float* lse_buff,
+                        __global float* inp,
+                        __global ulong* expected) {{
+        uint id = get_global_id(0);
+        inp += {classes} * id;
+        if(id < {works}) {{
+            float mx = inp[0];
+            for(uint i = 1; i < {classes}; i++) {{
+                mx = max(mx, inp[i]);
+            }}
+            float sum = 0.0;
+            for(uint i = 0; i < {classes}; i++) {{
+                sum += exp(inp[i] - mx);
+            }}
+            float lse = mx + log(sum);
+            lse_buff[id] = lse;
+            out[id] = lse * lse;
+        }}
+    }}"
+    );
+
+    let backward_source_code = format!(
+        "__kernel void grad_{out_id}(
+                        __global float* out,
+                        __global float* out_grad,
+                        __global float* lse_buff,
+                        __global float* inp,
+                        __global float* inp_grad,
+                        __global ulong* expected,
+                        __global float* expected_grad) {{
+        uint wid = get_global_id(0);
+        uint id = wid / {classes};
+        if(wid < {works} * {classes}) {{
+            float lse = lse_buff[id];
+            inp_grad[wid] += 2.0 * lse * exp(inp[wid] - lse) * out_grad[id];
+        }}
+    }}"
+    );
+
+    GpuFunction {
+        forward_funcs: vec![KernelCall {
+            source_code: forward_source_code,
+            kernel_name: format!("calc_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        backward_funcs: vec![KernelCall {
+            source_code: backward_source_code,
+            kernel_name: format!("grad_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works * classes,
+        }],
+        shared_buffers: vec![SharedBuffer::Float(works)],
+    }
+}
@@ -6,7 +6,7 @@ use super::{gpu, GpuFunction, TensorId};
 use std::sync::Arc;
 #[derive(Debug, Clone)]
 pub struct LayerNorm {
-    norm: Arc<Tensor<f32>>,
+    pub(crate) norm: Arc<Tensor<Float>>,
 }
 impl LayerNorm {
     pub fn new() -> Box<dyn Function> {
@@ -16,37 +16,42 @@ impl LayerNorm {
     }
 }
 
-const EPSILON: f32 = 1e-5;
+const EPSILON: Float = 1e-5;
 
 impl Function for LayerNorm {
     fn run(
         &mut self,
         inps: &[&GeneralTensor],
         _training: bool,
-    ) -> Result<Tensor<f32>, TensorError> {
+    ) -> Result<Tensor<Float>, TensorError> {
         let inps = inps
             .iter()
             .map(|t| t.as_float())
             .collect::<Result<Vec<_>, TensorError>>()?;
-        self.norm = Arc::new(inps[0].map(1, |l| {
-            let size_inv = 1. / l.size() as f32;
-            let avg = l.blob().iter().sum::<f32>() * size_inv;
-            let var = (l.blob().iter().map(|f| (f - avg).powi(2)).sum::<f32>() * size_inv
-                + EPSILON)
-                .sqrt();
+        let n = inps[0].keep_right(1)?.shape()[1];
+        let blob = inps[0].blob();
+        let mut norm = vec![0.; blob.len()];
+        for_each_row(&mut norm, n, n, |i, norm| {
+            let l = &blob[i * n..(i + 1) * n];
+            let size_inv = 1. / n as Float;
+            let avg = simd::sum(l) * size_inv;
+            let var = (simd::sum_sq_diff(l, avg) * size_inv + EPSILON).sqrt();
             let var_inv = 1. / var;
-            Tensor::raw(
-                l.shape(),
-                l.blob().iter().map(|v| (v - avg) * var_inv).collect(),
-            )
-        })?);
-        &(&self.norm.view() * inps[1])? + inps[2]
+            simd::sub_mul(l, avg, var_inv, norm);
+        });
+        self.norm = Arc::new(Tensor::raw(inps[0].shape(), norm)?);
+        let out = (&self.norm.view() * inps[1])?;
+        // Bias is optional
+        match inps.get(2) {
+            Some(bias) => &out + *bias,
+            None => Ok(out),
+        }
     }
     fn grad(
         &self,
         inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
         let inps = inps
             .iter()
             .map(|t| t.as_float())
@@ -61,10 +66,10 @@ impl Function for LayerNorm {
                 let o_blob = o.blob();
                 let inp1_blob = inps[1].blob();
                 let n = l.size();
-                let n_inv = 1. / n as f32;
-                let avg = l.blob().iter().sum::<f32>() * n_inv;
+                let n_inv = 1. / n as Float;
+                let avg = l.blob().iter().sum::<Float>() * n_inv;
                 let sigma2 =
-                    l.blob().iter().map(|f| (f - avg).powi(2)).sum::<f32>() * n_inv + EPSILON;
+                    l.blob().iter().map(|f| (f - avg).powi(2)).sum::<Float>() * n_inv + EPSILON;
                 let sigma2_inv = 1. / sigma2;
                 let sigma = sigma2.sqrt();
                 let sigma_inv = 1. / sigma;
@@ -89,18 +94,21 @@ impl Function for LayerNorm {
             })
             .flatten()
             .collect::<Vec<_>>();
-        Ok(vec![
+        let mut grads = vec![
             Tensor::raw(out_grad.shape(), grad_inp0)?,
             (out_grad * &self.norm.view())?,
-            out_grad.clone(),
-        ])
+        ];
+        if inps.len() > 2 {
+            grads.push(out_grad.clone());
+        }
+        Ok(grads)
     }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::layer_norm::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::layer_norm::gpu_impl(out_id, inps))
     }
 }
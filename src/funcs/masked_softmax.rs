@@ -0,0 +1,94 @@
+use super::Function;
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+use std::sync::Arc;
+
+// Fused Coeff, TrilMask and Softmax, as applied on attention scores. Every row i of
+// the n x m input is scaled, positions j > i + m - n are masked out, and the softmax is
+// taken over the rest, without materializing the scaled and masked intermediates.
+#[derive(Debug, Clone)]
+pub struct ScaledMaskedSoftmax {
+    pub(crate) coeff: Float,
+    pub(crate) n: usize,
+    out: Arc<Tensor<Float>>,
+}
+impl ScaledMaskedSoftmax {
+    pub fn new(coeff: Float, n: usize) -> Box<dyn Function> {
+        Box::new(Self {
+            coeff,
+            n,
+            out: Arc::new(Tensor::scalar(0.)),
+        })
+    }
+}
+impl Function for ScaledMaskedSoftmax {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        let inp = inps[0].as_float()?;
+        let matrix = inp.keep_right(2)?;
+        let (n, m) = (matrix.shape()[1], matrix.shape()[2]);
+        let offset = m.checked_sub(n).ok_or(TensorError::UnexpectedShape)?;
+        let t_blob = inp.blob();
+        let coeff = self.coeff;
+        let mut dat = vec![0.; t_blob.len()];
+        // Row r is the row r % n of its matrix
+        for_each_row(&mut dat, m, m, |r, out| {
+            let i = r % n.max(1);
+            let row = &t_blob[r * m..r * m + i + offset + 1];
+            let max = row
+                .iter()
+                .fold(Float::NEG_INFINITY, |a, b| Float::max(a, *b * coeff));
+            let sum = row.iter().map(|f| (f * coeff - max).exp()).sum::<Float>();
+            for (o, f) in out.iter_mut().zip(row.iter()) {
+                *o = (f * coeff - max).exp() / sum;
+            }
+        });
+        self.out = Arc::new(Tensor::raw(inp.shape(), dat)?);
+        Ok(self.out.as_ref().clone())
+    }
+    fn grad(
+        &self,
+        _inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        // Masked positions have a zero output, so their gradient vanishes as well
+        let n = *self
+            .out
+            .shape()
+            .last()
+            .ok_or(TensorError::UnexpectedShape)?;
+        let mut grad_inp0 = vec![0.; out_grad.size()];
+        for ((l_blob, o_blob), data) in self
+            .out
+            .blob()
+            .chunks(n.max(1))
+            .zip(out_grad.blob().chunks(n.max(1)))
+            .zip(grad_inp0.chunks_mut(n.max(1)))
+        {
+            let dot = l_blob
+                .iter()
+                .zip(o_blob.iter())
+                .map(|(s, g)| s * g)
+                .sum::<Float>();
+            for ((d, s), g) in data.iter_mut().zip(l_blob).zip(o_blob) {
+                *d = s * (g - dot) * self.coeff;
+            }
+        }
+        Ok(vec![Tensor::raw(out_grad.shape(), grad_inp0)?])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::masked_softmax::gpu_impl(
+            out_id, inps, self.coeff, self.n,
+        ))
+    }
+}
@@ -4,6 +4,24 @@ use crate::tensor::*;
 #[cfg(feature = "gpu")]
 use super::{gpu, GpuFunction, TensorId};
 
+// Gradients of both operands of `a ^ b`, multiplying by their transpositions in place
+pub(crate) fn matmul_grads(
+    a: &Tensor<Float>,
+    b: &Tensor<Float>,
+    out_grad: &Tensor<Float>,
+) -> Result<[Tensor<Float>; 2], TensorError> {
+    let a_grad = matmul(&out_grad.strided(), &b.strided().t()?)?;
+    // A matrix multiplying a batch gets the sum of the gradients of the batch, computed at
+    // once by multiplying all the rows of the batch
+    let b_grad = if b.dim() == 2 && a.dim() > 2 {
+        let rows = a.keep_right(1)?;
+        matmul(&rows.strided().t()?, &out_grad.keep_right(1)?.strided())?
+    } else {
+        matmul(&a.strided().t()?, &out_grad.strided())?
+    };
+    Ok([a_grad, b_grad])
+}
+
 #[derive(Debug, Clone)]
 pub struct MatMul;
 impl MatMul {
@@ -16,33 +34,39 @@ impl Function for MatMul {
         &mut self,
         inps: &[&GeneralTensor],
         _training: bool,
-    ) -> Result<Tensor<f32>, TensorError> {
+    ) -> Result<Tensor<Float>, TensorError> {
         let inps = inps
             .iter()
             .map(|t| t.as_float())
             .collect::<Result<Vec<_>, TensorError>>()?;
         inps[0] ^ inps[1]
     }
+    fn run_into(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+        out: &mut Tensor<Float>,
+    ) -> Result<(), TensorError> {
+        let (a, b) = (inps[0].as_float()?, inps[1].as_float()?);
+        matmul_into(&a.strided(), &b.strided(), out)
+    }
     fn grad(
         &self,
         inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
         let inps = inps
             .iter()
             .map(|t| t.as_float())
             .collect::<Result<Vec<_>, TensorError>>()?;
-        Ok(vec![
-            (out_grad ^ &inps[1].transpose()?)?,
-            (&inps[0].transpose()? ^ out_grad)?,
-        ])
+        Ok(matmul_grads(inps[0], inps[1], out_grad)?.to_vec())
     }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::matmul::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::matmul::gpu_impl(out_id, inps))
     }
 }
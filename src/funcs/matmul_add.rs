@@ -0,0 +1,68 @@
+use super::Function;
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+
+// Fused MatMul followed by the addition of a bias vector (inps[0] ^ inps[1] + inps[2]).
+// The bias is added in-place, saving the allocation of the intermediate product.
+#[derive(Debug, Clone)]
+pub struct MatMulAdd;
+impl MatMulAdd {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {})
+    }
+}
+impl Function for MatMulAdd {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        let mut out = Tensor::scalar(0.);
+        self.run_into(inps, training, &mut out)?;
+        Ok(out)
+    }
+    fn run_into(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+        out: &mut Tensor<Float>,
+    ) -> Result<(), TensorError> {
+        let inps = inps
+            .iter()
+            .map(|t| t.as_float())
+            .collect::<Result<Vec<_>, TensorError>>()?;
+        matmul_into(&inps[0].strided(), &inps[1].strided(), out)?;
+        let bias = inps[2].blob();
+        if out.shape().last() != Some(&bias.len()) {
+            return Err(TensorError::UnexpectedShape);
+        }
+        for row in out.blob_mut().chunks_mut(bias.len()) {
+            for (o, b) in row.iter_mut().zip(bias.iter()) {
+                *o += b;
+            }
+        }
+        Ok(())
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        let inps = inps
+            .iter()
+            .map(|t| t.as_float())
+            .collect::<Result<Vec<_>, TensorError>>()?;
+        let [a_grad, b_grad] = super::matmul_grads(inps[0], inps[1], out_grad)?;
+        Ok(vec![a_grad, b_grad, out_grad.clone()])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::matmul_add::gpu_impl(out_id, inps))
+    }
+}
@@ -8,6 +8,7 @@ mod gpu;
 pub use gpu::{GpuFunction, KernelCall, SharedBuffer};
 
 mod add;
+mod alibi;
 mod cat;
 mod coeff;
 mod crossentropy;
@@ -15,13 +16,25 @@ mod dropout;
 mod embedding;
 mod gelu;
 mod layer_norm;
+mod masked_softmax;
 mod matmul;
+mod matmul_add;
+mod mul;
+mod padmask;
+mod positional;
+mod prefixmask;
+mod prepend;
 mod relu;
+mod rope;
+mod silu;
+mod sinusoidal;
 mod softmax;
 mod transpose;
 mod trilmask;
+mod zloss;
 
 pub use add::*;
+pub use alibi::*;
 pub use cat::*;
 pub use coeff::*;
 pub use crossentropy::*;
@@ -29,23 +42,89 @@ pub use dropout::*;
 pub use embedding::*;
 pub use gelu::*;
 pub use layer_norm::*;
+pub use masked_softmax::*;
 pub use matmul::*;
+pub use matmul_add::*;
+pub use mul::*;
+pub use padmask::*;
+pub use positional::*;
+pub use prefixmask::*;
+pub use prepend::*;
 pub use relu::*;
+pub use rope::*;
+pub use silu::*;
+pub use sinusoidal::*;
 pub use softmax::*;
 pub use transpose::*;
 pub use trilmask::*;
+pub use zloss::*;
 
 use super::tensor::*;
 
-pub trait Function: std::fmt::Debug {
+// Position of the first row of a `[..., n, d]` input, given the `[..., m]` attention mask of
+// a pass attending to the `m - n` positions before it as well (See `Prepend`). Without the
+// mask, rows start at position 0.
+pub(crate) fn first_position(
+    inp: &Tensor<Float>,
+    mask: Option<&&GeneralTensor>,
+) -> Result<usize, TensorError> {
+    let mask = match mask {
+        Some(mask) => mask.as_float()?,
+        None => return Ok(0),
+    };
+    let n = inp.shape()[inp.dim().saturating_sub(2)];
+    let m = *mask.shape().last().ok_or(TensorError::UnexpectedShape)?;
+    m.checked_sub(n).ok_or(TensorError::UnexpectedShape)
+}
+
+// An operation of the computation graph. Besides the builtin functions of this module,
+// downstream crates may implement their own and put them in a graph through
+// `Graph::call`. See `tests/custom_op.rs` for an example.
+//
+// Functions are `Any`, so that graph passes (E.g. operator fusion) can inspect them
+pub trait Function: std::fmt::Debug + std::any::Any {
+    // Usually `Box::new(self.clone())`
     fn clone_box(&self) -> Box<dyn Function>;
-    fn run(&mut self, inps: &[&GeneralTensor], training: bool) -> Result<Tensor<f32>, TensorError>;
+    // Computes the output given the input tensors. Functions may cache whatever they
+    // need for the backward pass in `self`.
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        training: bool,
+    ) -> Result<Tensor<Float>, TensorError>;
+    // Same as `run`, writing the output into `out` instead. The graph passes the output of
+    // the previous pass, so that functions overriding it can reuse its buffer (See
+    // `Tensor::resize`) instead of allocating a new one.
+    fn run_into(
+        &mut self,
+        inps: &[&GeneralTensor],
+        training: bool,
+        out: &mut Tensor<Float>,
+    ) -> Result<(), TensorError> {
+        *out = self.run(inps, training)?;
+        Ok(())
+    }
+    // Given the gradient of the output, returns the gradients of the inputs, one for each
+    // input. (Gradients with extra leading dimensions, E.g. a batch, are summed up by the
+    // graph)
     fn grad(
         &self,
         inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError>;
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError>;
+    // Name of the operation, defaults to the name of the type
+    fn name(&self) -> &'static str {
+        let path = std::any::type_name::<Self>();
+        path.rsplit("::").next().unwrap_or(path)
+    }
+
+    // Seeds the random number generator of the function, if it has one
+    fn reseed(&mut self, _seed: u64) {}
 
+    // OpenCL kernels of the function. Functions without a GPU implementation (`None`) can
+    // only be used in a `CpuGraph`.
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, _out_id: TensorId, _inp_shapes: &[Vec<usize>]) -> GpuFunction;
+    fn gpu_impl(&self, _out_id: TensorId, _inp_shapes: &[Vec<usize>]) -> Option<GpuFunction> {
+        None
+    }
 }
@@ -0,0 +1,66 @@
+use super::Function;
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+
+// Elementwise multiplication of two tensors, broadcast to the same shape (See
+// `broadcast_shape`)
+#[derive(Debug, Clone)]
+pub struct Mul;
+impl Mul {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {})
+    }
+}
+impl Function for Mul {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        let inps = inps
+            .iter()
+            .map(|t| t.as_float())
+            .collect::<Result<Vec<_>, TensorError>>()?;
+        binary(inps[0], inps[1], |a, b| a * b)
+    }
+    fn run_into(
+        &mut self,
+        inps: &[&GeneralTensor],
+        training: bool,
+        out: &mut Tensor<Float>,
+    ) -> Result<(), TensorError> {
+        let (a, b) = (inps[0].as_float()?, inps[1].as_float()?);
+        if a.shape() != b.shape() {
+            *out = self.run(inps, training)?;
+            return Ok(());
+        }
+        for ((o, a), b) in out.resize(a.shape()).iter_mut().zip(a.blob()).zip(b.blob()) {
+            *o = a * b;
+        }
+        Ok(())
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        let inps = inps
+            .iter()
+            .map(|t| t.as_float())
+            .collect::<Result<Vec<_>, TensorError>>()?;
+        Ok(vec![
+            unbroadcast(&binary(out_grad, inps[1], |g, b| g * b)?, inps[0].shape())?,
+            unbroadcast(&binary(out_grad, inps[0], |g, a| g * a)?, inps[1].shape())?,
+        ])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::mul::gpu_impl(out_id, inps))
+    }
+}
@@ -0,0 +1,95 @@
+use super::Function;
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+
+// Score given to the masked positions. Large but finite, so that rows with nothing left to
+// attend to (E.g. the pad positions themselves) get a uniform softmax instead of NaNs.
+pub(crate) const MASKED_SCORE: Float = -1e9;
+
+// Masks out the attention scores of the padding tokens. Takes the `[..., n, m]` scores
+// and a `[..., m]` mask (1 for the real tokens, 0 for the pads); the columns whose mask
+// is zero are replaced by a large negative score.
+#[derive(Debug, Clone)]
+pub struct PadMask;
+impl PadMask {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {})
+    }
+}
+
+// Rows and columns of the scores
+fn check_shapes(
+    scores: &Tensor<Float>,
+    mask: &Tensor<Float>,
+) -> Result<(usize, usize), TensorError> {
+    let shape = scores.shape();
+    if shape.len() < 2 {
+        return Err(TensorError::UnexpectedShape);
+    }
+    let (n, m) = (shape[shape.len() - 2], shape[shape.len() - 1]);
+    if mask.shape().last() != Some(&m) || mask.size() * n != scores.size() {
+        return Err(TensorError::UnexpectedShape);
+    }
+    Ok((n, m))
+}
+
+impl Function for PadMask {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        let scores = inps[0].as_float()?;
+        let mask = inps[1].as_float()?;
+        let (n, m) = check_shapes(scores, mask)?;
+        let dat = scores
+            .blob()
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                // The column `i % m` of the instance `i / (n * m)`
+                if mask.blob()[i / (n * m) * m + i % m] > 0. {
+                    *s
+                } else {
+                    MASKED_SCORE
+                }
+            })
+            .collect();
+        Tensor::raw(scores.shape(), dat)
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        let scores = inps[0].as_float()?;
+        let mask = inps[1].as_float()?;
+        let (n, m) = check_shapes(scores, mask)?;
+        let dat = out_grad
+            .blob()
+            .iter()
+            .enumerate()
+            .map(|(i, g)| {
+                if mask.blob()[i / (n * m) * m + i % m] > 0. {
+                    *g
+                } else {
+                    0.
+                }
+            })
+            .collect();
+        Ok(vec![
+            Tensor::raw(scores.shape(), dat)?,
+            Tensor::zeros(mask.shape()),
+        ])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::padmask::gpu_impl(out_id, inps))
+    }
+}
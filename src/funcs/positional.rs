@@ -0,0 +1,85 @@
+use super::{first_position, Function};
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+
+// Adds learned positional vectors (The rows of the table, second input) to its input. Only
+// as many rows as the input has positions are used, so contexts shorter than the table work.
+// The attention mask may be given as a third input, the rows then start after the cached
+// positions (See `first_position`).
+#[derive(Debug, Clone)]
+pub struct Positional;
+impl Positional {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {})
+    }
+}
+
+// The first row of the table that is used, and the number of rows
+fn positions(
+    inp: &Tensor<Float>,
+    table: &Tensor<Float>,
+    mask: Option<&&GeneralTensor>,
+) -> Result<(usize, usize), TensorError> {
+    let shape = inp.shape();
+    if shape.len() < 2 || table.dim() != 2 || shape[shape.len() - 1] != table.shape()[1] {
+        return Err(TensorError::UnexpectedShape);
+    }
+    let first = first_position(inp, mask)?;
+    let n = shape[shape.len() - 2];
+    if first + n > table.shape()[0] {
+        return Err(TensorError::UnexpectedShape);
+    }
+    Ok((first, n))
+}
+
+impl Function for Positional {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        let inp = inps[0].as_float()?;
+        let table = inps[1].as_float()?;
+        let (first, n) = positions(inp, table, inps.get(2))?;
+        if n == table.shape()[0] {
+            return inp + table;
+        }
+        let d = table.shape()[1];
+        inp + &Tensor::raw(&[n, d], table.blob()[first * d..(first + n) * d].to_vec())?
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        let inp = inps[0].as_float()?;
+        let table = inps[1].as_float()?;
+        let (first, n) = positions(inp, table, inps.get(2))?;
+        let size = n * table.shape()[1];
+        let mut table_grad = vec![0.; table.size()];
+        for chunk in out_grad.blob().chunks(size) {
+            for (g, o) in table_grad[first * table.shape()[1]..].iter_mut().zip(chunk) {
+                *g += o;
+            }
+        }
+        let mut grads = vec![out_grad.clone(), Tensor::raw(table.shape(), table_grad)?];
+        for mask in inps.iter().skip(2) {
+            grads.push(Tensor::zeros(mask.shape()));
+        }
+        Ok(grads)
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        // (The shapes of GPU graphs are fixed, there are no cached positions)
+        if inps.len() > 2 {
+            return None;
+        }
+        Some(gpu::positional::gpu_impl(out_id, inps))
+    }
+}
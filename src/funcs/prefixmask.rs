@@ -0,0 +1,95 @@
+use super::Function;
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+
+// Causal mask of prefix language models: takes the `[..., n, n]` attention scores and a
+// `[..., n]` mask of the prefix tokens (1 for the tokens of the prefix, 0 for the others).
+// Tokens attend to the tokens before them, as with `TrilMask`, and the tokens of the prefix
+// attend to the whole prefix.
+#[derive(Debug, Clone)]
+pub struct PrefixMask;
+impl PrefixMask {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {})
+    }
+}
+
+fn check_shapes(scores: &Tensor<Float>, prefix: &Tensor<Float>) -> Result<usize, TensorError> {
+    let shape = scores.shape();
+    if shape.len() < 2 {
+        return Err(TensorError::UnexpectedShape);
+    }
+    let n = shape[shape.len() - 1];
+    if shape[shape.len() - 2] != n || prefix.size() * n != scores.size() {
+        return Err(TensorError::UnexpectedShape);
+    }
+    Ok(n)
+}
+
+// Whether the i-th score (The row `i / n % n` and the column `i % n` of the instance
+// `i / (n * n)`) is attended to
+fn attended(prefix: &[Float], n: usize, i: usize) -> bool {
+    let (row, col) = (i / n % n, i % n);
+    let instance = i / (n * n) * n;
+    col <= row || (prefix[instance + row] > 0. && prefix[instance + col] > 0.)
+}
+
+impl Function for PrefixMask {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        let scores = inps[0].as_float()?;
+        let prefix = inps[1].as_float()?;
+        let n = check_shapes(scores, prefix)?;
+        let dat = scores
+            .blob()
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                if attended(prefix.blob(), n, i) {
+                    *s
+                } else {
+                    Float::NEG_INFINITY
+                }
+            })
+            .collect();
+        Tensor::raw(scores.shape(), dat)
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        let scores = inps[0].as_float()?;
+        let prefix = inps[1].as_float()?;
+        let n = check_shapes(scores, prefix)?;
+        let dat = out_grad
+            .blob()
+            .iter()
+            .enumerate()
+            .map(|(i, g)| {
+                if attended(prefix.blob(), n, i) {
+                    *g
+                } else {
+                    0.
+                }
+            })
+            .collect();
+        Ok(vec![
+            Tensor::raw(scores.shape(), dat)?,
+            Tensor::zeros(prefix.shape()),
+        ])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::prefixmask::gpu_impl(out_id, inps))
+    }
+}
@@ -0,0 +1,71 @@
+use super::Function;
+use crate::tensor::*;
+
+// Puts the rows of a `[p, d]` tensor (Second input, E.g. the keys of the positions computed
+// by a previous pass) before the rows of every `[..., n, d]` matrix of its first input, which
+// gives `[..., p + n, d]` matrices. Without rows to put (`p == 0`) the input is returned as is.
+#[derive(Debug, Clone)]
+pub struct Prepend;
+impl Prepend {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {})
+    }
+}
+
+// Rows of the prefix, and of the matrices of the input
+fn rows(inp: &Tensor<Float>, prefix: &Tensor<Float>) -> Result<(usize, usize), TensorError> {
+    let shape = inp.shape();
+    if shape.len() < 2 || prefix.dim() != 2 || prefix.shape()[1] != shape[shape.len() - 1] {
+        return Err(TensorError::UnexpectedShape);
+    }
+    Ok((prefix.shape()[0], shape[shape.len() - 2]))
+}
+
+impl Function for Prepend {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        let inp = inps[0].as_float()?;
+        let prefix = inps[1].as_float()?;
+        let (p, n) = rows(inp, prefix)?;
+        if p == 0 {
+            return Ok(inp.clone());
+        }
+        let d = prefix.shape()[1];
+        let mut dat = Vec::with_capacity(inp.size() / n.max(1) * (p + n));
+        for matrix in inp.blob().chunks((n * d).max(1)) {
+            dat.extend_from_slice(prefix.blob());
+            dat.extend_from_slice(matrix);
+        }
+        let mut shape = inp.shape().to_vec();
+        shape[inp.dim() - 2] = p + n;
+        Tensor::raw(&shape, dat)
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        let inp = inps[0].as_float()?;
+        let prefix = inps[1].as_float()?;
+        let (p, n) = rows(inp, prefix)?;
+        let d = prefix.shape()[1];
+        let mut inp_grad = Vec::with_capacity(inp.size());
+        let mut prefix_grad = vec![0.; prefix.size()];
+        for matrix in out_grad.blob().chunks(((p + n) * d).max(1)) {
+            for (g, o) in prefix_grad.iter_mut().zip(matrix[..p * d].iter()) {
+                *g += o;
+            }
+            inp_grad.extend_from_slice(&matrix[p * d..]);
+        }
+        Ok(vec![
+            Tensor::raw(inp.shape(), inp_grad)?,
+            Tensor::raw(prefix.shape(), prefix_grad)?,
+        ])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+}
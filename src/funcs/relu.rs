@@ -16,18 +16,20 @@ impl Function for Relu {
         &mut self,
         inps: &[&GeneralTensor],
         _training: bool,
-    ) -> Result<Tensor<f32>, TensorError> {
+    ) -> Result<Tensor<Float>, TensorError> {
         let inps = inps
             .iter()
             .map(|t| t.as_float())
             .collect::<Result<Vec<_>, TensorError>>()?;
-        Ok(inps[0].map_values(|f| if f > 0. { f } else { 0.01 * f }))
+        let mut out = vec![0.; inps[0].size()];
+        simd::leaky_relu(inps[0].blob(), 0.01, &mut out);
+        Tensor::raw(inps[0].shape(), out)
     }
     fn grad(
         &self,
         inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
         let inps = inps
             .iter()
             .map(|t| t.as_float())
@@ -40,7 +42,7 @@ impl Function for Relu {
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::relu::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::relu::gpu_impl(out_id, inps))
     }
 }
@@ -0,0 +1,80 @@
+use super::{first_position, Function};
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+
+pub(crate) const ROPE_BASE: Float = 10000.;
+
+// Rotary positional embeddings (https://arxiv.org/abs/2104.09864)
+// Rotates each pair of features (2i, 2i + 1) of the vector at position p by
+// an angle of p * base^(-2i/d). Applied to queries and keys. The attention mask may be given
+// as a second input, the positions then start after the cached ones (See `first_position`).
+#[derive(Debug, Clone)]
+pub struct Rope;
+impl Rope {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {})
+    }
+}
+
+// Rotates the rows of `inp`, the first one being at position `first` (Positions are counted
+// from the start of the context)
+fn rotate<T: TensorOps<Float>>(
+    inp: &T,
+    direction: Float,
+    first: usize,
+) -> Result<Tensor<Float>, TensorError> {
+    inp.map(2, |t| {
+        let n = t.shape()[0];
+        let d = t.shape()[1];
+        let mut dat = t.blob().to_vec();
+        for p in 0..n {
+            for i in 0..d / 2 {
+                let theta = (first + p) as Float * ROPE_BASE.powf(-2. * i as Float / d as Float);
+                let (sin, cos) = (direction * theta).sin_cos();
+                let x0 = dat[p * d + 2 * i];
+                let x1 = dat[p * d + 2 * i + 1];
+                dat[p * d + 2 * i] = x0 * cos - x1 * sin;
+                dat[p * d + 2 * i + 1] = x0 * sin + x1 * cos;
+            }
+        }
+        Tensor::raw(&[n, d], dat)
+    })
+}
+
+impl Function for Rope {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        let inp = inps[0].as_float()?;
+        rotate(inp, 1., first_position(inp, inps.get(1))?)
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        // Rotations are orthogonal, the transpose is a rotation in the opposite direction
+        let first = first_position(inps[0].as_float()?, inps.get(1))?;
+        let mut grads = vec![rotate(out_grad, -1., first)?];
+        for mask in inps.iter().skip(1) {
+            grads.push(Tensor::zeros(mask.shape()));
+        }
+        Ok(grads)
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        // (The shapes of GPU graphs are fixed, there are no cached positions)
+        if inps.len() > 1 {
+            return None;
+        }
+        Some(gpu::rope::gpu_impl(out_id, inps, ROPE_BASE))
+    }
+}
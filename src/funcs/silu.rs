@@ -0,0 +1,51 @@
+use super::Function;
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+
+fn sigmoid(x: Float) -> Float {
+    1. / (1. + (-x).exp())
+}
+
+fn silu(x: Float) -> Float {
+    x * sigmoid(x)
+}
+
+fn silu_prime(x: Float) -> Float {
+    let s = sigmoid(x);
+    s * (1. + x * (1. - s))
+}
+
+#[derive(Debug, Clone)]
+pub struct Silu;
+impl Silu {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {})
+    }
+}
+impl Function for Silu {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        Ok(inps[0].as_float()?.map_values(silu))
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        let der = inps[0].as_float()?.map_values(silu_prime);
+        Ok(vec![(&der * out_grad)?])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::silu::gpu_impl(out_id, inps))
+    }
+}
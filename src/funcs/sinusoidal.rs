@@ -0,0 +1,90 @@
+use super::{first_position, Function};
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+use std::sync::Arc;
+
+pub(crate) fn pos_encode_inter(num_tokens: usize, embedding_size: usize) -> Tensor<Float> {
+    let mut raw_new = Vec::new();
+    let cols = embedding_size;
+    let rows = num_tokens;
+    for row in 0..rows {
+        for col in 0..cols {
+            let k = row as Float;
+            let i = (col / 2) as Float;
+            let factor = (10000. as Float).powf(2. * i / embedding_size as Float);
+
+            let pos = if col % 2 == 0 {
+                (k / factor).sin()
+            } else {
+                (k / factor).cos()
+            };
+
+            raw_new.push(pos);
+        }
+    }
+
+    Tensor::raw(&[rows, cols], raw_new).unwrap()
+}
+
+// Adds fixed (Non-trainable) sin/cos positional vectors to its input. The table is
+// not a graph tensor, so no gradient is computed for it. As with `Positional`, the attention
+// mask may be given as a second input.
+#[derive(Debug, Clone)]
+pub struct Sinusoidal {
+    pub(crate) table: Arc<Tensor<Float>>,
+}
+impl Sinusoidal {
+    pub fn new(num_tokens: usize, embedding_degree: usize) -> Box<dyn Function> {
+        Box::new(Self {
+            table: Arc::new(pos_encode_inter(num_tokens, embedding_degree)),
+        })
+    }
+}
+
+impl Function for Sinusoidal {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        // Contexts shorter than the table only use its first rows
+        let inp = inps[0].as_float()?;
+        let first = first_position(inp, inps.get(1))?;
+        let n = inp.shape()[inp.dim().saturating_sub(2)];
+        if first == 0 && n >= self.table.shape()[0] {
+            return inp + &self.table.view();
+        }
+        let d = self.table.shape()[1];
+        let rows = self
+            .table
+            .blob()
+            .get(first * d..(first + n) * d)
+            .ok_or(TensorError::UnexpectedShape)?;
+        inp + &Tensor::raw(&[n, d], rows.to_vec())?
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        let mut grads = vec![out_grad.clone()];
+        for mask in inps.iter().skip(1) {
+            grads.push(Tensor::zeros(mask.shape()));
+        }
+        Ok(grads)
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        // (The shapes of GPU graphs are fixed, there are no cached positions)
+        if inps.len() > 1 {
+            return None;
+        }
+        Some(gpu::sinusoidal::gpu_impl(out_id, inps))
+    }
+}
@@ -6,7 +6,7 @@ use super::{gpu, GpuFunction, TensorId};
 use std::sync::Arc;
 #[derive(Debug, Clone)]
 pub struct Softmax {
-    out: Arc<Tensor<f32>>,
+    pub(crate) out: Arc<Tensor<Float>>,
 }
 impl Softmax {
     pub fn new() -> Box<dyn Function> {
@@ -20,51 +20,53 @@ impl Function for Softmax {
         &mut self,
         inps: &[&GeneralTensor],
         _training: bool,
-    ) -> Result<Tensor<f32>, TensorError> {
+    ) -> Result<Tensor<Float>, TensorError> {
         let inps = inps
             .iter()
             .map(|t| t.as_float())
             .collect::<Result<Vec<_>, TensorError>>()?;
-        self.out = Arc::new(inps[0].map(1, |l| {
-            let max = l
-                .blob()
-                .iter()
-                .fold(f32::NEG_INFINITY, |a, b| f32::max(a, *b));
-            let sum = l.blob().iter().map(|f| (f - max).exp()).sum::<f32>();
-            Ok(l.map_values(|f| (f - max).exp() / sum))
-        })?);
+        // The rows are computed in place in a single output blob
+        let rows = inps[0].keep_right(1)?;
+        let n = rows.shape()[1];
+        let mut out = rows.blob().to_vec();
+        for_each_row(&mut out, n, n, |_, row| {
+            let max = simd::max(row);
+            row.iter_mut().for_each(|f| *f = (*f - max).exp());
+            let sum = simd::sum(row);
+            simd::scale(row, 1. / sum);
+        });
+        self.out = Arc::new(Tensor::raw(inps[0].shape(), out)?);
 
         Ok(self.out.as_ref().clone())
     }
     fn grad(
         &self,
         _inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError> {
-        let grad_inp0 = self
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        let n = *self
             .out
-            .keep_right(1)?
-            .inners()
-            .iter()
-            .zip(out_grad.keep_right(1)?.inners().iter())
-            .map(|(l, o)| {
-                let l_blob = l.blob();
-                let o_blob = o.blob();
-                let n = l.shape()[0];
-                let mut data = vec![0.; n];
-                for i in 0..n {
-                    let si = l_blob[i];
-                    let mut sum = 0.;
-                    for j in 0..n {
-                        let sj = l_blob[j];
-                        sum += (if i == j { si * (1. - si) } else { -si * sj }) * o_blob[j];
-                    }
-                    data[i] = sum;
+            .shape()
+            .last()
+            .ok_or(TensorError::UnexpectedShape)?;
+        let mut grad_inp0 = vec![0.; out_grad.size()];
+        for ((l_blob, o_blob), data) in self
+            .out
+            .blob()
+            .chunks(n.max(1))
+            .zip(out_grad.blob().chunks(n.max(1)))
+            .zip(grad_inp0.chunks_mut(n.max(1)))
+        {
+            for i in 0..n {
+                let si = l_blob[i];
+                let mut sum = 0.;
+                for j in 0..n {
+                    let sj = l_blob[j];
+                    sum += (if i == j { si * (1. - si) } else { -si * sj }) * o_blob[j];
                 }
-                data
-            })
-            .flatten()
-            .collect::<Vec<_>>();
+                data[i] = sum;
+            }
+        }
         Ok(vec![Tensor::raw(out_grad.shape(), grad_inp0)?])
     }
     fn clone_box(&self) -> Box<dyn Function> {
@@ -72,7 +74,7 @@ impl Function for Softmax {
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::softmax::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::softmax::gpu_impl(out_id, inps))
     }
 }
@@ -17,7 +17,7 @@ impl Function for Transpose {
         &mut self,
         inps: &[&GeneralTensor],
         _training: bool,
-    ) -> Result<Tensor<f32>, TensorError> {
+    ) -> Result<Tensor<Float>, TensorError> {
         let inps = inps
             .iter()
             .map(|t| t.as_float())
@@ -27,8 +27,8 @@ impl Function for Transpose {
     fn grad(
         &self,
         _inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
         Ok(vec![out_grad.transpose()?])
     }
     fn clone_box(&self) -> Box<dyn Function> {
@@ -36,7 +36,7 @@ impl Function for Transpose {
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::transpose::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::transpose::gpu_impl(out_id, inps))
     }
 }
@@ -4,9 +4,12 @@ use crate::tensor::*;
 #[cfg(feature = "gpu")]
 use super::{gpu, GpuFunction, TensorId};
 
+// Masks out the scores of the positions after the attending one. The scores may have more
+// columns than rows (`[..., n, m]`), the rows then being the last `n` of the `m` positions.
+// (See `Prepend`)
 #[derive(Debug, Clone)]
 pub struct TrilMask {
-    n: usize,
+    pub(crate) n: usize,
 }
 impl TrilMask {
     pub fn new(n: usize) -> Box<dyn Function> {
@@ -19,36 +22,44 @@ impl Function for TrilMask {
         &mut self,
         inps: &[&GeneralTensor],
         _training: bool,
-    ) -> Result<Tensor<f32>, TensorError> {
+    ) -> Result<Tensor<Float>, TensorError> {
         inps[0].as_float()?.map(2, |t| {
+            let (n, m) = (t.shape()[0], t.shape()[1]);
+            let offset = m.checked_sub(n).ok_or(TensorError::UnexpectedShape)?;
             let t_blob = t.blob();
-            let mut dat = Vec::with_capacity(self.n * self.n);
-            for i in 0..self.n {
-                for j in 0..self.n {
-                    dat.push(if j <= i {
-                        t_blob[i * self.n + j]
+            let mut dat = Vec::with_capacity(n * m);
+            for i in 0..n {
+                for j in 0..m {
+                    dat.push(if j <= i + offset {
+                        t_blob[i * m + j]
                     } else {
-                        f32::NEG_INFINITY
+                        Float::NEG_INFINITY
                     });
                 }
             }
-            Ok(Tensor::raw(&[self.n, self.n], dat)?)
+            Ok(Tensor::raw(&[n, m], dat)?)
         })
     }
     fn grad(
         &self,
         _inps: &[&GeneralTensor],
-        out_grad: &Tensor<f32>,
-    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
         Ok(vec![out_grad.map(2, |t| {
+            let (n, m) = (t.shape()[0], t.shape()[1]);
+            let offset = m.checked_sub(n).ok_or(TensorError::UnexpectedShape)?;
             let t_blob = t.blob();
-            let mut dat = Vec::with_capacity(self.n * self.n);
-            for i in 0..self.n {
-                for j in 0..self.n {
-                    dat.push(if j <= i { t_blob[i * self.n + j] } else { 0. });
+            let mut dat = Vec::with_capacity(n * m);
+            for i in 0..n {
+                for j in 0..m {
+                    dat.push(if j <= i + offset {
+                        t_blob[i * m + j]
+                    } else {
+                        0.
+                    });
                 }
             }
-            Ok(Tensor::raw(&[self.n, self.n], dat)?)
+            Ok(Tensor::raw(&[n, m], dat)?)
         })?])
     }
     fn clone_box(&self) -> Box<dyn Function> {
@@ -56,7 +67,7 @@ impl Function for TrilMask {
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::trilmask::gpu_impl(out_id, inps, self.n)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::trilmask::gpu_impl(out_id, inps, self.n))
     }
 }
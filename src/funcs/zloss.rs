@@ -0,0 +1,78 @@
+use super::Function;
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+
+use std::sync::Arc;
+
+// Squared log-sum-exp of the logits of each position (The z-loss of PaLM), which keeps the
+// softmax normalizer close to one when added to the cross-entropy. Takes the same inputs as
+// `CrossEntropy` (The targets only give the shape of the output).
+#[derive(Debug, Clone)]
+pub struct ZLoss {
+    lse: Arc<Tensor<Float>>,
+}
+impl ZLoss {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {
+            lse: Arc::new(Tensor::scalar(0.)),
+        })
+    }
+}
+impl Function for ZLoss {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        let inp = inps[0].as_float()?;
+        let target = inps[1].as_usize()?;
+        let lse = inp
+            .keep_right(1)?
+            .inners()
+            .iter()
+            .map(|o| {
+                let max = o
+                    .blob()
+                    .iter()
+                    .cloned()
+                    .fold(Float::NEG_INFINITY, Float::max);
+                max + o.blob().iter().map(|f| (f - max).exp()).sum::<Float>().ln()
+            })
+            .collect::<Vec<_>>();
+        self.lse = Arc::new(Tensor::raw(target.shape(), lse)?);
+        Ok(self.lse.map_values(|f| f * f))
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        let inp = inps[0].as_float()?;
+        // d(lse^2)/dx = 2 * lse * softmax(x)
+        Ok(vec![Tensor::raw(
+            inp.shape(),
+            inp.keep_right(1)?
+                .inners()
+                .iter()
+                .zip(self.lse.blob().iter())
+                .zip(out_grad.blob().iter())
+                .flat_map(|((o, lse), g)| {
+                    o.blob()
+                        .iter()
+                        .map(|f| 2. * lse * (f - lse).exp() * g)
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+        )?])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::zloss::gpu_impl(out_id, inps))
+    }
+}
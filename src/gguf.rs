@@ -0,0 +1,410 @@
+use crate::funcs::pos_encode_inter;
+use crate::gpt::{
+    Activation, FeedForward, GPTConfig, NormPlacement, PositionalEncoding, TrainingState,
+};
+use crate::gpt2;
+use crate::graph::GraphError;
+use crate::tensor::*;
+use crate::tokenizer::Tokenizer;
+use std::io::Write;
+
+// Export of trained models to GGUF (https://github.com/ggerganov/ggml/blob/master/docs/gguf.md),
+// the file format of llama.cpp and compatible runtimes. Models are exported with llama.cpp's
+// `gpt2` architecture, so only GPT-2 like configs can be exported:
+//
+// - `positional_encoding`: Learned or Sinusoidal (Exported as a learned table)
+// - `activation`: Gelu, `feedforward`: Mlp
+// - `norm_placement`: PreNorm, with `final_norm`
+// - `num_kv_heads == num_heads`, with the default `head_size`
+// - `bias`: false (The output layer of `gpt2` has no bias, zero biases are written for the
+//   other layers)
+
+const GGUF_VERSION: u32 = 3;
+const ALIGNMENT: usize = 32;
+const LAYER_NORM_EPSILON: f32 = 1e-5;
+
+enum Value {
+    U32(u32),
+    F32(f32),
+    Str(String),
+    Strs(Vec<String>),
+    I32s(Vec<i32>),
+}
+
+struct GgufTensor {
+    name: String,
+    shape: Vec<usize>, // Row-major shape
+    data: Vec<f32>,
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    out.extend((s.len() as u64).to_le_bytes());
+    out.extend(s.as_bytes());
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    const UINT32: u32 = 4;
+    const INT32: u32 = 5;
+    const FLOAT32: u32 = 6;
+    const STRING: u32 = 8;
+    const ARRAY: u32 = 9;
+    match value {
+        Value::U32(v) => {
+            out.extend(UINT32.to_le_bytes());
+            out.extend(v.to_le_bytes());
+        }
+        Value::F32(v) => {
+            out.extend(FLOAT32.to_le_bytes());
+            out.extend(v.to_le_bytes());
+        }
+        Value::Str(v) => {
+            out.extend(STRING.to_le_bytes());
+            write_str(out, v);
+        }
+        Value::Strs(vs) => {
+            out.extend(ARRAY.to_le_bytes());
+            out.extend(STRING.to_le_bytes());
+            out.extend((vs.len() as u64).to_le_bytes());
+            for v in vs {
+                write_str(out, v);
+            }
+        }
+        Value::I32s(vs) => {
+            out.extend(ARRAY.to_le_bytes());
+            out.extend(INT32.to_le_bytes());
+            out.extend((vs.len() as u64).to_le_bytes());
+            for v in vs {
+                out.extend(v.to_le_bytes());
+            }
+        }
+    }
+}
+
+fn padding(len: usize) -> usize {
+    (ALIGNMENT - len % ALIGNMENT) % ALIGNMENT
+}
+
+fn write_gguf<W: Write>(
+    w: &mut W,
+    metadata: &[(&str, Value)],
+    tensors: &[GgufTensor],
+) -> Result<(), GraphError> {
+    const F32: u32 = 0;
+    let mut header = Vec::new();
+    header.extend(b"GGUF");
+    header.extend(GGUF_VERSION.to_le_bytes());
+    header.extend((tensors.len() as u64).to_le_bytes());
+    header.extend((metadata.len() as u64).to_le_bytes());
+    for (key, value) in metadata {
+        write_str(&mut header, key);
+        write_value(&mut header, value);
+    }
+    let mut offset = 0;
+    for t in tensors {
+        write_str(&mut header, &t.name);
+        header.extend((t.shape.len() as u32).to_le_bytes());
+        // Dimensions are listed from the fastest changing one
+        for d in t.shape.iter().rev() {
+            header.extend((*d as u64).to_le_bytes());
+        }
+        header.extend(F32.to_le_bytes());
+        header.extend((offset as u64).to_le_bytes());
+        let size = t.data.len() * std::mem::size_of::<f32>();
+        offset += size + padding(size);
+    }
+    header.resize(header.len() + padding(header.len()), 0);
+    w.write_all(&header)?;
+    for t in tensors {
+        let data = t
+            .data
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect::<Vec<_>>();
+        w.write_all(&data)?;
+        w.write_all(&vec![0; padding(data.len())])?;
+    }
+    Ok(())
+}
+
+// GPT-2 BPE vocabularies are stored as strings over a byte-to-unicode alphabet, in which
+// whitespace and control bytes are mapped to printable characters
+fn byte_level(token: &str) -> String {
+    let printable = |b: u8| matches!(b, b'!'..=b'~' | 0xa1..=0xac | 0xae..=0xff);
+    let mut table = ['\0'; 256];
+    let mut shifted = 0;
+    for b in 0..=255u8 {
+        table[b as usize] = if printable(b) {
+            b as char
+        } else {
+            shifted += 1;
+            char::from_u32(255 + shifted).unwrap()
+        };
+    }
+    token.bytes().map(|b| table[b as usize]).collect()
+}
+
+fn check_exportable(config: &GPTConfig) -> Result<(), GraphError> {
+    let err = |msg: &str| Err(GraphError::InvalidConfig(format!("gguf export: {}", msg)));
+    if !matches!(
+        config.positional_encoding,
+        PositionalEncoding::Learned | PositionalEncoding::Sinusoidal
+    ) {
+        return err("only learned or sinusoidal positional encodings are supported");
+    }
+    if config.activation != Activation::Gelu || config.feedforward != FeedForward::Mlp {
+        return err("only Mlp feed-forward layers with Gelu activations are supported");
+    }
+    if config.norm_placement != NormPlacement::PreNorm || !config.final_norm {
+        return err("only PreNorm models with a final norm are supported");
+    }
+    if config.num_kv_heads != config.num_heads
+        || config.head_size()? * config.num_heads != config.embedding_degree
+    {
+        return err("only multi-head attention with the default head size is supported");
+    }
+    if config.bias {
+        return err("models with bias terms are not supported");
+    }
+    if config.prefix_lm {
+        return err("prefix language models are not supported");
+    }
+    Ok(())
+}
+
+// Exports the parameters of a model to a GGUF file with the `gpt2` architecture
+pub fn export_gpt2<W: Write, T: Tokenizer>(
+    w: &mut W,
+    config: &GPTConfig,
+    state: &TrainingState,
+    tokenizer: &T,
+) -> Result<(), GraphError> {
+    check_exportable(config)?;
+    let get = |name: &str| {
+        state
+            .tensors
+            .get(name)
+            .ok_or_else(|| GraphError::InvalidConfig(format!("tensor {} not found", name)))
+    };
+    let tensor = |name: &str, t: &Tensor<Float>| GgufTensor {
+        name: name.into(),
+        shape: t.shape().to_vec(),
+        data: t.cast::<f32>().blob().to_vec(),
+    };
+    // ggml weights are stored as [out, in] matrices, while our weights are [in, out]
+    let weights = |name: &str, t: &Tensor<Float>| -> Result<GgufTensor, GraphError> {
+        Ok(tensor(name, &t.transpose()?))
+    };
+    let zeros = |name: &str, n: usize| tensor(name, &Tensor::zeros(&[n]));
+
+    let d = config.embedding_degree;
+    let ff = config.feedforward_degree();
+    let mut tensors = vec![tensor("token_embd.weight", get("token_embedding")?)];
+    tensors.push(match config.positional_encoding {
+        PositionalEncoding::Learned => tensor("position_embd.weight", get("pos_embedding")?),
+        _ => tensor(
+            "position_embd.weight",
+            &pos_encode_inter(config.num_tokens, d),
+        ),
+    });
+
+    // (The transposed per-head projections are stacked on top of each other)
+    for l in 0..config.num_layers {
+        let mut qkv = Vec::new();
+        for name in gpt2::qkv_heads(config, l) {
+            qkv.extend(get(&name)?.transpose()?.cast::<f32>().blob());
+        }
+        tensors.extend([
+            GgufTensor {
+                name: format!("blk.{}.attn_qkv.weight", l),
+                shape: vec![3 * d, d],
+                data: qkv,
+            },
+            zeros(&format!("blk.{}.attn_qkv.bias", l), 3 * d),
+        ]);
+    }
+    for t in gpt2::mapped_tensors(config) {
+        tensors.push(match t.shape.len() {
+            // Biases are zero, as the config has no bias terms
+            1 if t.femto.ends_with("_bias") => zeros(&t.gguf, t.shape[0]),
+            1 => tensor(&t.gguf, get(&t.femto)?),
+            _ => weights(&t.gguf, get(&t.femto)?)?,
+        });
+    }
+    tensors.push(weights("output.weight", get("head_map_weights")?)?);
+
+    let tokens = (0..config.vocab_size)
+        .map(|i| byte_level(&tokenizer.untokenize(&[i])))
+        .collect::<Vec<_>>();
+    let metadata = [
+        ("general.architecture", Value::Str("gpt2".into())),
+        ("general.name", Value::Str("femtoGPT".into())),
+        ("gpt2.context_length", Value::U32(config.num_tokens as u32)),
+        ("gpt2.embedding_length", Value::U32(d as u32)),
+        ("gpt2.feed_forward_length", Value::U32(ff as u32)),
+        ("gpt2.block_count", Value::U32(config.num_layers as u32)),
+        (
+            "gpt2.attention.head_count",
+            Value::U32(config.num_heads as u32),
+        ),
+        (
+            "gpt2.attention.layer_norm_epsilon",
+            Value::F32(LAYER_NORM_EPSILON),
+        ),
+        ("tokenizer.ggml.model", Value::Str("gpt2".into())),
+        ("tokenizer.ggml.tokens", Value::Strs(tokens)),
+        (
+            "tokenizer.ggml.token_type",
+            Value::I32s(vec![1; config.vocab_size]),
+        ),
+        // Every token is a single piece, there is nothing to merge
+        ("tokenizer.ggml.merges", Value::Strs(Vec::new())),
+        ("tokenizer.ggml.bos_token_id", Value::U32(0)),
+        ("tokenizer.ggml.eos_token_id", Value::U32(0)),
+    ];
+    write_gguf(w, &metadata, &tensors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::GPT;
+    use crate::graph::CpuGraph;
+    use crate::tokenizer::SimpleTokenizer;
+
+    fn config() -> GPTConfig {
+        GPTConfig {
+            num_tokens: 4,
+            num_layers: 2,
+            positional_encoding: PositionalEncoding::Learned,
+            feedforward_multiplier: 4.,
+            ..GPTConfig::tiny()
+        }
+    }
+
+    struct Reader<'a>(&'a [u8]);
+    impl Reader<'_> {
+        fn bytes(&mut self, n: usize) -> &[u8] {
+            let (b, rest) = self.0.split_at(n);
+            self.0 = rest;
+            b
+        }
+        fn u32(&mut self) -> u32 {
+            u32::from_le_bytes(self.bytes(4).try_into().unwrap())
+        }
+        fn u64(&mut self) -> u64 {
+            u64::from_le_bytes(self.bytes(8).try_into().unwrap())
+        }
+        fn str(&mut self) -> String {
+            let len = self.u64() as usize;
+            String::from_utf8(self.bytes(len).to_vec()).unwrap()
+        }
+        fn skip_value(&mut self, typ: u32) {
+            match typ {
+                4..=6 => {
+                    self.bytes(4);
+                }
+                8 => {
+                    self.str();
+                }
+                9 => {
+                    let typ = self.u32();
+                    for _ in 0..self.u64() {
+                        self.skip_value(typ);
+                    }
+                }
+                _ => panic!("unexpected type {}", typ),
+            }
+        }
+    }
+
+    #[test]
+    fn test_export_gpt2() {
+        let mut rng = rand::thread_rng();
+        let tokenizer = SimpleTokenizer::new("ab c\n");
+        let gpt = GPT::new(&mut rng, CpuGraph::new(), None, config()).unwrap();
+        let mut out = Vec::new();
+        export_gpt2(
+            &mut out,
+            gpt.config(),
+            &gpt.get_training_state().unwrap(),
+            &tokenizer,
+        )
+        .unwrap();
+
+        let mut r = Reader(&out);
+        assert_eq!(r.bytes(4), b"GGUF");
+        assert_eq!(r.u32(), GGUF_VERSION);
+        let num_tensors = r.u64();
+        assert_eq!(num_tensors, 2 + 2 * 12 + 3);
+        let mut tokens = Vec::new();
+        for _ in 0..r.u64() {
+            let key = r.str();
+            let typ = r.u32();
+            if key == "tokenizer.ggml.tokens" {
+                r.u32();
+                for _ in 0..r.u64() {
+                    tokens.push(r.str());
+                }
+            } else {
+                r.skip_value(typ);
+            }
+        }
+        assert_eq!(tokens, ["Ċ", "Ġ", "a", "b", "c"]);
+
+        let mut infos = Vec::new();
+        for _ in 0..num_tensors {
+            let name = r.str();
+            let dims = (0..r.u32()).map(|_| r.u64()).collect::<Vec<_>>();
+            assert_eq!(r.u32(), 0);
+            infos.push((name, dims, r.u64()));
+        }
+        let qkv = infos
+            .iter()
+            .find(|i| i.0 == "blk.1.attn_qkv.weight")
+            .unwrap();
+        assert_eq!(qkv.1, [8, 24]);
+        let output = infos.last().unwrap();
+        assert_eq!(
+            (output.0.as_str(), &output.1[..]),
+            ("output.weight", &[8, 5][..])
+        );
+
+        // Tensor data starts at the next aligned offset
+        let data_len = r.0.len() - padding(out.len() - r.0.len());
+        assert_eq!(
+            data_len as u64,
+            output.2 + 8 * 5 * 4 + padding(8 * 5 * 4) as u64
+        );
+    }
+
+    #[test]
+    fn test_unsupported_configs() {
+        let mut rng = rand::thread_rng();
+        let tokenizer = SimpleTokenizer::new("ab c\n");
+        for config in [
+            GPTConfig {
+                bias: true,
+                ..config()
+            },
+            GPTConfig {
+                norm_placement: NormPlacement::Original,
+                ..config()
+            },
+            GPTConfig {
+                positional_encoding: PositionalEncoding::Rope,
+                ..config()
+            },
+        ] {
+            let gpt = GPT::new(&mut rng, CpuGraph::new(), None, config).unwrap();
+            assert!(matches!(
+                export_gpt2(
+                    &mut Vec::new(),
+                    gpt.config(),
+                    &gpt.get_training_state().unwrap(),
+                    &tokenizer
+                ),
+                Err(GraphError::InvalidConfig(_))
+            ));
+        }
+    }
+}
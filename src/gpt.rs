@@ -1,103 +1,858 @@
+use crate::callback::{StepInfo, TensorStats, TrainCallback, TrainingStats};
+use crate::checkpoint::{read_training_state, save_sharded, Manifest, ShardedCheckpoint};
+use crate::compute;
+use crate::dataset::{Batch, CompletionRow, Dataset, PreferencePair, PrefetchConfig, Prefetcher};
 use crate::funcs::*;
-use crate::graph::{Graph, GraphError, TensorId};
-use crate::optimizer::{Optimizer, OptimizerState};
-use crate::tensor::{GeneralTensor, Tensor, TensorError, TensorOps};
-use rand::Rng;
+use crate::gguf;
+use crate::graph::{Graph, GraphError, Profile, TensorId};
+use crate::npy;
+use crate::optimizer::{
+    add_gradient_noise, clip_gradients, GradNoise, LossScaler, Optimizer, OptimizerState,
+    SwaConfig, SwaState,
+};
+use crate::prefix_cache::{AttentionState, PrefixCache};
+use crate::sampling::{LogitProcessor, SamplingParams};
+use crate::scheduler::{BatchSize, LearningRate, Schedule};
+pub use crate::tensor::Precision;
+use crate::tensor::{Float, Tensor, TensorError, TensorMutOps, TensorOps};
+use crate::tokenizer::{SavedTokenizer, Tokenizer};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
+mod json {
+    use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        value: &Option<T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let json = value.as_ref().map(serde_json::to_string).transpose();
+        json.map_err(ser::Error::custom)?.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: de::DeserializeOwned, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<T>, D::Error> {
+        let json = Option::<String>::deserialize(deserializer)?;
+        let value = json.map(|json| serde_json::from_str(&json)).transpose();
+        value.map_err(de::Error::custom)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrainingState {
-    pub tensors: HashMap<String, Tensor<f32>>,
+    pub tensors: HashMap<String, Tensor<Float>>,
     pub optimizer: OptimizerState,
+    // Learning rate schedule of the last training run, if it was given as a `Schedule`
+    pub schedule: Option<Schedule>,
+    // Exponential moving averages of the parameters (Empty when EMA is disabled)
+    pub ema: HashMap<String, Tensor<Float>>,
+    // Bookkeeping of the training loops, for resuming a run exactly where it stopped
+    pub progress: Option<TrainingProgress>,
+    // Architecture of the model, which `GPT::load_from` rebuilds. Stored as JSON, so that the
+    // fields added to `GPTConfig` with a default value don't break older checkpoints.
+    #[serde(with = "json")]
+    pub config: Option<GPTConfig>,
+    // Checksums of the tensors and metadata, verified when the state is loaded
+    pub manifest: Option<Manifest>,
+    // Stochastic weight average of the parameters, see `TrainingOptions::swa`
+    pub swa: Option<SwaState>,
+    // Tokenizer the model is trained with, see `GPT::set_tokenizer`
+    pub tokenizer: Option<SavedTokenizer>,
+}
+
+// How the hidden states of a sequence are pooled into a single embedding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pooling {
+    // Mean of the hidden states of all the positions
+    Mean,
+    // Hidden state of the last position, the only one attending to the whole sequence
+    Last,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrainingProgress {
+    // Seed, stream and position of the RNG the batches are sampled with
+    pub rng_seed: [u8; 32],
+    pub rng_stream: u64,
+    pub rng_word_pos: u128,
+    pub best: BestValidation,
+}
+
+// Best validation loss so far, tracked for early stopping
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BestValidation {
+    pub loss: Option<Float>,
+    pub step: Option<usize>,
+    pub evals_without_improvement: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+pub enum PositionalEncoding {
+    // Trainable positional embeddings added to the token embeddings
+    Learned,
+    // Fixed sin/cos vectors added to the token embeddings (No parameters, no gradients)
+    #[default]
+    Sinusoidal,
+    // Queries and keys are rotated according to their positions
+    Rope,
+    // No positional embeddings, attention scores are linearly penalized by distance instead
+    Alibi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+pub enum Activation {
+    Relu,
+    #[default]
+    Gelu,
+}
+
+impl Activation {
+    fn function(&self) -> Box<dyn Function> {
+        match self {
+            Activation::Relu => Relu::new(),
+            Activation::Gelu => Gelu::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+pub enum FeedForward {
+    // Linear -> Activation -> Linear
+    #[default]
+    Mlp,
+    // LLaMA-style gated block (The `activation` option is ignored)
+    SwiGlu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+pub enum NormPlacement {
+    // Normalize before the attention and after adding the attention results, both
+    // residual connections start from normalized values
+    #[default]
+    Original,
+    // x + Attention(Norm(x)), x + FeedForward(Norm(x))
+    PreNorm,
+    // Norm(x + Attention(x)), Norm(x + FeedForward(x))
+    PostNorm,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GPTConfig {
+    pub vocab_size: usize,
+    pub embedding_degree: usize,
+    pub num_tokens: usize,
+    pub num_layers: usize,
+    pub num_heads: usize,
+    // Number of heads with distinct key/value projections. Equal to `num_heads` for regular
+    // multi-head attention, 1 for multi-query attention, or a divisor of `num_heads` for
+    // grouped-query attention.
+    pub num_kv_heads: usize,
+    // Size of each attention head, defaults to `embedding_degree / num_heads`
+    pub head_size: Option<usize>,
+    pub dropout: Float,
+    pub positional_encoding: PositionalEncoding,
+    pub activation: Activation,
+    pub feedforward: FeedForward,
+    // Width of the hidden feed-forward layer relative to `embedding_degree`
+    pub feedforward_multiplier: Float,
+    pub norm_placement: NormPlacement,
+    // Apply a LayerNorm on the output of the last block
+    pub final_norm: bool,
+    // Whether projections, feed-forward layers, norms and the output layer have bias terms
+    pub bias: bool,
+    // Precision of the activations, and of the copies of the parameters they are computed
+    // with (The parameters are kept in full precision), see `Precision`. Half-precision
+    // training scales the loss with a `LossScaler`.
+    pub precision: Precision,
+    // Low-rank adapters on the projections of the blocks. When set, only the adapters are
+    // trained, the other parameters are frozen.
+    #[serde(default)]
+    pub lora: Option<LoraConfig>,
+    // Classification head on the pooled hidden states, trained with `GPT::train_classifier`
+    #[serde(default)]
+    pub classifier: Option<ClassifierConfig>,
+    // How the parameters of a new model are initialized
+    #[serde(default)]
+    pub init: InitScheme,
+    // Prefix language model: the tokens of the prefix of each sequence (See `Batch::prefix`)
+    // attend to each other bidirectionally, the other tokens causally
+    #[serde(default)]
+    pub prefix_lm: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum InitScheme {
+    // Every weight, norm gains included, drawn from N(0, 0.02), as in the first versions
+    Legacy,
+    // Weights and embeddings drawn from N(0, std), scaled down by 1/sqrt(2 * num_layers) for
+    // the projections writing into the residual stream, and norm gains of one (As in GPT-2)
+    Scaled { std: Float },
+}
+
+impl Default for InitScheme {
+    fn default() -> Self {
+        InitScheme::Scaled { std: 0.02 }
+    }
+}
+
+impl InitScheme {
+    // Standard deviation of the weights of a projection
+    fn std(&self, residual: bool, num_layers: usize) -> Float {
+        match self {
+            InitScheme::Legacy => 0.02,
+            InitScheme::Scaled { std } if residual => std / (2. * num_layers as Float).sqrt(),
+            InitScheme::Scaled { std } => *std,
+        }
+    }
+
+    fn norm_gain<R: Rng>(&self, rng: &mut R, embedding_degree: usize) -> Tensor<Float> {
+        match self {
+            InitScheme::Legacy => Tensor::<Float>::rand(rng, &[embedding_degree]),
+            InitScheme::Scaled { .. } => Tensor::constant(&[embedding_degree], 1.),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClassifierConfig {
+    pub num_classes: usize,
+    pub pooling: Pooling,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LoraConfig {
+    pub rank: usize,
+    // The output of an adapter is scaled by `alpha / rank`
+    pub alpha: Float,
+}
+
+impl LoraConfig {
+    fn scale(&self) -> Float {
+        self.alpha / self.rank as Float
+    }
+}
+
+// Adapters of a model, saved separately from its base parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Adapters {
+    pub config: LoraConfig,
+    pub tensors: HashMap<String, Tensor<Float>>,
+}
+
+// Options of the training loops
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrainingOptions {
+    // Gradients are scaled down so that their global L2 norm does not exceed this
+    pub max_grad_norm: Option<Float>,
+    // Gradients are clamped to [-max_grad_value, max_grad_value] (Before norm clipping)
+    pub max_grad_value: Option<Float>,
+    // An exponential moving average of the parameters is updated after each step, with
+    // this decay (E.g. 0.999), see `GPT::swap_ema`
+    pub ema_decay: Option<Float>,
+    // Coefficient of the z-loss (The squared log-sum-exp of the logits, E.g. 1e-4) added to
+    // the cross-entropy to keep the logits from drifting. Included in the training loss,
+    // but not in the evaluations.
+    pub z_loss: Option<Float>,
+    // The loss on the validation dataset (See `GPT::set_validation_dataset`) is evaluated
+    // every `eval_interval` steps, on at most `eval_batches` batches (All of it by default)
+    pub eval_interval: Option<usize>,
+    pub eval_batches: Option<usize>,
+    pub early_stopping: Option<EarlyStopping>,
+    // Where and when the training loops save the training state
+    pub checkpoint: Option<CheckpointConfig>,
+    // Dropout rates of the components of the model, instead of `GPTConfig::dropout`
+    pub dropout: Option<DropoutConfig>,
+    // Noise added to the gradients (After clipping), decaying with the optimizer step
+    pub grad_noise: Option<GradNoise>,
+    // The parameters are averaged over the tail of training, see `GPT::swap_swa`
+    pub swa: Option<SwaConfig>,
+    // Batches are sampled on worker threads while the training steps compute, see
+    // `dataset::Prefetcher`
+    pub prefetch: Option<PrefetchConfig>,
+    // Statistics of the gradients and activations are computed every `monitor_interval` steps
+    // of `train`/`train_cpu`, and passed to `TrainCallback::on_stats`
+    pub monitor_interval: Option<usize>,
+    // Steps of `train`/`train_cpu` with a non-finite loss or gradients are skipped
+    pub nan_guard: Option<NanGuard>,
+    pub spike_rollback: Option<SpikeRollback>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckpointConfig {
+    pub dir: PathBuf,
+    // Saved every `interval` steps, as `step_<step>.dat`
+    pub interval: usize,
+    // Older checkpoints are removed, so that only the last `keep_last` remain
+    pub keep_last: Option<usize>,
+    // The state with the best validation loss is also saved, as `best.dat`
+    pub save_best: bool,
+}
+
+// Training stops once the validation loss has not improved by at least `min_delta` for
+// `patience` evaluations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EarlyStopping {
+    pub patience: usize,
+    pub min_delta: Float,
+}
+
+// The optimizer update of a step whose loss or gradients contain NaN/Inf is skipped, so that
+// they do not spread to the parameters. With `lr_backoff` (E.g. 0.5), the learning rate is
+// also multiplied by it for the `recovery_steps` steps following a skipped one (Compounding
+// over consecutive skips).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct NanGuard {
+    pub lr_backoff: Option<Float>,
+    pub recovery_steps: usize,
+}
+
+// Watchdog of `train`/`train_cpu`, which snapshots the training state every `interval` steps.
+// Once `warmup_steps` losses have been seen, a loss above `threshold` times their moving
+// average (With decay `ema_decay`, E.g. 0.9) rolls the parameters and the optimizer state back
+// to the last snapshot, and the learning rate is multiplied by `lr_factor` for the
+// `recovery_steps` following steps. The snapshot is kept in memory, or saved to `dir` as
+// `last_good.dat`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpikeRollback {
+    pub threshold: Float,
+    pub ema_decay: Float,
+    pub warmup_steps: usize,
+    pub interval: usize,
+    pub lr_factor: Float,
+    pub recovery_steps: usize,
+    pub dir: Option<PathBuf>,
+}
+
+// Bookkeeping of `SpikeRollback`
+#[derive(Debug, Clone, Default)]
+struct Watchdog {
+    loss_average: Float,
+    losses: usize,
+    // Optimizer step of the last snapshot, and the snapshot itself when it's kept in memory
+    snapshot_step: Option<usize>,
+    snapshot: Option<TrainingState>,
+}
+
+// Each rate defaults to `GPTConfig::dropout`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DropoutConfig {
+    // Attention weights
+    pub attention: Option<Float>,
+    // Outputs of the attention and feed-forward layers, before they're added to the residual
+    // stream
+    pub residual: Option<Float>,
+    // Embeddings of the input tokens (And of their positions)
+    pub embedding: Option<Float>,
+    // Factor of the rates as a function of the optimizer step, e.g.
+    // `Schedule::Linear { from: 1., to: 0., steps }` to phase dropout out during training
+    pub schedule: Option<Schedule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Evaluation {
+    // Mean cross-entropy per token
+    pub loss: Float,
+    pub perplexity: Float,
+}
+
+// Splits a dataset into training and validation parts, the last `validation_fraction` of
+// the tokens being held out for validation
+pub fn split_dataset(dataset: &[usize], validation_fraction: Float) -> (&[usize], &[usize]) {
+    let validation_len = (dataset.len() as Float * validation_fraction.clamp(0., 1.)) as usize;
+    dataset.split_at(dataset.len() - validation_len)
+}
+
+// Returned by the training loops
+#[derive(Debug, Clone, Default)]
+pub struct TrainingSummary {
+    // Number of optimizer steps taken in this run
+    pub steps: usize,
+    // Training loss of the last step
+    pub loss: Float,
+    // Validation results of the run, with their optimizer steps and training losses
+    pub evaluations: Vec<(usize, Float, Evaluation)>,
+    pub best_validation_loss: Option<Float>,
+    pub best_step: Option<usize>,
+    // Training state with the best validation loss
+    pub best_state: Option<TrainingState>,
+    pub evals_without_improvement: usize,
+    // Stopped before `num_batches`, by early stopping or by a callback
+    pub stopped_early: bool,
+    // Steps skipped by `TrainingOptions::nan_guard`, or on a half-precision overflow
+    pub skipped_steps: usize,
+    // Loss spikes rolled back by `TrainingOptions::spike_rollback` (Their steps are skipped)
+    pub rollbacks: usize,
+}
+
+// Parameters of a transformer block, per component
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LayerSummary {
+    // Key/query/value projections and the output projection
+    pub attention: usize,
+    pub feedforward: usize,
+    pub norms: usize,
+}
+
+// Returned by `GPT::summary`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelSummary {
+    // Token (And learned positional) embeddings
+    pub embeddings: usize,
+    pub layers: Vec<LayerSummary>,
+    // Final norm and projection to the vocabulary
+    pub head: usize,
+    pub total: usize,
+    pub batch_size: usize,
+    // Estimated size of the activations of a training step with `batch_size` full contexts,
+    // along with their gradients (The graph keeps both)
+    pub activation_bytes: usize,
+    // Estimated floating point operations of a forward pass, per token of a full context.
+    // A training step takes about three times as many.
+    pub flops_per_token: usize,
+}
+
+impl std::fmt::Display for ModelSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Embeddings: {}", self.embeddings)?;
+        for (l, layer) in self.layers.iter().enumerate() {
+            writeln!(
+                f,
+                "Layer {}: {} (Attention: {}, feed-forward: {}, norms: {})",
+                l,
+                layer.attention + layer.feedforward + layer.norms,
+                layer.attention,
+                layer.feedforward,
+                layer.norms
+            )?;
+        }
+        writeln!(f, "Head: {}", self.head)?;
+        writeln!(f, "Total: {}", self.total)?;
+        writeln!(
+            f,
+            "Activations (Batch size {}): {:.1} MiB",
+            self.batch_size,
+            self.activation_bytes as f64 / (1024. * 1024.)
+        )?;
+        write!(f, "Forward FLOPs per token: {}", self.flops_per_token)
+    }
 }
 
 pub struct GPT<G: Graph> {
     graph: G,
+    config: GPTConfig,
+    options: TrainingOptions,
     num_tokens: usize,
+    // Shapes of models allocated with a batch size are fixed
+    batch_size: Option<usize>,
+    precision: Precision,
+    loss_scaler: LossScaler,
+    // Factor of the learning rate after a step skipped by `NanGuard` (Or rolled back by
+    // `SpikeRollback`), and the number of steps it still applies to
+    lr_backoff: (Float, usize),
+    watchdog: Watchdog,
+    prefix_cache: Option<PrefixCache>,
+    rng: ChaCha8Rng,
+    best: BestValidation,
+    schedule: Option<Schedule>,
+    ema: HashMap<String, Tensor<Float>>,
+    swa: SwaState,
+    validation: Vec<usize>,
+    // Fingerprint of the tokenizer the model is trained with, see `set_tokenizer`
+    tokenizer: Option<u64>,
+    saved_tokenizer: Option<SavedTokenizer>,
     token_input: TensorId,
-    pos_input: TensorId,
+    attention_mask: TensorId,
+    // 1 for the tokens of the prefix of the input, only allocated for prefix language models
+    prefix_mask: Option<TensorId>,
+    // Output of the last block (After the final norm), which the head maps to the logits
+    hidden: TensorId,
     output: TensorId,
     expected_output: TensorId,
+    loss_weights: TensorId,
+    z_loss_coeff: TensorId,
+    // Single-element tensors holding the dropout rates, see `DropoutConfig`
+    attention_dropout: TensorId,
+    residual_dropout: TensorId,
+    embedding_dropout: TensorId,
     loss: TensorId,
-    pos_input_fixed: Tensor<f32>,
+    classifier: Option<ClassifierHead>,
+    // Post-softmax attention weights of every head of every layer, see `attention`
+    attention_weights: Vec<Vec<TensorId>>,
+    // Residual stream after every block, see `TrainingStats`
+    block_outputs: Vec<TensorId>,
+    // Projections attended by every key/value group of every layer, see `PrefixCache` (Only
+    // allocated for models without a batch size)
+    cached_attention: Vec<CachedAttention>,
 }
 
-fn sample_dataset<R: Rng>(
-    dataset: &[usize],
-    batch_size: usize,
-    context_size: usize,
-    rng: &mut R,
-) -> (Tensor<usize>, Tensor<usize>) {
-    let mut xs: Vec<usize> = Vec::with_capacity(batch_size * context_size);
-    let mut ys: Vec<usize> = Vec::with_capacity(batch_size * context_size);
-    for _i in 0..batch_size {
-        let start: usize = rng.gen_range(0..dataset.len());
-        let all = dataset
-            .iter()
-            .cycle()
-            .skip(start)
-            .take(context_size + 1)
-            .cloned()
-            .collect::<Vec<_>>();
-        xs.extend(&all[0..context_size]);
-        ys.extend(&all[1..context_size + 1]);
+#[derive(Debug, Clone, Copy)]
+struct CachedAttention {
+    // `[len, head_size]` projections of the positions computed by a previous pass, which the
+    // rows of the context are put after (Empty unless the pass goes through the prefix cache)
+    past_keys: TensorId,
+    past_values: TensorId,
+    // Projections of all the positions
+    keys: TensorId,
+    values: TensorId,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ClassifierHead {
+    pooling: Pooling,
+    // Weights of the hidden states of the positions in the pooled state of each sequence,
+    // `[batch, 1, num_tokens]`
+    pooling_weights: TensorId,
+    logits: TensorId,
+    labels: TensorId,
+    loss: TensorId,
+}
+
+impl ClassifierHead {
+    // Pools the sequences of the attention mask, labeled with `labels` (Zeros when not
+    // training the classifier)
+    fn load<G: Graph>(
+        &self,
+        g: &mut G,
+        mask: &Tensor<Float>,
+        labels: Option<&[usize]>,
+    ) -> Result<(), GraphError> {
+        let weights = pooling_weights(self.pooling, mask)?;
+        let rows = weights.shape()[0];
+        let labels = match labels {
+            Some(labels) => labels.to_vec(),
+            None => vec![0; rows],
+        };
+        g.load(self.pooling_weights, &weights)?;
+        g.load_usize(self.labels, &Tensor::raw(&[rows, 1], labels)?)
+    }
+}
+
+// Weights of the positions of the sequences of an attention mask (`[batch, width]`) in their
+// pooled hidden states, as a `[batch, 1, width]` tensor
+fn pooling_weights(pooling: Pooling, mask: &Tensor<Float>) -> Result<Tensor<Float>, GraphError> {
+    let width = mask.shape()[mask.dim() - 1];
+    let mut weights = Vec::with_capacity(mask.size());
+    for row in mask.blob().chunks(width) {
+        let len = row.iter().filter(|m| **m > 0.).count().max(1);
+        let last = row.iter().rposition(|m| *m > 0.);
+        weights.extend(row.iter().enumerate().map(|(i, m)| match pooling {
+            Pooling::Mean => m / len as Float,
+            Pooling::Last if Some(i) == last => 1.,
+            Pooling::Last => 0.,
+        }));
+    }
+    Ok(Tensor::raw(&[mask.size() / width, 1, width], weights)?)
+}
+
+// Per-position loss weights of the batches (Zero on padding), scaled so that the mean of the
+// weighted losses is the weighted mean of the losses
+// Returns the next batch of the training loops, see `GPT::with_batches`
+type BatchSampler<'a> = dyn FnMut(&mut ChaCha8Rng) -> Result<Batch, GraphError> + 'a;
+
+fn loss_weights(batches: &[&Batch]) -> Result<Vec<Tensor<Float>>, GraphError> {
+    let weights = batches
+        .iter()
+        .map(|b| {
+            Ok(match (&b.weights, &b.attention_mask) {
+                (Some(w), Some(m)) => (w * m)?,
+                (Some(w), None) => w.clone(),
+                (None, Some(m)) => m.clone(),
+                (None, None) => Tensor::constant(b.ys.shape(), 1.),
+            })
+        })
+        .collect::<Result<Vec<_>, GraphError>>()?;
+    let count = batches.iter().map(|b| b.ys.size()).sum::<usize>() as Float;
+    let total = weights
+        .iter()
+        .map(|w| w.blob().iter().sum::<Float>())
+        .sum::<Float>();
+    let scale = if total > 0. { count / total } else { 0. };
+    Ok(weights
+        .iter()
+        .map(|w| w.map_values(|v| v * scale))
+        .collect())
+}
+
+// ln(1 + e^x), without overflowing
+fn softplus(x: Float) -> Float {
+    x.max(0.) + (-x.abs()).exp().ln_1p()
+}
+
+// Statistics of the named tensors, as computed by the last forward pass of the graph
+fn tensor_stats<G: Graph>(
+    graph: &mut G,
+    tensors: &[(String, TensorId)],
+) -> Result<Vec<TensorStats>, GraphError> {
+    tensors
+        .iter()
+        .map(|(name, id)| {
+            graph.fetch(*id, false)?;
+            Ok(TensorStats::new(
+                name.clone(),
+                graph.get(*id)?.to_float()?.blob(),
+            ))
+        })
+        .collect()
+}
+
+// Loads the prefix of the input of a prefix language model, no prefix when `None`
+fn load_prefix<G: Graph>(
+    graph: &mut G,
+    prefix_mask: Option<TensorId>,
+    prefix: Option<&Tensor<Float>>,
+    shape: &[usize],
+) -> Result<(), GraphError> {
+    match (prefix_mask, prefix) {
+        (Some(id), Some(prefix)) => graph.load(id, &Tensor::raw(shape, prefix.blob().to_vec())?),
+        (Some(id), None) => graph.load(id, &Tensor::zeros(shape)),
+        (None, _) => Ok(()),
+    }
+}
+
+fn attention_mask(batch: &Batch) -> Tensor<Float> {
+    batch
+        .attention_mask
+        .clone()
+        .unwrap_or_else(|| Tensor::constant(batch.xs.shape(), 1.))
+}
+
+// Block of a parameter, the first number in its name (E.g. `head_2_0_q` or `norm_2_coeff`)
+pub(crate) fn layer_of(name: &str) -> Option<usize> {
+    name.split('_').skip(1).find_map(|t| t.parse().ok())
+}
+
+fn is_adapter(name: &str) -> bool {
+    name.ends_with("_lora_a") || name.ends_with("_lora_b")
+}
+
+impl GPTConfig {
+    // A tiny model of 5 tokens, for tests (Other fields can be set with struct update syntax)
+    pub fn tiny() -> Self {
+        GPTConfig {
+            vocab_size: 5,
+            embedding_degree: 8,
+            num_tokens: 6,
+            num_layers: 1,
+            num_heads: 2,
+            num_kv_heads: 2,
+            head_size: None,
+            feedforward_multiplier: 2.,
+            dropout: 0.0,
+            positional_encoding: PositionalEncoding::Sinusoidal,
+            activation: Activation::Gelu,
+            feedforward: FeedForward::Mlp,
+            norm_placement: NormPlacement::PreNorm,
+            final_norm: true,
+            bias: false,
+            precision: Precision::F32,
+            lora: None,
+            classifier: None,
+            init: InitScheme::default(),
+            prefix_lm: false,
+        }
+    }
+
+    pub fn head_size(&self) -> Result<usize, GraphError> {
+        match self.head_size {
+            Some(head_size) => Ok(head_size),
+            None => {
+                if self.num_heads == 0 || !self.embedding_degree.is_multiple_of(self.num_heads) {
+                    return Err(GraphError::InvalidConfig(format!(
+                        "embedding_degree ({}) should be divisible by num_heads ({}) when \
+                        head_size is not specified",
+                        self.embedding_degree, self.num_heads
+                    )));
+                }
+                Ok(self.embedding_degree / self.num_heads)
+            }
+        }
+    }
+
+    pub fn feedforward_degree(&self) -> usize {
+        (self.feedforward_multiplier * self.embedding_degree as Float).round() as usize
+    }
+
+    pub fn validate(&self) -> Result<(), GraphError> {
+        let err = |msg: String| Err(GraphError::InvalidConfig(msg));
+        for (name, value) in [
+            ("vocab_size", self.vocab_size),
+            ("embedding_degree", self.embedding_degree),
+            ("num_tokens", self.num_tokens),
+            ("num_heads", self.num_heads),
+            ("num_kv_heads", self.num_kv_heads),
+        ] {
+            if value == 0 {
+                return err(format!("{} should be greater than zero", name));
+            }
+        }
+        if !self.num_heads.is_multiple_of(self.num_kv_heads) {
+            return err(format!(
+                "num_heads ({}) should be divisible by num_kv_heads ({})",
+                self.num_heads, self.num_kv_heads
+            ));
+        }
+        let head_size = self.head_size()?;
+        if head_size == 0 {
+            return err("head_size should be greater than zero".into());
+        }
+        if self.positional_encoding == PositionalEncoding::Rope && head_size % 2 != 0 {
+            return err(format!(
+                "head_size ({}) should be even when using rotary embeddings",
+                head_size
+            ));
+        }
+        if self.feedforward_degree() == 0 {
+            return err(format!(
+                "feedforward_multiplier ({}) is too small for embedding_degree ({})",
+                self.feedforward_multiplier, self.embedding_degree
+            ));
+        }
+        if !(0.0..1.0).contains(&self.dropout) {
+            return err(format!("dropout ({}) should be in [0, 1)", self.dropout));
+        }
+        if self.classifier.is_some_and(|c| c.num_classes == 0) {
+            return err("num_classes should be greater than zero".into());
+        }
+        if let InitScheme::Scaled { std } = self.init {
+            if std.is_nan() || std <= 0. {
+                return err(format!("the init std ({}) should be positive", std));
+            }
+        }
+        Ok(())
     }
+}
 
-    (
-        Tensor::raw(&[batch_size, context_size], xs).unwrap(),
-        Tensor::raw(&[batch_size, context_size], ys).unwrap(),
-    )
+fn layer_norm<G: Graph, R: Rng>(
+    g: &mut G,
+    rng: &mut R,
+    inp: TensorId,
+    embedding_degree: usize,
+    name: String,
+    bias: bool,
+    init: InitScheme,
+) -> Result<TensorId, GraphError> {
+    let coeff = g.alloc(
+        init.norm_gain(rng, embedding_degree),
+        true,
+        format!("{}_coeff", name),
+    )?;
+    if bias {
+        let bias = g.alloc(
+            Tensor::<Float>::zeros(&[embedding_degree]),
+            true,
+            format!("{}_bias", name),
+        )?;
+        g.call(LayerNorm::new(), &[inp, coeff, bias])
+    } else {
+        g.call(LayerNorm::new(), &[inp, coeff])
+    }
 }
 
-fn select<R: Rng, T: TensorOps<f32>>(
+// Adds a low-rank adapter to `result`, the product of `inp` and the weights `weights`:
+// `result + (inp * A * B) * alpha / rank`. `B` starts at zero, so that the adapter does not
+// change the model before training.
+fn lora_adapter<G: Graph, R: Rng>(
+    g: &mut G,
     rng: &mut R,
-    t: &T,
-    temperature: f32,
-) -> Result<usize, TensorError> {
-    let t = Softmax::new().run(
-        &[&GeneralTensor::Float(Tensor::<f32>::raw(
-            t.shape(),
-            t.blob().to_vec(),
-        )?)],
-        false,
+    inp: TensorId,
+    weights: TensorId,
+    result: TensorId,
+    lora: Option<LoraConfig>,
+) -> Result<TensorId, GraphError> {
+    let lora = match lora {
+        Some(lora) => lora,
+        None => return Ok(result),
+    };
+    let shape = g.get(weights)?.as_float()?.shape().to_vec();
+    let name = g.name_of(weights)?.clone();
+    let a = g.alloc(
+        Tensor::<Float>::rand(rng, &[shape[0], lora.rank]),
+        true,
+        format!("{}_lora_a", name),
     )?;
-    let mut ts = t.blob().iter().cloned().enumerate().collect::<Vec<_>>();
-    ts.sort_by_key(|(_, b)| (b * 1000.) as usize);
-    let dice = rng.gen_range(0.0..temperature);
-    let mut accum = 0.;
-    for (id, t) in ts.iter().rev() {
-        accum += t;
-        if dice < accum {
-            return Ok(*id);
-        }
-    }
-    panic!();
-}
-
-fn pos_encode_inter(num_tokens: usize, embedding_size: usize) -> Tensor<f32> {
-    let mut raw_new = Vec::new();
-    let cols = embedding_size;
-    let rows = num_tokens;
-    for row in 0..rows {
-        for col in 0..cols {
-            let k = row as f32;
-            let i = (col / 2) as f32;
-            let factor = 10000f32.powf(2f32 * i / embedding_size as f32);
-
-            let pos = if col % 2 == 0 {
-                (k / factor).sin()
-            } else {
-                (k / factor).cos()
-            };
+    let b = g.alloc(
+        Tensor::<Float>::zeros(&[lora.rank, shape[1]]),
+        true,
+        format!("{}_lora_b", name),
+    )?;
+    let down = g.call(MatMul::new(), &[inp, a])?;
+    let up = g.call(MatMul::new(), &[down, b])?;
+    let scaled = g.call(Coeff::new(lora.scale()), &[up])?;
+    g.call(Add::new(), &[result, scaled])
+}
 
-            raw_new.push(pos);
-        }
+// Maps `in_degree` dimension vectors into `out_degree` dimension vectors (Plus an optional bias)
+#[allow(clippy::too_many_arguments)]
+fn linear<G: Graph, R: Rng>(
+    g: &mut G,
+    rng: &mut R,
+    inp: TensorId,
+    in_degree: usize,
+    out_degree: usize,
+    name: String,
+    bias: bool,
+    lora: Option<LoraConfig>,
+    std: Float,
+) -> Result<TensorId, GraphError> {
+    let weights = g.alloc(
+        Tensor::<Float>::rand_normal(rng, std, &[in_degree, out_degree]),
+        true,
+        format!("{}_weights", name),
+    )?;
+    let result = g.call(MatMul::new(), &[inp, weights])?;
+    let result = lora_adapter(g, rng, inp, weights, result, lora)?;
+    if bias {
+        let bias = g.alloc(
+            Tensor::<Float>::zeros(&[out_degree]),
+            true,
+            format!("{}_bias", name),
+        )?;
+        g.call(Add::new(), &[result, bias])
+    } else {
+        Ok(result)
     }
+}
 
-    Tensor::raw(&[rows, cols], raw_new).unwrap()
+// Query/key/value projection of an attention head. Same as `linear`, but the weights are
+// named after the head.
+#[allow(clippy::too_many_arguments)]
+fn head_projection<G: Graph, R: Rng>(
+    g: &mut G,
+    rng: &mut R,
+    inp: TensorId,
+    embedding_degree: usize,
+    head_size: usize,
+    name: String,
+    bias: bool,
+    lora: Option<LoraConfig>,
+    std: Float,
+) -> Result<TensorId, GraphError> {
+    let weights = g.alloc(
+        Tensor::<Float>::rand_normal(rng, std, &[embedding_degree, head_size]),
+        true,
+        name.clone(),
+    )?;
+    let result = g.call(MatMul::new(), &[inp, weights])?;
+    let result = lora_adapter(g, rng, inp, weights, result, lora)?;
+    if bias {
+        let bias = g.alloc(
+            Tensor::<Float>::zeros(&[head_size]),
+            true,
+            format!("{}_bias", name),
+        )?;
+        g.call(Add::new(), &[result, bias])
+    } else {
+        Ok(result)
+    }
 }
 
 impl<G: Graph> GPT<G> {
@@ -105,17 +860,39 @@ impl<G: Graph> GPT<G> {
         rng: &mut R,
         mut g: G,
         batch_size: Option<usize>,
-        vocab_size: usize,
-        embedding_degree: usize,
-        num_tokens: usize,
-        num_layers: usize,
-        num_heads: usize,
-        head_size: usize,
-        dropout: f32,
+        config: GPTConfig,
     ) -> Result<Self, GraphError> {
+        let GPTConfig {
+            vocab_size,
+            embedding_degree,
+            num_tokens,
+            num_layers,
+            num_heads,
+            num_kv_heads,
+            dropout,
+            positional_encoding,
+            activation,
+            feedforward,
+            norm_placement,
+            final_norm,
+            bias,
+            precision,
+            lora,
+            classifier,
+            init,
+            prefix_lm,
+            ..
+        } = config;
+
+        config.validate()?;
+        let head_size = config.head_size()?;
+        let feedforward_degree = config.feedforward_degree();
+        let std = init.std(false, num_layers);
+        let residual_std = init.std(true, num_layers);
+
         // Mapping each token to a `embedding_degree` dimension space through a lookup table
         let token_embedding = g.alloc(
-            Tensor::<f32>::rand(rng, &[vocab_size, embedding_degree]),
+            Tensor::<Float>::rand_normal(rng, std, &[vocab_size, embedding_degree]),
             true,
             "token_embedding".into(),
         )?;
@@ -136,6 +913,34 @@ impl<G: Graph> GPT<G> {
             "token_input".into(),
         )?;
 
+        // 1 for the real tokens of the input, 0 for the padding
+        let attention_mask = g.alloc(
+            Tensor::<Float>::constant(
+                &if let Some(batch_size) = batch_size {
+                    vec![batch_size, num_tokens]
+                } else {
+                    vec![num_tokens]
+                },
+                1.,
+            ),
+            false,
+            "attention_mask".into(),
+        )?;
+
+        let prefix_mask = if prefix_lm {
+            Some(g.alloc(
+                Tensor::<Float>::zeros(&if let Some(batch_size) = batch_size {
+                    vec![batch_size, num_tokens]
+                } else {
+                    vec![num_tokens]
+                }),
+                false,
+                "prefix_mask".into(),
+            )?)
+        } else {
+            None
+        };
+
         let expected_output = g.alloc_usize(
             Tensor::<usize>::zeros(&if let Some(batch_size) = batch_size {
                 vec![batch_size, num_tokens]
@@ -145,389 +950,2754 @@ impl<G: Graph> GPT<G> {
             "expected_output".into(),
         )?;
 
+        // Weights of the losses of the positions, for masking out parts of the sequences
+        let loss_weights = g.alloc(
+            Tensor::<Float>::constant(
+                &if let Some(batch_size) = batch_size {
+                    vec![batch_size, num_tokens]
+                } else {
+                    vec![num_tokens]
+                },
+                1.,
+            ),
+            false,
+            "loss_weights".into(),
+        )?;
+        let z_loss_coeff = g.alloc(
+            Tensor::<Float>::zeros(&if let Some(batch_size) = batch_size {
+                vec![batch_size, num_tokens]
+            } else {
+                vec![num_tokens]
+            }),
+            false,
+            "z_loss_coeff".into(),
+        )?;
+        // Dropout rates, loaded by the training loops
+        let mut dropout_rate =
+            |name: &str| g.alloc(Tensor::<Float>::constant(&[1], dropout), false, name.into());
+        let attention_dropout = dropout_rate("attention_dropout")?;
+        let residual_dropout = dropout_rate("residual_dropout")?;
+        let embedding_dropout = dropout_rate("embedding_dropout")?;
+        // Without a batch size, the positions may start after the ones of a previous pass,
+        // which the positional encodings tell from the attention mask (See `PrefixCache`)
+        let positions = |inps: &[TensorId]| {
+            let mask = batch_size.is_none().then_some(attention_mask);
+            inps.iter().cloned().chain(mask).collect::<Vec<_>>()
+        };
+
         // Map the token index into a `embedding_degree` dimension vector through the `token_embedding`
         // lookup table.
         let embedded_token_input = g.call(Embedding::new(), &[token_input, token_embedding])?;
 
-        // Map token positions into `embedding_degree` dimension vectors.
-        let pos_input = g.alloc(
-            Tensor::<f32>::rand(rng, &[num_tokens, embedding_degree]),
-            false,
-            "pos_input".into(),
-        )?;
+        let inp = match positional_encoding {
+            PositionalEncoding::Learned => {
+                // Map token positions into `embedding_degree` dimension vectors.
+                let pos_embedding = g.alloc(
+                    Tensor::<Float>::rand_normal(rng, std, &[num_tokens, embedding_degree]),
+                    true,
+                    "pos_embedding".into(),
+                )?;
 
-        // Positional+Token information will both reside in a single `embedding_degree` dimension
-        // vector.
-        let inp = g.call(Add::new(), &[embedded_token_input, pos_input])?;
+                // Positional+Token information will both reside in a single `embedding_degree`
+                // dimension vector.
+                g.call(
+                    Positional::new(),
+                    &positions(&[embedded_token_input, pos_embedding]),
+                )?
+            }
+            PositionalEncoding::Sinusoidal => g.call(
+                Sinusoidal::new(num_tokens, embedding_degree),
+                &positions(&[embedded_token_input]),
+            )?,
+            // Positions are taken into account inside the attention layers
+            PositionalEncoding::Rope | PositionalEncoding::Alibi => embedded_token_input,
+        };
 
-        let mut curr_inp = inp;
+        let mut curr_inp = g.call(Dropout::new(), &[inp, embedding_dropout])?;
+        let mut attention_weights = Vec::new();
+        let mut block_outputs = Vec::new();
+        let mut cached_attention = Vec::new();
         for l in 0..num_layers {
-            // Normalize input before applying multi-head attention
-            let norm_coeff = g.alloc(
-                Tensor::<f32>::rand(rng, &[embedding_degree]),
-                true,
-                format!("norm_{}_coeff", l),
-            )?;
-            let norm_bias = g.alloc(
-                Tensor::<f32>::zeros(&[embedding_degree]),
-                true,
-                format!("norm_{}_bias", l),
-            )?;
-            let norm_inp = g.call(LayerNorm::new(), &[curr_inp, norm_coeff, norm_bias])?;
+            // Normalize input before applying multi-head attention (Unless post-norm)
+            let (norm_inp, atten_residual) = match norm_placement {
+                NormPlacement::Original => {
+                    let norm_inp = layer_norm(
+                        &mut g,
+                        rng,
+                        curr_inp,
+                        embedding_degree,
+                        format!("norm_{}", l),
+                        bias,
+                        init,
+                    )?;
+                    (norm_inp, norm_inp)
+                }
+                NormPlacement::PreNorm => {
+                    let norm_inp = layer_norm(
+                        &mut g,
+                        rng,
+                        curr_inp,
+                        embedding_degree,
+                        format!("norm_{}", l),
+                        bias,
+                        init,
+                    )?;
+                    (norm_inp, curr_inp)
+                }
+                NormPlacement::PostNorm => (curr_inp, curr_inp),
+            };
+
+            // Note: in this implementation `k` projections index the rows of the attention
+            // matrix (The attending positions) while `q` projections index its columns (The
+            // attended positions). That's why `q` and `v` are the projections shared among the
+            // heads of the same group in grouped-query attention.
+            let mut kv_groups = Vec::new();
+            for kv in 0..num_kv_heads {
+                let mut q = head_projection(
+                    &mut g,
+                    rng,
+                    norm_inp,
+                    embedding_degree,
+                    head_size,
+                    format!("head_{}_{}_q", l, kv),
+                    bias,
+                    lora,
+                    std,
+                )?;
+                let v = head_projection(
+                    &mut g,
+                    rng,
+                    norm_inp,
+                    embedding_degree,
+                    head_size,
+                    format!("head_{}_{}_v", l, kv),
+                    bias,
+                    lora,
+                    std,
+                )?;
+
+                if positional_encoding == PositionalEncoding::Rope {
+                    q = g.call(Rope::new(), &positions(&[q]))?;
+                }
+
+                // Put after the projections of the cached positions
+                if batch_size.is_none() {
+                    let mut past = |name: &str| {
+                        g.alloc(
+                            Tensor::<Float>::zeros(&[0, head_size]),
+                            false,
+                            format!("head_{}_{}_{}_past", l, kv, name),
+                        )
+                    };
+                    let (past_keys, past_values) = (past("q")?, past("v")?);
+                    let keys = g.call(Prepend::new(), &[q, past_keys])?;
+                    let values = g.call(Prepend::new(), &[v, past_values])?;
+                    cached_attention.push(CachedAttention {
+                        past_keys,
+                        past_values,
+                        keys,
+                        values,
+                    });
+                    kv_groups.push((keys, values));
+                } else {
+                    kv_groups.push((q, v));
+                }
+            }
 
             let mut heads = Vec::new();
+            let mut weights = Vec::new();
 
             // Multi-head Attention
             for h in 0..num_heads {
                 // Key
-                let k_params = g.alloc(
-                    Tensor::<f32>::rand(rng, &[embedding_degree, head_size]),
-                    true,
+                let mut k = head_projection(
+                    &mut g,
+                    rng,
+                    norm_inp,
+                    embedding_degree,
+                    head_size,
                     format!("head_{}_{}_k", l, h),
+                    bias,
+                    lora,
+                    std,
                 )?;
-                let k = g.call(MatMul::new(), &[norm_inp, k_params])?;
 
-                // Query
-                let q_params = g.alloc(
-                    Tensor::<f32>::rand(rng, &[embedding_degree, head_size]),
-                    true,
-                    format!("head_{}_{}_q", l, h),
-                )?;
-                let q = g.call(MatMul::new(), &[norm_inp, q_params])?;
+                if positional_encoding == PositionalEncoding::Rope {
+                    k = g.call(Rope::new(), &positions(&[k]))?;
+                }
 
-                // Value
-                let v_params = g.alloc(
-                    Tensor::<f32>::rand(rng, &[embedding_degree, head_size]),
-                    true,
-                    format!("head_{}_{}_v", l, h),
-                )?;
-                let v = g.call(MatMul::new(), &[norm_inp, v_params])?;
+                // Query and Value, shared among the heads of a group
+                let (q, v) = kv_groups[h / (num_heads / num_kv_heads)];
 
                 let q_t = g.call(Transpose::new(), &[q])?;
                 let kq = g.call(MatMul::new(), &[k, q_t])?;
+                let kq = g.call(PadMask::new(), &[kq, attention_mask])?;
 
-                let head_size_sqrt_inv = (head_size as f32).powf(-0.5);
+                let head_size_sqrt_inv = (head_size as Float).powf(-0.5);
                 let kq_coeff = g.call(Coeff::new(head_size_sqrt_inv), &[kq])?;
 
-                let masked_kq = g.call(TrilMask::new(num_tokens), &[kq_coeff])?;
+                let kq_coeff = if positional_encoding == PositionalEncoding::Alibi {
+                    g.call(Alibi::new(Alibi::slope(h, num_heads)), &[kq_coeff])?
+                } else {
+                    kq_coeff
+                };
+
+                let masked_kq = match prefix_mask {
+                    Some(prefix_mask) => g.call(PrefixMask::new(), &[kq_coeff, prefix_mask])?,
+                    None => g.call(TrilMask::new(num_tokens), &[kq_coeff])?,
+                };
                 let soft_masked_kq = g.call(Softmax::new(), &[masked_kq])?;
-                let dropped_soft_masked_kq = g.call(Dropout::new(dropout), &[soft_masked_kq])?;
+                let dropped_soft_masked_kq =
+                    g.call(Dropout::new(), &[soft_masked_kq, attention_dropout])?;
                 let atten = g.call(MatMul::new(), &[dropped_soft_masked_kq, v])?;
                 heads.push(atten);
+                weights.push(soft_masked_kq);
             }
+            attention_weights.push(weights);
 
             // Concat head results and project into embedding_degree
             let cat = g.call(Cat::new(), &heads)?;
-            let proj_params = g.alloc(
-                Tensor::<f32>::rand(rng, &[num_heads * head_size, embedding_degree]),
-                true,
-                format!("proj_{}_weights", l),
-            )?;
-            let proj_bias_params = g.alloc(
-                Tensor::<f32>::zeros(&[embedding_degree]),
-                true,
-                format!("proj_{}_bias", l),
-            )?;
-            let proj_cat = g.call(MatMul::new(), &[cat, proj_params])?;
-            let proj_cat_bias = g.call(Add::new(), &[proj_cat, proj_bias_params])?;
-            let dropped_proj_cat_bias = g.call(Dropout::new(dropout), &[proj_cat_bias])?;
-
-            // Add attention results to input and then normalize
-            let add_atten = g.call(Add::new(), &[norm_inp, dropped_proj_cat_bias])?;
-            let add_atten_norm_coeff = g.alloc(
-                Tensor::<f32>::rand(rng, &[embedding_degree]),
-                true,
-                format!("atten_norm_{}_coeff", l),
-            )?;
-            let add_atten_norm_bias = g.alloc(
-                Tensor::<f32>::zeros(&[embedding_degree]),
-                true,
-                format!("atten_norm_{}_bias", l),
-            )?;
-            let add_atten_norm = g.call(
-                LayerNorm::new(),
-                &[add_atten, add_atten_norm_coeff, add_atten_norm_bias],
+            let proj_cat_bias = linear(
+                &mut g,
+                rng,
+                cat,
+                num_heads * head_size,
+                embedding_degree,
+                format!("proj_{}", l),
+                bias,
+                lora,
+                residual_std,
             )?;
+            let dropped_proj_cat_bias =
+                g.call(Dropout::new(), &[proj_cat_bias, residual_dropout])?;
+
+            // Add attention results to the residual stream and then normalize
+            let add_atten = g.call(Add::new(), &[atten_residual, dropped_proj_cat_bias])?;
+            let (add_atten_norm, feedforward_residual) = match norm_placement {
+                NormPlacement::Original => {
+                    let add_atten_norm = layer_norm(
+                        &mut g,
+                        rng,
+                        add_atten,
+                        embedding_degree,
+                        format!("atten_norm_{}", l),
+                        bias,
+                        init,
+                    )?;
+                    (add_atten_norm, add_atten_norm)
+                }
+                NormPlacement::PreNorm => {
+                    let add_atten_norm = layer_norm(
+                        &mut g,
+                        rng,
+                        add_atten,
+                        embedding_degree,
+                        format!("atten_norm_{}", l),
+                        bias,
+                        init,
+                    )?;
+                    (add_atten_norm, add_atten)
+                }
+                NormPlacement::PostNorm => {
+                    let add_atten_norm = layer_norm(
+                        &mut g,
+                        rng,
+                        add_atten,
+                        embedding_degree,
+                        format!("norm_{}", l),
+                        bias,
+                        init,
+                    )?;
+                    (add_atten_norm, add_atten_norm)
+                }
+            };
 
             // A feed-forward layer:
-            // Linear embedding_degree -> 4*embedding_degree
-            // Relu
-            // Linear 4*embedding_degree -> embedding_degree
-            let lin1_params = g.alloc(
-                Tensor::<f32>::rand(rng, &[embedding_degree, 4 * embedding_degree]),
-                true,
-                format!("feedforward1_{}_weights", l),
-            )?;
-            let bias1_params = g.alloc(
-                Tensor::<f32>::zeros(&[4 * embedding_degree]),
-                true,
-                format!("feedforward1_{}_bias", l),
-            )?;
-            let lin1_result = g.call(MatMul::new(), &[add_atten_norm, lin1_params])?;
-            let lin1_bias_result = g.call(Add::new(), &[lin1_result, bias1_params])?;
-            let lin1_act = g.call(Gelu::new(), &[lin1_bias_result])?;
-            let lin2_params = g.alloc(
-                Tensor::<f32>::rand(rng, &[4 * embedding_degree, embedding_degree]),
-                true,
-                format!("feedforward2_{}_weights", l),
+            // Linear embedding_degree -> feedforward_multiplier*embedding_degree
+            // Activation (Gelu/Relu), or a SiLU gate in case of SwiGLU
+            // Linear feedforward_multiplier*embedding_degree -> embedding_degree
+            let lin1_bias_result = linear(
+                &mut g,
+                rng,
+                add_atten_norm,
+                embedding_degree,
+                feedforward_degree,
+                format!("feedforward1_{}", l),
+                bias,
+                lora,
+                std,
             )?;
-            let bias2_params = g.alloc(
-                Tensor::<f32>::zeros(&[embedding_degree]),
-                true,
-                format!("feedforward2_{}_bias", l),
+            let lin1_act = match feedforward {
+                FeedForward::Mlp => g.call(activation.function(), &[lin1_bias_result])?,
+                FeedForward::SwiGlu => {
+                    // Gate the SiLU activated projection with a second parallel projection:
+                    // Silu(x * W1) * (x * W3)
+                    let lin3_bias_result = linear(
+                        &mut g,
+                        rng,
+                        add_atten_norm,
+                        embedding_degree,
+                        feedforward_degree,
+                        format!("feedforward3_{}", l),
+                        bias,
+                        lora,
+                        std,
+                    )?;
+                    let gate = g.call(Silu::new(), &[lin1_bias_result])?;
+                    g.call(Mul::new(), &[gate, lin3_bias_result])?
+                }
+            };
+            let lin2_bias_result = linear(
+                &mut g,
+                rng,
+                lin1_act,
+                feedforward_degree,
+                embedding_degree,
+                format!("feedforward2_{}", l),
+                bias,
+                lora,
+                residual_std,
             )?;
-            let lin2_result = g.call(MatMul::new(), &[lin1_act, lin2_params])?;
-            let lin2_bias_result = g.call(Add::new(), &[lin2_result, bias2_params])?;
 
-            curr_inp = g.call(Add::new(), &[add_atten_norm, lin2_bias_result])?;
+            let dropped_lin2_bias_result =
+                g.call(Dropout::new(), &[lin2_bias_result, residual_dropout])?;
+            let add_feedforward = g.call(
+                Add::new(),
+                &[feedforward_residual, dropped_lin2_bias_result],
+            )?;
+            curr_inp = if norm_placement == NormPlacement::PostNorm {
+                layer_norm(
+                    &mut g,
+                    rng,
+                    add_feedforward,
+                    embedding_degree,
+                    format!("atten_norm_{}", l),
+                    bias,
+                    init,
+                )?
+            } else {
+                add_feedforward
+            };
+            block_outputs.push(curr_inp);
         }
 
         // Normalize the output after the last layer
-        let norm_out_coeff = g.alloc(
-            Tensor::<f32>::rand(rng, &[embedding_degree]),
-            true,
-            format!("head_norm_coeff"),
-        )?;
-        let norm_out_bias = g.alloc(
-            Tensor::<f32>::zeros(&[embedding_degree]),
-            true,
-            format!("head_norm_bias"),
-        )?;
-        let norm_out = g.call(LayerNorm::new(), &[curr_inp, norm_out_coeff, norm_out_bias])?;
+        let norm_out = if final_norm {
+            layer_norm(
+                &mut g,
+                rng,
+                curr_inp,
+                embedding_degree,
+                "head_norm".into(),
+                bias,
+                init,
+            )?
+        } else {
+            curr_inp
+        };
 
         // Map from embedding_degree to vocab_size through a linear layer
-        let to_vocab = g.alloc(
-            Tensor::<f32>::rand(rng, &[embedding_degree, vocab_size]),
-            true,
-            format!("head_map_weights"),
-        )?;
-        let to_vocab_bias = g.alloc(
-            Tensor::<f32>::zeros(&[vocab_size]),
-            true,
-            format!("head_map_bias"),
+        let output = linear(
+            &mut g,
+            rng,
+            norm_out,
+            embedding_degree,
+            vocab_size,
+            "head_map".into(),
+            bias,
+            None,
+            std,
         )?;
-        let result_lin = g.call(MatMul::new(), &[norm_out, to_vocab])?;
-        let output = g.call(Add::new(), &[result_lin, to_vocab_bias])?;
 
-        let loss = g.call(CrossEntropy::new(), &[output, expected_output])?;
+        let cross_entropy = g.call(CrossEntropy::new(), &[output, expected_output])?;
+        let z_loss = g.call(ZLoss::new(), &[output, expected_output])?;
+        let z_loss = g.call(Mul::new(), &[z_loss, z_loss_coeff])?;
+        let token_loss = g.call(Add::new(), &[cross_entropy, z_loss])?;
+        let loss = g.call(Mul::new(), &[token_loss, loss_weights])?;
+
+        // The classes are predicted from a weighted sum of the hidden states of the positions
+        let classifier = match classifier {
+            Some(ClassifierConfig {
+                num_classes,
+                pooling,
+            }) => {
+                let rows = batch_size.unwrap_or(1);
+                let full = Tensor::<Float>::constant(&[rows, num_tokens], 1.);
+                let pooling_weights = g.alloc(
+                    pooling_weights(pooling, &full)?,
+                    false,
+                    "pooling_weights".into(),
+                )?;
+                let pooled = g.call(MatMul::new(), &[pooling_weights, norm_out])?;
+                let logits = linear(
+                    &mut g,
+                    rng,
+                    pooled,
+                    embedding_degree,
+                    num_classes,
+                    "classifier".into(),
+                    bias,
+                    None,
+                    std,
+                )?;
+                let labels = g.alloc_usize(Tensor::zeros(&[rows, 1]), "labels".into())?;
+                let loss = g.call(CrossEntropy::new(), &[logits, labels])?;
+                Some(ClassifierHead {
+                    pooling,
+                    pooling_weights,
+                    logits,
+                    labels,
+                    loss,
+                })
+            }
+            None => None,
+        };
+
+        if lora.is_some() {
+            for p in g.params().to_vec() {
+                let name = g.name_of(p)?;
+                if !is_adapter(name) && !name.starts_with("classifier") {
+                    g.set_frozen(p, true)?;
+                }
+            }
+        }
+
+        let loss_scaler = LossScaler::new();
+        g.set_precision(precision)?;
+        if precision != Precision::F32 {
+            g.set_loss_scale(loss_scaler.scale());
+        }
 
         Ok(Self {
             graph: g,
+            config,
+            options: TrainingOptions::default(),
             num_tokens,
+            batch_size,
+            precision,
+            loss_scaler,
+            lr_backoff: (1., 0),
+            watchdog: Watchdog::default(),
+            prefix_cache: None,
+            rng: ChaCha8Rng::seed_from_u64(rng.gen()),
+            best: BestValidation::default(),
+            schedule: None,
+            ema: HashMap::new(),
+            swa: SwaState::default(),
+            validation: Vec::new(),
+            tokenizer: None,
+            saved_tokenizer: None,
             token_input,
-            pos_input,
+            attention_mask,
+            prefix_mask,
+            hidden: norm_out,
             output,
             expected_output,
+            loss_weights,
+            z_loss_coeff,
+            attention_dropout,
+            residual_dropout,
+            embedding_dropout,
             loss,
-            pos_input_fixed: pos_encode_inter(num_tokens, embedding_degree),
+            classifier,
+            attention_weights,
+            block_outputs,
+            cached_attention,
         })
     }
 
-    pub fn sync(&mut self) -> Result<(), GraphError> {
-        self.graph
-            .params()
-            .to_vec()
-            .into_iter()
-            .map(|p| self.graph.fetch(p, false))
-            .collect::<Result<Vec<_>, GraphError>>()?;
-        Ok(())
+    pub fn config(&self) -> &GPTConfig {
+        &self.config
     }
 
-    pub fn num_params(&self) -> usize {
-        self.graph
-            .params()
-            .to_vec()
-            .into_iter()
-            .map(|p| self.graph.get(p).unwrap().as_float().unwrap().size())
-            .sum::<usize>()
+    // Records the tokenizer the model is trained with in its checkpoints, so that it can be
+    // checked with `check_tokenizer` when they are loaded. Tokenizers that can be saved (See
+    // `Tokenizer::saved`) are stored in the checkpoints as well, and restored by `load_from`.
+    pub fn set_tokenizer<T: Tokenizer>(&mut self, tokenizer: &T) {
+        self.tokenizer = Some(tokenizer.fingerprint());
+        self.saved_tokenizer = tokenizer.saved();
     }
 
-    pub fn set_training_state(
-        &mut self,
-        training_state: TrainingState,
-        load_optimizer: bool,
-    ) -> Result<(), GraphError> {
-        for p in self.graph.params().to_vec() {
-            let name = self.graph.name_of(p)?;
-            if let Some(t) = training_state.tensors.get(name) {
-                self.graph.load(p, t)?;
-            }
-        }
+    pub fn tokenizer(&self) -> Option<&SavedTokenizer> {
+        self.saved_tokenizer.as_ref()
+    }
+
+    // Tokenizes `text` with the tokenizer of the model
+    pub fn encode(&self, text: &str) -> Result<Vec<usize>, GraphError> {
+        Ok(self.saved_tokenizer()?.tokenize(text))
+    }
+
+    pub fn decode(&self, tokens: &[usize]) -> Result<String, GraphError> {
+        Ok(self.saved_tokenizer()?.untokenize(tokens))
+    }
+
+    fn saved_tokenizer(&self) -> Result<&SavedTokenizer, GraphError> {
+        self.saved_tokenizer.as_ref().ok_or_else(|| {
+            GraphError::InvalidConfig("the model has no tokenizer, see `set_tokenizer`".into())
+        })
+    }
+
+    // Fails if the model is known to be trained with another tokenizer
+    pub fn check_tokenizer<T: Tokenizer>(&self, tokenizer: &T) -> Result<(), GraphError> {
+        match self.tokenizer {
+            Some(fingerprint) if fingerprint != tokenizer.fingerprint() => {
+                Err(GraphError::TokenizerMismatch)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn set_training_options(&mut self, options: TrainingOptions) {
+        self.options = options;
+    }
+
+    // Saves the training state (Parameters, optimizer state, schedule and moving averages)
+    pub fn save_checkpoint<P: AsRef<Path>>(&mut self, path: P) -> Result<(), GraphError> {
+        self.sync()?;
+        std::fs::write(path, self.get_training_state()?.to_bytes()?)?;
+        Ok(())
+    }
+
+    // Sharded checkpoints (Directories written by `save_sharded`) are loaded a shard at a time
+    pub fn load_checkpoint<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        load_optimizer: bool,
+    ) -> Result<(), GraphError> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            return self.load_sharded(&ShardedCheckpoint::open(path)?, load_optimizer);
+        }
+        self.set_training_state(read_training_state(path)?, load_optimizer)
+    }
+
+    // Saves the training state as a sharded checkpoint, see `checkpoint::save_sharded`
+    pub fn save_sharded<P: AsRef<Path>>(
+        &mut self,
+        dir: P,
+        max_shard_bytes: usize,
+    ) -> Result<(), GraphError> {
+        self.sync()?;
+        save_sharded(&self.get_training_state()?, dir, max_shard_bytes)
+    }
+
+    fn load_sharded(
+        &mut self,
+        checkpoint: &ShardedCheckpoint,
+        load_optimizer: bool,
+    ) -> Result<(), GraphError> {
+        // Shapes are checked against the index, before any shard is read
+        let shapes = checkpoint.params().collect::<HashMap<_, _>>();
+        for p in self.graph.params().to_vec() {
+            let name = self.graph.name_of(p)?;
+            if let Some(found) = shapes.get(name.as_str()) {
+                let name = name.clone();
+                self.graph.fetch(p, false)?;
+                let expected = self.graph.get(p)?.shape();
+                if *found != expected {
+                    return Err(GraphError::ShapeMismatch {
+                        name,
+                        expected: expected.to_vec(),
+                        found: found.to_vec(),
+                    });
+                }
+            }
+        }
+        for i in 0..checkpoint.num_shards() {
+            self.load_params(&checkpoint.load_shard(i)?)?;
+        }
+        if let Some(manifest) = checkpoint.manifest() {
+            self.tokenizer = manifest.tokenizer.or(self.tokenizer);
+        }
+        if let Some(tokenizer) = checkpoint.tokenizer() {
+            self.saved_tokenizer = Some(tokenizer.clone());
+        }
+        if load_optimizer {
+            self.load_optimizer_state(checkpoint.training_state_with(false)?)?;
+        }
+        Ok(())
+    }
+
+    // Builds the model stored in a checkpoint (Sharded or not) on `graph`, and loads its
+    // training state
+    pub fn load_from<P: AsRef<Path>>(
+        graph: G,
+        batch_size: Option<usize>,
+        path: P,
+    ) -> Result<Self, GraphError> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            return Self::from_training_state(graph, batch_size, read_training_state(path)?);
+        }
+        let checkpoint = ShardedCheckpoint::open(path)?;
+        let config = checkpoint.config().cloned().ok_or_else(|| {
+            GraphError::InvalidConfig("the checkpoint does not store its config".into())
+        })?;
+        let mut gpt = Self::new(&mut rand::thread_rng(), graph, batch_size, config)?;
+        gpt.check_names(
+            &checkpoint
+                .params()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>(),
+        )?;
+        gpt.load_sharded(&checkpoint, true)?;
+        Ok(gpt)
+    }
+
+    // Unlike `set_training_state`, every parameter of the model has to be in the state, and
+    // every tensor of the state has to be a parameter of the model
+    pub fn from_training_state(
+        graph: G,
+        batch_size: Option<usize>,
+        state: TrainingState,
+    ) -> Result<Self, GraphError> {
+        let config = state.config.clone().ok_or_else(|| {
+            GraphError::InvalidConfig("the checkpoint does not store its config".into())
+        })?;
+        // The initial parameters are all replaced
+        let mut gpt = Self::new(&mut rand::thread_rng(), graph, batch_size, config)?;
+        gpt.check_names(&state.tensors.keys().map(|k| k.as_str()).collect::<Vec<_>>())?;
+        gpt.set_training_state(state, true)?;
+        Ok(gpt)
+    }
+
+    // Checks that the parameters of the model are exactly the tensors named `names`
+    fn check_names(&self, names: &[&str]) -> Result<(), GraphError> {
+        let names = names.iter().cloned().collect::<HashSet<_>>();
+        let mut params = HashSet::new();
+        for p in self.graph.params().iter() {
+            let name = self.graph.name_of(*p)?;
+            if !names.contains(name.as_str()) {
+                return Err(GraphError::MissingTensor(name.clone()));
+            }
+            params.insert(name.as_str());
+        }
+        if let Some(name) = names.iter().find(|name| !params.contains(*name)) {
+            return Err(GraphError::UnexpectedTensor(name.to_string()));
+        }
+        Ok(())
+    }
+
+    // Saves a checkpoint to the configured directory, removing the oldest ones beyond
+    // `keep_last`
+    fn checkpoint(&mut self) -> Result<(), GraphError> {
+        let config = match self.options.checkpoint.clone() {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        std::fs::create_dir_all(&config.dir)?;
+        let step = self.graph.optimizer_step();
+        self.save_checkpoint(config.dir.join(format!("step_{:08}.dat", step)))?;
+        if let Some(keep_last) = config.keep_last {
+            let mut checkpoints = std::fs::read_dir(&config.dir)?
+                .map(|entry| Ok(entry?.path()))
+                .collect::<Result<Vec<_>, std::io::Error>>()?
+                .into_iter()
+                .filter(|path| {
+                    path.file_name()
+                        .and_then(|name| name.to_str())
+                        .is_some_and(|name| name.starts_with("step_") && name.ends_with(".dat"))
+                })
+                .collect::<Vec<_>>();
+            checkpoints.sort();
+            let num_removed = checkpoints.len().saturating_sub(keep_last);
+            for path in &checkpoints[..num_removed] {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Reseeds the RNG the training loops sample batches and dropout masks with. Together
+    // with a seeded initialization (The RNG given to `GPT::new`), training runs with the
+    // same seed are reproducible (With the same number of threads on the CPU, as the
+    // gradients of the workers are summed up in chunks).
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = ChaCha8Rng::seed_from_u64(seed);
+    }
+
+    // Held-out tokens the training loops evaluate the model on, see `TrainingOptions`
+    pub fn set_validation_dataset(&mut self, dataset: Vec<usize>) {
+        self.validation = dataset;
+    }
+
+    // Mean loss of the model on consecutive, non-overlapping windows of the dataset, without
+    // dropout and without updating the weights
+    pub fn evaluate<D: Dataset + ?Sized>(
+        &mut self,
+        dataset: &D,
+        max_batches: Option<usize>,
+    ) -> Result<Evaluation, GraphError> {
+        // Models allocated with a batch size are evaluated with full batches
+        let shape = self.graph.get(self.token_input)?.shape();
+        let batch_size = if shape.len() > 1 { shape[0] } else { 1 };
+        let num_windows = dataset.len().saturating_sub(1) / self.num_tokens;
+        let mut num_batches = num_windows / batch_size;
+        if let Some(max_batches) = max_batches {
+            num_batches = usize::min(num_batches, max_batches);
+        }
+        if num_batches == 0 {
+            return Err(GraphError::InvalidConfig(
+                "the dataset is smaller than a batch".into(),
+            ));
+        }
+
+        let mut loss_sum = 0.;
+        for b in 0..num_batches {
+            let mut xs = Vec::with_capacity(batch_size * self.num_tokens);
+            let mut ys = Vec::with_capacity(batch_size * self.num_tokens);
+            for w in b * batch_size..(b + 1) * batch_size {
+                let start = w * self.num_tokens;
+                let window = dataset.read(start, self.num_tokens + 1)?;
+                xs.extend(&window[..self.num_tokens]);
+                ys.extend(&window[1..]);
+            }
+            let shape = [batch_size, self.num_tokens];
+            self.graph
+                .load_usize(self.token_input, &Tensor::raw(&shape, xs)?)?;
+            self.graph
+                .load_usize(self.expected_output, &Tensor::raw(&shape, ys)?)?;
+            self.graph
+                .load(self.attention_mask, &Tensor::<Float>::constant(&shape, 1.))?;
+            load_prefix(&mut self.graph, self.prefix_mask, None, &shape)?;
+            self.graph
+                .load(self.loss_weights, &Tensor::<Float>::constant(&shape, 1.))?;
+            self.graph
+                .load(self.z_loss_coeff, &Tensor::<Float>::zeros(&shape))?;
+            self.graph.forward(false)?;
+            self.graph.fetch(self.loss, false)?;
+            let loss = self.graph.get(self.loss)?.to_float()?;
+            loss_sum += loss.blob().iter().sum::<Float>() / loss.size() as Float;
+        }
+        let loss = loss_sum / num_batches as Float;
+        Ok(Evaluation {
+            loss,
+            perplexity: loss.exp(),
+        })
+    }
+
+    // Evaluates the validation loss when it's due, keeping the state with the best loss.
+    // Returns true when training should stop early.
+    fn validate<C: TrainCallback<G>>(
+        &mut self,
+        info: &StepInfo,
+        summary: &mut TrainingSummary,
+        callback: &mut C,
+    ) -> Result<bool, GraphError> {
+        let interval = match self.options.eval_interval {
+            Some(interval) if !self.validation.is_empty() => interval,
+            _ => return Ok(false),
+        };
+        if !self
+            .graph
+            .optimizer_step()
+            .is_multiple_of(usize::max(interval, 1))
+        {
+            return Ok(false);
+        }
+        let validation = std::mem::take(&mut self.validation);
+        let eval = self.evaluate(&validation, self.options.eval_batches);
+        self.validation = validation;
+        let eval = eval?;
+        summary.evaluations.push((info.step, info.loss, eval));
+        if callback.on_eval(self, info, &eval)?.is_break() {
+            summary.stopped_early = true;
+        }
+        let loss = eval.loss;
+
+        let min_delta = self
+            .options
+            .early_stopping
+            .as_ref()
+            .map(|e| e.min_delta)
+            .unwrap_or(0.);
+        if self.best.loss.is_none_or(|best| loss < best - min_delta) {
+            self.sync()?;
+            self.best = BestValidation {
+                loss: Some(loss),
+                step: Some(self.graph.optimizer_step()),
+                evals_without_improvement: 0,
+            };
+            let state = self.get_training_state()?;
+            if let Some(config) = self.options.checkpoint.as_ref().filter(|c| c.save_best) {
+                std::fs::create_dir_all(&config.dir)?;
+                std::fs::write(config.dir.join("best.dat"), state.to_bytes()?)?;
+            }
+            summary.best_state = Some(state);
+        } else {
+            self.best.evals_without_improvement += 1;
+        }
+        summary.best_validation_loss = self.best.loss;
+        summary.best_step = self.best.step;
+        summary.evals_without_improvement = self.best.evals_without_improvement;
+        if let Some(early_stopping) = &self.options.early_stopping {
+            if self.best.evals_without_improvement >= early_stopping.patience {
+                tracing::info!(
+                    step = self.graph.optimizer_step(),
+                    evals = self.best.evals_without_improvement,
+                    "no improvement of the validation loss, stopping early"
+                );
+                summary.stopped_early = true;
+            }
+        }
+        Ok(summary.stopped_early)
+    }
+
+    // Learning rate schedule of the last training run (Or of the loaded training state),
+    // for resuming it
+    pub fn schedule(&self) -> Option<&Schedule> {
+        self.schedule.as_ref()
+    }
+
+    // Exports the model to a GGUF file, see `gguf::export_gpt2` for the supported configs
+    pub fn export_gguf<P: AsRef<Path>, T: Tokenizer>(
+        &self,
+        path: P,
+        tokenizer: &T,
+    ) -> Result<(), GraphError> {
+        let mut file = BufWriter::new(File::create(path)?);
+        gguf::export_gpt2(
+            &mut file,
+            &self.config,
+            &self.get_training_state()?,
+            tokenizer,
+        )?;
+        Ok(file.flush()?)
+    }
+
+    // Exports the parameters to a NumPy `.npz` archive, one array per parameter, see `npy`
+    pub fn export_npz<P: AsRef<Path>>(&self, path: P) -> Result<(), GraphError> {
+        npy::save_npz(path, &self.get_training_state()?.tensors)
+    }
+
+    // Loads parameters from a `.npz` archive (E.g. written by `export_npz` and edited in
+    // Python). The archive may hold a subset of the parameters, the others are kept.
+    pub fn import_npz<P: AsRef<Path>>(&mut self, path: P) -> Result<(), GraphError> {
+        let tensors = npy::load_npz(path)?;
+        let mut params = HashSet::new();
+        for p in self.graph.params().iter() {
+            params.insert(self.graph.name_of(*p)?.as_str());
+        }
+        if let Some(name) = tensors.keys().find(|name| !params.contains(name.as_str())) {
+            return Err(GraphError::UnexpectedTensor(name.clone()));
+        }
+        self.load_params(&tensors)
+    }
+
+    // Exports the inference graph (Token ids and attention mask to logits) to an ONNX file.
+    // The batch size of the exported model is the one the model was built with.
+    pub fn export_onnx<P: AsRef<Path>>(&self, path: P) -> Result<(), GraphError> {
+        let model = self.graph.to_onnx(
+            &[self.token_input, self.attention_mask],
+            &[(self.output, "logits")],
+        )?;
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&model)?;
+        Ok(file.flush()?)
+    }
+
+    // Fuses the operators of the model, leaving its outputs intact
+    pub fn fuse(&mut self) -> Result<usize, GraphError> {
+        let mut keep = vec![self.hidden, self.output, self.loss];
+        if let Some(head) = &self.classifier {
+            keep.extend([head.logits, head.loss]);
+        }
+        self.graph.fuse(&keep)
+    }
+
+    // Graphviz DOT description of the model's computation graph
+    pub fn to_dot(&self) -> String {
+        self.graph.to_dot()
+    }
+
+    // Per-operation timings of the forward/backward passes from now on (Including the ones
+    // of the training workers), see `Profile`
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.graph.set_profiling(enabled)
+    }
+
+    pub fn profile(&self) -> Option<Profile> {
+        self.graph.profile()
+    }
+
+    // Keeps the attention states of the last `capacity` contexts of `infer` (None disables
+    // it), see `PrefixCache`
+    pub fn set_prefix_cache(&mut self, capacity: Option<usize>) {
+        self.prefix_cache = capacity.map(PrefixCache::new);
+    }
+
+    pub fn prefix_cache(&self) -> Option<&PrefixCache> {
+        self.prefix_cache.as_ref()
+    }
+
+    // Drops the cached states, which the parameters do not match anymore
+    fn invalidate_prefix_cache(&mut self) {
+        if let Some(cache) = &mut self.prefix_cache {
+            cache.clear();
+        }
+    }
+
+    pub fn sync(&mut self) -> Result<(), GraphError> {
+        self.graph
+            .params()
+            .to_vec()
+            .into_iter()
+            .map(|p| self.graph.fetch(p, false))
+            .collect::<Result<Vec<_>, GraphError>>()?;
+        Ok(())
+    }
+
+    pub fn num_params(&self) -> usize {
+        self.graph
+            .params()
+            .to_vec()
+            .into_iter()
+            .map(|p| self.graph.get(p).unwrap().as_float().unwrap().size())
+            .sum::<usize>()
+    }
+
+    // Parameter counts per component, and estimates of the activation memory and the compute
+    // of the model
+    pub fn summary(&self, batch_size: usize) -> Result<ModelSummary, GraphError> {
+        let config = &self.config;
+        let mut summary = ModelSummary {
+            embeddings: 0,
+            layers: vec![LayerSummary::default(); config.num_layers],
+            head: 0,
+            total: 0,
+            batch_size,
+            activation_bytes: 0,
+            flops_per_token: 0,
+        };
+        // Parameters taking part in matrix multiplications, 2 FLOPs (Multiply and add) each
+        let mut matmul_params = 0;
+        for p in self.graph.params().iter() {
+            let name = self.graph.name_of(*p)?;
+            let size = self.graph.get(*p)?.as_float()?.size();
+            summary.total += size;
+            if name == "token_embedding" || name == "pos_embedding" {
+                summary.embeddings += size;
+                continue;
+            }
+            let is_norm = name.ends_with("_coeff") || name.contains("norm_");
+            if !is_norm && !name.ends_with("_bias") {
+                matmul_params += size;
+            }
+            if name.starts_with("head_norm")
+                || name.starts_with("head_map")
+                || name.starts_with("classifier")
+            {
+                summary.head += size;
+                continue;
+            }
+            let layer = layer_of(name)
+                .and_then(|l| summary.layers.get_mut(l))
+                .ok_or_else(|| GraphError::InvalidConfig(format!("unknown parameter {}", name)))?;
+            if is_norm {
+                layer.norms += size;
+            } else if name.starts_with("feedforward") {
+                layer.feedforward += size;
+            } else {
+                layer.attention += size;
+            }
+        }
+
+        let (d, n) = (config.embedding_degree, config.num_tokens);
+        let attention_degree = config.num_heads * config.head_size()?;
+        let kv_degree = config.num_kv_heads * config.head_size()?;
+        // Attention scores and their products with the values, for every position
+        let attention_flops = 4 * n * attention_degree;
+        summary.flops_per_token = 2 * matmul_params + config.num_layers * attention_flops;
+
+        // Values per token of the intermediate tensors of a block: norms, projections,
+        // scores (Before and after masking and softmax), head outputs and their
+        // concatenation, feed-forward hidden units and residual additions
+        let feedforward = match config.feedforward {
+            FeedForward::Mlp => 2,
+            FeedForward::SwiGlu => 4,
+        } * config.feedforward_degree();
+        let per_layer = 2 * d
+            + attention_degree
+            + 2 * kv_degree
+            + 3 * config.num_heads * n
+            + 2 * attention_degree
+            + feedforward
+            + 4 * d;
+        // Embeddings, the final norm, the logits and the loss
+        let per_token = config.num_layers * per_layer + 3 * d + 2 * config.vocab_size;
+        summary.activation_bytes = 2 * batch_size * n * per_token * std::mem::size_of::<Float>();
+        Ok(summary)
+    }
+
+    pub fn set_training_state(
+        &mut self,
+        training_state: TrainingState,
+        load_optimizer: bool,
+    ) -> Result<(), GraphError> {
+        if let Some(manifest) = &training_state.manifest {
+            self.tokenizer = manifest.tokenizer.or(self.tokenizer);
+        }
+        if let Some(tokenizer) = &training_state.tokenizer {
+            self.saved_tokenizer = Some(tokenizer.clone());
+        }
+        self.load_params(&training_state.tensors)?;
         if load_optimizer {
-            self.graph.set_optimizer_state(&training_state.optimizer)?;
+            self.load_optimizer_state(training_state)?;
+        }
+        Ok(())
+    }
+
+    // Loads everything but the parameters
+    fn load_optimizer_state(&mut self, training_state: TrainingState) -> Result<(), GraphError> {
+        self.graph.set_optimizer_state(&training_state.optimizer)?;
+        self.schedule = training_state.schedule;
+        self.ema = training_state.ema;
+        self.swa = training_state.swa.unwrap_or_default();
+        if let Some(progress) = training_state.progress {
+            self.rng = ChaCha8Rng::from_seed(progress.rng_seed);
+            self.rng.set_stream(progress.rng_stream);
+            self.rng.set_word_pos(progress.rng_word_pos);
+            self.best = progress.best;
         }
         Ok(())
     }
 
-    pub fn get_training_state(&self) -> Result<TrainingState, GraphError> {
-        let mut state = TrainingState {
-            tensors: Default::default(),
-            optimizer: self.graph.get_optimizer_state()?,
-        };
-        for p in self.graph.params().iter() {
-            let k = self.graph.name_of(*p)?.to_string();
-            let v = self.graph.get(*p)?.as_float()?.clone();
-            state.tensors.insert(k, v);
+    // Loads the parameters found in `tensors` (By name). Their shapes are all checked
+    // before any of them is loaded, so a mismatching state leaves the model untouched.
+    fn load_params(&mut self, tensors: &HashMap<String, Tensor<Float>>) -> Result<(), GraphError> {
+        let mut loads = Vec::new();
+        for p in self.graph.params().to_vec() {
+            let name = self.graph.name_of(p)?;
+            if let Some(t) = tensors.get(name) {
+                let name = name.clone();
+                self.graph.fetch(p, false)?;
+                let expected = self.graph.get(p)?.shape();
+                if t.shape() != expected {
+                    return Err(GraphError::ShapeMismatch {
+                        name,
+                        expected: expected.to_vec(),
+                        found: t.shape().to_vec(),
+                    });
+                }
+                loads.push((p, t));
+            }
+        }
+        for (p, t) in loads {
+            self.graph.load(p, t)?;
+        }
+        self.invalidate_prefix_cache();
+        Ok(())
+    }
+
+    // Parameters updated by the optimizer
+    fn trainable_params(&self) -> Vec<TensorId> {
+        self.graph
+            .params()
+            .iter()
+            .cloned()
+            .filter(|p| !self.graph.is_frozen(*p))
+            .collect()
+    }
+
+    fn set_frozen_where<F: Fn(&str) -> bool>(
+        &mut self,
+        f: F,
+        frozen: bool,
+    ) -> Result<usize, GraphError> {
+        let mut count = 0;
+        for p in self.graph.params().to_vec() {
+            if f(self.graph.name_of(p)?) {
+                self.graph.set_frozen(p, frozen)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // Freezes the parameters whose names contain any of the patterns (E.g. "embedding").
+    // Frozen parameters are not updated, and the backward pass skips the computations that
+    // only depend on frozen parameters. Returns the number of matching parameters.
+    pub fn freeze(&mut self, patterns: &[&str]) -> Result<usize, GraphError> {
+        self.set_frozen_where(|name| patterns.iter().any(|p| name.contains(p)), true)
+    }
+
+    pub fn unfreeze(&mut self, patterns: &[&str]) -> Result<usize, GraphError> {
+        self.set_frozen_where(|name| patterns.iter().any(|p| name.contains(p)), false)
+    }
+
+    // Freezes the parameters of the given blocks
+    pub fn freeze_layers(&mut self, layers: Range<usize>) -> Result<usize, GraphError> {
+        self.set_frozen_where(
+            |name| layer_of(name).is_some_and(|l| layers.contains(&l)),
+            true,
+        )
+    }
+
+    pub fn get_adapters(&mut self) -> Result<Adapters, GraphError> {
+        let config = self.config.lora.ok_or(GraphError::InvalidConfig(
+            "the model has no adapters".into(),
+        ))?;
+        let mut tensors = HashMap::new();
+        for p in self.graph.params().to_vec() {
+            let name = self.graph.name_of(p)?.clone();
+            if is_adapter(&name) {
+                self.graph.fetch(p, false)?;
+                tensors.insert(name, self.graph.get(p)?.as_float()?.clone());
+            }
+        }
+        Ok(Adapters { config, tensors })
+    }
+
+    pub fn set_adapters(&mut self, adapters: &Adapters) -> Result<(), GraphError> {
+        if self.config.lora != Some(adapters.config) {
+            return Err(GraphError::InvalidConfig(
+                "the adapters were trained with another LoRA config".into(),
+            ));
+        }
+        self.load_params(&adapters.tensors)
+    }
+
+    pub fn save_adapters<P: AsRef<Path>>(&mut self, path: P) -> Result<(), GraphError> {
+        let bytes = bincode::serialize(&self.get_adapters()?)?;
+        Ok(std::fs::write(path, bytes)?)
+    }
+
+    pub fn load_adapters<P: AsRef<Path>>(&mut self, path: P) -> Result<(), GraphError> {
+        let adapters: Adapters = bincode::deserialize(&std::fs::read(path)?)?;
+        self.set_adapters(&adapters)
+    }
+
+    // Adds the adapters to the weights they adapt and resets them, so that the base
+    // parameters can be saved or exported on their own
+    pub fn merge_adapters(&mut self) -> Result<(), GraphError> {
+        let scale = self
+            .config
+            .lora
+            .ok_or(GraphError::InvalidConfig(
+                "the model has no adapters".into(),
+            ))?
+            .scale();
+        let mut ids = HashMap::new();
+        for p in self.graph.params().to_vec() {
+            self.graph.fetch(p, false)?;
+            ids.insert(self.graph.name_of(p)?.clone(), p);
+        }
+        for (name, a) in ids.iter() {
+            let base = match name.strip_suffix("_lora_a") {
+                Some(base) => base,
+                None => continue,
+            };
+            let b = ids[&format!("{}_lora_b", base)];
+            let w = ids[base];
+            let delta = (self.graph.get(*a)?.as_float()? ^ self.graph.get(b)?.as_float()?)?;
+            let merged = (self.graph.get(w)?.as_float()? + &delta.map_values(|f| f * scale))?;
+            self.graph.load(w, &merged)?;
+            let zeros = Tensor::<Float>::zeros(self.graph.get(b)?.as_float()?.shape());
+            self.graph.load(b, &zeros)?;
+        }
+        self.invalidate_prefix_cache();
+        Ok(())
+    }
+
+    pub fn get_training_state(&self) -> Result<TrainingState, GraphError> {
+        let mut state = TrainingState {
+            tensors: Default::default(),
+            optimizer: self.graph.get_optimizer_state()?,
+            schedule: self.schedule.clone(),
+            ema: self.ema.clone(),
+            progress: Some(TrainingProgress {
+                rng_seed: self.rng.get_seed(),
+                rng_stream: self.rng.get_stream(),
+                rng_word_pos: self.rng.get_word_pos(),
+                best: self.best.clone(),
+            }),
+            config: Some(self.config.clone()),
+            manifest: None,
+            swa: (self.swa.count > 0).then(|| self.swa.clone()),
+            tokenizer: self.saved_tokenizer.clone(),
+        };
+        for p in self.graph.params().iter() {
+            let k = self.graph.name_of(*p)?.to_string();
+            let v = self.graph.get(*p)?.as_float()?.clone();
+            state.tensors.insert(k, v);
+        }
+        state.manifest = Some(Manifest::new(&state, self.tokenizer));
+        Ok(state)
+    }
+
+    // Moves the parameters towards their exponential moving averages. Parameters are
+    // fetched from the graph, so this is also called by the GPU training loop.
+    fn update_ema(&mut self) -> Result<(), GraphError> {
+        let decay = match self.options.ema_decay {
+            Some(decay) => decay,
+            None => return Ok(()),
+        };
+        for p in self.graph.params().to_vec() {
+            self.graph.fetch(p, false)?;
+            let param = self.graph.get(p)?.as_float()?;
+            let name = self.graph.name_of(p)?;
+            let avg = match self.ema.get(name) {
+                Some(avg) => {
+                    (&(avg * &Tensor::scalar(decay))? + &(param * &Tensor::scalar(1. - decay))?)?
+                }
+                None => param.clone(),
+            };
+            self.ema.insert(name.to_string(), avg);
+        }
+        Ok(())
+    }
+
+    // Swaps the parameters of the model with their exponential moving averages, for
+    // evaluation or inference. Calling it again swaps the trained parameters back in.
+    pub fn swap_ema(&mut self) -> Result<(), GraphError> {
+        if self.ema.is_empty() {
+            return Err(GraphError::InvalidConfig(
+                "no moving averages, set `ema_decay` in the training options".into(),
+            ));
+        }
+        for p in self.graph.params().to_vec() {
+            let name = self.graph.name_of(p)?.to_string();
+            if let Some(avg) = self.ema.remove(&name) {
+                self.graph.fetch(p, false)?;
+                let param = self.graph.get(p)?.as_float()?.clone();
+                self.graph.load(p, &avg)?;
+                self.ema.insert(name, param);
+            }
+        }
+        self.invalidate_prefix_cache();
+        Ok(())
+    }
+
+    // Adds the parameters to their stochastic weight average, on the steps it is due
+    fn update_swa(&mut self) -> Result<(), GraphError> {
+        let swa = match self.options.swa {
+            Some(swa) => swa,
+            None => return Ok(()),
+        };
+        if swa.interval == 0 {
+            return Err(GraphError::InvalidConfig(
+                "the SWA interval must be positive".into(),
+            ));
+        }
+        if !swa.is_due(self.graph.optimizer_step()) {
+            return Ok(());
+        }
+        let params = self.graph.params().to_vec();
+        for p in params.iter() {
+            self.graph.fetch(*p, false)?;
+        }
+        let mut named = Vec::new();
+        for p in params {
+            named.push((self.graph.name_of(p)?, self.graph.get(p)?.as_float()?));
+        }
+        self.swa.add(named)?;
+        Ok(())
+    }
+
+    // Swaps the parameters of the model with their stochastic weight average, for evaluation
+    // or saving. Calling it again swaps the trained parameters back in.
+    pub fn swap_swa(&mut self) -> Result<(), GraphError> {
+        if self.swa.count == 0 {
+            return Err(GraphError::InvalidConfig(
+                "no weight average, set `swa` in the training options".into(),
+            ));
+        }
+        for p in self.graph.params().to_vec() {
+            let name = self.graph.name_of(p)?.to_string();
+            if let Some(avg) = self.swa.tensors.remove(&name) {
+                self.graph.fetch(p, false)?;
+                let param = self.graph.get(p)?.as_float()?.clone();
+                self.graph.load(p, &avg)?;
+                self.swa.tensors.insert(name, param);
+            }
+        }
+        self.invalidate_prefix_cache();
+        Ok(())
+    }
+
+    // Clips the gradients of the graph and adds noise to them, returning their norm (Before
+    // the noise). Gradients are only fetched from the device when they are modified.
+    fn process_graph_gradients(&mut self) -> Result<Option<Float>, GraphError> {
+        if self.options.max_grad_norm.is_none()
+            && self.options.max_grad_value.is_none()
+            && self.options.grad_noise.is_none()
+        {
+            return Ok(None);
+        }
+        let params = self.trainable_params();
+        let mut grads = self.graph_gradients(&params)?;
+        let grad_norm = clip_gradients(
+            &mut grads,
+            self.options.max_grad_norm,
+            self.options.max_grad_value,
+        );
+        self.add_grad_noise(&mut grads);
+        for (p, grad) in params.iter().zip(grads) {
+            self.graph.load_grad(*p, &grad)?;
+        }
+        Ok(Some(grad_norm))
+    }
+
+    fn graph_gradients(&mut self, params: &[TensorId]) -> Result<Vec<Tensor<Float>>, GraphError> {
+        let mut grads = Vec::with_capacity(params.len());
+        for p in params.iter() {
+            self.graph.fetch(*p, true)?;
+            grads.push(self.graph.get_grad(*p)?.clone());
+        }
+        Ok(grads)
+    }
+
+    // Whether the step with these loss-scaled gradients overflowed the half precision and has
+    // to be skipped, see `LossScaler`. Otherwise the gradients are unscaled.
+    fn skip_overflow(&mut self, grads: &mut [Tensor<Float>]) -> bool {
+        if self.precision == Precision::F32 {
+            return false;
+        }
+        let scale = self.loss_scaler.scale();
+        let overflow = grads
+            .iter()
+            .any(|grad| grad.blob().iter().any(|f| !f.is_finite()));
+        let step = self.loss_scaler.update(overflow);
+        self.graph.set_loss_scale(self.loss_scaler.scale());
+        if !step {
+            tracing::warn!(
+                step = self.graph.optimizer_step(),
+                loss_scale = self.loss_scaler.scale(),
+                "gradient overflow, skipping the step"
+            );
+            return true;
+        }
+        for grad in grads.iter_mut() {
+            grad.mul_scalar_assign(1. / scale);
+        }
+        false
+    }
+
+    // `skip_overflow` on the gradients of the graph
+    fn skip_graph_overflow(&mut self, params: &[TensorId]) -> Result<bool, GraphError> {
+        if self.precision == Precision::F32 {
+            return Ok(false);
+        }
+        let mut grads = self.graph_gradients(params)?;
+        if self.skip_overflow(&mut grads) {
+            return Ok(true);
+        }
+        for (p, grad) in params.iter().zip(grads) {
+            self.graph.load_grad(*p, &grad)?;
+        }
+        Ok(false)
+    }
+
+    // Whether the step with this loss and these gradients has to be skipped, see `NanGuard`
+    fn skip_non_finite(&mut self, loss: Float, grads: &[Tensor<Float>]) -> bool {
+        let Some(guard) = self.options.nan_guard else {
+            return false;
+        };
+        let non_finite = grads
+            .iter()
+            .map(|grad| grad.blob().iter().filter(|f| !f.is_finite()).count())
+            .sum::<usize>();
+        if loss.is_finite() && non_finite == 0 {
+            return false;
+        }
+        if let Some(backoff) = guard.lr_backoff {
+            self.lr_backoff = (self.lr_backoff.0 * backoff, guard.recovery_steps);
+        }
+        tracing::warn!(
+            step = self.graph.optimizer_step(),
+            loss,
+            non_finite_gradients = non_finite,
+            lr_factor = self.lr_backoff.0,
+            "non-finite loss or gradients, skipping the step"
+        );
+        true
+    }
+
+    // Checks the loss of the coming step against the moving average of the previous ones, see
+    // `SpikeRollback`. Returns whether the model was rolled back, in which case the step has to
+    // be skipped.
+    fn watch_loss(&mut self, loss: Float) -> Result<bool, GraphError> {
+        let Some(config) = self.options.spike_rollback.clone() else {
+            return Ok(false);
+        };
+        let step = self.graph.optimizer_step();
+        let average = self.watchdog.loss_average;
+        let spike =
+            self.watchdog.losses >= config.warmup_steps.max(1) && loss > config.threshold * average;
+        if let (true, Some(snapshot_step)) = (spike, self.watchdog.snapshot_step) {
+            let state = match &config.dir {
+                Some(dir) => read_training_state(dir.join("last_good.dat"))?,
+                None => self
+                    .watchdog
+                    .snapshot
+                    .clone()
+                    .ok_or(GraphError::InvalidConfig(
+                        "the snapshot of the watchdog is missing".into(),
+                    ))?,
+            };
+            // The RNG is not rolled back, so that training continues on other batches
+            self.load_params(&state.tensors)?;
+            self.graph.set_optimizer_state(&state.optimizer)?;
+            self.ema = state.ema;
+            self.swa = state.swa.unwrap_or_default();
+            self.lr_backoff = (self.lr_backoff.0 * config.lr_factor, config.recovery_steps);
+            tracing::warn!(
+                step,
+                loss,
+                average,
+                snapshot_step,
+                lr_factor = self.lr_backoff.0,
+                "loss spike, rolling back"
+            );
+            return Ok(true);
+        }
+
+        self.watchdog.loss_average = match self.watchdog.losses {
+            0 => loss,
+            _ => config.ema_decay * average + (1. - config.ema_decay) * loss,
+        };
+        self.watchdog.losses += 1;
+        // The parameters that computed a loss without a spike are a good state
+        if self.watchdog.snapshot_step.is_none() || step.is_multiple_of(config.interval.max(1)) {
+            match &config.dir {
+                Some(dir) => {
+                    std::fs::create_dir_all(dir)?;
+                    self.save_checkpoint(dir.join("last_good.dat"))?;
+                }
+                None => {
+                    self.sync()?;
+                    self.watchdog.snapshot = Some(self.get_training_state()?);
+                }
+            }
+            self.watchdog.snapshot_step = Some(step);
+        }
+        Ok(false)
+    }
+
+    // Learning rate of a step that is taken, reduced after a skipped one, see `NanGuard`
+    fn backed_off(&mut self, lr: Float) -> Float {
+        let (factor, steps) = self.lr_backoff;
+        if steps == 0 {
+            return lr;
+        }
+        self.lr_backoff = match steps {
+            1 => (1., 0),
+            _ => (factor, steps - 1),
+        };
+        lr * factor
+    }
+
+    // Whether the statistics of the coming step are monitored, see
+    // `TrainingOptions::monitor_interval`
+    fn monitored(&self) -> bool {
+        self.options.monitor_interval.is_some_and(|interval| {
+            (self.graph.optimizer_step() + 1).is_multiple_of(interval.max(1))
+        })
+    }
+
+    // Activations whose statistics are monitored, see `TrainingStats`
+    fn monitored_activations(&self) -> Vec<(String, TensorId)> {
+        let names = (0..self.block_outputs.len())
+            .map(|l| format!("block_{}", l))
+            .chain(["hidden".into(), "logits".into()]);
+        let ids = self
+            .block_outputs
+            .iter()
+            .chain([&self.hidden, &self.output]);
+        names.zip(ids.cloned()).collect()
+    }
+
+    fn gradient_stats(
+        &self,
+        params: &[TensorId],
+        grads: &[Tensor<Float>],
+    ) -> Result<Vec<TensorStats>, GraphError> {
+        params
+            .iter()
+            .zip(grads)
+            .map(|(p, grad)| {
+                Ok(TensorStats::new(
+                    self.graph.name_of(*p)?.clone(),
+                    grad.blob(),
+                ))
+            })
+            .collect()
+    }
+
+    fn add_grad_noise(&mut self, grads: &mut [Tensor<Float>]) {
+        if let Some(noise) = self.options.grad_noise {
+            let std = noise.std(self.graph.optimizer_step());
+            add_gradient_noise(grads, std, &mut self.rng);
+        }
+    }
+
+    // Rates of the dropout layers at the current step, with the tensors to load them in
+    fn dropout_rates(&self) -> Result<Vec<(TensorId, Tensor<Float>)>, GraphError> {
+        let config = self.options.dropout.clone().unwrap_or_default();
+        let factor = config
+            .schedule
+            .map(|s| s.at(self.graph.optimizer_step()))
+            .unwrap_or(1.);
+        [
+            (self.attention_dropout, config.attention),
+            (self.residual_dropout, config.residual),
+            (self.embedding_dropout, config.embedding),
+        ]
+        .into_iter()
+        .map(|(id, rate)| {
+            let rate = rate.unwrap_or(self.config.dropout) * factor;
+            if !(0.0..1.0).contains(&rate) {
+                return Err(GraphError::InvalidConfig(format!(
+                    "dropout ({}) should be in [0, 1)",
+                    rate
+                )));
+            }
+            Ok((id, Tensor::constant(&[1], rate)))
+        })
+        .collect()
+    }
+
+    // Calls `f` with a sampler of batches of `rows` sequences, sampled on worker threads when
+    // `TrainingOptions::prefetch` is set. Prefetched batches are derived from a seed drawn
+    // from the RNG of the model when training starts.
+    fn with_batches<D: Dataset + Sync + ?Sized, T>(
+        &mut self,
+        dataset: &D,
+        rows: usize,
+        f: impl FnOnce(&mut Self, &mut BatchSampler) -> Result<T, GraphError>,
+    ) -> Result<T, GraphError> {
+        let num_tokens = self.num_tokens;
+        match self.options.prefetch {
+            Some(prefetch) => {
+                let seed = self.rng.gen();
+                std::thread::scope(|scope| {
+                    let mut batches =
+                        Prefetcher::spawn(scope, dataset, &prefetch, rows, num_tokens, seed)?;
+                    f(self, &mut |_| batches.next_batch())
+                })
+            }
+            None => f(self, &mut |rng| dataset.sample(rng, rows, num_tokens)),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_cpu<
+        D: Dataset + Sync + ?Sized,
+        B: BatchSize,
+        O: Optimizer,
+        L: LearningRate,
+        C: TrainCallback<G>,
+    >(
+        &mut self,
+        dataset: &D,
+        num_batches: usize,
+        batch_size: B,
+        limit: Option<usize>,
+        optimizer: &O,
+        learning_rate: L,
+        callback: C,
+    ) -> Result<TrainingSummary, GraphError>
+    where
+        G: Clone + Send + Sync,
+    {
+        self.with_batches(dataset, 1, |gpt, sample| {
+            gpt.train_cpu_steps(
+                sample,
+                num_batches,
+                batch_size,
+                limit,
+                optimizer,
+                learning_rate,
+                callback,
+            )
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn train_cpu_steps<B: BatchSize, O: Optimizer, L: LearningRate, C: TrainCallback<G>>(
+        &mut self,
+        sample: &mut BatchSampler,
+        num_batches: usize,
+        batch_size: B,
+        limit: Option<usize>,
+        optimizer: &O,
+        learning_rate: L,
+        mut callback: C,
+    ) -> Result<TrainingSummary, GraphError>
+    where
+        G: Clone + Send + Sync,
+    {
+        self.schedule = learning_rate.schedule();
+        let mut summary = TrainingSummary::default();
+        let start = Instant::now();
+        // The graphs of the workers are kept across the steps, so that their activations
+        // are allocated once (See `Graph::share_inputs`)
+        let mut workers: Vec<G> = Vec::new();
+        for i in 0..num_batches {
+            let timer = Instant::now();
+            let params = self.trainable_params();
+            let batch_size = batch_size.batch_size(self.graph.optimizer_step());
+            if batch_size == 0 {
+                return Err(GraphError::InvalidConfig(
+                    "batch_size should be positive".into(),
+                ));
+            }
+
+            // Inputs of the step loaded by every worker on top of the shared tensors
+            let z_loss = self.options.z_loss.unwrap_or(0.);
+            let mut step_inputs = vec![(
+                self.z_loss_coeff,
+                Tensor::<Float>::constant(&[1, self.num_tokens], z_loss),
+            )];
+            step_inputs.extend(self.dropout_rates()?);
+
+            // Samples, and the seeds of their dropout masks, are drawn up front from the
+            // model's RNG, so that they do not depend on the scheduling of the workers
+            let mut batches = Vec::with_capacity(batch_size);
+            let mut seeds = Vec::with_capacity(batch_size);
+            for _ in 0..batch_size {
+                batches.push(sample(&mut self.rng)?);
+                seeds.push(self.rng.gen::<u64>());
+            }
+            let weights = loss_weights(&batches.iter().collect::<Vec<_>>())?;
+            let masks = batches.iter().map(attention_mask).collect::<Vec<_>>();
+            let samples = batches
+                .iter()
+                .zip(weights.iter().zip(masks.iter()))
+                .zip(seeds)
+                .map(|((b, (w, m)), seed)| (&b.xs, &b.ys, w, m, b.prefix.as_ref(), seed))
+                .collect::<Vec<_>>();
+
+            // Each worker processes a fixed share of the batch on its own copy of the graph
+            // (Weights are shared between the copies) and sums up the gradients of the
+            // parameters. The partial sums are added up in order, so that the result does
+            // not depend on the scheduling of the workers. (A single worker processes the
+            // whole batch when the `ComputeConfig` only parallelizes the operations)
+            // The activations of the first sample are monitored
+            let monitored = match self.monitored() {
+                true => self.monitored_activations(),
+                false => Vec::new(),
+            };
+            let chunk_size = if compute::parallelism().across_batch() {
+                batch_size.div_ceil(compute::num_threads()).max(1)
+            } else {
+                batch_size.max(1)
+            };
+            while workers.len() < batch_size.div_ceil(chunk_size) {
+                workers.push(self.graph.clone());
+            }
+            let graph = &self.graph;
+            let partial_sums = compute::install(|| {
+                samples
+                    .par_chunks(chunk_size)
+                    .zip(workers.par_iter_mut())
+                    .enumerate()
+                    .map(|(c, (chunk, worker))| {
+                        worker.share_inputs(graph)?;
+                        for (id, tensor) in step_inputs.iter() {
+                            worker.load(*id, tensor)?;
+                        }
+                        let graph = worker;
+                        let mut grads = Vec::<Tensor<Float>>::new();
+                        let mut loss_sum = 0.;
+                        let mut activations = Vec::new();
+                        for (xs, ys, weights, mask, prefix, seed) in chunk {
+                            graph.seed(*seed);
+                            graph.load_usize(self.token_input, *xs)?;
+                            graph.load(self.attention_mask, *mask)?;
+                            load_prefix(graph, self.prefix_mask, *prefix, mask.shape())?;
+                            if let Some(head) = &self.classifier {
+                                head.load(graph, mask, None)?;
+                            }
+                            graph.load_usize(self.expected_output, *ys)?;
+                            graph.load(self.loss_weights, *weights)?;
+                            graph.forward(true)?;
+                            if c == 0 && grads.is_empty() {
+                                activations = tensor_stats(graph, &monitored)?;
+                            }
+                            graph.zero_grad()?;
+                            loss_sum += graph.backward_all(self.loss, limit)?;
+                            if grads.is_empty() {
+                                grads = params
+                                    .iter()
+                                    .map(|id| graph.get_grad(*id).cloned())
+                                    .collect::<Result<Vec<_>, GraphError>>()?;
+                            } else {
+                                for (grad, id) in grads.iter_mut().zip(params.iter()) {
+                                    grad.add_assign(graph.get_grad(*id)?)?;
+                                }
+                            }
+                        }
+                        Ok::<_, GraphError>((grads, loss_sum, activations))
+                    })
+                    .collect::<Result<Vec<_>, GraphError>>()
+            });
+            // The weights are only referenced by the model's graph again, which updates them
+            // in place
+            for worker in workers.iter_mut() {
+                worker.release_inputs();
+            }
+            let partial_sums = partial_sums?;
+            let mut activations = Vec::new();
+            let (grads, loss_sum) = partial_sums.into_iter().try_fold(
+                (Vec::new(), 0.),
+                |(mut a, a_loss): (Vec<Tensor<Float>>, Float), (b, b_loss, b_activations)| {
+                    if activations.is_empty() {
+                        activations = b_activations;
+                    }
+                    if a.is_empty() {
+                        a = b;
+                    } else {
+                        for (a, b) in a.iter_mut().zip(b.iter()) {
+                            a.add_assign(b)?;
+                        }
+                    }
+                    Ok::<_, TensorError>((a, a_loss + b_loss))
+                },
+            )?;
+            let mut grads = grads;
+            for grad in grads.iter_mut() {
+                grad.mul_scalar_assign(1. / batch_size as Float);
+            }
+            let avg_loss = loss_sum / batch_size as Float;
+            if self.skip_overflow(&mut grads) {
+                summary.skipped_steps += 1;
+                continue;
+            }
+            let stats = match monitored.is_empty() {
+                true => None,
+                false => Some(TrainingStats {
+                    gradients: self.gradient_stats(&params, &grads)?,
+                    activations,
+                }),
+            };
+            if self.skip_non_finite(avg_loss, &grads) {
+                summary.skipped_steps += 1;
+                continue;
+            }
+            if self.watch_loss(avg_loss)? {
+                summary.rollbacks += 1;
+                continue;
+            }
+
+            let grad_norm = clip_gradients(
+                &mut grads,
+                self.options.max_grad_norm,
+                self.options.max_grad_value,
+            );
+            self.add_grad_noise(&mut grads);
+            for (id, grad) in params.into_iter().zip(grads) {
+                self.graph.load_grad(id, &grad)?;
+            }
+            let lr = learning_rate.learning_rate(self.graph.optimizer_step());
+            let lr = self.backed_off(lr);
+            self.graph.optimize(optimizer, lr)?;
+            self.update_ema()?;
+            self.update_swa()?;
+            summary.steps += 1;
+            summary.loss = avg_loss;
+            let info = StepInfo {
+                step: self.graph.optimizer_step(),
+                loss: avg_loss,
+                learning_rate: lr,
+                grad_norm: Some(grad_norm),
+                tokens: batch_size * self.num_tokens,
+                elapsed: timer.elapsed(),
+                wall_time: start.elapsed(),
+            };
+            if callback.on_step_end(self, &info)?.is_break() {
+                summary.stopped_early = true;
+            }
+            if let Some(stats) = stats {
+                callback.on_stats(self, &info, &stats)?;
+            }
+            let interval = self.options.checkpoint.as_ref().map_or(10, |c| c.interval);
+            if i % usize::max(interval, 1) == 0 {
+                self.checkpoint()?;
+                self.sync()?;
+                callback.on_checkpoint(self)?;
+            }
+            if self.validate(&info, &mut summary, &mut callback)? || summary.stopped_early {
+                break;
+            }
         }
-        Ok(state)
+        Ok(summary)
     }
 
-    pub fn train_cpu<
+    // Batches larger than the batch size of the model are processed in chunks, whose
+    // gradients are averaged
+    #[allow(clippy::too_many_arguments)]
+    pub fn train<
+        D: Dataset + Sync + ?Sized,
+        B: BatchSize,
         O: Optimizer,
-        F: Fn(usize) -> f32,
-        C: Fn(&mut Self) -> Result<(), GraphError>,
+        L: LearningRate,
+        C: TrainCallback<G>,
     >(
         &mut self,
-        dataset: &[usize],
+        dataset: &D,
         num_batches: usize,
-        batch_size: usize,
+        batch_size: B,
         limit: Option<usize>,
         optimizer: &O,
-        learning_rate: F,
+        learning_rate: L,
         callback: C,
-    ) -> Result<(), GraphError>
-    where
-        G: Clone + Send + Sync,
-    {
-        self.graph.load(self.pos_input, &self.pos_input_fixed)?;
+    ) -> Result<TrainingSummary, GraphError> {
+        let rows = self.batch_size.unwrap_or(1);
+        self.with_batches(dataset, rows, |gpt, sample| {
+            gpt.train_steps(
+                sample,
+                num_batches,
+                batch_size,
+                limit,
+                optimizer,
+                learning_rate,
+                callback,
+            )
+        })
+    }
 
+    #[allow(clippy::too_many_arguments)]
+    fn train_steps<B: BatchSize, O: Optimizer, L: LearningRate, C: TrainCallback<G>>(
+        &mut self,
+        sample: &mut BatchSampler,
+        num_batches: usize,
+        batch_size: B,
+        limit: Option<usize>,
+        optimizer: &O,
+        learning_rate: L,
+        mut callback: C,
+    ) -> Result<TrainingSummary, GraphError> {
+        self.schedule = learning_rate.schedule();
+        let mut summary = TrainingSummary::default();
+        let start = Instant::now();
         for i in 0..num_batches {
             let timer = Instant::now();
-            let (graphs, errs): (Vec<G>, Vec<f32>) = (0..batch_size)
-                .into_par_iter()
-                .map(|_| {
-                    let mut rng = rand::thread_rng();
-                    let mut graph = self.graph.clone();
-                    let (xs, ys) = sample_dataset(dataset, 1, self.num_tokens, &mut rng);
-
-                    graph.load_usize(self.token_input, &xs)?;
-                    graph.load_usize(self.expected_output, &ys)?;
-                    graph.forward(true)?;
-                    graph.zero_grad()?;
-                    let err = graph.backward_all(self.loss, limit)?;
-                    Ok((graph, err))
-                })
-                .collect::<Result<Vec<(G, f32)>, GraphError>>()?
-                .into_iter()
-                .unzip();
-            for (id, avg) in self
-                .graph
-                .params()
-                .to_vec()
-                .into_par_iter()
-                .map(|id| {
-                    let mut avg = Tensor::<f32>::scalar(0.);
-                    for g in graphs.iter() {
-                        avg = (&avg + g.get_grad(id)?)?;
-                    }
-                    avg = avg.map_values(|f| f / graphs.len() as f32);
-                    Ok((id, avg))
-                })
-                .collect::<Result<Vec<_>, GraphError>>()?
-            {
-                self.graph.load_grad(id, &avg)?;
+            let params = self.trainable_params();
+            let batch_size = batch_size.batch_size(self.graph.optimizer_step());
+            let chunks = self.num_chunks(batch_size)?;
+            let mut grads = Vec::<Tensor<Float>>::new();
+            let mut loss_sum = 0.;
+            // The activations of the first chunk are monitored
+            let monitored = self.monitored();
+            let mut activations = Vec::new();
+            for _ in 0..chunks {
+                let batch = sample(&mut self.rng)?;
+                self.graph.seed(self.rng.gen());
+
+                let mask = attention_mask(&batch);
+                self.graph.load_usize(self.token_input, &batch.xs)?;
+                self.graph.load(self.attention_mask, &mask)?;
+                load_prefix(
+                    &mut self.graph,
+                    self.prefix_mask,
+                    batch.prefix.as_ref(),
+                    mask.shape(),
+                )?;
+                if let Some(head) = &self.classifier {
+                    head.load(&mut self.graph, &mask, None)?;
+                }
+                self.graph.load_usize(self.expected_output, &batch.ys)?;
+                self.graph
+                    .load(self.loss_weights, &loss_weights(&[&batch])?[0])?;
+                let z_loss = self.options.z_loss.unwrap_or(0.);
+                self.graph.load(
+                    self.z_loss_coeff,
+                    &Tensor::<Float>::constant(batch.ys.shape(), z_loss),
+                )?;
+                for (id, rate) in self.dropout_rates()? {
+                    self.graph.load(id, &rate)?;
+                }
+
+                self.graph.forward(true)?;
+                if monitored && activations.is_empty() {
+                    let tensors = self.monitored_activations();
+                    activations = tensor_stats(&mut self.graph, &tensors)?;
+                }
+                self.graph.zero_grad()?;
+                loss_sum += self.graph.backward_all(self.loss, limit)?;
+                if chunks > 1 {
+                    self.accumulate_gradients(&params, &mut grads)?;
+                }
+            }
+            for (p, mut grad) in params.iter().zip(grads) {
+                grad.mul_scalar_assign(1. / chunks as Float);
+                self.graph.load_grad(*p, &grad)?;
+            }
+            let err = loss_sum / chunks as Float;
+            if self.skip_graph_overflow(&params)? {
+                summary.skipped_steps += 1;
+                continue;
+            }
+            // Gradients are only fetched from the device when they are inspected
+            let grads = match monitored || self.options.nan_guard.is_some() {
+                true => self.graph_gradients(&params)?,
+                false => Vec::new(),
+            };
+            let stats = match monitored {
+                true => Some(TrainingStats {
+                    gradients: self.gradient_stats(&params, &grads)?,
+                    activations,
+                }),
+                false => None,
+            };
+            if self.skip_non_finite(err, &grads) {
+                summary.skipped_steps += 1;
+                continue;
+            }
+            if self.watch_loss(err)? {
+                summary.rollbacks += 1;
+                continue;
             }
-            let avg_loss = errs.iter().sum::<f32>() / errs.len() as f32;
-            let lr = learning_rate(self.graph.optimizer_step());
+            let grad_norm = self.process_graph_gradients()?;
+            let lr = learning_rate.learning_rate(self.graph.optimizer_step());
+            let lr = self.backed_off(lr);
             self.graph.optimize(optimizer, lr)?;
-            if i % 10 == 0 {
+            self.update_ema()?;
+            self.update_swa()?;
+            summary.steps += 1;
+            summary.loss = err;
+            let info = StepInfo {
+                step: self.graph.optimizer_step(),
+                loss: err,
+                learning_rate: lr,
+                grad_norm,
+                tokens: batch_size * self.num_tokens,
+                elapsed: timer.elapsed(),
+                wall_time: start.elapsed(),
+            };
+            if callback.on_step_end(self, &info)?.is_break() {
+                summary.stopped_early = true;
+            }
+            if let Some(stats) = stats {
+                callback.on_stats(self, &info, &stats)?;
+            }
+            let interval = self.options.checkpoint.as_ref().map_or(50, |c| c.interval);
+            if i % usize::max(interval, 1) == 0 {
+                self.checkpoint()?;
                 self.sync()?;
-                callback(self)?;
+                callback.on_checkpoint(self)?;
+            }
+            if self.validate(&info, &mut summary, &mut callback)? || summary.stopped_early {
+                break;
             }
-            println!(
-                "Step: {} Loss: {} (Elapsed: {}ms)",
-                self.graph.optimizer_step(),
-                avg_loss,
-                timer.elapsed().as_millis()
-            );
         }
-        Ok(())
+        Ok(summary)
     }
 
-    pub fn train<O: Optimizer, F: Fn(usize) -> f32, C: Fn(&mut Self) -> Result<(), GraphError>>(
+    // Fine-tunes the model on labeled sequences (Truncated to `num_tokens` tokens) through its
+    // classification head, minimizing the cross-entropy of the classes. The language modeling
+    // head is left untouched.
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_classifier<O: Optimizer, L: LearningRate, C: TrainCallback<G>>(
         &mut self,
-        dataset: &[usize],
+        examples: &[(Vec<usize>, usize)],
         num_batches: usize,
         batch_size: usize,
-        limit: Option<usize>,
         optimizer: &O,
-        learning_rate: F,
-        callback: C,
+        learning_rate: L,
+        mut callback: C,
+    ) -> Result<TrainingSummary, GraphError> {
+        let (head, num_classes) = match (self.classifier, self.config.classifier) {
+            (Some(head), Some(config)) => (head, config.num_classes),
+            _ => {
+                return Err(GraphError::InvalidConfig(
+                    "the model has no classification head".into(),
+                ))
+            }
+        };
+        if examples.is_empty() {
+            return Err(GraphError::InvalidConfig("no examples".into()));
+        }
+        if let Some((_, label)) = examples.iter().find(|(_, l)| *l >= num_classes) {
+            return Err(GraphError::InvalidConfig(format!(
+                "label {} of a model with {} classes",
+                label, num_classes
+            )));
+        }
+        let lm_head = self
+            .graph
+            .params()
+            .to_vec()
+            .into_iter()
+            .filter(|p| !self.graph.is_frozen(*p))
+            .filter(|p| {
+                self.graph
+                    .name_of(*p)
+                    .is_ok_and(|name| name.starts_with("head_map"))
+            })
+            .collect::<Vec<_>>();
+        for p in lm_head.iter() {
+            self.graph.set_frozen(*p, true)?;
+        }
+        let result = self.classifier_steps(
+            head,
+            examples,
+            num_batches,
+            batch_size,
+            optimizer,
+            learning_rate,
+            &mut callback,
+        );
+        for p in lm_head {
+            self.graph.set_frozen(p, false)?;
+        }
+        result
+    }
+
+    // Number of chunks a batch is processed in. Models without a batch size run on one
+    // sequence at a time.
+    fn num_chunks(&self, batch_size: usize) -> Result<usize, GraphError> {
+        let rows = self.batch_size.unwrap_or(1);
+        if batch_size == 0 || !batch_size.is_multiple_of(rows) {
+            return Err(GraphError::InvalidConfig(format!(
+                "batch_size ({}) should be a multiple of {}",
+                batch_size, rows
+            )));
+        }
+        Ok(batch_size / rows)
+    }
+
+    // Adds the gradients of the parameters to `sums`, for averaging them over the chunks
+    // of a batch
+    fn accumulate_gradients(
+        &mut self,
+        params: &[TensorId],
+        sums: &mut Vec<Tensor<Float>>,
     ) -> Result<(), GraphError> {
-        self.graph.load(self.pos_input, &self.pos_input_fixed)?;
+        for (j, p) in params.iter().enumerate() {
+            self.graph.fetch(*p, true)?;
+            let grad = self.graph.get_grad(*p)?;
+            match sums.get_mut(j) {
+                Some(sum) => sum.add_assign(grad)?,
+                None => sums.push(grad.clone()),
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn classifier_steps<O: Optimizer, L: LearningRate, C: TrainCallback<G>>(
+        &mut self,
+        head: ClassifierHead,
+        examples: &[(Vec<usize>, usize)],
+        num_batches: usize,
+        batch_size: usize,
+        optimizer: &O,
+        learning_rate: L,
+        callback: &mut C,
+    ) -> Result<TrainingSummary, GraphError> {
+        let chunks = self.num_chunks(batch_size)?;
+        let rows = batch_size / chunks;
+        self.schedule = learning_rate.schedule();
+        let mut summary = TrainingSummary::default();
+        let start = Instant::now();
+        let shape = [rows, self.num_tokens];
+        for i in 0..num_batches {
+            let timer = Instant::now();
+            let params = self.trainable_params();
+            let mut grads = Vec::<Tensor<Float>>::new();
+            let mut loss_sum = 0.;
+            for _ in 0..chunks {
+                let mut xs = Vec::with_capacity(rows * self.num_tokens);
+                let mut mask = Vec::with_capacity(rows * self.num_tokens);
+                let mut labels = Vec::with_capacity(rows);
+                for _ in 0..rows {
+                    let (tokens, label) = &examples[self.rng.gen_range(0..examples.len())];
+                    let len = tokens.len().min(self.num_tokens);
+                    xs.extend(&tokens[..len]);
+                    xs.resize(xs.len() + self.num_tokens - len, 0);
+                    mask.extend((0..self.num_tokens).map(|i| if i < len { 1. } else { 0. }));
+                    labels.push(*label);
+                }
+                self.graph.seed(self.rng.gen());
+
+                let mask = Tensor::raw(&shape, mask)?;
+                self.graph
+                    .load_usize(self.token_input, &Tensor::raw(&shape, xs)?)?;
+                self.graph.load(self.attention_mask, &mask)?;
+                load_prefix(&mut self.graph, self.prefix_mask, None, &shape)?;
+                head.load(&mut self.graph, &mask, Some(&labels))?;
+                // The language modeling loss is computed as well, but doesn't count
+                self.graph
+                    .load_usize(self.expected_output, &Tensor::zeros(&shape))?;
+                self.graph
+                    .load(self.loss_weights, &Tensor::<Float>::zeros(&shape))?;
+                self.graph
+                    .load(self.z_loss_coeff, &Tensor::<Float>::zeros(&shape))?;
+                for (id, rate) in self.dropout_rates()? {
+                    self.graph.load(id, &rate)?;
+                }
+
+                self.graph.forward(true)?;
+                self.graph.zero_grad()?;
+                loss_sum += self.graph.backward_all(head.loss, None)?;
+                if chunks > 1 {
+                    self.accumulate_gradients(&params, &mut grads)?;
+                }
+            }
+            for (p, mut grad) in params.iter().zip(grads) {
+                grad.mul_scalar_assign(1. / chunks as Float);
+                self.graph.load_grad(*p, &grad)?;
+            }
+            let err = loss_sum / chunks as Float;
+            if self.skip_graph_overflow(&params)? {
+                summary.skipped_steps += 1;
+                continue;
+            }
+            let grad_norm = self.process_graph_gradients()?;
+            let lr = learning_rate.learning_rate(self.graph.optimizer_step());
+            self.graph.optimize(optimizer, lr)?;
+            self.update_ema()?;
+            self.update_swa()?;
+            summary.steps += 1;
+            summary.loss = err;
+            let info = StepInfo {
+                step: self.graph.optimizer_step(),
+                loss: err,
+                learning_rate: lr,
+                grad_norm,
+                tokens: batch_size * self.num_tokens,
+                elapsed: timer.elapsed(),
+                wall_time: start.elapsed(),
+            };
+            if callback.on_step_end(self, &info)?.is_break() {
+                summary.stopped_early = true;
+                break;
+            }
+            let interval = self.options.checkpoint.as_ref().map_or(50, |c| c.interval);
+            if i % usize::max(interval, 1) == 0 {
+                self.checkpoint()?;
+                self.sync()?;
+                callback.on_checkpoint(self)?;
+            }
+        }
+        Ok(summary)
+    }
 
+    // Direct Preference Optimization (https://arxiv.org/abs/2305.18290): fine-tunes the model
+    // to prefer the chosen completions of the pairs to the rejected ones, relative to the
+    // `reference` log-probabilities of `dpo_reference`. The loss of a pair is
+    // `-ln(sigmoid(beta * ((chosen - ref_chosen) - (rejected - ref_rejected))))`, with the
+    // log-probabilities of the completions given their prompt. Batches hold `batch_size`
+    // pairs, models allocated with a batch size run its rows on as many completions at once.
+    #[allow(clippy::too_many_arguments)]
+    pub fn train_dpo<O: Optimizer, L: LearningRate, C: TrainCallback<G>>(
+        &mut self,
+        pairs: &[PreferencePair],
+        reference: &[(Float, Float)],
+        beta: Float,
+        num_batches: usize,
+        batch_size: usize,
+        optimizer: &O,
+        learning_rate: L,
+        mut callback: C,
+    ) -> Result<TrainingSummary, GraphError> {
+        self.check_pairs(pairs)?;
+        if reference.len() != pairs.len() {
+            return Err(GraphError::InvalidConfig(format!(
+                "{} reference log-probabilities for {} pairs",
+                reference.len(),
+                pairs.len()
+            )));
+        }
+        if beta.is_nan() || beta <= 0. {
+            return Err(GraphError::InvalidConfig(format!(
+                "beta ({}) should be positive",
+                beta
+            )));
+        }
+        if batch_size == 0 {
+            return Err(GraphError::InvalidConfig(
+                "batch_size should be positive".into(),
+            ));
+        }
+        self.schedule = learning_rate.schedule();
+        let mut summary = TrainingSummary::default();
+        let start = Instant::now();
+        let rows = self.batch_size.unwrap_or(1);
+        // The backward passes average the weighted losses of the positions
+        let size = (rows * self.num_tokens) as Float;
         for i in 0..num_batches {
             let timer = Instant::now();
-            let mut rng = rand::thread_rng();
-            let (xs, ys) = sample_dataset(dataset, batch_size, self.num_tokens, &mut rng);
+            let params = self.trainable_params();
+            let picked = (0..batch_size)
+                .map(|_| self.rng.gen_range(0..pairs.len()))
+                .collect::<Vec<_>>();
+            let completions = picked
+                .iter()
+                .flat_map(|p| {
+                    let pair = &pairs[*p];
+                    [
+                        (&pair.prompt[..], &pair.chosen[..]),
+                        (&pair.prompt[..], &pair.rejected[..]),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            let chunks = completions.len().div_ceil(rows);
+            let seeds = (0..chunks).map(|_| self.rng.gen()).collect::<Vec<u64>>();
+
+            // Log-probabilities of the policy, with the dropout masks of the backward passes
+            let mut logprobs = Vec::with_capacity(completions.len());
+            for (chunk, seed) in completions.chunks(rows).zip(seeds.iter()) {
+                self.load_completions(chunk, &vec![1.; chunk.len()])?;
+                self.graph.seed(*seed);
+                self.graph.forward(true)?;
+                logprobs.extend(self.completion_logprobs(chunk.len())?);
+            }
 
-            self.graph.load_usize(self.token_input, &xs)?;
-            self.graph.load_usize(self.expected_output, &ys)?;
+            // The gradient of the loss of a pair is the gradient of the cross-entropy of its
+            // completions, weighted by the derivative of the loss w.r.t. their log-probabilities
+            let mut loss = 0.;
+            let mut coeffs = Vec::with_capacity(completions.len());
+            for (j, p) in picked.iter().enumerate() {
+                let (ref_chosen, ref_rejected) = reference[*p];
+                let margin =
+                    beta * ((logprobs[2 * j] - ref_chosen) - (logprobs[2 * j + 1] - ref_rejected));
+                loss += softplus(-margin);
+                let d = beta / (1. + margin.exp()) / batch_size as Float;
+                coeffs.extend([d * size, -d * size]);
+            }
+            let loss = loss / batch_size as Float;
 
-            self.graph.forward(true)?;
-            self.graph.zero_grad()?;
-            let err = self.graph.backward_all(self.loss, limit)?;
-            let lr = learning_rate(self.graph.optimizer_step());
+            let mut grads = Vec::<Tensor<Float>>::new();
+            for ((chunk, coeffs), seed) in
+                completions.chunks(rows).zip(coeffs.chunks(rows)).zip(seeds)
+            {
+                self.load_completions(chunk, coeffs)?;
+                self.graph.seed(seed);
+                self.graph.forward(true)?;
+                self.graph.zero_grad()?;
+                self.graph.backward_all(self.loss, None)?;
+                if chunks > 1 {
+                    self.accumulate_gradients(&params, &mut grads)?;
+                }
+            }
+            for (p, grad) in params.iter().zip(grads) {
+                self.graph.load_grad(*p, &grad)?;
+            }
+            if self.skip_graph_overflow(&params)? {
+                summary.skipped_steps += 1;
+                continue;
+            }
+            let grad_norm = self.process_graph_gradients()?;
+            let lr = learning_rate.learning_rate(self.graph.optimizer_step());
             self.graph.optimize(optimizer, lr)?;
-            if i % 50 == 0 {
-                callback(self)?;
-            }
-            println!(
-                "Step: {} Loss: {} (Elapsed: {}ms)",
-                self.graph.optimizer_step(),
-                err,
-                timer.elapsed().as_millis()
-            );
+            self.update_ema()?;
+            self.update_swa()?;
+            summary.steps += 1;
+            summary.loss = loss;
+            let info = StepInfo {
+                step: self.graph.optimizer_step(),
+                loss,
+                learning_rate: lr,
+                grad_norm,
+                tokens: completions.len() * self.num_tokens,
+                elapsed: timer.elapsed(),
+                wall_time: start.elapsed(),
+            };
+            if callback.on_step_end(self, &info)?.is_break() {
+                summary.stopped_early = true;
+                break;
+            }
+            let interval = self.options.checkpoint.as_ref().map_or(50, |c| c.interval);
+            if i % usize::max(interval, 1) == 0 {
+                self.checkpoint()?;
+                self.sync()?;
+                callback.on_checkpoint(self)?;
+            }
+        }
+        Ok(summary)
+    }
+
+    // Log-probabilities of the chosen and rejected completions of the pairs, given their
+    // prompt, as computed by the model as it is now (Without dropout): the reference of
+    // `train_dpo`, computed before training by the model itself or by another one
+    pub fn dpo_reference(
+        &mut self,
+        pairs: &[PreferencePair],
+    ) -> Result<Vec<(Float, Float)>, GraphError> {
+        self.check_pairs(pairs)?;
+        let completions = pairs
+            .iter()
+            .flat_map(|pair| {
+                [
+                    (&pair.prompt[..], &pair.chosen[..]),
+                    (&pair.prompt[..], &pair.rejected[..]),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let mut logprobs = Vec::with_capacity(completions.len());
+        for chunk in completions.chunks(self.batch_size.unwrap_or(1)) {
+            self.load_completions(chunk, &vec![1.; chunk.len()])?;
+            self.graph.forward(false)?;
+            logprobs.extend(self.completion_logprobs(chunk.len())?);
+        }
+        Ok(logprobs.chunks(2).map(|l| (l[0], l[1])).collect())
+    }
+
+    fn check_pairs(&self, pairs: &[PreferencePair]) -> Result<(), GraphError> {
+        if pairs.is_empty() {
+            return Err(GraphError::InvalidConfig("no preference pairs".into()));
+        }
+        for pair in pairs {
+            if pair.prompt.is_empty() || pair.chosen.is_empty() || pair.rejected.is_empty() {
+                return Err(GraphError::InvalidConfig(
+                    "preference pairs should have a prompt and two completions".into(),
+                ));
+            }
+            let tokens = pair.prompt.iter().chain(&pair.chosen).chain(&pair.rejected);
+            if let Some(t) = tokens.clone().find(|t| **t >= self.config.vocab_size) {
+                return Err(GraphError::InvalidConfig(format!(
+                    "token {} out of a vocabulary of {}",
+                    t, self.config.vocab_size
+                )));
+            }
         }
         Ok(())
     }
 
-    pub fn infer<R: Rng, F: Fn(usize) -> ()>(
+    // Loads prompts followed by their completions (Truncated as in `SftDataset`) in the rows
+    // of the graph, the loss of the completion tokens of each weighted by its coefficient.
+    // Rows left over are filled with unweighted padding.
+    fn load_completions(
+        &mut self,
+        completions: &[(&[usize], &[usize])],
+        coeffs: &[Float],
+    ) -> Result<(), GraphError> {
+        let rows = self.batch_size.unwrap_or(1);
+        let shape = [rows, self.num_tokens];
+        let mut xs = Vec::with_capacity(rows * self.num_tokens);
+        let mut ys = Vec::with_capacity(rows * self.num_tokens);
+        let mut weights = Vec::with_capacity(rows * self.num_tokens);
+        let mut mask = Vec::with_capacity(rows * self.num_tokens);
+        let mut prefix = Vec::with_capacity(rows * self.num_tokens);
+        for r in 0..rows {
+            match completions.get(r).zip(coeffs.get(r)) {
+                Some(((prompt, completion), coeff)) => {
+                    let tokens = [*prompt, *completion].concat();
+                    let row = CompletionRow::new(&tokens, prompt.len(), self.num_tokens, 0);
+                    xs.extend(row.xs);
+                    ys.extend(row.ys);
+                    weights.extend(row.weights.iter().map(|w| w * coeff));
+                    mask.extend(row.mask);
+                    prefix.extend(row.prefix);
+                }
+                None => {
+                    xs.extend(std::iter::repeat_n(0, self.num_tokens));
+                    ys.extend(std::iter::repeat_n(0, self.num_tokens));
+                    weights.extend(std::iter::repeat_n(0., self.num_tokens));
+                    mask.extend(std::iter::repeat_n(1., self.num_tokens));
+                    prefix.extend(std::iter::repeat_n(0., self.num_tokens));
+                }
+            }
+        }
+        let mask = Tensor::raw(&shape, mask)?;
+        self.graph
+            .load_usize(self.token_input, &Tensor::raw(&shape, xs)?)?;
+        self.graph.load(self.attention_mask, &mask)?;
+        load_prefix(
+            &mut self.graph,
+            self.prefix_mask,
+            Some(&Tensor::raw(&shape, prefix)?),
+            &shape,
+        )?;
+        if let Some(head) = &self.classifier {
+            head.load(&mut self.graph, &mask, None)?;
+        }
+        self.graph
+            .load_usize(self.expected_output, &Tensor::raw(&shape, ys)?)?;
+        self.graph
+            .load(self.loss_weights, &Tensor::raw(&shape, weights)?)?;
+        self.graph
+            .load(self.z_loss_coeff, &Tensor::<Float>::zeros(&shape))?;
+        for (id, rate) in self.dropout_rates()? {
+            self.graph.load(id, &rate)?;
+        }
+        Ok(())
+    }
+
+    // Log-probabilities of the completions of the first rows loaded by `load_completions`
+    // (With unit coefficients), after a forward pass
+    fn completion_logprobs(&mut self, rows: usize) -> Result<Vec<Float>, GraphError> {
+        self.graph.fetch(self.loss, false)?;
+        let loss = self.graph.get(self.loss)?.to_float()?;
+        (0..rows)
+            .map(|r| Ok(-loss.get(r)?.blob().iter().sum::<Float>()))
+            .collect()
+    }
+
+    // Logits of every position of a context of 1 to `num_tokens` tokens, as a
+    // `[context.len(), vocab_size]` tensor. Only the positions of the context are computed,
+    // except on models allocated with a batch size, which run on the context padded (And
+    // masked) to `num_tokens` tokens.
+    pub fn forward(&mut self, context: &[usize]) -> Result<Tensor<Float>, GraphError> {
+        self.forward_with_prefix(context, 0)
+    }
+
+    // Same as `forward`, the first `prefix_len` tokens of the context being its prefix on
+    // prefix language models (See `GPTConfig::prefix_lm`, ignored by the others)
+    pub fn forward_with_prefix(
+        &mut self,
+        context: &[usize],
+        prefix_len: usize,
+    ) -> Result<Tensor<Float>, GraphError> {
+        let logits = self
+            .run_prefixed(context, prefix_len, &[self.output])?
+            .remove(0);
+        Ok(logits.slice(0..context.len())?.into())
+    }
+
+    // Logits of the token following the context, through the prefix cache when there is
+    // one. The attention of prefix language models depends on the length of the prefix, their
+    // contexts are not cached.
+    pub(crate) fn next_logits(
+        &mut self,
+        context: &[usize],
+        prefix_len: usize,
+    ) -> Result<Tensor<Float>, GraphError> {
+        let step = self.graph.optimizer_step();
+        let cached = self.prefix_mask.is_none() && !self.cached_attention.is_empty();
+        let state = match &mut self.prefix_cache {
+            Some(cache) if cached && !context.is_empty() => {
+                cache.sync(step);
+                cache.lookup(context)?
+            }
+            _ => {
+                let logits = self.forward_with_prefix(context, prefix_len)?;
+                return Ok(logits.get(context.len() - 1)?.into());
+            }
+        };
+        let result = self.extend(context, state);
+        // The other passes do not attend to the cached positions
+        let empty = Tensor::<Float>::zeros(&[0, self.config.head_size()?]);
+        for c in self.cached_attention.clone() {
+            self.graph.load(c.past_keys, &empty)?;
+            self.graph.load(c.past_values, &empty)?;
+        }
+        let (logits, state) = result?;
+        if let Some(cache) = &mut self.prefix_cache {
+            cache.insert(context, state);
+        }
+        Ok(logits)
+    }
+
+    // Runs the positions of the context after the ones of the state, attending to its
+    // projections as well, and returns the logits of the last position along with the state
+    // of the whole context
+    fn extend(
+        &mut self,
+        context: &[usize],
+        state: AttentionState,
+    ) -> Result<(Tensor<Float>, AttentionState), GraphError> {
+        let len = context.len();
+        if len > self.num_tokens {
+            return Err(GraphError::InvalidConfig(format!(
+                "context of {} tokens, expected 1 to {}",
+                len, self.num_tokens
+            )));
+        }
+        let rows = len - state.len;
+        let empty = Tensor::<Float>::zeros(&[0, self.config.head_size()?]);
+        let mut layers = state.layers.iter();
+        for c in self.cached_attention.iter() {
+            let (keys, values) = match layers.next() {
+                Some((keys, values)) => (keys, values),
+                None => (&empty, &empty),
+            };
+            self.graph.load(c.past_keys, keys)?;
+            self.graph.load(c.past_values, values)?;
+        }
+        let shape = [1, rows];
+        self.graph.load_usize(
+            self.token_input,
+            &Tensor::raw(&shape, context[state.len..].to_vec())?,
+        )?;
+        // The mask covers the cached positions as well
+        self.graph
+            .load(self.attention_mask, &Tensor::constant(&[1, len], 1.))?;
+        if let Some(head) = &self.classifier {
+            head.load(&mut self.graph, &Tensor::constant(&shape, 1.), None)?;
+        }
+        self.graph
+            .load_usize(self.expected_output, &Tensor::zeros(&shape))?;
+        self.graph
+            .load(self.loss_weights, &Tensor::<Float>::zeros(&shape))?;
+        self.graph
+            .load(self.z_loss_coeff, &Tensor::<Float>::zeros(&shape))?;
+        self.graph.forward(false)?;
+
+        self.graph.fetch(self.output, false)?;
+        let output = self.graph.get(self.output)?.to_float()?;
+        let logits = output.get(0)?.get(rows - 1)?.into();
+        let mut layers = Vec::with_capacity(self.cached_attention.len());
+        for c in self.cached_attention.iter() {
+            self.graph.fetch(c.keys, false)?;
+            self.graph.fetch(c.values, false)?;
+            let keys = self.graph.get(c.keys)?.to_float()?.get(0)?.into();
+            let values = self.graph.get(c.values)?.to_float()?.get(0)?.into();
+            layers.push((keys, values));
+        }
+        Ok((logits, AttentionState { len, layers }))
+    }
+
+    // Log-probabilities of every token of the text but the first one, given the tokens before
+    // it. Texts longer than the context are scored in windows of `num_tokens` tokens,
+    // overlapping by half, so that tokens are predicted from at least `num_tokens / 2` others.
+    pub fn score(&mut self, tokens: &[usize]) -> Result<Vec<Float>, GraphError> {
+        let vocab_size = self.config.vocab_size;
+        if let Some(t) = tokens.iter().find(|t| **t >= vocab_size) {
+            return Err(GraphError::InvalidConfig(format!(
+                "token {} out of a vocabulary of {}",
+                t, vocab_size
+            )));
+        }
+        let stride = (self.num_tokens / 2).max(1);
+        let mut scores = Vec::with_capacity(tokens.len().saturating_sub(1));
+        let mut start = 0;
+        while scores.len() + 1 < tokens.len() {
+            let window = &tokens[start..tokens.len().min(start + self.num_tokens)];
+            let logits = self.forward(window)?;
+            // Positions of the window predicting tokens that were not scored yet
+            for pos in scores.len() - start..window.len() {
+                let next = start + pos + 1;
+                if next == tokens.len() {
+                    break;
+                }
+                let logits = logits.get(pos)?;
+                let logits = logits.blob();
+                let max = logits.iter().cloned().fold(Float::NEG_INFINITY, Float::max);
+                let log_sum = logits.iter().map(|l| (l - max).exp()).sum::<Float>().ln() + max;
+                scores.push(logits[tokens[next]] - log_sum);
+            }
+            start += stride;
+        }
+        Ok(scores)
+    }
+
+    // Perplexity of the model on the text, the exponential of the mean negative
+    // log-probability of its tokens (See `score`)
+    pub fn perplexity(&mut self, tokens: &[usize]) -> Result<Float, GraphError> {
+        if tokens.len() < 2 {
+            return Err(GraphError::InvalidConfig(
+                "at least two tokens are needed".into(),
+            ));
+        }
+        let scores = self.score(tokens)?;
+        Ok((-scores.iter().sum::<Float>() / scores.len() as Float).exp())
+    }
+
+    // Final-layer hidden states (Of shape `[context.len(), embedding_degree]`) of every
+    // position of the context, for using the model as an encoder
+    pub fn hidden_states(&mut self, context: &[usize]) -> Result<Tensor<Float>, GraphError> {
+        let hidden = self.run(context, self.hidden)?;
+        Ok(hidden.slice(0..context.len())?.into())
+    }
+
+    // Attention weights of every head (`[layer][head]`) on the context, as `[len, len]`
+    // tensors whose row i holds the weights of the positions attended by position i. (The
+    // weights after the softmax, before the attention dropout)
+    pub fn attention(&mut self, context: &[usize]) -> Result<Vec<Vec<Tensor<Float>>>, GraphError> {
+        let ids = self.attention_weights.clone();
+        let len = context.len();
+        let mut weights = self.run_all(context, &ids.concat())?.into_iter();
+        let mut layers = Vec::with_capacity(ids.len());
+        for heads in ids.iter() {
+            let mut layer = Vec::with_capacity(heads.len());
+            for w in weights.by_ref().take(heads.len()) {
+                // Models allocated with a batch size run on padded contexts
+                layer.push(w.strided().slice(0, 0..len)?.slice(1, 0..len)?.into());
+            }
+            layers.push(layer);
+        }
+        Ok(layers)
+    }
+
+    // Embedding of the whole context, pooled from its hidden states
+    pub fn embed(&mut self, context: &[usize], pooling: Pooling) -> Result<Vec<Float>, GraphError> {
+        let hidden = self.hidden_states(context)?;
+        let weights = pooling_weights(pooling, &Tensor::constant(&[1, context.len()], 1.))?;
+        Ok((&weights ^ &hidden)?.blob().to_vec())
+    }
+
+    // Logits of the classes of the context (Truncated to `num_tokens` tokens, as in
+    // `train_classifier`), predicted by the classification head
+    pub fn classify(&mut self, context: &[usize]) -> Result<Vec<Float>, GraphError> {
+        let head = self.classifier.ok_or_else(|| {
+            GraphError::InvalidConfig("the model has no classification head".into())
+        })?;
+        let context = &context[..context.len().min(self.num_tokens)];
+        Ok(self.run(context, head.logits)?.blob().to_vec())
+    }
+
+    // Runs the model on the context, returning `tensor` for its first (And only) sequence
+    fn run(&mut self, context: &[usize], tensor: TensorId) -> Result<Tensor<Float>, GraphError> {
+        Ok(self.run_all(context, &[tensor])?.remove(0))
+    }
+
+    // Same as `run`, returning several tensors of the same pass
+    fn run_all(
+        &mut self,
+        context: &[usize],
+        tensors: &[TensorId],
+    ) -> Result<Vec<Tensor<Float>>, GraphError> {
+        self.run_prefixed(context, 0, tensors)
+    }
+
+    // Same as `run_all`, with the first `prefix_len` tokens of the context as its prefix
+    fn run_prefixed(
+        &mut self,
+        context: &[usize],
+        prefix_len: usize,
+        tensors: &[TensorId],
+    ) -> Result<Vec<Tensor<Float>>, GraphError> {
+        let len = context.len();
+        if len == 0 || len > self.num_tokens {
+            return Err(GraphError::InvalidConfig(format!(
+                "context of {} tokens, expected 1 to {}",
+                len, self.num_tokens
+            )));
+        }
+        let width = if self.batch_size.is_some() {
+            self.num_tokens
+        } else {
+            len
+        };
+        let mut tokens = context.to_vec();
+        tokens.resize(width, 0);
+        let mask = (0..width).map(|i| if i < len { 1. } else { 0. }).collect();
+        let shape = [1, width];
+        let mask = Tensor::raw(&shape, mask)?;
+        self.graph
+            .load_usize(self.token_input, &Tensor::raw(&shape, tokens)?)?;
+        self.graph.load(self.attention_mask, &mask)?;
+        let prefix = (0..width)
+            .map(|i| if i < prefix_len.min(len) { 1. } else { 0. })
+            .collect();
+        load_prefix(
+            &mut self.graph,
+            self.prefix_mask,
+            Some(&Tensor::raw(&shape, prefix)?),
+            &shape,
+        )?;
+        if let Some(head) = &self.classifier {
+            head.load(&mut self.graph, &mask, None)?;
+        }
+        if self.batch_size.is_none() {
+            // The loss is computed as well, its inputs have to match the context
+            self.graph
+                .load_usize(self.expected_output, &Tensor::zeros(&shape))?;
+            self.graph
+                .load(self.loss_weights, &Tensor::<Float>::zeros(&shape))?;
+            self.graph
+                .load(self.z_loss_coeff, &Tensor::<Float>::zeros(&shape))?;
+        }
+
+        self.graph.forward(false)?;
+        let mut values = Vec::with_capacity(tensors.len());
+        for tensor in tensors {
+            self.graph.fetch(*tensor, false)?;
+            let value = self.graph.get(*tensor)?.to_float()?;
+            values.push(value.get(0)?.into());
+        }
+        Ok(values)
+    }
+
+    // Generates `params.max_tokens` tokens after the prompt, which the returned tokens start
+    // with. `callback` is called on every token of the prompt, then on the generated ones.
+    pub fn infer<R: Rng, F: Fn(usize)>(
+        &mut self,
+        rng: &mut R,
+        prompt: &[usize],
+        params: &SamplingParams,
+        callback: F,
+    ) -> Result<Vec<usize>, GraphError> {
+        self.infer_with(rng, prompt, params, &params.processors(), callback)
+    }
+
+    // Same as `infer`, sampling through `processors` instead of the built-in processors of
+    // `params`, see `SamplingParams::select_with`
+    pub fn infer_with<R: Rng, F: Fn(usize)>(
+        &mut self,
+        rng: &mut R,
+        prompt: &[usize],
+        params: &SamplingParams,
+        processors: &[Box<dyn LogitProcessor>],
+        callback: F,
+    ) -> Result<Vec<usize>, GraphError> {
+        self.generate(rng, prompt, params, processors, |token| {
+            callback(token);
+            true
+        })
+    }
+
+    // Same as `infer`, stopping after the first generated token for which `callback` returns
+    // false (E.g. once the text contains a stop sequence). What it returns on the tokens of
+    // the prompt is ignored.
+    pub fn infer_until<R: Rng, F: FnMut(usize) -> bool>(
+        &mut self,
+        rng: &mut R,
+        prompt: &[usize],
+        params: &SamplingParams,
+        callback: F,
+    ) -> Result<Vec<usize>, GraphError> {
+        self.generate(rng, prompt, params, &params.processors(), callback)
+    }
+
+    fn generate<R: Rng, F: FnMut(usize) -> bool>(
         &mut self,
         rng: &mut R,
         prompt: &[usize],
-        count: usize,
-        temperature: f32,
+        params: &SamplingParams,
+        processors: &[Box<dyn LogitProcessor>],
         callback: F,
     ) -> Result<Vec<usize>, GraphError> {
-        let mut cnt = prompt.len();
-        let mut context = vec![0; self.num_tokens];
-        context[..prompt.len()].copy_from_slice(prompt);
+        params.validate()?;
+        match params.seed {
+            Some(seed) => self.sample(
+                &mut ChaCha8Rng::seed_from_u64(seed),
+                prompt,
+                params,
+                processors,
+                callback,
+            ),
+            None => self.sample(rng, prompt, params, processors, callback),
+        }
+    }
 
-        self.graph.load(self.pos_input, &self.pos_input_fixed)?;
+    fn sample<R: Rng, F: FnMut(usize) -> bool>(
+        &mut self,
+        rng: &mut R,
+        prompt: &[usize],
+        params: &SamplingParams,
+        processors: &[Box<dyn LogitProcessor>],
+        mut callback: F,
+    ) -> Result<Vec<usize>, GraphError> {
+        // The context holds the last `num_tokens` tokens, the ones of the prompt being its
+        // prefix
+        let mut context = prompt[prompt.len().saturating_sub(self.num_tokens)..].to_vec();
+        let mut prefix_len = context.len();
 
         for ch in prompt {
             callback(*ch);
         }
         let mut chs = prompt.to_vec();
-        for _ in 0..count {
-            self.graph.load_usize(
-                self.token_input,
-                &Tensor::raw(&[1, self.num_tokens], context.clone())?,
-            )?;
-
-            self.graph.forward(false)?;
-            self.graph.fetch(self.output, false)?;
-            let next_ch = select(
-                rng,
-                &self
-                    .graph
-                    .get(self.output)?
-                    .as_float()?
-                    .get(0)?
-                    .get(cnt - 1)?,
-                temperature,
-            )?;
+        for _ in 0..params.max_tokens {
+            let logits = self.next_logits(&context, prefix_len)?;
+            let next_ch = params.select_with(rng, logits.blob(), &context, processors)?;
+            if params.stop_tokens.contains(&next_ch) {
+                break;
+            }
 
             chs.push(next_ch);
-            callback(next_ch);
-            if cnt == self.num_tokens {
+            if !callback(next_ch) {
+                break;
+            }
+            if context.len() == self.num_tokens {
                 context.remove(0);
-                context.push(0);
-                cnt -= 1;
+                prefix_len = prefix_len.saturating_sub(1);
             }
-            context[cnt] = next_ch;
-            cnt += 1;
+            context.push(next_ch);
         }
         Ok(chs)
     }
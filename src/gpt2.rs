@@ -0,0 +1,445 @@
+use crate::gpt::{
+    Activation, FeedForward, GPTConfig, InitScheme, NormPlacement, PositionalEncoding, Precision,
+    TrainingState,
+};
+use crate::graph::GraphError;
+use crate::npy;
+use crate::optimizer::OptimizerState;
+use crate::tensor::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Import of pretrained GPT-2 weights (https://github.com/openai/gpt-2). The weights are
+// mapped onto the parameters of a femtoGPT model with the config returned by `config`,
+// and can be loaded with `GPT::set_training_state`.
+//
+// Note: token ids should come from the GPT-2 BPE tokenizer.
+
+const VOCAB_SIZE: usize = 50257;
+const EMBEDDING_DEGREE: usize = 768;
+const NUM_LAYERS: usize = 12;
+const NUM_HEADS: usize = 12;
+
+// Config of the 124M GPT-2 model, with a context of `num_tokens` (At most 1024) tokens
+pub fn config(num_tokens: usize) -> GPTConfig {
+    GPTConfig {
+        vocab_size: VOCAB_SIZE,
+        embedding_degree: EMBEDDING_DEGREE,
+        num_tokens,
+        num_layers: NUM_LAYERS,
+        num_heads: NUM_HEADS,
+        num_kv_heads: NUM_HEADS,
+        head_size: None,
+        dropout: 0.,
+        positional_encoding: PositionalEncoding::Learned,
+        activation: Activation::Gelu,
+        feedforward: FeedForward::Mlp,
+        feedforward_multiplier: 4.,
+        norm_placement: NormPlacement::PreNorm,
+        final_norm: true,
+        bias: true,
+        precision: Precision::F32,
+        lora: None,
+        classifier: None,
+        init: InitScheme::default(),
+        prefix_lm: false,
+    }
+}
+
+// A tensor of GPT-2, with its femtoGPT parameter. Weights are [in, out] matrices in both
+// models (GGUF stores them as [out, in] matrices).
+pub(crate) struct Gpt2Tensor {
+    pub femto: String,
+    pub hf: String,   // Name in the Hugging Face checkpoints
+    pub gguf: String, // Name in llama.cpp
+    pub shape: Vec<usize>,
+}
+
+// The tensors of the blocks and of the final norm, except for the fused attention
+// projections (See `qkv_heads`). Biases are only femtoGPT parameters in configs with `bias`.
+pub(crate) fn mapped_tensors(config: &GPTConfig) -> Vec<Gpt2Tensor> {
+    let d = config.embedding_degree;
+    let ff = config.feedforward_degree();
+    let mut tensors = Vec::new();
+    let mut push = |femto: String, hf: String, gguf: String, shape: &[usize]| {
+        tensors.push(Gpt2Tensor {
+            femto,
+            hf,
+            gguf,
+            shape: shape.to_vec(),
+        })
+    };
+    for l in 0..config.num_layers {
+        for (femto, hf, gguf, shape) in [
+            ("norm_{}_coeff", "ln_1.weight", "attn_norm.weight", vec![d]),
+            ("norm_{}_bias", "ln_1.bias", "attn_norm.bias", vec![d]),
+            (
+                "proj_{}_weights",
+                "attn.c_proj.weight",
+                "attn_output.weight",
+                vec![d, d],
+            ),
+            (
+                "proj_{}_bias",
+                "attn.c_proj.bias",
+                "attn_output.bias",
+                vec![d],
+            ),
+            (
+                "atten_norm_{}_coeff",
+                "ln_2.weight",
+                "ffn_norm.weight",
+                vec![d],
+            ),
+            ("atten_norm_{}_bias", "ln_2.bias", "ffn_norm.bias", vec![d]),
+            (
+                "feedforward1_{}_weights",
+                "mlp.c_fc.weight",
+                "ffn_up.weight",
+                vec![d, ff],
+            ),
+            (
+                "feedforward1_{}_bias",
+                "mlp.c_fc.bias",
+                "ffn_up.bias",
+                vec![ff],
+            ),
+            (
+                "feedforward2_{}_weights",
+                "mlp.c_proj.weight",
+                "ffn_down.weight",
+                vec![ff, d],
+            ),
+            (
+                "feedforward2_{}_bias",
+                "mlp.c_proj.bias",
+                "ffn_down.bias",
+                vec![d],
+            ),
+        ] {
+            push(
+                femto.replace("{}", &l.to_string()),
+                format!("h.{}.{}", l, hf),
+                format!("blk.{}.{}", l, gguf),
+                &shape,
+            );
+        }
+    }
+    push(
+        "head_norm_coeff".into(),
+        "ln_f.weight".into(),
+        "output_norm.weight".into(),
+        &[d],
+    );
+    push(
+        "head_norm_bias".into(),
+        "ln_f.bias".into(),
+        "output_norm.bias".into(),
+        &[d],
+    );
+    tensors
+}
+
+// The fused attention projection of block `l` (`attn.c_attn`, `attn_qkv` in GGUF) is laid out as
+// [Query | Key | Value] columns, each split into heads. In femtoGPT, the `k` projections are the
+// attending side of attention (I.e. the queries of GPT-2) and the `q` projections the attended
+// side (The keys of GPT-2). Returns the femtoGPT projections in the order of the columns, each
+// taking `head_size` of them.
+pub(crate) fn qkv_heads(config: &GPTConfig, l: usize) -> Vec<String> {
+    ["k", "q", "v"]
+        .iter()
+        .flat_map(|kind| (0..config.num_heads).map(move |h| format!("head_{}_{}_{}", l, h, kind)))
+        .collect()
+}
+
+fn missing(name: &str) -> GraphError {
+    GraphError::InvalidConfig(format!("gpt-2 tensor {} not found", name))
+}
+
+// Loads the weights from a `model.safetensors` file (As published on Hugging Face)
+pub fn from_safetensors(config: &GPTConfig, bytes: &[u8]) -> Result<TrainingState, GraphError> {
+    let tensors = safetensors::SafeTensors::deserialize(bytes).map_err(|e| {
+        GraphError::DeserializationError(format!("invalid safetensors file: {}", e))
+    })?;
+    import(config, |name| {
+        // Some exports prefix the names with `transformer.`
+        let t = tensors
+            .tensor(name)
+            .or_else(|_| tensors.tensor(&format!("transformer.{}", name)))
+            .map_err(|_| missing(name))?;
+        let data = t.data();
+        let values = match t.dtype() {
+            safetensors::Dtype::F32 => data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64)
+                .collect::<Vec<_>>(),
+            safetensors::Dtype::F16 => data
+                .chunks_exact(2)
+                .map(|b| half::f16::from_le_bytes([b[0], b[1]]).to_f64())
+                .collect(),
+            safetensors::Dtype::BF16 => data
+                .chunks_exact(2)
+                .map(|b| half::bf16::from_le_bytes([b[0], b[1]]).to_f64())
+                .collect(),
+            dtype => {
+                return Err(GraphError::DeserializationError(format!(
+                    "unsupported dtype {:?} of gpt-2 tensor {}",
+                    dtype, name
+                )))
+            }
+        };
+        Ok(Tensor::<f64>::raw(t.shape(), values)?.cast())
+    })
+}
+
+// Names of the variables in the original TensorFlow checkpoints
+fn tf_name(name: &str) -> String {
+    let mut parts = name.split('.').collect::<Vec<_>>();
+    let last = parts.pop().unwrap_or_default();
+    let layer = if parts.first() == Some(&"h") && parts.len() > 1 {
+        let layer = format!("h{}", parts[1]);
+        parts.drain(..2);
+        Some(layer)
+    } else {
+        None
+    };
+    let is_norm = parts.last().is_some_and(|p| p.starts_with("ln_"));
+    let suffix = match (last, is_norm) {
+        ("bias", _) => Some("b"),
+        ("weight", true) => Some("g"),
+        // Embedding tables have no suffix
+        ("weight", false) if layer.is_none() && parts.len() == 1 && parts[0].starts_with('w') => {
+            None
+        }
+        _ => Some("w"),
+    };
+    std::iter::once("model".to_string())
+        .chain(layer)
+        .chain(parts.iter().map(|p| p.to_string()))
+        .chain(suffix.map(String::from))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Loads the weights from a directory of .npy files, one per variable of the original
+// TensorFlow checkpoint (E.g. `model/h0/attn/c_attn/w.npy`)
+pub fn from_numpy_dir<P: AsRef<Path>>(
+    config: &GPTConfig,
+    dir: P,
+) -> Result<TrainingState, GraphError> {
+    import(config, |name| {
+        let path = dir.as_ref().join(format!("{}.npy", tf_name(name)));
+        if !path.is_file() {
+            return Err(missing(name));
+        }
+        npy::from_npy(&std::fs::read(path)?)
+    })
+}
+
+// Maps the GPT-2 weights (Named as in the Hugging Face checkpoints) to femtoGPT parameters
+fn import<F: Fn(&str) -> Result<Tensor<Float>, GraphError>>(
+    config: &GPTConfig,
+    get_any: F,
+) -> Result<TrainingState, GraphError> {
+    // Dimensions are checked against the shapes of the imported tensors, so that smaller
+    // models with the same architecture can be imported too
+    if config.num_kv_heads != config.num_heads
+        || config.head_size()? * config.num_heads != config.embedding_degree
+        || config.positional_encoding != PositionalEncoding::Learned
+        || config.activation != Activation::Gelu
+        || config.feedforward != FeedForward::Mlp
+        || config.norm_placement != NormPlacement::PreNorm
+        || !config.final_norm
+        || !config.bias
+    {
+        return Err(GraphError::InvalidConfig(
+            "the config is not compatible with gpt-2, see `gpt2::config`".into(),
+        ));
+    }
+    let d = config.embedding_degree;
+    let head_size = config.head_size()?;
+
+    // TensorFlow stores the projections as [1, in, out] tensors
+    let get = |name: &str, shape: &[usize]| -> Result<Tensor<Float>, GraphError> {
+        let t = get_any(name)?;
+        if t.size() != shape.iter().product::<usize>() {
+            return Err(GraphError::ShapeMismatch {
+                name: name.into(),
+                expected: shape.to_vec(),
+                found: t.shape().to_vec(),
+            });
+        }
+        Ok(Tensor::raw(shape, t.blob().to_vec())?)
+    };
+    // Columns [start, start + len) of a matrix
+    let columns =
+        |t: &Tensor<Float>, start: usize, len: usize| -> Result<Tensor<Float>, GraphError> {
+            let cols = t.shape()[t.dim() - 1];
+            let data = t
+                .blob()
+                .chunks(cols)
+                .flat_map(|row| row[start..start + len].iter().cloned())
+                .collect::<Vec<_>>();
+            let mut shape = t.shape().to_vec();
+            *shape.last_mut().unwrap() = len;
+            Ok(Tensor::raw(&shape, data)?)
+        };
+
+    let mut tensors = HashMap::new();
+    let wte = get("wte.weight", &[config.vocab_size, d])?;
+    // Only the first `num_tokens` positions are used
+    let wpe = get_any("wpe.weight")?;
+    if wpe.size() < config.num_tokens * d {
+        return Err(GraphError::InvalidConfig(format!(
+            "gpt-2 supports at most {} tokens",
+            wpe.size() / d
+        )));
+    }
+    tensors.insert(
+        "pos_embedding".to_string(),
+        Tensor::raw(
+            &[config.num_tokens, d],
+            wpe.blob()[..config.num_tokens * d].to_vec(),
+        )?,
+    );
+    // The output layer shares its weights with the token embedding in GPT-2
+    tensors.insert("head_map_weights".to_string(), wte.transpose()?);
+    tensors.insert(
+        "head_map_bias".to_string(),
+        Tensor::zeros(&[config.vocab_size]),
+    );
+    tensors.insert("token_embedding".to_string(), wte);
+
+    for t in mapped_tensors(config) {
+        tensors.insert(t.femto, get(&t.hf, &t.shape)?);
+    }
+    for l in 0..config.num_layers {
+        let qkv = get(&format!("h.{}.attn.c_attn.weight", l), &[d, 3 * d])?;
+        let qkv_bias = get(&format!("h.{}.attn.c_attn.bias", l), &[1, 3 * d])?;
+        for (i, name) in qkv_heads(config, l).into_iter().enumerate() {
+            let start = i * head_size;
+            tensors.insert(name.clone(), columns(&qkv, start, head_size)?);
+            let bias = columns(&qkv_bias, start, head_size)?;
+            tensors.insert(
+                format!("{}_bias", name),
+                Tensor::raw(&[head_size], bias.blob().to_vec())?,
+            );
+        }
+    }
+
+    Ok(TrainingState {
+        tensors,
+        optimizer: OptimizerState::default(),
+        schedule: None,
+        ema: HashMap::new(),
+        progress: None,
+        config: Some(config.clone()),
+        manifest: None,
+        swa: None,
+        tokenizer: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::GPT;
+    use crate::graph::CpuGraph;
+
+    fn small_config() -> GPTConfig {
+        GPTConfig {
+            vocab_size: 7,
+            embedding_degree: 8,
+            num_layers: 2,
+            num_heads: 2,
+            num_kv_heads: 2,
+            ..config(4)
+        }
+    }
+
+    // Fake GPT-2 checkpoint, where each value encodes its column
+    fn checkpoint(config: &GPTConfig) -> Vec<u8> {
+        let (d, v) = (config.embedding_degree, config.vocab_size);
+        let mut shapes = vec![
+            ("wte.weight".to_string(), vec![v, d]),
+            ("wpe.weight".to_string(), vec![16, d]),
+            ("ln_f.weight".to_string(), vec![d]),
+            ("ln_f.bias".to_string(), vec![d]),
+        ];
+        for l in 0..config.num_layers {
+            for (name, shape) in [
+                ("ln_1.weight", vec![d]),
+                ("ln_1.bias", vec![d]),
+                ("attn.c_attn.weight", vec![d, 3 * d]),
+                ("attn.c_attn.bias", vec![3 * d]),
+                ("attn.c_proj.weight", vec![d, d]),
+                ("attn.c_proj.bias", vec![d]),
+                ("ln_2.weight", vec![d]),
+                ("ln_2.bias", vec![d]),
+                ("mlp.c_fc.weight", vec![d, 4 * d]),
+                ("mlp.c_fc.bias", vec![4 * d]),
+                ("mlp.c_proj.weight", vec![4 * d, d]),
+                ("mlp.c_proj.bias", vec![d]),
+            ] {
+                shapes.push((format!("h.{}.{}", l, name), shape));
+            }
+        }
+        let data = shapes
+            .iter()
+            .map(|(name, shape)| {
+                let cols = *shape.last().unwrap();
+                let bytes = (0..shape.iter().product::<usize>())
+                    .flat_map(|i| ((i % cols) as f32).to_le_bytes())
+                    .collect::<Vec<_>>();
+                (name.clone(), shape.clone(), bytes)
+            })
+            .collect::<Vec<_>>();
+        let views = data
+            .iter()
+            .map(|(name, shape, bytes)| {
+                let view = safetensors::tensor::TensorView::new(
+                    safetensors::Dtype::F32,
+                    shape.clone(),
+                    bytes,
+                )
+                .unwrap();
+                (name.clone(), view)
+            })
+            .collect::<Vec<_>>();
+        safetensors::serialize(views, &None).unwrap()
+    }
+
+    #[test]
+    fn test_from_safetensors() {
+        let config = small_config();
+        let state = from_safetensors(&config, &checkpoint(&config)).unwrap();
+
+        // Every parameter of the model is imported, with the right shape
+        let mut rng = rand::thread_rng();
+        let gpt = GPT::new(&mut rng, CpuGraph::new(), None, config).unwrap();
+        let params = gpt.get_training_state().unwrap().tensors;
+        assert_eq!(params.len(), state.tensors.len());
+        for (name, t) in params {
+            assert_eq!(state.tensors[&name].shape(), t.shape(), "{}", name);
+        }
+
+        // The second head attends with the queries of GPT-2, to its keys
+        let k = &state.tensors["head_1_1_k"];
+        let q = &state.tensors["head_1_1_q"];
+        let v_bias = &state.tensors["head_1_1_v_bias"];
+        assert_eq!(k.blob()[..4], [4., 5., 6., 7.]);
+        assert_eq!(q.blob()[..4], [12., 13., 14., 15.]);
+        assert_eq!(v_bias.blob(), [20., 21., 22., 23.]);
+    }
+
+    #[test]
+    fn test_tf_names() {
+        assert_eq!(tf_name("wte.weight"), "model/wte");
+        assert_eq!(tf_name("ln_f.weight"), "model/ln_f/g");
+        assert_eq!(tf_name("h.3.ln_2.bias"), "model/h3/ln_2/b");
+        assert_eq!(
+            tf_name("h.11.attn.c_attn.weight"),
+            "model/h11/attn/c_attn/w"
+        );
+    }
+}
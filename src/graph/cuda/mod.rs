@@ -0,0 +1,448 @@
+use super::*;
+use crate::funcs::{MatMul, MatMulAdd};
+use cudarc::cublas::{sys::cublasOperation_t, CudaBlas, Gemm, GemmConfig, StridedBatchedConfig};
+use cudarc::driver::{CudaDevice, CudaSlice};
+use std::any::Any;
+
+impl From<cudarc::driver::DriverError> for GraphError {
+    fn from(error: cudarc::driver::DriverError) -> Self {
+        GraphError::CudaError(error.to_string())
+    }
+}
+
+impl From<cudarc::cublas::result::CublasError> for GraphError {
+    fn from(error: cudarc::cublas::result::CublasError) -> Self {
+        GraphError::CudaError(error.to_string())
+    }
+}
+
+// Offloads the matrix multiplications (Forward and backward) of a `CpuGraph` to cuBLAS, on an
+// NVIDIA GPU. This is not a GPU graph: the tensors stay on the CPU, and the other functions
+// and the optimizer run on the CPU. The weights (Parameters multiplied from the right) are
+// kept on the device until the parameters change, the activations are copied to the device
+// and the result back on every multiplication. It only pays off when the multiplications are
+// large enough for the copies to be worth it.
+#[derive(Clone)]
+pub struct MatMulOffloadGraph {
+    graph: CpuGraph,
+    cublas: Arc<Cublas>,
+}
+
+impl MatMulOffloadGraph {
+    pub fn new() -> Result<Self, GraphError> {
+        Ok(Self {
+            graph: CpuGraph::new(),
+            cublas: Arc::new(Cublas::new(0)?),
+        })
+    }
+}
+
+// Device copies of the weights, by the address and the size of their blob on the CPU
+type Weights = HashMap<(usize, usize), Arc<CudaSlice<f32>>>;
+
+pub struct Cublas {
+    device: Arc<CudaDevice>,
+    blas: CudaBlas,
+    // Shared by the clones of the graph, which share their parameters. Cleared whenever the
+    // parameters change, see `MatMulOffloadGraph::optimize`
+    weights: Mutex<Weights>,
+}
+
+impl Cublas {
+    pub fn new(ordinal: usize) -> Result<Self, GraphError> {
+        let device = CudaDevice::new(ordinal)?;
+        let blas = CudaBlas::new(device.clone())?;
+        Ok(Self {
+            device,
+            blas,
+            weights: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Batched row-major matrix multiplication, with the same broadcasting rules as the
+    // `^` operator of tensors.
+    pub fn matmul<A: TensorOps<f32>, B: TensorOps<f32>>(
+        &self,
+        a: &A,
+        b: &B,
+    ) -> Result<Tensor<f32>, GraphError> {
+        let b_dev = self.device.htod_sync_copy(b.blob())?;
+        self.gemm(a, &b_dev, b.shape(), false)
+    }
+
+    // Device copy of a weight, uploaded on its first use since the parameters changed
+    fn weight(&self, w: &Tensor<f32>) -> Result<Arc<CudaSlice<f32>>, GraphError> {
+        let key = (w.blob().as_ptr() as usize, w.size());
+        let mut weights = self.weights.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(w_dev) = weights.get(&key) {
+            return Ok(w_dev.clone());
+        }
+        let w_dev = Arc::new(self.device.htod_sync_copy(w.blob())?);
+        weights.insert(key, w_dev.clone());
+        Ok(w_dev)
+    }
+
+    fn clear_weights(&self) {
+        self.weights
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+
+    // Same as `matmul`, the second operand (Of shape `b_shape`) being on the device already,
+    // and transposed (Its last two dimensions) when `transpose_b` is set
+    fn gemm<A: TensorOps<f32>>(
+        &self,
+        a: &A,
+        b_dev: &CudaSlice<f32>,
+        b_shape: &[usize],
+        transpose_b: bool,
+    ) -> Result<Tensor<f32>, GraphError> {
+        let (device, blas) = (&self.device, &self.blas);
+        if a.dim() < 2 || b_shape.len() < 2 {
+            return Err(TensorError::UnexpectedShape.into());
+        }
+        let (m, n) = (a.shape()[a.dim() - 2], a.shape()[a.dim() - 1]);
+        // Rows and columns of the stored matrices of the second operand
+        let (rows, cols) = (b_shape[b_shape.len() - 2], b_shape[b_shape.len() - 1]);
+        let (k, p) = if transpose_b {
+            (cols, rows)
+        } else {
+            (rows, cols)
+        };
+        let (a_lead, b_lead) = (&a.shape()[..a.dim() - 2], &b_shape[..b_shape.len() - 2]);
+        let a_is_long = a_lead.len() >= b_lead.len();
+        let (long, short) = if a_is_long {
+            (a_lead, b_lead)
+        } else {
+            (b_lead, a_lead)
+        };
+        if k != n || !long.ends_with(short) {
+            return Err(TensorError::UnexpectedShape.into());
+        }
+        let mats = long.iter().product::<usize>();
+        let short_mats = short.iter().product::<usize>();
+
+        let a_dev = device.htod_sync_copy(a.blob())?;
+        let mut c_dev = device.alloc_zeros::<f32>(mats * m * p)?;
+
+        // cuBLAS is column-major: C^T = B^T * A^T is computed instead of C = A * B (A stored
+        // row-major matrix is its transpose in column-major, so B^T is the stored one unless
+        // B is transposed)
+        let gemm = GemmConfig {
+            transa: if transpose_b {
+                cublasOperation_t::CUBLAS_OP_T
+            } else {
+                cublasOperation_t::CUBLAS_OP_N
+            },
+            transb: cublasOperation_t::CUBLAS_OP_N,
+            m: p as i32,
+            n: m as i32,
+            k: n as i32,
+            alpha: 1.,
+            lda: cols as i32,
+            ldb: n as i32,
+            beta: 0.,
+            ldc: p as i32,
+        };
+        let (a_stride, b_stride, c_stride) = (m * n, n * p, m * p);
+        if short_mats == 1 {
+            // A single matrix is broadcast with a zero stride
+            let cfg = StridedBatchedConfig {
+                gemm,
+                batch_size: mats as i32,
+                stride_a: if a_is_long { 0 } else { b_stride as i64 },
+                stride_b: if a_is_long { a_stride as i64 } else { 0 },
+                stride_c: c_stride as i64,
+            };
+            unsafe { blas.gemm_strided_batched(cfg, b_dev, &a_dev, &mut c_dev) }?;
+        } else {
+            // Otherwise, the shorter operand is repeated for each chunk of the longer one
+            let cfg = StridedBatchedConfig {
+                gemm,
+                batch_size: short_mats as i32,
+                stride_a: b_stride as i64,
+                stride_b: a_stride as i64,
+                stride_c: c_stride as i64,
+            };
+            for chunk in 0..mats / short_mats {
+                let a_off = if a_is_long {
+                    chunk * short_mats * a_stride
+                } else {
+                    0
+                };
+                let b_off = if a_is_long {
+                    0
+                } else {
+                    chunk * short_mats * b_stride
+                };
+                let c_off = chunk * short_mats * c_stride;
+                unsafe {
+                    blas.gemm_strided_batched(
+                        cfg,
+                        &b_dev.slice(b_off..),
+                        &a_dev.slice(a_off..),
+                        &mut c_dev.slice_mut(c_off..),
+                    )
+                }?;
+            }
+        }
+
+        let mut shape = long.to_vec();
+        shape.extend([m, p]);
+        Ok(Tensor::raw(&shape, device.dtoh_sync_copy(&c_dev)?)?)
+    }
+
+    // Same as `matmul`, the second operand being a weight when it is one of the parameters
+    // (By the addresses of their blobs)
+    fn matmul_weight(
+        &self,
+        a: &Tensor<f32>,
+        b: &Tensor<f32>,
+        params: &HashSet<usize>,
+        transpose_b: bool,
+    ) -> Result<Tensor<f32>, GraphError> {
+        if params.contains(&(b.blob().as_ptr() as usize)) {
+            let b_dev = self.weight(b)?;
+            self.gemm(a, &b_dev, b.shape(), transpose_b)
+        } else if transpose_b {
+            self.matmul(a, &b.transpose()?)
+        } else {
+            self.matmul(a, b)
+        }
+    }
+
+    fn run(
+        &self,
+        f: &mut dyn Function,
+        inps: &[&GeneralTensor],
+        training: bool,
+        out: &mut Tensor<f32>,
+        params: &HashSet<usize>,
+    ) -> Result<(), GraphError> {
+        let any = f as &dyn Any;
+        if any.is::<MatMul>() || any.is::<MatMulAdd>() {
+            let (a, b) = (inps[0].as_float()?, inps[1].as_float()?);
+            *out = self.matmul_weight(a, b, params, false)?;
+            if let Some(bias) = inps.get(2) {
+                let bias = bias.as_float()?.blob();
+                if out.shape().last() != Some(&bias.len()) {
+                    return Err(TensorError::UnexpectedShape.into());
+                }
+                for row in out.blob_mut().chunks_mut(bias.len()) {
+                    for (o, b) in row.iter_mut().zip(bias.iter()) {
+                        *o += b;
+                    }
+                }
+            }
+            Ok(())
+        } else {
+            Ok(f.run_into(inps, training, out)?)
+        }
+    }
+    fn grad(
+        &self,
+        f: &dyn Function,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<f32>,
+        params: &HashSet<usize>,
+    ) -> Result<Vec<Tensor<f32>>, GraphError> {
+        let any = f as &dyn Any;
+        if any.is::<MatMul>() || any.is::<MatMulAdd>() {
+            let (a, b) = (inps[0].as_float()?, inps[1].as_float()?);
+            let mut grads = vec![
+                self.matmul_weight(out_grad, b, params, true)?,
+                self.matmul(&a.transpose()?, out_grad)?,
+            ];
+            if any.is::<MatMulAdd>() {
+                grads.push(out_grad.clone());
+            }
+            Ok(grads)
+        } else {
+            Ok(f.grad(inps, out_grad)?)
+        }
+    }
+}
+
+impl MatMulOffloadGraph {
+    // Addresses of the blobs of the parameters, whose device copies are kept
+    fn param_blobs(&self) -> HashSet<usize> {
+        self.graph
+            .params()
+            .iter()
+            .filter_map(|p| self.graph.get(*p).ok()?.as_float().ok())
+            .map(|p| p.blob().as_ptr() as usize)
+            .collect()
+    }
+}
+
+impl Graph for MatMulOffloadGraph {
+    fn alloc(
+        &mut self,
+        t: Tensor<Float>,
+        is_param: bool,
+        name: String,
+    ) -> Result<TensorId, GraphError> {
+        self.graph.alloc(t, is_param, name)
+    }
+    fn alloc_usize(&mut self, t: Tensor<usize>, name: String) -> Result<TensorId, GraphError> {
+        self.graph.alloc_usize(t, name)
+    }
+    fn params(&self) -> &[TensorId] {
+        self.graph.params()
+    }
+    fn load<T: TensorOps<Float>>(
+        &mut self,
+        tensor_id: TensorId,
+        tensor: &T,
+    ) -> Result<(), GraphError> {
+        if self.graph.params().contains(&tensor_id) {
+            self.cublas.clear_weights();
+        }
+        self.graph.load(tensor_id, tensor)
+    }
+    fn load_usize<T: TensorOps<usize>>(
+        &mut self,
+        tensor_id: TensorId,
+        tensor: &T,
+    ) -> Result<(), GraphError> {
+        self.graph.load_usize(tensor_id, tensor)
+    }
+    fn load_grad<T: TensorOps<Float>>(
+        &mut self,
+        tensor_id: TensorId,
+        tensor: &T,
+    ) -> Result<(), GraphError> {
+        self.graph.load_grad(tensor_id, tensor)
+    }
+    fn zero_grad(&mut self) -> Result<(), GraphError> {
+        self.graph.zero_grad()
+    }
+    fn name_of(&self, id: TensorId) -> Result<&String, GraphError> {
+        self.graph.name_of(id)
+    }
+    fn fetch(&mut self, id: TensorId, grad: bool) -> Result<(), GraphError> {
+        self.graph.fetch(id, grad)
+    }
+    fn get(&self, id: TensorId) -> Result<&GeneralTensor, GraphError> {
+        self.graph.get(id)
+    }
+    fn get_grad(&self, id: TensorId) -> Result<&Tensor<Float>, GraphError> {
+        self.graph.get_grad(id)
+    }
+    fn backward_all(&mut self, id: TensorId, limit: Option<usize>) -> Result<Float, GraphError> {
+        let (cublas, params) = (self.cublas.clone(), self.param_blobs());
+        self.graph.backward_with(id, limit, |f, inps, out_grad| {
+            cublas.grad(f, inps, out_grad, &params)
+        })
+    }
+    fn forward(&mut self, training: bool) -> Result<(), GraphError> {
+        let (cublas, params) = (self.cublas.clone(), self.param_blobs());
+        self.graph.forward_with(training, |f, inps, training, out| {
+            cublas.run(f, inps, training, out, &params)
+        })
+    }
+    fn call(
+        &mut self,
+        f: Box<dyn Function>,
+        tensor_ids: &[TensorId],
+    ) -> Result<TensorId, GraphError> {
+        self.graph.call(f, tensor_ids)
+    }
+    fn optimize<O: Optimizer>(
+        &mut self,
+        optimizer: &O,
+        learning_rate: Float,
+    ) -> Result<(), GraphError> {
+        // (The parameters are updated in place)
+        self.cublas.clear_weights();
+        self.graph.optimize(optimizer, learning_rate)
+    }
+    fn optimizer_step(&self) -> usize {
+        self.graph.optimizer_step()
+    }
+    fn set_frozen(&mut self, id: TensorId, frozen: bool) -> Result<(), GraphError> {
+        self.graph.set_frozen(id, frozen)
+    }
+    fn is_frozen(&self, id: TensorId) -> bool {
+        self.graph.is_frozen(id)
+    }
+    fn get_optimizer_state(&self) -> Result<OptimizerState, GraphError> {
+        self.graph.get_optimizer_state()
+    }
+    fn set_optimizer_state(&mut self, state: &OptimizerState) -> Result<(), GraphError> {
+        self.graph.set_optimizer_state(state)
+    }
+    fn fuse(&mut self, keep: &[TensorId]) -> Result<usize, GraphError> {
+        self.graph.fuse(keep)
+    }
+    fn seed(&mut self, seed: u64) {
+        self.graph.seed(seed)
+    }
+    fn to_dot(&self) -> String {
+        self.graph.to_dot()
+    }
+    fn to_onnx(
+        &self,
+        inputs: &[TensorId],
+        outputs: &[(TensorId, &str)],
+    ) -> Result<Vec<u8>, GraphError> {
+        self.graph.to_onnx(inputs, outputs)
+    }
+    fn set_profiling(&mut self, enabled: bool) {
+        self.graph.set_profiling(enabled)
+    }
+    fn share_inputs(&mut self, other: &Self) -> Result<(), GraphError> {
+        self.graph.share_inputs(&other.graph)
+    }
+    fn release_inputs(&mut self) {
+        self.graph.release_inputs()
+    }
+    fn set_precision(&mut self, precision: Precision) -> Result<(), GraphError> {
+        self.graph.set_precision(precision)
+    }
+    fn set_loss_scale(&mut self, scale: Float) {
+        self.graph.set_loss_scale(scale)
+    }
+    fn profile(&self) -> Option<Profile> {
+        self.graph.profile()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matmul_matches_cpu() {
+        let mut rng = rand::thread_rng();
+        let cublas = Cublas::new(0).unwrap();
+        let shapes: [(&[usize], &[usize]); 4] = [
+            (&[5, 8], &[8, 6]),
+            (&[3, 5, 8], &[8, 6]),
+            (&[5, 8], &[3, 8, 6]),
+            (&[2, 3, 5, 8], &[3, 8, 6]),
+        ];
+        for (a_shape, b_shape) in shapes {
+            let a = Tensor::<f32>::rand_range(&mut rng, -1., 1., a_shape);
+            let b = Tensor::<f32>::rand_range(&mut rng, -1., 1., b_shape);
+            let expected = (&a ^ &b).unwrap();
+            let result = cublas.matmul(&a, &b).unwrap();
+            assert_eq!(expected.shape(), result.shape());
+            for (x, y) in expected.blob().iter().zip(result.blob().iter()) {
+                assert!((x - y).abs() < 1e-4);
+            }
+        }
+        // Transposed weights on the device, as in the backward pass
+        let a = Tensor::<f32>::rand_range(&mut rng, -1., 1., &[3, 5, 6]);
+        let w = Tensor::<f32>::rand_range(&mut rng, -1., 1., &[8, 6]);
+        let expected = (&a ^ &w.transpose().unwrap()).unwrap();
+        let w_dev = cublas.weight(&w).unwrap();
+        let result = cublas.gemm(&a, &w_dev, w.shape(), true).unwrap();
+        assert_eq!(expected.shape(), result.shape());
+        for (x, y) in expected.blob().iter().zip(result.blob().iter()) {
+            assert!((x - y).abs() < 1e-4);
+        }
+        assert!(Arc::ptr_eq(&w_dev, &cublas.weight(&w).unwrap()));
+    }
+}
@@ -0,0 +1,86 @@
+use super::{Computation, TensorId};
+use std::fmt::Write;
+
+// Describes the computation graph in Graphviz DOT format. Tensors are drawn as
+// ellipses labeled with their names and shapes (Parameters are filled), operations as
+// boxes. Tensors that are neither parameters nor part of a computation (E.g. leftovers
+// of operator fusion) are left out.
+pub fn to_dot<'a, I: Iterator<Item = (TensorId, &'a Computation)> + Clone>(
+    names: &[String],
+    shapes: &[Vec<usize>],
+    params: &[TensorId],
+    computations: I,
+) -> String {
+    let mut used = vec![false; shapes.len()];
+    for id in params.iter() {
+        used[*id] = true;
+    }
+    for (out, comp) in computations.clone() {
+        used[out] = true;
+        for inp in comp.inps.iter() {
+            used[*inp] = true;
+        }
+    }
+
+    let mut dot = String::from("digraph {\n");
+    for (id, shape) in shapes.iter().enumerate() {
+        if !used[id] {
+            continue;
+        }
+        let label = if names[id].is_empty() {
+            format!("{:?}", shape)
+        } else {
+            format!("{}\\n{:?}", names[id], shape)
+        };
+        let style = if params.contains(&id) {
+            ", style=filled, fillcolor=lightblue"
+        } else {
+            ""
+        };
+        writeln!(dot, "    t{} [label=\"{}\"{}];", id, label, style).unwrap();
+    }
+    for (out, comp) in computations {
+        writeln!(
+            dot,
+            "    op{} [label=\"{}\", shape=box];",
+            out,
+            comp.func.name()
+        )
+        .unwrap();
+        for inp in comp.inps.iter() {
+            writeln!(dot, "    t{} -> op{};", inp, out).unwrap();
+        }
+        writeln!(dot, "    op{} -> t{};", out, out).unwrap();
+    }
+    dot += "}\n";
+    dot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CpuGraph, Graph};
+    use crate::funcs::*;
+    use crate::tensor::*;
+
+    #[test]
+    fn test_to_dot() {
+        let mut g = CpuGraph::new();
+        let x = g
+            .alloc(Tensor::<Float>::zeros(&[2, 3]), false, "x".into())
+            .unwrap();
+        let w = g
+            .alloc(Tensor::<Float>::zeros(&[3, 4]), true, "w".into())
+            .unwrap();
+        let out = g.call(MatMul::new(), &[x, w]).unwrap();
+        let dot = g.to_dot();
+        assert!(dot.starts_with("digraph {"));
+        assert!(dot.contains(&format!("t{} [label=\"x\\n[2, 3]\"];", x)));
+        assert!(dot.contains(&format!(
+            "t{} [label=\"w\\n[3, 4]\", style=filled, fillcolor=lightblue];",
+            w
+        )));
+        assert!(dot.contains(&format!("op{} [label=\"MatMul\", shape=box];", out)));
+        assert!(dot.contains(&format!("t{} -> op{};", w, out)));
+        assert!(dot.contains(&format!("op{} -> t{};", out, out)));
+    }
+}
@@ -0,0 +1,165 @@
+use super::{Computation, TensorId};
+use crate::funcs::*;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+
+fn downcast<T: Function>(f: &dyn Function) -> Option<&T> {
+    (f as &dyn Any).downcast_ref::<T>()
+}
+
+// Replaces chains of computations with fused functions:
+//
+//  - MatMul -> Add (bias vector) => MatMulAdd
+//  - Coeff -> TrilMask -> Softmax => ScaledMaskedSoftmax
+//
+// (LayerNorm already applies its affine transform internally)
+//
+// An intermediate result is only eliminated if nothing else consumes it and it's not
+// listed in `keep`. Eliminated tensors stay allocated but are no longer computed.
+// Returns the number of computations removed.
+pub fn fuse_computations<S: Fn(TensorId) -> Vec<usize>>(
+    computations: &mut BTreeMap<TensorId, Computation>,
+    shape_of: S,
+    keep: &[TensorId],
+) -> usize {
+    let mut uses = HashMap::<TensorId, usize>::new();
+    for comp in computations.values() {
+        for inp in comp.inps.iter() {
+            *uses.entry(*inp).or_default() += 1;
+        }
+    }
+
+    let mut removed = 0;
+    let ids = computations.keys().cloned().collect::<Vec<_>>();
+    for id in ids {
+        let fusible = |computations: &BTreeMap<TensorId, Computation>, t: TensorId| {
+            !keep.contains(&t) && uses.get(&t) == Some(&1) && computations.contains_key(&t)
+        };
+        let comp = match computations.get(&id) {
+            Some(comp) => comp,
+            None => continue,
+        };
+
+        if downcast::<Add>(comp.func.as_ref()).is_some() {
+            let (x, bias) = (comp.inps[0], comp.inps[1]);
+            let out_degree = shape_of(id).last().cloned();
+            if fusible(computations, x)
+                && downcast::<MatMul>(computations[&x].func.as_ref()).is_some()
+                && shape_of(bias).len() == 1
+                && shape_of(bias).last().cloned() == out_degree
+            {
+                let matmul = computations.remove(&x).unwrap();
+                computations.insert(
+                    id,
+                    Computation {
+                        func: MatMulAdd::new(),
+                        inps: vec![matmul.inps[0], matmul.inps[1], bias],
+                    },
+                );
+                removed += 1;
+            }
+        } else if downcast::<Softmax>(comp.func.as_ref()).is_some() {
+            let masked = comp.inps[0];
+            if !fusible(computations, masked) {
+                continue;
+            }
+            let n = match downcast::<TrilMask>(computations[&masked].func.as_ref()) {
+                Some(mask) => mask.n,
+                None => continue,
+            };
+            let scaled = computations[&masked].inps[0];
+            if !fusible(computations, scaled) {
+                continue;
+            }
+            let coeff = match downcast::<Coeff>(computations[&scaled].func.as_ref()) {
+                Some(coeff) => coeff.coeff,
+                None => continue,
+            };
+            computations.remove(&masked);
+            let scale = computations.remove(&scaled).unwrap();
+            computations.insert(
+                id,
+                Computation {
+                    func: ScaledMaskedSoftmax::new(coeff, n),
+                    inps: scale.inps,
+                },
+            );
+            removed += 2;
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CpuGraph, Graph};
+    use crate::funcs::*;
+    use crate::tensor::*;
+    use rand::SeedableRng;
+
+    // x -> MatMul+Add -> Coeff -> TrilMask -> Softmax
+    fn build(fuse: bool) -> (Tensor<Float>, Vec<Tensor<Float>>) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(123);
+        let mut g = CpuGraph::new();
+        let x = g
+            .alloc(
+                Tensor::<Float>::rand(&mut rng, &[2, 4, 3]),
+                true,
+                "x".into(),
+            )
+            .unwrap();
+        let w = g
+            .alloc(Tensor::<Float>::rand(&mut rng, &[3, 4]), true, "w".into())
+            .unwrap();
+        let b = g
+            .alloc(Tensor::<Float>::rand(&mut rng, &[4]), true, "b".into())
+            .unwrap();
+        let xw = g.call(MatMul::new(), &[x, w]).unwrap();
+        let xwb = g.call(Add::new(), &[xw, b]).unwrap();
+        let scaled = g.call(Coeff::new(0.5), &[xwb]).unwrap();
+        let masked = g.call(TrilMask::new(4), &[scaled]).unwrap();
+        let out = g.call(Softmax::new(), &[masked]).unwrap();
+        if fuse {
+            assert_eq!(g.fuse(&[out]).unwrap(), 3);
+        }
+        g.forward(true).unwrap();
+        g.zero_grad().unwrap();
+        g.load_grad(out, &Tensor::<Float>::rand(&mut rng, &[2, 4, 4]))
+            .unwrap();
+        g.backward_all(out, None).unwrap();
+        let grads = [x, w, b]
+            .iter()
+            .map(|id| g.get_grad(*id).unwrap().clone())
+            .collect();
+        (g.get(out).unwrap().as_float().unwrap().clone(), grads)
+    }
+
+    fn assert_close(a: &Tensor<Float>, b: &Tensor<Float>) {
+        assert_eq!(a.shape(), b.shape());
+        for (x, y) in a.blob().iter().zip(b.blob().iter()) {
+            assert!((x - y).abs() < 1e-5, "{} != {}", x, y);
+        }
+    }
+
+    #[test]
+    fn test_fused_matches_unfused() {
+        let (out, grads) = build(false);
+        let (fused_out, fused_grads) = build(true);
+        assert_close(&out, &fused_out);
+        for (g, fg) in grads.iter().zip(fused_grads.iter()) {
+            assert_close(g, fg);
+        }
+    }
+
+    #[test]
+    fn test_kept_tensors_are_not_fused() {
+        let mut g = CpuGraph::new();
+        let x = g
+            .alloc(Tensor::<Float>::zeros(&[4, 4]), true, "x".into())
+            .unwrap();
+        let scaled = g.call(Coeff::new(0.5), &[x]).unwrap();
+        let masked = g.call(TrilMask::new(4), &[scaled]).unwrap();
+        g.call(Softmax::new(), &[masked]).unwrap();
+        assert_eq!(g.fuse(&[scaled]).unwrap(), 0);
+    }
+}
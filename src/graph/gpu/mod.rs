@@ -1,8 +1,9 @@
 pub mod program;
 use super::*;
 use crate::funcs::{GpuFunction, SharedBuffer};
+use crate::optimizer::{AdamW, GpuArg, GpuOptimizer};
 use program::{Brand, Buffer, Device, Program, ProgramError};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 pub enum GeneralBuffer {
     Float(Buffer<f32>),
@@ -20,6 +21,7 @@ impl GeneralBuffer {
         let mut buff = match t {
             GeneralTensor::Float(t) => GeneralBuffer::Float(prog.create_buffer::<f32>(t.size())?),
             GeneralTensor::Usize(t) => GeneralBuffer::Usize(prog.create_buffer::<usize>(t.size())?),
+            _ => return Err(GraphError::IncompatibleTypes),
         };
         buff.write_from(t)?;
         Ok(buff)
@@ -36,17 +38,17 @@ impl GeneralBuffer {
                 GeneralTensor::Float(t) => {
                     b.write_from(t.blob())?;
                 }
-                GeneralTensor::Usize(_) => {
+                _ => {
                     return Err(GraphError::IncompatibleTypes);
                 }
             },
             GeneralBuffer::Usize(b) => match t {
-                GeneralTensor::Float(_) => {
-                    return Err(GraphError::IncompatibleTypes);
-                }
                 GeneralTensor::Usize(t) => {
                     b.write_from(t.blob())?;
                 }
+                _ => {
+                    return Err(GraphError::IncompatibleTypes);
+                }
             },
         }
         Ok(())
@@ -59,19 +61,19 @@ impl GeneralBuffer {
                     b.read_into(&mut blob)?;
                     *t = Tensor::raw(t.shape(), blob)?;
                 }
-                GeneralTensor::Usize(_) => {
+                _ => {
                     return Err(GraphError::IncompatibleTypes);
                 }
             },
             GeneralBuffer::Usize(b) => match t {
-                GeneralTensor::Float(_) => {
-                    return Err(GraphError::IncompatibleTypes);
-                }
                 GeneralTensor::Usize(t) => {
                     let mut blob = vec![0; t.size()];
                     b.read_into(&mut blob)?;
                     *t = Tensor::raw(t.shape(), blob)?;
                 }
+                _ => {
+                    return Err(GraphError::IncompatibleTypes);
+                }
             },
         }
         Ok(())
@@ -108,8 +110,10 @@ pub struct GpuGraph {
     tensors: Vec<GpuTensor>,
     grads: Vec<GpuTensor>,
     params: Vec<TensorId>,
+    frozen: HashSet<TensorId>,
     names: Vec<String>,
     computations: BTreeMap<TensorId, GpuComputation>,
+    optimizer: Option<GpuOptimizer>,
     optimizer_state: HashMap<String, GpuTensor>,
     optimizer_step: usize,
 }
@@ -124,6 +128,8 @@ impl GpuGraph {
             computations: Default::default(),
             names: Default::default(),
             params: Default::default(),
+            frozen: Default::default(),
+            optimizer: None,
             optimizer_state: Default::default(),
             optimizer_step: 0,
             program: None,
@@ -132,10 +138,26 @@ impl GpuGraph {
     pub fn get(&self, id: TensorId) -> Result<&GpuTensor, GraphError> {
         self.tensors.get(id).ok_or(GraphError::TensorNotFound(id))
     }
+    fn param_shapes(&self) -> Result<HashMap<String, Vec<usize>>, GraphError> {
+        self.params
+            .iter()
+            .map(|p| {
+                Ok((
+                    self.name_of(*p)?.clone(),
+                    self.get(*p)?.mirror.shape().to_vec(),
+                ))
+            })
+            .collect()
+    }
     pub fn compile(&mut self) -> Result<(), GraphError> {
         if self.program.is_some() {
             return Ok(());
         }
+        // AdamW is assumed until `optimize` is called with another optimizer
+        let optimizer = match &self.optimizer {
+            Some(optimizer) => optimizer.clone(),
+            None => AdamW::new().gpu_impl(&self.param_shapes()?),
+        };
         let mut src = String::new();
         src += "
         __kernel void zeroize(__global float *buff, uint n) {
@@ -153,66 +175,45 @@ impl GpuGraph {
                 src = src + &func.source_code;
             }
         }
-        src += "
-        __kernel void optimizer(__global float *param, __global float *grad, __global float *m, __global float *v,  float learning_rate, ulong step, ulong n) {
-            uint id = get_global_id(0);
-            param += id;
-            grad += id;
-            m += id;
-            v += id;
-            float beta1 = 0.9;
-            float beta2 = 0.999;
-            float weight_decay = 0.01;
-            if(id < n) {
-                *param = *param - *param * learning_rate * weight_decay;
-                *m = beta1 * (*m) + (1 - beta1) * (*grad);
-                *v = beta2 * (*v) + (1 - beta2) * (*grad) * (*grad);
-                float m_hat = *m / (1.0 - pow(beta1, step + 1));
-                float v_hat = *v / (1.0 - pow(beta2, step + 1));
-                float v_hat_sqrt_inv = learning_rate / (sqrt(v_hat) + 1e-8);
-                *param = *param - m_hat * v_hat_sqrt_inv;
-            }
-        }
-        ";
+        src += &optimizer.source_code;
         let prog = Program::from_opencl(&self.device, &src)?;
 
         let mut comp_buffers = HashMap::new();
 
         for (id, comp) in self.computations.iter() {
-            comp_buffers.entry(*id).or_insert_with(|| {
-                comp.gpu_function
-                    .shared_buffers
-                    .iter()
-                    .map(|sb| match sb {
-                        SharedBuffer::Float(sz) => prog
-                            .create_buffer::<f32>(*sz)
-                            .map(|b| GeneralBuffer::Float(b)),
-                        SharedBuffer::Usize(sz) => prog
-                            .create_buffer::<usize>(*sz)
-                            .map(|b| GeneralBuffer::Usize(b)),
-                    })
-                    .collect::<Result<Vec<_>, ProgramError>>()
-                    .unwrap()
-            });
+            let buffers = comp
+                .gpu_function
+                .shared_buffers
+                .iter()
+                .map(|sb| match sb {
+                    SharedBuffer::Float(sz) => prog
+                        .create_buffer::<f32>(*sz)
+                        .map(|b| GeneralBuffer::Float(b)),
+                    SharedBuffer::Usize(sz) => prog
+                        .create_buffer::<usize>(*sz)
+                        .map(|b| GeneralBuffer::Usize(b)),
+                })
+                .collect::<Result<Vec<_>, ProgramError>>()?;
+            comp_buffers.insert(*id, buffers);
         }
 
+        let param_shapes = self.param_shapes()?;
         let mut optimizer_state = HashMap::new();
-        for p in self.params.to_vec() {
-            let t = self.tensors.get(p).unwrap().mirror.shape().to_vec();
-            let m_val = GeneralTensor::Float(Tensor::zeros(&t));
-            let v_val = GeneralTensor::Float(Tensor::zeros(&t));
-            let m = GpuTensor {
-                buffer: Some(GeneralBuffer::new(&prog, &m_val)?),
-                mirror: m_val,
-                is_sync: true,
-            };
-            let v = GpuTensor {
-                buffer: Some(GeneralBuffer::new(&prog, &v_val)?),
-                mirror: v_val,
-                is_sync: true,
-            };
-            optimizer_state.insert(format!("{}_m", self.name_of(p)?), m);
-            optimizer_state.insert(format!("{}_v", self.name_of(p)?), v);
+        for (name, buffers) in optimizer.extra_buffers.iter() {
+            for (key, size) in buffers.iter() {
+                // Buffers as large as their parameter are given the same shape
+                let shape = match param_shapes.get(name) {
+                    Some(shape) if shape.iter().product::<usize>() == *size => shape.clone(),
+                    _ => vec![*size],
+                };
+                let val = GeneralTensor::Float(Tensor::zeros(&shape));
+                let t = GpuTensor {
+                    buffer: Some(GeneralBuffer::new(&prog, &val)?),
+                    mirror: val,
+                    is_sync: true,
+                };
+                optimizer_state.insert(key.clone(), t);
+            }
         }
         for (v, g) in self.tensors.iter_mut().zip(self.grads.iter_mut()) {
             v.buffer = Some(GeneralBuffer::new(&prog, &v.mirror)?);
@@ -224,6 +225,7 @@ impl GpuGraph {
             program: prog,
             comp_buffers,
         });
+        self.optimizer = Some(optimizer);
         self.optimizer_state = optimizer_state;
         Ok(())
     }
@@ -231,7 +233,10 @@ impl GpuGraph {
 
 impl GpuGraph {
     pub fn fetch_grad(&mut self, tensor_id: TensorId) -> Result<&Tensor<f32>, GraphError> {
-        let gt = self.grads.get_mut(tensor_id).unwrap();
+        let gt = self
+            .grads
+            .get_mut(tensor_id)
+            .ok_or(GraphError::TensorNotFound(tensor_id))?;
         if !gt.is_sync {
             gt.buffer
                 .as_mut()
@@ -265,7 +270,10 @@ impl Graph for GpuGraph {
         tensor: &T,
     ) -> Result<(), GraphError> {
         self.compile()?;
-        let gt = self.tensors.get_mut(tensor_id).unwrap();
+        let gt = self
+            .tensors
+            .get_mut(tensor_id)
+            .ok_or(GraphError::TensorNotFound(tensor_id))?;
         gt.mirror = GeneralTensor::Usize(tensor.view().into());
         gt.buffer
             .as_mut()
@@ -303,7 +311,10 @@ impl Graph for GpuGraph {
         tensor: &T,
     ) -> Result<(), GraphError> {
         self.compile()?;
-        let gt = self.tensors.get_mut(tensor_id).unwrap();
+        let gt = self
+            .tensors
+            .get_mut(tensor_id)
+            .ok_or(GraphError::TensorNotFound(tensor_id))?;
         gt.mirror = GeneralTensor::Float(tensor.view().into());
         gt.buffer
             .as_mut()
@@ -318,7 +329,10 @@ impl Graph for GpuGraph {
         tensor: &T,
     ) -> Result<(), GraphError> {
         self.compile()?;
-        let gt = self.grads.get_mut(tensor_id).unwrap();
+        let gt = self
+            .grads
+            .get_mut(tensor_id)
+            .ok_or(GraphError::TensorNotFound(tensor_id))?;
         gt.mirror = GeneralTensor::Float(tensor.view().into());
         gt.buffer
             .as_mut()
@@ -351,14 +365,14 @@ impl Graph for GpuGraph {
         self.names.get(id).ok_or(GraphError::TensorNotFound(id))
     }
     fn get(&self, id: TensorId) -> Result<&GeneralTensor, GraphError> {
-        let gt = self.tensors.get(id).unwrap();
+        let gt = self.tensors.get(id).ok_or(GraphError::TensorNotFound(id))?;
         if !gt.is_sync {
             return Err(GraphError::NotReady);
         }
         Ok(&gt.mirror)
     }
     fn get_grad(&self, id: TensorId) -> Result<&Tensor<f32>, GraphError> {
-        let gt = self.grads.get(id).unwrap();
+        let gt = self.grads.get(id).ok_or(GraphError::TensorNotFound(id))?;
         if !gt.is_sync {
             return Err(GraphError::NotReady);
         }
@@ -372,9 +386,24 @@ impl Graph for GpuGraph {
         let mean_coeff = 1. / output.size() as f32;
         self.load_grad(id, &Tensor::constant(output.shape(), mean_coeff))?;
 
+        // Frozen subgraphs are skipped, see `CpuGraph::needs_grad`
+        let mut needs = HashSet::new();
+        for (i, t) in self.tensors.iter().enumerate() {
+            let needed = match self.computations.get(&i) {
+                Some(c) => c.computation.inps.iter().any(|inp| needs.contains(inp)),
+                None => t.mirror.as_float().is_ok() && !self.frozen.contains(&i),
+            };
+            if needed {
+                needs.insert(i);
+            }
+        }
+
         let program = self.program.as_mut().ok_or(GraphError::NotReady)?;
 
         for (id, c) in self.computations.clone().iter().rev() {
+            if !needs.contains(id) {
+                continue;
+            }
             let inps = c
                 .computation
                 .inps
@@ -498,9 +527,12 @@ impl Graph for GpuGraph {
             .collect::<Result<Vec<_>, GraphError>>()?
             .into_iter()
             .unzip();
+        // (The id of the output, allocated below)
+        let gpu_function = f
+            .gpu_impl(self.tensors.len(), &shapes)
+            .ok_or(GraphError::NoGpuImplementation(f.name()))?;
         let out = f.run(&tensors, false)?;
         let child = self.alloc(out, false, "".into())?;
-        let gpu_function = f.gpu_impl(child, &shapes);
 
         self.computations.insert(
             child,
@@ -517,64 +549,69 @@ impl Graph for GpuGraph {
     }
     fn optimize<O: Optimizer>(
         &mut self,
-        _optimizer: &O, // TODO: Generate OpenCL code with this
+        optimizer: &O,
         learning_rate: f32,
     ) -> Result<(), GraphError> {
+        let gpu_optimizer = optimizer.gpu_impl(&self.param_shapes()?);
+        if self.optimizer.as_ref() != Some(&gpu_optimizer) {
+            // The optimizer kernel is part of the program, which has to be rebuilt
+            let state = if self.program.is_some() {
+                for p in self.params.to_vec() {
+                    self.fetch(p, false)?;
+                }
+                Some(self.get_optimizer_state()?)
+            } else {
+                None
+            };
+            self.optimizer = Some(gpu_optimizer);
+            self.program = None;
+            self.compile()?;
+            if let Some(state) = state {
+                self.set_optimizer_state(&state)?;
+            }
+        }
         self.compile()?;
 
         for p in self.params.iter() {
             self.tensors.get_mut(*p).unwrap().is_sync = false;
         }
 
-        let program = self.program.as_mut().ok_or(GraphError::NotReady)?;
-
-        let tens: Vec<(
-            &GeneralBuffer,
-            &GeneralBuffer,
-            &GeneralBuffer,
-            &GeneralBuffer,
-        )> = self
-            .tensors
-            .iter_mut()
-            .enumerate()
-            .filter(|(id, _)| self.params.contains(id))
-            .map(|(id, params)| {
-                let name = self.names.get(id).ok_or(GraphError::TensorNotFound(id))?;
-                let grad = self.grads.get(id).ok_or(GraphError::TensorNotFound(id))?;
-                let m = self
-                    .optimizer_state
-                    .get(&format!("{}_m", name))
-                    .unwrap()
-                    .buffer
-                    .as_ref()
-                    .unwrap();
-                let v = self
-                    .optimizer_state
-                    .get(&format!("{}_v", name))
-                    .unwrap()
-                    .buffer
-                    .as_ref()
-                    .unwrap();
-                Ok((
-                    params.buffer.as_ref().ok_or(GraphError::NotReady)?,
-                    grad.buffer.as_ref().ok_or(GraphError::NotReady)?,
-                    m,
-                    v,
-                ))
-            })
-            .collect::<Result<Vec<_>, GraphError>>()?;
-        for (param, grad, m, v) in tens {
+        let program = self.program.as_ref().ok_or(GraphError::NotReady)?;
+        let optimizer = self.optimizer.as_ref().ok_or(GraphError::NotReady)?;
+        for p in self.params.iter().filter(|p| !self.frozen.contains(p)) {
+            let name = self.names.get(*p).ok_or(GraphError::TensorNotFound(*p))?;
+            let param = self.get(*p)?.buffer.as_ref().ok_or(GraphError::NotReady)?;
+            let grad = self
+                .grads
+                .get(*p)
+                .ok_or(GraphError::TensorNotFound(*p))?
+                .buffer
+                .as_ref()
+                .ok_or(GraphError::NotReady)?;
             let works = param.length();
             let local_work_size = 32;
             let global_work_size =
                 works + ((local_work_size - (works % local_work_size)) % local_work_size);
-            let mut kern = program
-                .program
-                .create_kernel("optimizer", global_work_size, 32);
+            let mut kern =
+                program
+                    .program
+                    .create_kernel(&optimizer.kernel_name, global_work_size, 32);
             kern = kern.arg(param);
             kern = kern.arg(grad);
-            kern = kern.arg(m);
-            kern = kern.arg(v);
+            for (key, _) in optimizer.extra_buffers.get(name).into_iter().flatten() {
+                let buffer = self
+                    .optimizer_state
+                    .get(key)
+                    .and_then(|t| t.buffer.as_ref())
+                    .ok_or(GraphError::NotReady)?;
+                kern = kern.arg(buffer);
+            }
+            for arg in optimizer.extra_args.get(name).into_iter().flatten() {
+                kern = match arg {
+                    GpuArg::Ulong(v) => kern.arg(*v),
+                    GpuArg::Float(v) => kern.arg(*v),
+                };
+            }
             kern = kern.arg(learning_rate);
             kern = kern.arg(self.optimizer_step);
             kern = kern.arg(works);
@@ -585,7 +622,10 @@ impl Graph for GpuGraph {
     }
     fn fetch(&mut self, tensor_id: TensorId, grad: bool) -> Result<(), GraphError> {
         self.compile()?;
-        let gt = self.tensors.get_mut(tensor_id).unwrap();
+        let gt = self
+            .tensors
+            .get_mut(tensor_id)
+            .ok_or(GraphError::TensorNotFound(tensor_id))?;
         if !gt.is_sync {
             gt.buffer
                 .as_mut()
@@ -594,7 +634,10 @@ impl Graph for GpuGraph {
             gt.is_sync = true;
         }
         if grad {
-            let gg = self.grads.get_mut(tensor_id).unwrap();
+            let gg = self
+                .grads
+                .get_mut(tensor_id)
+                .ok_or(GraphError::TensorNotFound(tensor_id))?;
             if !gg.is_sync {
                 gg.buffer
                     .as_mut()
@@ -611,20 +654,29 @@ impl Graph for GpuGraph {
     fn optimizer_step(&self) -> usize {
         self.optimizer_step
     }
+    fn set_frozen(&mut self, id: TensorId, frozen: bool) -> Result<(), GraphError> {
+        if !self.params.contains(&id) {
+            return Err(GraphError::TensorNotFound(id));
+        }
+        if frozen {
+            self.frozen.insert(id);
+        } else {
+            self.frozen.remove(&id);
+        }
+        Ok(())
+    }
+    fn is_frozen(&self, id: TensorId) -> bool {
+        self.frozen.contains(&id)
+    }
     fn get_optimizer_state(&self) -> Result<OptimizerState, GraphError> {
         let mut result = HashMap::new();
-        for p in self.params.iter() {
-            let name = self.name_of(*p)?;
-            let key_m = format!("{}_m", name);
-            let key_v = format!("{}_v", name);
-            let m = self.optimizer_state.get(&key_m).unwrap();
-            let v = self.optimizer_state.get(&key_v).unwrap();
-            let mut m_val = GeneralTensor::Float(Tensor::zeros(m.mirror.shape()));
-            let mut v_val = GeneralTensor::Float(Tensor::zeros(v.mirror.shape()));
-            m.buffer.as_ref().unwrap().read_into(&mut m_val)?;
-            v.buffer.as_ref().unwrap().read_into(&mut v_val)?;
-            result.insert(key_m, m_val.as_float()?.clone());
-            result.insert(key_v, v_val.as_float()?.clone());
+        for (key, t) in self.optimizer_state.iter() {
+            let mut val = GeneralTensor::Float(Tensor::zeros(t.mirror.shape()));
+            t.buffer
+                .as_ref()
+                .ok_or(GraphError::NotReady)?
+                .read_into(&mut val)?;
+            result.insert(key.clone(), val.as_float()?.clone());
         }
         Ok(OptimizerState {
             step: self.optimizer_step,
@@ -632,37 +684,113 @@ impl Graph for GpuGraph {
         })
     }
     fn set_optimizer_state(&mut self, state: &OptimizerState) -> Result<(), GraphError> {
+        self.compile()?;
         self.optimizer_step = state.step;
-        for p in self.params.iter() {
-            let name = self.name_of(*p)?;
-            let key_m = format!("{}_m", name);
-            let key_v = format!("{}_v", name);
-
-            if let Some(m_content) = state.state.get(&key_m).cloned() {
-                let m = self
-                    .optimizer_state
-                    .get_mut(&key_m)
-                    .unwrap()
-                    .buffer
-                    .as_mut()
-                    .unwrap();
-                let m_val = GeneralTensor::Float(m_content);
-                m.write_from(&m_val)?;
-                drop(m);
-            }
-
-            if let Some(v_content) = state.state.get(&key_v).cloned() {
-                let v = self
-                    .optimizer_state
-                    .get_mut(&key_v)
-                    .unwrap()
-                    .buffer
+        for (key, t) in self.optimizer_state.iter_mut() {
+            if let Some(content) = state.state.get(key).cloned() {
+                t.buffer
                     .as_mut()
-                    .unwrap();
-                let v_val = GeneralTensor::Float(v_content);
-                v.write_from(&v_val)?;
+                    .ok_or(GraphError::NotReady)?
+                    .write_from(&GeneralTensor::Float(content))?;
             }
         }
         Ok(())
     }
+    fn fuse(&mut self, keep: &[TensorId]) -> Result<usize, GraphError> {
+        let mut computations = self
+            .computations
+            .iter()
+            .map(|(id, c)| (*id, c.computation.clone()))
+            .collect::<BTreeMap<_, _>>();
+        let tensors = &self.tensors;
+        let removed = fusion::fuse_computations(
+            &mut computations,
+            |id| tensors[id].mirror.shape().to_vec(),
+            keep,
+        );
+        if removed > 0 {
+            self.computations = computations
+                .into_iter()
+                .map(|(id, computation)| {
+                    let shapes = computation
+                        .inps
+                        .iter()
+                        .map(|inp| self.tensors[*inp].mirror.shape().to_vec())
+                        .collect::<Vec<_>>();
+                    let gpu_function = computation
+                        .func
+                        .gpu_impl(id, &shapes)
+                        .ok_or(GraphError::NoGpuImplementation(computation.func.name()))?;
+                    Ok((
+                        id,
+                        GpuComputation {
+                            computation,
+                            gpu_function,
+                        },
+                    ))
+                })
+                .collect::<Result<_, GraphError>>()?;
+            self.program = None; // Needs recompile
+        }
+        Ok(removed)
+    }
+    fn seed(&mut self, _seed: u64) {
+        // The dropout kernels generate their masks from per-element generators, which
+        // start from the same state on every run
+    }
+    fn to_dot(&self) -> String {
+        let shapes = self
+            .tensors
+            .iter()
+            .map(|t| t.mirror.shape().to_vec())
+            .collect::<Vec<_>>();
+        dot::to_dot(
+            &self.names,
+            &shapes,
+            &self.params,
+            self.computations
+                .iter()
+                .map(|(id, c)| (*id, &c.computation)),
+        )
+    }
+    fn to_onnx(
+        &self,
+        inputs: &[TensorId],
+        outputs: &[(TensorId, &str)],
+    ) -> Result<Vec<u8>, GraphError> {
+        let tensors = self.tensors.iter().map(|t| &t.mirror).collect::<Vec<_>>();
+        onnx::to_onnx(
+            &self.names,
+            &tensors,
+            self.computations
+                .iter()
+                .map(|(id, c)| (*id, &c.computation)),
+            inputs,
+            outputs,
+        )
+    }
+    // The kernels run asynchronously on the device, so their time can not be attributed to
+    // the operations from the host. Profiling is not supported by this backend.
+    fn set_profiling(&mut self, _enabled: bool) {}
+    // The tensors live on the device, GPU graphs are not shared between workers
+    fn share_inputs(&mut self, _other: &Self) -> Result<(), GraphError> {
+        Err(GraphError::InvalidConfig(
+            "GPU graphs can not share their tensors".into(),
+        ))
+    }
+    fn release_inputs(&mut self) {}
+    fn set_precision(&mut self, precision: Precision) -> Result<(), GraphError> {
+        match precision {
+            Precision::F32 => Ok(()),
+            precision => Err(GraphError::InvalidConfig(format!(
+                "GPU graphs do not support the {:?} precision",
+                precision
+            ))),
+        }
+    }
+    // Only F32 is supported, whose gradients are not scaled
+    fn set_loss_scale(&mut self, _scale: Float) {}
+    fn profile(&self) -> Option<Profile> {
+        None
+    }
 }
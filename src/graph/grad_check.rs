@@ -0,0 +1,319 @@
+use super::{Graph, GraphError, TensorId};
+use crate::tensor::*;
+use rand::Rng;
+
+// Numerically verifies the backward pass of the subgraph producing `out`, with respect
+// to the leaf tensors `wrt` (Tensors that are not outputs of computations, E.g.
+// parameters or inputs).
+//
+// The output is reduced to a scalar through a random projection, so that every element
+// of the output contributes. Every element of `wrt` is then nudged by +/- `epsilon` and
+// the central difference is compared against the gradient computed by the graph.
+// Returns the largest absolute difference, or an error if any difference is larger
+// than `tolerance` (Relative to the gradient, when greater than one).
+pub fn grad_check<G: Graph, R: Rng>(
+    g: &mut G,
+    rng: &mut R,
+    out: TensorId,
+    wrt: &[TensorId],
+    epsilon: Float,
+    tolerance: Float,
+) -> Result<Float, GraphError> {
+    g.forward(false)?;
+    g.fetch(out, false)?;
+    let out_shape = g.get(out)?.as_float()?.shape().to_vec();
+    let projection = Tensor::<Float>::rand_range(rng, -1., 1., &out_shape);
+
+    // backward_all() adds the gradient of the mean of the output on top
+    let mean_coeff = 1. / projection.size() as Float;
+    let weights = projection.map_values(|f| f + mean_coeff);
+    let loss = |g: &mut G| -> Result<f64, GraphError> {
+        g.forward(false)?;
+        g.fetch(out, false)?;
+        Ok(g.get(out)?
+            .as_float()?
+            .blob()
+            .iter()
+            .zip(weights.blob().iter())
+            .map(|(o, w)| *o as f64 * *w as f64)
+            .sum())
+    };
+
+    g.zero_grad()?;
+    g.load_grad(out, &projection)?;
+    g.backward_all(out, None)?;
+
+    let mut max_diff: Float = 0.;
+    for id in wrt.iter() {
+        g.fetch(*id, true)?;
+        let analytic = g.get_grad(*id)?.clone();
+        g.fetch(*id, false)?;
+        let original = g.get(*id)?.as_float()?.clone();
+        for (i, analytic) in analytic.blob().iter().enumerate() {
+            let mut nudged = original.clone();
+            nudged.blob_mut()[i] = original.blob()[i] + epsilon;
+            g.load(*id, &nudged)?;
+            let plus = loss(g)?;
+            nudged.blob_mut()[i] = original.blob()[i] - epsilon;
+            g.load(*id, &nudged)?;
+            let minus = loss(g)?;
+            let numeric = ((plus - minus) / (2. * epsilon as f64)) as Float;
+
+            let diff = (analytic - numeric).abs();
+            if diff > tolerance * Float::max(1., analytic.abs()) {
+                g.load(*id, &original)?;
+                return Err(GraphError::GradientMismatch {
+                    id: *id,
+                    index: i,
+                    analytic: *analytic,
+                    numeric,
+                });
+            }
+            max_diff = max_diff.max(diff);
+        }
+        g.load(*id, &original)?;
+    }
+    g.forward(false)?;
+    Ok(max_diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::CpuGraph;
+    use super::*;
+    use crate::funcs::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn check<F: Fn(&mut CpuGraph, &mut StdRng) -> (TensorId, Vec<TensorId>)>(
+        build: F,
+    ) -> Result<Float, GraphError> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut g = CpuGraph::new();
+        let (out, wrt) = build(&mut g, &mut rng);
+        grad_check(&mut g, &mut rng, out, &wrt, 1e-2, 1e-2)
+    }
+
+    fn rand(g: &mut CpuGraph, rng: &mut StdRng, shape: &[usize]) -> TensorId {
+        g.alloc(
+            Tensor::<Float>::rand_range(rng, -1., 1., shape),
+            true,
+            "".into(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_linear() {
+        check(|g, rng| {
+            let x = rand(g, rng, &[2, 3, 4]);
+            let w = rand(g, rng, &[4, 5]);
+            let b = rand(g, rng, &[5]);
+            let xw = g.call(MatMul::new(), &[x, w]).unwrap();
+            (g.call(Add::new(), &[xw, b]).unwrap(), vec![x, w, b])
+        })
+        .unwrap();
+        check(|g, rng| {
+            let x = rand(g, rng, &[2, 3, 4]);
+            let w = rand(g, rng, &[4, 5]);
+            let b = rand(g, rng, &[5]);
+            (g.call(MatMulAdd::new(), &[x, w, b]).unwrap(), vec![x, w, b])
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_attention_scores() {
+        check(|g, rng| {
+            let x = rand(g, rng, &[2, 4, 4]);
+            let scaled = g.call(Coeff::new(0.5), &[x]).unwrap();
+            let masked = g.call(TrilMask::new(4), &[scaled]).unwrap();
+            (g.call(Softmax::new(), &[masked]).unwrap(), vec![x])
+        })
+        .unwrap();
+        check(|g, rng| {
+            let x = rand(g, rng, &[2, 4, 4]);
+            let mask = Tensor::raw(&[2, 4], vec![1., 0., 1., 1., 0., 1., 1., 0.]).unwrap();
+            let mask = g.alloc(mask, false, "".into()).unwrap();
+            let padded = g.call(PadMask::new(), &[x, mask]).unwrap();
+            let masked = g.call(TrilMask::new(4), &[padded]).unwrap();
+            (g.call(Softmax::new(), &[masked]).unwrap(), vec![x])
+        })
+        .unwrap();
+        check(|g, rng| {
+            let x = rand(g, rng, &[2, 4, 4]);
+            let prefix = Tensor::raw(&[2, 4], vec![1., 1., 0., 0., 1., 1., 1., 0.]).unwrap();
+            let prefix = g.alloc(prefix, false, "".into()).unwrap();
+            let masked = g.call(PrefixMask::new(), &[x, prefix]).unwrap();
+            (g.call(Softmax::new(), &[masked]).unwrap(), vec![x])
+        })
+        .unwrap();
+        check(|g, rng| {
+            let x = rand(g, rng, &[2, 4, 4]);
+            (
+                g.call(ScaledMaskedSoftmax::new(0.5, 4), &[x]).unwrap(),
+                vec![x],
+            )
+        })
+        .unwrap();
+        // The last 3 rows of a context of 5 positions (See `Prepend`)
+        check(|g, rng| {
+            let x = rand(g, rng, &[2, 3, 5]);
+            let mask = Tensor::raw(&[2, 5], vec![1., 1., 0., 1., 1., 1., 1., 1., 1., 1.]).unwrap();
+            let mask = g.alloc(mask, false, "".into()).unwrap();
+            let padded = g.call(PadMask::new(), &[x, mask]).unwrap();
+            let biased = g.call(Alibi::new(0.5), &[padded]).unwrap();
+            let masked = g.call(TrilMask::new(5), &[biased]).unwrap();
+            (g.call(Softmax::new(), &[masked]).unwrap(), vec![x])
+        })
+        .unwrap();
+        check(|g, rng| {
+            let x = rand(g, rng, &[2, 3, 5]);
+            (
+                g.call(ScaledMaskedSoftmax::new(0.5, 5), &[x]).unwrap(),
+                vec![x],
+            )
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_layer_norm() {
+        check(|g, rng| {
+            let x = rand(g, rng, &[3, 6]);
+            let coeff = rand(g, rng, &[6]);
+            let bias = rand(g, rng, &[6]);
+            (
+                g.call(LayerNorm::new(), &[x, coeff, bias]).unwrap(),
+                vec![x, coeff, bias],
+            )
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_elementwise() {
+        for f in [Gelu::new, Silu::new] {
+            check(|g, rng| {
+                let x = rand(g, rng, &[3, 4]);
+                (g.call(f(), &[x]).unwrap(), vec![x])
+            })
+            .unwrap();
+        }
+        check(|g, rng| {
+            let a = rand(g, rng, &[3, 4]);
+            let b = rand(g, rng, &[3, 4]);
+            (g.call(Mul::new(), &[a, b]).unwrap(), vec![a, b])
+        })
+        .unwrap();
+        // Broadcast operands (A `[B, T, C] * [C]` scaling, a `[B, 1, T]` mask)
+        for (a, b) in [([2, 3, 4], vec![4]), ([2, 1, 4], vec![2, 3, 1])] {
+            for f in [Add::new, Mul::new] {
+                check(|g, rng| {
+                    let a = rand(g, rng, &a);
+                    let b = rand(g, rng, &b);
+                    (g.call(f(), &[a, b]).unwrap(), vec![a, b])
+                })
+                .unwrap();
+            }
+        }
+        // Fewer positions than rows in the table
+        check(|g, rng| {
+            let x = rand(g, rng, &[2, 3, 4]);
+            let table = rand(g, rng, &[5, 4]);
+            (
+                g.call(Positional::new(), &[x, table]).unwrap(),
+                vec![x, table],
+            )
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_rope_and_cat() {
+        check(|g, rng| {
+            let a = rand(g, rng, &[2, 3, 4]);
+            let b = rand(g, rng, &[2, 3, 4]);
+            let rotated = g.call(Rope::new(), &[a]).unwrap();
+            (g.call(Cat::new(), &[rotated, b]).unwrap(), vec![a, b])
+        })
+        .unwrap();
+        // Rows after 2 cached positions
+        check(|g, rng| {
+            let x = rand(g, rng, &[2, 3, 4]);
+            let past = rand(g, rng, &[2, 4]);
+            let mask = g
+                .alloc(Tensor::constant(&[1, 5], 1.), false, "".into())
+                .unwrap();
+            let rotated = g.call(Rope::new(), &[x, mask]).unwrap();
+            (
+                g.call(Prepend::new(), &[rotated, past]).unwrap(),
+                vec![x, past],
+            )
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_embedding_and_cross_entropy() {
+        check(|g, rng| {
+            let tokens = g
+                .alloc_usize(
+                    Tensor::raw(&[2, 3], vec![0, 2, 2, 1, 3, 0]).unwrap(),
+                    "".into(),
+                )
+                .unwrap();
+            let targets = g
+                .alloc_usize(
+                    Tensor::raw(&[2, 3], vec![1, 0, 3, 3, 2, 1]).unwrap(),
+                    "".into(),
+                )
+                .unwrap();
+            let emb = rand(g, rng, &[4, 4]);
+            let embedded = g.call(Embedding::new(), &[tokens, emb]).unwrap();
+            (
+                g.call(CrossEntropy::new(), &[embedded, targets]).unwrap(),
+                vec![emb],
+            )
+        })
+        .unwrap();
+        check(|g, rng| {
+            let x = rand(g, rng, &[2, 3, 4]);
+            let targets = g.alloc_usize(Tensor::zeros(&[2, 3]), "".into()).unwrap();
+            (g.call(ZLoss::new(), &[x, targets]).unwrap(), vec![x])
+        })
+        .unwrap();
+    }
+
+    // A function with a deliberately wrong gradient
+    #[derive(Debug, Clone)]
+    struct WrongSquare;
+    impl Function for WrongSquare {
+        fn run(
+            &mut self,
+            inps: &[&GeneralTensor],
+            _training: bool,
+        ) -> Result<Tensor<Float>, TensorError> {
+            Ok(inps[0].as_float()?.map_values(|f| f * f))
+        }
+        fn grad(
+            &self,
+            inps: &[&GeneralTensor],
+            out_grad: &Tensor<Float>,
+        ) -> Result<Vec<Tensor<Float>>, TensorError> {
+            Ok(vec![(out_grad * inps[0].as_float()?)?])
+        }
+        fn clone_box(&self) -> Box<dyn Function> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn test_detects_wrong_gradient() {
+        let res = check(|g, rng| {
+            let x = rand(g, rng, &[3, 4]);
+            (g.call(Box::new(WrongSquare), &[x]).unwrap(), vec![x])
+        });
+        assert!(matches!(res, Err(GraphError::GradientMismatch { .. })));
+    }
+}
@@ -1,10 +1,27 @@
 #[cfg(feature = "gpu")]
 pub mod gpu;
+#[cfg(feature = "wgpu")]
+pub mod wgpu;
+
+#[cfg(feature = "cuda")]
+pub mod cuda;
+
+mod dot;
+mod fusion;
+mod grad_check;
+mod onnx;
+mod profile;
+
+pub use grad_check::*;
+pub use profile::*;
 
 use crate::funcs::Function;
 use crate::optimizer::{Optimizer, OptimizerState};
 use crate::tensor::*;
-use std::collections::{BTreeMap, HashMap};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use thiserror::Error;
 
 pub type TensorId = usize;
@@ -12,13 +29,13 @@ pub type TensorId = usize;
 pub trait Graph {
     fn alloc(
         &mut self,
-        t: Tensor<f32>,
+        t: Tensor<Float>,
         is_param: bool,
         name: String,
     ) -> Result<TensorId, GraphError>;
     fn alloc_usize(&mut self, t: Tensor<usize>, name: String) -> Result<TensorId, GraphError>;
     fn params(&self) -> &[TensorId];
-    fn load<T: TensorOps<f32>>(
+    fn load<T: TensorOps<Float>>(
         &mut self,
         tensor_id: TensorId,
         tensor: &T,
@@ -28,7 +45,7 @@ pub trait Graph {
         tensor_id: TensorId,
         tensor: &T,
     ) -> Result<(), GraphError>;
-    fn load_grad<T: TensorOps<f32>>(
+    fn load_grad<T: TensorOps<Float>>(
         &mut self,
         tensor_id: TensorId,
         tensor: &T,
@@ -37,8 +54,8 @@ pub trait Graph {
     fn name_of(&self, id: TensorId) -> Result<&String, GraphError>;
     fn fetch(&mut self, id: TensorId, grad: bool) -> Result<(), GraphError>;
     fn get(&self, id: TensorId) -> Result<&GeneralTensor, GraphError>;
-    fn get_grad(&self, id: TensorId) -> Result<&Tensor<f32>, GraphError>;
-    fn backward_all(&mut self, id: TensorId, limit: Option<usize>) -> Result<f32, GraphError>;
+    fn get_grad(&self, id: TensorId) -> Result<&Tensor<Float>, GraphError>;
+    fn backward_all(&mut self, id: TensorId, limit: Option<usize>) -> Result<Float, GraphError>;
     fn forward(&mut self, training: bool) -> Result<(), GraphError>;
     fn call(
         &mut self,
@@ -48,11 +65,48 @@ pub trait Graph {
     fn optimize<O: Optimizer>(
         &mut self,
         optimizer: &O,
-        learning_rate: f32,
+        learning_rate: Float,
     ) -> Result<(), GraphError>;
     fn optimizer_step(&self) -> usize;
+    // Frozen parameters are left untouched by the optimizer
+    fn set_frozen(&mut self, id: TensorId, frozen: bool) -> Result<(), GraphError>;
+    fn is_frozen(&self, id: TensorId) -> bool;
     fn get_optimizer_state(&self) -> Result<OptimizerState, GraphError>;
     fn set_optimizer_state(&mut self, state: &OptimizerState) -> Result<(), GraphError>;
+    // Fuses chains of computations into single functions. Tensors listed in `keep`
+    // remain available. Returns the number of computations removed.
+    fn fuse(&mut self, keep: &[TensorId]) -> Result<usize, GraphError>;
+    // Seeds the random functions of the graph (E.g. the dropout masks), for reproducible
+    // training
+    fn seed(&mut self, seed: u64);
+    // Graphviz DOT description of the graph, for visualization
+    fn to_dot(&self) -> String;
+    // ONNX model computing `outputs` (Under the given names) from `inputs`
+    fn to_onnx(
+        &self,
+        inputs: &[TensorId],
+        outputs: &[(TensorId, &str)],
+    ) -> Result<Vec<u8>, GraphError>;
+    // Records the time spent in each operation of the forward/backward passes. Enabling it
+    // starts a new report, shared by the clones of the graph made afterwards.
+    fn set_profiling(&mut self, enabled: bool);
+    // Report of the passes since profiling was enabled, `None` when disabled
+    fn profile(&self) -> Option<Profile>;
+    // Shares the parameters and inputs of `other`, a graph of the same model, with this one
+    // while keeping the outputs of its own computations, whose buffers are reused by its next
+    // forward pass. (The training workers keep their graphs across the steps this way)
+    fn share_inputs(&mut self, other: &Self) -> Result<(), GraphError>;
+    // Drops the parameters and inputs shared by `share_inputs`, so that the graph they were
+    // shared from can update them in place
+    fn release_inputs(&mut self);
+    // Stores the outputs of the computations and their gradients at the given precision. The
+    // computations see copies of the parameters at that precision, while the parameters
+    // themselves (What `get` returns and the optimizer updates) stay in full precision.
+    fn set_precision(&mut self, precision: Precision) -> Result<(), GraphError>;
+    // Factor of the gradient the backward passes start from, so that small gradients do not
+    // underflow the half precision (See `LossScaler`). The gradients of the parameters are
+    // scaled by it as well.
+    fn set_loss_scale(&mut self, scale: Float);
 }
 
 unsafe impl Send for CpuGraph {}
@@ -75,14 +129,22 @@ impl Clone for Computation {
 unsafe impl Send for Computation {}
 unsafe impl Sync for Computation {}
 
+// Tensors are reference-counted, so that clones of a graph (E.g. one per worker thread
+// during training) share the same weights until they are overwritten.
 #[derive(Clone)]
 pub struct CpuGraph {
-    tensors: Vec<GeneralTensor>,
-    grads: Vec<Tensor<f32>>,
+    tensors: Vec<Arc<GeneralTensor>>,
+    grads: Vec<GeneralTensor>,
     names: Vec<String>,
     params: Vec<TensorId>,
+    frozen: HashSet<TensorId>,
     computations: BTreeMap<TensorId, Computation>,
     optimizer_state: OptimizerState,
+    profile: Option<Arc<Mutex<Profile>>>,
+    precision: Precision,
+    // Copies of the parameters the computations see, when the precision is not F32
+    half_params: HashMap<TensorId, Arc<GeneralTensor>>,
+    loss_scale: Float,
 }
 
 #[derive(Error, Debug)]
@@ -95,10 +157,53 @@ pub enum GraphError {
     NotReady,
     #[error("tensor types incompatible!")]
     IncompatibleTypes,
+    #[error("invalid model config: {0}")]
+    InvalidConfig(String),
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("serialization error: {0}")]
+    SerializationError(#[from] bincode::Error),
+    #[error("deserialization error: {0}")]
+    DeserializationError(String),
+    #[error("shape mismatch on tensor {name}: expected {expected:?}, found {found:?}")]
+    ShapeMismatch {
+        name: String,
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+    #[error("tensor {0} is missing from the checkpoint")]
+    MissingTensor(String),
+    #[error("tensor {0} of the checkpoint is not a parameter of the model")]
+    UnexpectedTensor(String),
+    #[error("corrupt checkpoint: {0}")]
+    CorruptCheckpoint(String),
+    #[error("the model was trained with a different tokenizer")]
+    TokenizerMismatch,
+    #[error("can not sample from an empty distribution")]
+    EmptyDistribution,
+    #[error("gradient mismatch on tensor {id} at index {index}: {analytic} (analytic) != {numeric} (numeric)")]
+    GradientMismatch {
+        id: TensorId,
+        index: usize,
+        analytic: Float,
+        numeric: Float,
+    },
 
     #[cfg(feature = "gpu")]
     #[error("gpu error: {0}")]
     GpuError(#[from] gpu::program::ProgramError),
+
+    #[cfg(feature = "gpu")]
+    #[error("{0} has no GPU implementation")]
+    NoGpuImplementation(&'static str),
+
+    #[cfg(feature = "wgpu")]
+    #[error("wgpu error: {0}")]
+    WgpuError(String),
+
+    #[cfg(feature = "cuda")]
+    #[error("cuda error: {0}")]
+    CudaError(String),
 }
 
 #[cfg(feature = "gpu")]
@@ -109,43 +214,194 @@ impl From<ocl::Error> for GraphError {
 }
 
 impl CpuGraph {
-    fn add_grad<T: TensorOps<f32>>(&mut self, id: TensorId, add: T) -> Result<(), GraphError> {
+    // The forward/backward passes, with the execution of the functions left to the caller
+    // (So that other backends can run some of them on accelerators)
+    //
+    // Every computation writes its output into the one of the previous pass (When no clone
+    // of the graph shares it), so that the activations are allocated once and reused by the
+    // following passes, see `Function::run_into`.
+    pub(crate) fn forward_with<
+        F: Fn(
+            &mut dyn Function,
+            &[&GeneralTensor],
+            bool,
+            &mut Tensor<Float>,
+        ) -> Result<(), GraphError>,
+    >(
+        &mut self,
+        training: bool,
+        run: F,
+    ) -> Result<(), GraphError> {
+        let start = Instant::now();
+        let empty = Arc::new(GeneralTensor::Float(Tensor::scalar(0.)));
+        for (out, c) in self.computations.iter_mut() {
+            let previous = std::mem::replace(&mut self.tensors[*out], empty.clone());
+            // The previous output, when it is still shared, is put back on errors
+            let (mut result, shared) = match Arc::try_unwrap(previous) {
+                Ok(GeneralTensor::Float(t)) => (t, None),
+                Ok(t) => (Tensor::scalar(0.), Some(Arc::new(t))),
+                Err(previous) => (Tensor::scalar(0.), Some(previous)),
+            };
+            let tensors = c
+                .inps
+                .iter()
+                .map(|id| input(&self.tensors, &self.half_params, *id))
+                .collect::<Result<Vec<_>, GraphError>>();
+            let timer = Instant::now();
+            let ran = tensors.and_then(|t| {
+                let t = t.iter().map(|t| t.as_ref()).collect::<Vec<_>>();
+                run(c.func.as_mut(), &t, training, &mut result)
+            });
+            if let Err(e) = ran {
+                self.tensors[*out] =
+                    shared.unwrap_or_else(|| Arc::new(GeneralTensor::Float(result)));
+                return Err(e);
+            }
+            record(&self.profile, c.func.name(), timer, result.size());
+            self.tensors[*out] = Arc::new(match self.precision {
+                Precision::F32 => GeneralTensor::Float(result),
+                precision => precision.store(&result),
+            });
+        }
+        self.record_pass(start);
+        Ok(())
+    }
+    // Tensors whose gradients are needed: float tensors that are not frozen, and outputs of
+    // computations depending on them. Frozen subgraphs are skipped by the backward pass.
+    pub(crate) fn needs_grad(&self) -> HashSet<TensorId> {
+        let mut needs = HashSet::new();
+        for id in 0..self.tensors.len() {
+            let needed = match self.computations.get(&id) {
+                Some(c) => c.inps.iter().any(|inp| needs.contains(inp)),
+                None => self.tensors[id].is_float() && !self.frozen.contains(&id),
+            };
+            if needed {
+                needs.insert(id);
+            }
+        }
+        needs
+    }
+    pub(crate) fn backward_with<
+        F: Fn(
+            &dyn Function,
+            &[&GeneralTensor],
+            &Tensor<Float>,
+        ) -> Result<Vec<Tensor<Float>>, GraphError>,
+    >(
+        &mut self,
+        id: TensorId,
+        limit: Option<usize>,
+        grad: F,
+    ) -> Result<Float, GraphError> {
+        let start = Instant::now();
+        let output = self.get(id)?.to_float()?.into_owned();
+        let mean_coeff = self.loss_scale / output.size() as Float;
+        self.add_grad(id, Tensor::constant(output.shape(), mean_coeff))?;
+
+        let needs = self.needs_grad();
+        let timer = Instant::now();
+        let computations = self.computations.clone();
+        record(&self.profile, "Clone", timer, 0);
+        for (i, (id, comp)) in computations.iter().rev().enumerate() {
+            if let Some(limit) = limit {
+                if i >= limit {
+                    break;
+                }
+            }
+            if !needs.contains(id) {
+                continue;
+            }
+            let inps = comp
+                .inps
+                .iter()
+                .map(|id| input(&self.tensors, &self.half_params, *id))
+                .collect::<Result<Vec<_>, GraphError>>()?;
+            let inps = inps.iter().map(|t| t.as_ref()).collect::<Vec<_>>();
+            let grad_out = self.grads[*id].to_float()?;
+            let timer = Instant::now();
+            let grads = grad(comp.func.as_ref(), &inps, &grad_out)?;
+            if self.profile.is_some() {
+                let name = format!("{} (grad)", comp.func.name());
+                let size = grads.iter().map(|g| g.size()).sum();
+                record(&self.profile, &name, timer, size);
+            }
+            let timer = Instant::now();
+            for (id, grad) in comp.inps.clone().into_iter().zip(grads.into_iter()) {
+                if needs.contains(&id) {
+                    self.add_grad(id, grad)?;
+                }
+            }
+            record(&self.profile, "AddGrad", timer, 0);
+        }
+
+        self.record_pass(start);
+        Ok(output.mean())
+    }
+    fn record_pass(&self, start: Instant) {
+        if let Some(profile) = &self.profile {
+            let mut profile = lock(profile);
+            profile.total += start.elapsed();
+            profile.passes += 1;
+        }
+    }
+    fn add_grad<T: TensorOps<Float>>(&mut self, id: TensorId, add: T) -> Result<(), GraphError> {
         // Usize tensors do not have gradient
-        if self.get(id)?.as_float().is_err() {
+        if !self.get(id)?.is_float() {
             return Ok(());
         }
 
-        let shape = self.get(id)?.as_float()?.shape().to_vec();
-        let grad = self
+        let shape = self.get(id)?.shape().to_vec();
+        let stored = self
             .grads
             .get_mut(id)
             .ok_or(GraphError::TensorNotFound(id))?;
+        // Gradients stored in half precision are accumulated in full precision
+        let mut grad = match std::mem::replace(stored, GeneralTensor::Float(Tensor::scalar(0.))) {
+            GeneralTensor::Float(t) => t,
+            t => t.to_float()?.into_owned(),
+        };
+        // The gradient of a tensor loaded with another shape (E.g. the inputs of a shorter
+        // context) takes the new one
+        if grad.shape() != shape {
+            grad = (&Tensor::zeros(&shape) + &grad)?;
+        }
         if add.dim() >= shape.len() {
             for t in add.keep_right(shape.len())?.inners().iter() {
-                *grad = (&*grad + t)?;
+                grad.add_assign(t)?;
             }
         } else {
-            *grad = (&*grad + &add.view())?;
+            grad.add_assign(&add)?;
         }
+        *stored = match self.precision {
+            Precision::F32 => GeneralTensor::Float(grad),
+            _ if !self.computations.contains_key(&id) => GeneralTensor::Float(grad),
+            precision => precision.store(&grad),
+        };
         Ok(())
     }
 }
 
 impl Graph for CpuGraph {
     fn alloc_usize(&mut self, t: Tensor<usize>, name: String) -> Result<TensorId, GraphError> {
-        self.grads.push(Tensor::zeros(t.shape()));
-        self.tensors.push(GeneralTensor::Usize(t));
+        self.grads
+            .push(GeneralTensor::Float(Tensor::zeros(t.shape())));
+        self.tensors.push(Arc::new(GeneralTensor::Usize(t)));
         self.names.push(name);
         Ok(self.tensors.len() - 1)
     }
     fn alloc(
         &mut self,
-        t: Tensor<f32>,
+        t: Tensor<Float>,
         is_param: bool,
         name: String,
     ) -> Result<TensorId, GraphError> {
-        self.grads.push(Tensor::zeros(t.shape()));
-        self.tensors.push(GeneralTensor::Float(t));
+        self.grads
+            .push(GeneralTensor::Float(Tensor::zeros(t.shape())));
+        if is_param && self.precision != Precision::F32 {
+            let half = self.precision.store(&t);
+            self.half_params.insert(self.tensors.len(), Arc::new(half));
+        }
+        self.tensors.push(Arc::new(GeneralTensor::Float(t)));
         self.names.push(name);
         let id = self.tensors.len() - 1;
         if is_param {
@@ -153,12 +409,19 @@ impl Graph for CpuGraph {
         }
         Ok(id)
     }
-    fn load<T: TensorOps<f32>>(
+    fn load<T: TensorOps<Float>>(
         &mut self,
         tensor_id: TensorId,
         tensor: &T,
     ) -> Result<(), GraphError> {
-        self.tensors[tensor_id] = GeneralTensor::Float(tensor.view().into());
+        *self
+            .tensors
+            .get_mut(tensor_id)
+            .ok_or(GraphError::TensorNotFound(tensor_id))? =
+            Arc::new(GeneralTensor::Float(tensor.view().into()));
+        if let Some(half) = self.half_params.get_mut(&tensor_id) {
+            *half = Arc::new(self.precision.store(self.tensors[tensor_id].as_float()?));
+        }
         Ok(())
     }
     fn load_usize<T: TensorOps<usize>>(
@@ -166,20 +429,31 @@ impl Graph for CpuGraph {
         tensor_id: TensorId,
         tensor: &T,
     ) -> Result<(), GraphError> {
-        self.tensors[tensor_id] = GeneralTensor::Usize(tensor.view().into());
+        *self
+            .tensors
+            .get_mut(tensor_id)
+            .ok_or(GraphError::TensorNotFound(tensor_id))? =
+            Arc::new(GeneralTensor::Usize(tensor.view().into()));
         Ok(())
     }
-    fn load_grad<T: TensorOps<f32>>(
+    fn load_grad<T: TensorOps<Float>>(
         &mut self,
         tensor_id: TensorId,
         tensor: &T,
     ) -> Result<(), GraphError> {
-        self.grads[tensor_id] = tensor.view().into();
+        *self
+            .grads
+            .get_mut(tensor_id)
+            .ok_or(GraphError::TensorNotFound(tensor_id))? =
+            GeneralTensor::Float(tensor.view().into());
         Ok(())
     }
     fn zero_grad(&mut self) -> Result<(), GraphError> {
-        self.grads.iter_mut().for_each(|t| {
-            t.fill(0.);
+        self.grads.iter_mut().for_each(|t| match t {
+            GeneralTensor::Float(t) => t.fill(0.),
+            GeneralTensor::Usize(t) => t.fill(0),
+            GeneralTensor::F16(t) => t.fill(half::f16::ZERO),
+            GeneralTensor::Bf16(t) => t.fill(half::bf16::ZERO),
         });
         Ok(())
     }
@@ -188,47 +462,25 @@ impl Graph for CpuGraph {
         self.names.get(id).ok_or(GraphError::TensorNotFound(id))
     }
     fn get(&self, id: TensorId) -> Result<&GeneralTensor, GraphError> {
-        self.tensors.get(id).ok_or(GraphError::TensorNotFound(id))
+        self.tensors
+            .get(id)
+            .map(|t| t.as_ref())
+            .ok_or(GraphError::TensorNotFound(id))
     }
-    fn get_grad(&self, id: TensorId) -> Result<&Tensor<f32>, GraphError> {
-        self.grads.get(id).ok_or(GraphError::TensorNotFound(id))
+    fn get_grad(&self, id: TensorId) -> Result<&Tensor<Float>, GraphError> {
+        Ok(self
+            .grads
+            .get(id)
+            .ok_or(GraphError::TensorNotFound(id))?
+            .as_float()?)
     }
-    fn backward_all(&mut self, id: TensorId, limit: Option<usize>) -> Result<f32, GraphError> {
-        let output = self.get(id)?.as_float()?.clone();
-        let mean_coeff = 1. / output.size() as f32;
-        self.add_grad(id, Tensor::constant(output.shape(), mean_coeff))?;
-
-        for (i, (id, comp)) in self.computations.clone().iter().rev().enumerate() {
-            if let Some(limit) = limit {
-                if i >= limit {
-                    break;
-                }
-            }
-            let inps = comp
-                .inps
-                .iter()
-                .map(|id| &self.tensors[*id])
-                .collect::<Vec<_>>();
-            let grad_out = &self.grads[*id];
-            let grads = comp.func.grad(&inps, grad_out)?;
-            for (id, grad) in comp.inps.clone().into_iter().zip(grads.into_iter()) {
-                self.add_grad(id, grad)?;
-            }
-        }
-
-        Ok(output.mean())
+    fn backward_all(&mut self, id: TensorId, limit: Option<usize>) -> Result<Float, GraphError> {
+        self.backward_with(id, limit, |f, inps, out_grad| Ok(f.grad(inps, out_grad)?))
     }
     fn forward(&mut self, training: bool) -> Result<(), GraphError> {
-        for (out, c) in self.computations.iter_mut() {
-            let tensors = c
-                .inps
-                .iter()
-                .map(|id| self.tensors.get(*id).ok_or(GraphError::TensorNotFound(*id)))
-                .collect::<Result<Vec<_>, GraphError>>()?;
-            let result = c.func.run(&tensors, training)?;
-            self.tensors[*out] = GeneralTensor::Float(result);
-        }
-        Ok(())
+        self.forward_with(training, |f, inps, training, out| {
+            Ok(f.run_into(inps, training, out)?)
+        })
     }
     fn call(
         &mut self,
@@ -253,24 +505,35 @@ impl Graph for CpuGraph {
     fn optimize<O: Optimizer>(
         &mut self,
         optimizer: &O,
-        learning_rate: f32,
+        learning_rate: Float,
     ) -> Result<(), GraphError> {
         let pg = self
             .tensors
             .iter_mut()
             .enumerate()
-            .filter(|(id, _)| self.params.contains(id))
+            .filter(|(id, _)| self.params.contains(id) && !self.frozen.contains(id))
             .map(|(id, params)| {
                 let name = self
                     .names
                     .get(id)
                     .cloned()
                     .ok_or(GraphError::TensorNotFound(id))?;
-                let grad = self.grads.get(id).ok_or(GraphError::TensorNotFound(id))?;
-                Ok((name, (params.as_float_mut()?, grad)))
+                let grad = self
+                    .grads
+                    .get(id)
+                    .ok_or(GraphError::TensorNotFound(id))?
+                    .as_float()?;
+                // Clones the tensor only if it's still shared with another graph
+                Ok((name, (Arc::make_mut(params).as_float_mut()?, grad)))
             })
-            .collect::<Result<HashMap<String, (&mut Tensor<f32>, &Tensor<f32>)>, GraphError>>()?;
+            .collect::<Result<HashMap<String, (&mut Tensor<Float>, &Tensor<Float>)>, GraphError>>(
+            )?;
         optimizer.step(pg, &mut self.optimizer_state, learning_rate)?;
+        for (id, half) in self.half_params.iter_mut() {
+            if !self.frozen.contains(id) {
+                *half = Arc::new(self.precision.store(self.tensors[*id].as_float()?));
+            }
+        }
         Ok(())
     }
     fn fetch(&mut self, _tensor_id: TensorId, _grad: bool) -> Result<(), GraphError> {
@@ -283,6 +546,20 @@ impl Graph for CpuGraph {
     fn optimizer_step(&self) -> usize {
         self.optimizer_state.step
     }
+    fn set_frozen(&mut self, id: TensorId, frozen: bool) -> Result<(), GraphError> {
+        if !self.params.contains(&id) {
+            return Err(GraphError::TensorNotFound(id));
+        }
+        if frozen {
+            self.frozen.insert(id);
+        } else {
+            self.frozen.remove(&id);
+        }
+        Ok(())
+    }
+    fn is_frozen(&self, id: TensorId) -> bool {
+        self.frozen.contains(&id)
+    }
     fn get_optimizer_state(&self) -> Result<OptimizerState, GraphError> {
         Ok(self.optimizer_state.clone())
     }
@@ -290,6 +567,95 @@ impl Graph for CpuGraph {
         self.optimizer_state = state.clone();
         Ok(())
     }
+    fn fuse(&mut self, keep: &[TensorId]) -> Result<usize, GraphError> {
+        let tensors = &self.tensors;
+        Ok(fusion::fuse_computations(
+            &mut self.computations,
+            |id| tensors[id].shape().to_vec(),
+            keep,
+        ))
+    }
+    fn seed(&mut self, seed: u64) {
+        // Each function gets its own stream, derived from the id of its output
+        for (id, comp) in self.computations.iter_mut() {
+            comp.func
+                .reseed(seed ^ (*id as u64).wrapping_mul(0x9e3779b97f4a7c15));
+        }
+    }
+    fn to_dot(&self) -> String {
+        let shapes = self
+            .tensors
+            .iter()
+            .map(|t| t.shape().to_vec())
+            .collect::<Vec<_>>();
+        dot::to_dot(
+            &self.names,
+            &shapes,
+            &self.params,
+            self.computations.iter().map(|(id, c)| (*id, c)),
+        )
+    }
+    fn set_profiling(&mut self, enabled: bool) {
+        self.profile = enabled.then(Default::default);
+    }
+    fn share_inputs(&mut self, other: &Self) -> Result<(), GraphError> {
+        if other.tensors.len() != self.tensors.len() {
+            return Err(GraphError::InvalidConfig(
+                "the inputs are shared between graphs of different models".into(),
+            ));
+        }
+        for (id, tensor) in other.tensors.iter().enumerate() {
+            if !self.computations.contains_key(&id) {
+                self.tensors[id] = tensor.clone();
+            }
+        }
+        self.frozen = other.frozen.clone();
+        self.profile = other.profile.clone();
+        self.precision = other.precision;
+        self.half_params = other.half_params.clone();
+        self.loss_scale = other.loss_scale;
+        Ok(())
+    }
+    fn release_inputs(&mut self) {
+        let empty = Arc::new(GeneralTensor::Float(Tensor::scalar(0.)));
+        for id in 0..self.tensors.len() {
+            if !self.computations.contains_key(&id) {
+                self.tensors[id] = empty.clone();
+            }
+        }
+        self.half_params.clear();
+    }
+    fn set_precision(&mut self, precision: Precision) -> Result<(), GraphError> {
+        self.precision = precision;
+        self.half_params.clear();
+        if precision != Precision::F32 {
+            for p in self.params.iter() {
+                let half = precision.store(self.tensors[*p].as_float()?);
+                self.half_params.insert(*p, Arc::new(half));
+            }
+        }
+        Ok(())
+    }
+    fn set_loss_scale(&mut self, scale: Float) {
+        self.loss_scale = scale;
+    }
+    fn profile(&self) -> Option<Profile> {
+        self.profile.as_ref().map(|profile| lock(profile).clone())
+    }
+    fn to_onnx(
+        &self,
+        inputs: &[TensorId],
+        outputs: &[(TensorId, &str)],
+    ) -> Result<Vec<u8>, GraphError> {
+        let tensors = self.tensors.iter().map(|t| t.as_ref()).collect::<Vec<_>>();
+        onnx::to_onnx(
+            &self.names,
+            &tensors,
+            self.computations.iter().map(|(id, c)| (*id, c)),
+            inputs,
+            outputs,
+        )
+    }
 }
 
 impl CpuGraph {
@@ -299,8 +665,190 @@ impl CpuGraph {
             grads: Default::default(),
             computations: Default::default(),
             params: Default::default(),
+            frozen: Default::default(),
             names: Default::default(),
             optimizer_state: Default::default(),
+            profile: None,
+            precision: Precision::F32,
+            half_params: Default::default(),
+            loss_scale: 1.,
+        }
+    }
+}
+
+// Tensor a computation sees as its input: the copy of a parameter in the precision of the
+// graph when there is one, converted back to `Float` like the half-precision outputs
+fn input<'a>(
+    tensors: &'a [Arc<GeneralTensor>],
+    half_params: &'a HashMap<TensorId, Arc<GeneralTensor>>,
+    id: TensorId,
+) -> Result<Cow<'a, GeneralTensor>, GraphError> {
+    let tensor = half_params
+        .get(&id)
+        .or_else(|| tensors.get(id))
+        .ok_or(GraphError::TensorNotFound(id))?;
+    Ok(match tensor.as_ref() {
+        GeneralTensor::F16(_) | GeneralTensor::Bf16(_) => {
+            Cow::Owned(GeneralTensor::Float(tensor.to_float()?.into_owned()))
+        }
+        tensor => Cow::Borrowed(tensor),
+    })
+}
+
+// Adds an operation to the profile, if profiling is enabled
+fn record(profile: &Option<Arc<Mutex<Profile>>>, op: &str, timer: Instant, size: usize) {
+    if let Some(profile) = profile {
+        lock(profile).record(op, timer.elapsed(), size * std::mem::size_of::<Float>());
+    }
+}
+
+// A worker panicking while holding the profile leaves it usable
+fn lock(profile: &Mutex<Profile>) -> std::sync::MutexGuard<'_, Profile> {
+    profile.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::funcs::*;
+    use crate::optimizer::Sgd;
+
+    #[test]
+    fn test_activations_are_reused() {
+        let mut g = CpuGraph::new();
+        let x = g
+            .alloc(Tensor::<Float>::zeros(&[4, 3]), true, "x".into())
+            .unwrap();
+        let w = g
+            .alloc(Tensor::<Float>::zeros(&[3, 5]), true, "w".into())
+            .unwrap();
+        let xw = g.call(MatMul::new(), &[x, w]).unwrap();
+        let out = g.call(Gelu::new(), &[xw]).unwrap();
+        g.forward(false).unwrap();
+        let ptrs = [xw, out].map(|id| g.get(id).unwrap().as_float().unwrap().blob().as_ptr());
+        g.load(x, &Tensor::<Float>::constant(&[4, 3], 1.)).unwrap();
+        g.load(w, &Tensor::<Float>::constant(&[3, 5], 2.)).unwrap();
+        g.forward(false).unwrap();
+        for (id, ptr) in [xw, out].iter().zip(ptrs) {
+            assert_eq!(g.get(*id).unwrap().as_float().unwrap().blob().as_ptr(), ptr);
+        }
+        let xw_out = g.get(xw).unwrap().as_float().unwrap();
+        assert_eq!(xw_out.shape(), &[4, 5]);
+        assert!(xw_out.blob().iter().all(|v| *v == 6.));
+        // A failing pass leaves the outputs of the previous one in place
+        g.load(x, &Tensor::<Float>::constant(&[4, 4], 1.)).unwrap();
+        assert!(g.forward(false).is_err());
+        assert_eq!(g.get(xw).unwrap().as_float().unwrap().shape(), &[4, 5]);
+    }
+
+    #[test]
+    fn test_shared_inputs() {
+        let mut g = CpuGraph::new();
+        let x = g
+            .alloc(Tensor::<Float>::zeros(&[4, 3]), true, "x".into())
+            .unwrap();
+        let w = g
+            .alloc(Tensor::<Float>::zeros(&[3, 5]), true, "w".into())
+            .unwrap();
+        let xw = g.call(MatMul::new(), &[x, w]).unwrap();
+        let mut worker = g.clone();
+        worker.forward(false).unwrap();
+        let ptr = worker.get(xw).unwrap().as_float().unwrap().blob().as_ptr();
+        worker.release_inputs();
+        assert_eq!(Arc::strong_count(&g.tensors[w]), 1);
+
+        g.load(x, &Tensor::<Float>::constant(&[4, 3], 1.)).unwrap();
+        g.load(w, &Tensor::<Float>::constant(&[3, 5], 2.)).unwrap();
+        worker.share_inputs(&g).unwrap();
+        worker.forward(false).unwrap();
+        let out = worker.get(xw).unwrap().as_float().unwrap();
+        assert_eq!(out.blob().as_ptr(), ptr);
+        assert!(out.blob().iter().all(|v| *v == 6.));
+        assert!(CpuGraph::new().share_inputs(&g).is_err());
+    }
+
+    #[test]
+    fn test_half_precision() {
+        let mut g = CpuGraph::new();
+        let x = g
+            .alloc(Tensor::<Float>::constant(&[4, 3], 0.1), true, "x".into())
+            .unwrap();
+        let w = g
+            .alloc(Tensor::<Float>::constant(&[3, 5], 0.1), true, "w".into())
+            .unwrap();
+        let xw = g.call(MatMul::new(), &[x, w]).unwrap();
+        g.set_precision(Precision::F16).unwrap();
+        g.set_loss_scale(1024.);
+        g.forward(true).unwrap();
+        // Computed from the half-precision copies of the parameters, which stay in full
+        // precision
+        let rounded = half::f16::from_f64(0.1).to_f64() as Float;
+        assert!(matches!(g.get(xw).unwrap(), GeneralTensor::F16(_)));
+        let out = g.get(xw).unwrap().to_float().unwrap();
+        assert!(out
+            .blob()
+            .iter()
+            .all(|v| (v - 3. * rounded * rounded).abs() < 1e-4));
+        assert_eq!(g.get(w).unwrap().as_float().unwrap().blob()[0], 0.1);
+
+        // The gradients of the parameters are scaled by the loss scale, the ones of the
+        // outputs are stored in half precision
+        g.zero_grad().unwrap();
+        g.backward_all(xw, None).unwrap();
+        let grad = g.get_grad(w).unwrap();
+        let expected = 4. * rounded / 20. * 1024.;
+        assert!(grad.blob().iter().all(|v| (v - expected).abs() < 1e-2));
+        assert!(g.get_grad(xw).is_err());
+        let update = grad.blob()[0] * 0.001;
+
+        // The copies follow the parameters updated by the optimizer, and are shared with
+        // the workers
+        g.optimize(&Sgd::new(), 0.001).unwrap();
+        let master = g.get(w).unwrap().as_float().unwrap();
+        let copy = g.half_params[&w].to_float().unwrap();
+        assert!((master.blob()[0] - (0.1 - update)).abs() < 1e-6);
+        for (c, m) in copy.blob().iter().zip(master.blob()) {
+            assert!((c - m).abs() < 1e-4);
         }
+        let mut worker = g.clone();
+        worker.release_inputs();
+        worker.share_inputs(&g).unwrap();
+        assert!(Arc::ptr_eq(&worker.half_params[&w], &g.half_params[&w]));
+    }
+
+    #[test]
+    fn test_profile() {
+        let mut g = CpuGraph::new();
+        let x = g
+            .alloc(Tensor::<Float>::zeros(&[4, 3]), true, "x".into())
+            .unwrap();
+        let w = g
+            .alloc(Tensor::<Float>::zeros(&[3, 5]), true, "w".into())
+            .unwrap();
+        let xw = g.call(MatMul::new(), &[x, w]).unwrap();
+        let out = g.call(Gelu::new(), &[xw]).unwrap();
+        assert!(g.profile().is_none());
+        g.set_profiling(true);
+        // Clones of the graph report to the same profile
+        let mut clone = g.clone();
+        for g in [&mut g, &mut clone] {
+            g.forward(true).unwrap();
+            g.zero_grad().unwrap();
+            g.backward_all(out, None).unwrap();
+        }
+        let profile = g.profile().unwrap();
+        assert_eq!(profile.passes, 4);
+        let matmul = &profile.ops["MatMul"];
+        assert_eq!(matmul.calls, 2);
+        assert_eq!(matmul.bytes, 2 * 20 * std::mem::size_of::<Float>());
+        assert_eq!(profile.ops["Gelu (grad)"].calls, 2);
+        assert_eq!(
+            profile.ops["MatMul (grad)"].bytes,
+            2 * 27 * std::mem::size_of::<Float>()
+        );
+        assert_eq!(profile.rows().len(), 6);
+        assert!(profile.to_string().starts_with("op "));
+        g.set_profiling(false);
+        assert!(g.profile().is_none());
     }
 }
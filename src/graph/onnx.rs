@@ -0,0 +1,508 @@
+use super::{Computation, GraphError, TensorId};
+use crate::funcs::*;
+use crate::tensor::*;
+use std::any::Any;
+use std::collections::HashMap;
+
+// Serialization of (The inference part of) the computation graph to ONNX
+// (https://onnx.ai), so that models can be run by onnxruntime or visualized in Netron.
+// Each function is translated to one or more standard ONNX operators. The protobuf
+// messages are encoded by hand, see https://github.com/onnx/onnx/blob/main/onnx/onnx.proto
+// for the field numbers.
+
+const IR_VERSION: i64 = 8;
+const OPSET_VERSION: i64 = 17; // LayerNormalization was added in opset 17
+
+// ONNX tensor element types
+const FLOAT: i64 = 1;
+const INT64: i64 = 7;
+
+#[derive(Default)]
+struct Proto(Vec<u8>);
+
+impl Proto {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.varint((field << 3) | wire_type);
+    }
+    fn int(mut self, field: u64, v: i64) -> Self {
+        self.key(field, 0);
+        self.varint(v as u64);
+        self
+    }
+    fn float(mut self, field: u64, v: f32) -> Self {
+        self.key(field, 5);
+        self.0.extend(v.to_le_bytes());
+        self
+    }
+    fn bytes(mut self, field: u64, b: &[u8]) -> Self {
+        self.key(field, 2);
+        self.varint(b.len() as u64);
+        self.0.extend(b);
+        self
+    }
+    fn str(self, field: u64, s: &str) -> Self {
+        self.bytes(field, s.as_bytes())
+    }
+    fn msg(self, field: u64, m: Proto) -> Self {
+        self.bytes(field, &m.0)
+    }
+}
+
+enum Attr {
+    Int(i64),
+    Float(f32),
+    Ints(Vec<i64>),
+}
+
+fn tensor_proto(name: &str, shape: &[usize], data_type: i64, raw: Vec<u8>) -> Proto {
+    let mut t = Proto::default();
+    for d in shape {
+        t = t.int(1, *d as i64);
+    }
+    t.int(2, data_type).str(8, name).bytes(9, &raw)
+}
+
+fn value_info(name: &str, shape: &[usize], elem_type: i64) -> Proto {
+    let dims = shape.iter().fold(Proto::default(), |s, d| {
+        s.msg(1, Proto::default().int(1, *d as i64))
+    });
+    let tensor_type = Proto::default().int(1, elem_type).msg(2, dims);
+    Proto::default()
+        .str(1, name)
+        .msg(2, Proto::default().msg(1, tensor_type))
+}
+
+#[derive(Default)]
+struct Builder {
+    nodes: Vec<Proto>,
+    initializers: Vec<Proto>,
+    temps: usize,
+}
+
+impl Builder {
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("tmp_{}", self.temps)
+    }
+    fn node(&mut self, op: &str, inps: &[&str], out: &str, attrs: &[(&str, Attr)]) {
+        let mut node = Proto::default();
+        for inp in inps {
+            node = node.str(1, inp);
+        }
+        node = node.str(2, out).str(3, out).str(4, op);
+        for (name, attr) in attrs {
+            let a = Proto::default().str(1, name);
+            let a = match attr {
+                Attr::Float(f) => a.float(2, *f).int(20, 1),
+                Attr::Int(i) => a.int(3, *i).int(20, 2),
+                Attr::Ints(is) => is.iter().fold(a, |a, i| a.int(8, *i)).int(20, 7),
+            };
+            node = node.msg(5, a);
+        }
+        self.nodes.push(node);
+    }
+    // Applies an operator on new intermediate value
+    fn apply(&mut self, op: &str, inps: &[&str], attrs: &[(&str, Attr)]) -> String {
+        let out = self.temp();
+        self.node(op, inps, &out, attrs);
+        out
+    }
+    fn initializer(&mut self, name: &str, t: &Tensor<Float>) {
+        let raw = t
+            .cast::<f32>()
+            .blob()
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect();
+        self.initializers
+            .push(tensor_proto(name, t.shape(), FLOAT, raw));
+    }
+    fn constant(&mut self, t: &Tensor<Float>) -> String {
+        let name = self.temp();
+        self.initializer(&name, t);
+        name
+    }
+    fn scalar(&mut self, v: Float) -> String {
+        self.constant(&Tensor::scalar(v))
+    }
+    fn ints(&mut self, vs: &[i64]) -> String {
+        let name = self.temp();
+        let raw = vs.iter().flat_map(|v| v.to_le_bytes()).collect();
+        self.initializers
+            .push(tensor_proto(&name, &[vs.len()], INT64, raw));
+        name
+    }
+}
+
+// Cosine and sine tables, and the pair-swapping matrix R, so that rotary embeddings can be
+// written as x * cos + (x ^ R) * sin
+pub(crate) fn rope_tables(n: usize, d: usize) -> (Tensor<Float>, Tensor<Float>, Tensor<Float>) {
+    let mut cos = vec![1.; n * d];
+    let mut sin = vec![0.; n * d];
+    let mut r = vec![0.; d * d];
+    for i in 0..d / 2 {
+        for p in 0..n {
+            let theta = p as Float * ROPE_BASE.powf(-2. * i as Float / d as Float);
+            for j in [2 * i, 2 * i + 1] {
+                cos[p * d + j] = theta.cos();
+                sin[p * d + j] = theta.sin();
+            }
+        }
+        r[(2 * i + 1) * d + 2 * i] = -1.;
+        r[2 * i * d + 2 * i + 1] = 1.;
+    }
+    (
+        Tensor::raw(&[n, d], cos).unwrap(),
+        Tensor::raw(&[n, d], sin).unwrap(),
+        Tensor::raw(&[d, d], r).unwrap(),
+    )
+}
+
+// [n, n] matrix to be added to attention scores, 0 where attention is allowed and -inf
+// elsewhere
+fn causal_mask(n: usize) -> Tensor<Float> {
+    let data = (0..n * n)
+        .map(|i| {
+            if i % n <= i / n {
+                0.
+            } else {
+                Float::NEG_INFINITY
+            }
+        })
+        .collect();
+    Tensor::raw(&[n, n], data).unwrap()
+}
+
+fn translate(
+    b: &mut Builder,
+    f: &dyn Function,
+    inps: &[&str],
+    inp_shapes: &[&[usize]],
+    out: &str,
+) -> Result<(), GraphError> {
+    let f_any = f as &dyn Any;
+    let last_dim = |i: usize| inp_shapes[i].last().copied().unwrap_or(1);
+    if f_any.is::<Add>() {
+        b.node("Add", inps, out, &[]);
+    } else if f_any.is::<Mul>() {
+        b.node("Mul", inps, out, &[]);
+    } else if f_any.is::<MatMul>() {
+        b.node("MatMul", inps, out, &[]);
+    } else if f_any.is::<MatMulAdd>() {
+        let prod = b.apply("MatMul", &inps[..2], &[]);
+        b.node("Add", &[&prod, inps[2]], out, &[]);
+    } else if f_any.is::<Embedding>() {
+        b.node(
+            "Gather",
+            &[inps[1], inps[0]],
+            out,
+            &[("axis", Attr::Int(0))],
+        );
+    } else if let Some(s) = f_any.downcast_ref::<Sinusoidal>() {
+        let table = b.constant(&s.table);
+        b.node("Add", &[inps[0], &table], out, &[]);
+    } else if f_any.is::<LayerNorm>() {
+        b.node(
+            "LayerNormalization",
+            inps,
+            out,
+            &[("axis", Attr::Int(-1)), ("epsilon", Attr::Float(1e-5))],
+        );
+    } else if f_any.is::<Transpose>() {
+        let rank = inp_shapes[0].len() as i64;
+        let mut perm = (0..rank).collect::<Vec<_>>();
+        perm.swap(rank as usize - 1, rank as usize - 2);
+        b.node("Transpose", inps, out, &[("perm", Attr::Ints(perm))]);
+    } else if let Some(c) = f_any.downcast_ref::<Coeff>() {
+        let coeff = b.scalar(c.coeff);
+        b.node("Mul", &[inps[0], &coeff], out, &[]);
+    } else if let Some(t) = f_any.downcast_ref::<TrilMask>() {
+        let mask = b.constant(&causal_mask(t.n));
+        b.node("Add", &[inps[0], &mask], out, &[]);
+    } else if f_any.is::<Positional>() {
+        // (The positions of the exported model start at 0, the attention mask is not needed)
+        b.node("Add", &inps[..2], out, &[]);
+    } else if f_any.is::<Prepend>() && inp_shapes[1][0] == 0 {
+        b.node("Identity", &inps[..1], out, &[]);
+    } else if f_any.is::<PadMask>() {
+        // scores * mask + (1 - mask) * MASKED_SCORE, with the mask broadcast over the rows
+        let axes = b.ints(&[-2]);
+        let mask = b.apply("Unsqueeze", &[inps[1], &axes], &[]);
+        let (one, masked) = (b.scalar(1.), b.scalar(MASKED_SCORE));
+        let kept = b.apply("Mul", &[inps[0], &mask], &[]);
+        let pads = b.apply("Sub", &[&one, &mask], &[]);
+        let fill = b.apply("Mul", &[&pads, &masked], &[]);
+        b.node("Add", &[&kept, &fill], out, &[]);
+    } else if let Some(a) = f_any.downcast_ref::<Alibi>() {
+        let n = last_dim(0);
+        let data = (0..n * n)
+            .map(|i| -a.slope * (i / n).abs_diff(i % n) as Float)
+            .collect();
+        let bias = b.constant(&Tensor::raw(&[n, n], data)?);
+        b.node("Add", &[inps[0], &bias], out, &[]);
+    } else if f_any.is::<Softmax>() {
+        b.node("Softmax", inps, out, &[("axis", Attr::Int(-1))]);
+    } else if let Some(s) = f_any.downcast_ref::<ScaledMaskedSoftmax>() {
+        let coeff = b.scalar(s.coeff);
+        let mask = b.constant(&causal_mask(s.n));
+        let scaled = b.apply("Mul", &[inps[0], &coeff], &[]);
+        let masked = b.apply("Add", &[&scaled, &mask], &[]);
+        b.node("Softmax", &[&masked], out, &[("axis", Attr::Int(-1))]);
+    } else if f_any.is::<Dropout>() {
+        b.node("Identity", &inps[..1], out, &[]);
+    } else if f_any.is::<Cat>() {
+        b.node("Concat", inps, out, &[("axis", Attr::Int(-1))]);
+    } else if f_any.is::<Relu>() {
+        b.node("LeakyRelu", inps, out, &[("alpha", Attr::Float(0.01))]);
+    } else if f_any.is::<Silu>() {
+        let sigmoid = b.apply("Sigmoid", inps, &[]);
+        b.node("Mul", &[inps[0], &sigmoid], out, &[]);
+    } else if f_any.is::<Gelu>() {
+        // 0.5 * x * (1 + tanh(sqrt(2 / pi) * (x + 0.044715 * x^3)))
+        let (c1, c2) = (b.scalar(GELU_CONST), b.scalar(SQRT_2_OVER_PI));
+        let (half, one) = (b.scalar(0.5), b.scalar(1.));
+        let x2 = b.apply("Mul", &[inps[0], inps[0]], &[]);
+        let x3 = b.apply("Mul", &[&x2, inps[0]], &[]);
+        let x3_c1 = b.apply("Mul", &[&x3, &c1], &[]);
+        let inner = b.apply("Add", &[inps[0], &x3_c1], &[]);
+        let inner_c2 = b.apply("Mul", &[&inner, &c2], &[]);
+        let tanh = b.apply("Tanh", &[&inner_c2], &[]);
+        let tanh_1 = b.apply("Add", &[&tanh, &one], &[]);
+        let half_x = b.apply("Mul", &[inps[0], &half], &[]);
+        b.node("Mul", &[&half_x, &tanh_1], out, &[]);
+    } else if f_any.is::<Rope>() {
+        let shape = inp_shapes[0];
+        let (cos, sin, r) = rope_tables(shape[shape.len() - 2], shape[shape.len() - 1]);
+        let (cos, sin, r) = (b.constant(&cos), b.constant(&sin), b.constant(&r));
+        let x_cos = b.apply("Mul", &[inps[0], &cos], &[]);
+        let swapped = b.apply("MatMul", &[inps[0], &r], &[]);
+        let swapped_sin = b.apply("Mul", &[&swapped, &sin], &[]);
+        b.node("Add", &[&x_cos, &swapped_sin], out, &[]);
+    } else {
+        return Err(GraphError::InvalidConfig(format!(
+            "{} can not be exported to ONNX",
+            f.name()
+        )));
+    }
+    Ok(())
+}
+
+// Serializes the computations needed for evaluating `outputs` from `inputs`. Parameters
+// (And any other tensor that is not computed) are stored as initializers. Outputs are
+// named by the caller.
+pub fn to_onnx<'a, I: Iterator<Item = (TensorId, &'a Computation)>>(
+    names: &[String],
+    tensors: &[&GeneralTensor],
+    computations: I,
+    inputs: &[TensorId],
+    outputs: &[(TensorId, &str)],
+) -> Result<Vec<u8>, GraphError> {
+    let computations = computations.collect::<HashMap<_, _>>();
+
+    // Only the computations the outputs depend on are exported
+    let mut needed = vec![false; tensors.len()];
+    let mut stack = outputs.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+    while let Some(id) = stack.pop() {
+        if needed[id] || inputs.contains(&id) {
+            needed[id] = true;
+            continue;
+        }
+        needed[id] = true;
+        if let Some(c) = computations.get(&id) {
+            stack.extend(c.inps.iter());
+        }
+    }
+
+    let value_name = |id: TensorId| {
+        if !computations.contains_key(&id) && !names[id].is_empty() {
+            names[id].clone()
+        } else {
+            format!("t{}", id)
+        }
+    };
+    let mut b = Builder::default();
+    let mut graph = Proto::default();
+    for id in inputs {
+        let (elem_type, shape) = match tensors[*id] {
+            GeneralTensor::Usize(t) => (INT64, t.shape()),
+            t => (FLOAT, t.shape()),
+        };
+        graph = graph.msg(11, value_info(&value_name(*id), shape, elem_type));
+    }
+    for id in (0..tensors.len()).filter(|id| needed[*id]) {
+        if inputs.contains(&id) {
+            continue;
+        }
+        match computations.get(&id) {
+            Some(c) => {
+                let inps = c.inps.iter().map(|i| value_name(*i)).collect::<Vec<_>>();
+                let inps = inps.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+                let shapes = c
+                    .inps
+                    .iter()
+                    .map(|i| tensors[*i].shape())
+                    .collect::<Vec<_>>();
+                translate(&mut b, c.func.as_ref(), &inps, &shapes, &value_name(id))?;
+            }
+            None => b.initializer(&value_name(id), tensors[id].as_float()?),
+        }
+    }
+    for (id, name) in outputs {
+        b.node("Identity", &[&value_name(*id)], name, &[]);
+        graph = graph.msg(12, value_info(name, tensors[*id].shape(), FLOAT));
+    }
+
+    for node in b.nodes {
+        graph = graph.msg(1, node);
+    }
+    graph = graph.str(2, "femtoGPT");
+    for init in b.initializers {
+        graph = graph.msg(5, init);
+    }
+    let model = Proto::default()
+        .int(1, IR_VERSION)
+        .str(2, "femtoGPT")
+        .msg(7, graph)
+        .msg(8, Proto::default().str(1, "").int(2, OPSET_VERSION));
+    Ok(model.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CpuGraph, Graph};
+    use super::*;
+
+    // Minimal protobuf decoder, returning the (field, payload) pairs of a message
+    fn fields(mut b: &[u8]) -> Vec<(u64, &[u8])> {
+        let varint = |b: &mut &[u8]| {
+            let (mut v, mut shift) = (0u64, 0);
+            loop {
+                let byte = b[0];
+                *b = &b[1..];
+                v |= ((byte & 0x7f) as u64) << shift;
+                shift += 7;
+                if byte < 0x80 {
+                    return v;
+                }
+            }
+        };
+        let mut result = Vec::new();
+        while !b.is_empty() {
+            let key = varint(&mut b);
+            let len = match key & 7 {
+                0 => {
+                    varint(&mut b);
+                    0
+                }
+                2 => varint(&mut b) as usize,
+                5 => 4,
+                _ => panic!("unexpected wire type"),
+            };
+            let (payload, rest) = b.split_at(len);
+            result.push((key >> 3, payload));
+            b = rest;
+        }
+        result
+    }
+
+    fn strings(msg: &[u8], field: u64) -> Vec<String> {
+        fields(msg)
+            .into_iter()
+            .filter(|(f, _)| *f == field)
+            .map(|(_, p)| String::from_utf8(p.to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_rope_tables() {
+        let mut rng = rand::thread_rng();
+        for (n, d) in [(4, 6), (3, 5)] {
+            let x = Tensor::<Float>::rand_range(&mut rng, -1., 1., &[n, d]);
+            let expected = Rope::new()
+                .run(&[&GeneralTensor::Float(x.clone())], false)
+                .unwrap();
+            let (cos, sin, r) = rope_tables(n, d);
+            let swapped = (&x ^ &r).unwrap();
+            let result = (&(&x * &cos).unwrap() + &(&swapped * &sin).unwrap()).unwrap();
+            for (a, b) in expected.blob().iter().zip(result.blob().iter()) {
+                assert!((a - b).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_to_onnx() {
+        let mut rng = rand::thread_rng();
+        let mut g = CpuGraph::new();
+        let inp = g
+            .alloc_usize(Tensor::<usize>::zeros(&[4]), "inp".into())
+            .unwrap();
+        let expected = g
+            .alloc_usize(Tensor::<usize>::zeros(&[4]), "expected".into())
+            .unwrap();
+        let table = Tensor::<Float>::rand_range(&mut rng, -1., 1., &[5, 6]);
+        let table = g.alloc(table, true, "table".into()).unwrap();
+        let coeff = g.alloc(Tensor::zeros(&[6]), true, "coeff".into()).unwrap();
+        let bias = g.alloc(Tensor::zeros(&[6]), true, "bias".into()).unwrap();
+        let emb = g.call(Embedding::new(), &[inp, table]).unwrap();
+        let emb = g.call(Sinusoidal::new(4, 6), &[emb]).unwrap();
+        let norm = g.call(LayerNorm::new(), &[emb, coeff, bias]).unwrap();
+        let rotated = g.call(Rope::new(), &[norm]).unwrap();
+        let rotated_t = g.call(Transpose::new(), &[rotated]).unwrap();
+        let scores = g.call(MatMul::new(), &[rotated, rotated_t]).unwrap();
+        let att = g.call(ScaledMaskedSoftmax::new(0.5, 4), &[scores]).unwrap();
+        let out = g.call(MatMul::new(), &[att, norm]).unwrap();
+        let out = g.call(Gelu::new(), &[out]).unwrap();
+        let logits = g.call(MatMul::new(), &[out, rotated_t]).unwrap();
+        let loss = g.call(CrossEntropy::new(), &[logits, expected]).unwrap();
+
+        let model = g.to_onnx(&[inp], &[(logits, "logits")]).unwrap();
+        let model_fields = fields(&model);
+        let graph = model_fields.iter().find(|(f, _)| *f == 7).unwrap().1;
+        let graph_fields = fields(graph);
+        let of = |field| {
+            graph_fields
+                .iter()
+                .filter(move |(f, _)| *f == field)
+                .map(|(_, p)| *p)
+        };
+
+        let mut defined = of(11)
+            .chain(of(5))
+            .flat_map(|m| strings(m, 1).into_iter().chain(strings(m, 8)))
+            .collect::<Vec<_>>();
+        assert!(defined.contains(&"inp".to_string()));
+        assert!(defined.contains(&"table".to_string()));
+        assert!(!defined.contains(&"expected".to_string()));
+        let mut op_types = Vec::new();
+        for node in of(1) {
+            // Nodes are topologically sorted
+            for inp in strings(node, 1) {
+                assert!(defined.contains(&inp), "{} is not defined", inp);
+            }
+            defined.extend(strings(node, 2));
+            op_types.extend(strings(node, 4));
+        }
+        assert!(defined.contains(&"logits".to_string()));
+        for op in [
+            "Gather",
+            "LayerNormalization",
+            "Transpose",
+            "Softmax",
+            "Tanh",
+        ] {
+            assert!(op_types.contains(&op.to_string()));
+        }
+        assert_eq!(strings(of(12).next().unwrap(), 1), ["logits"]);
+
+        // Training-only functions can not be exported
+        assert!(g.to_onnx(&[inp], &[(loss, "loss")]).is_err());
+    }
+}
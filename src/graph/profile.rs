@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// Time spent, and bytes produced, by the operations of the forward/backward passes of a
+// graph with profiling enabled (See `Graph::set_profiling`). Operations are named after
+// their function, the backward pass of a function with a " (grad)" suffix. The bookkeeping
+// of the backward pass is reported as "Clone" (Copying the computations before walking
+// them) and "AddGrad" (Accumulating the gradients of the inputs).
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpStats {
+    pub calls: usize,
+    pub time: Duration,
+    // Size of the outputs (Or gradients) computed
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub ops: BTreeMap<String, OpStats>,
+    // Wall time of the profiled passes, summed over the clones of the graph
+    pub total: Duration,
+    pub passes: usize,
+}
+
+impl Profile {
+    pub(crate) fn record(&mut self, op: &str, time: Duration, bytes: usize) {
+        let stats = self.ops.entry(op.into()).or_default();
+        stats.calls += 1;
+        stats.time += time;
+        stats.bytes += bytes;
+    }
+
+    // Operations sorted by decreasing time
+    pub fn rows(&self) -> Vec<(&str, &OpStats)> {
+        let mut rows = self
+            .ops
+            .iter()
+            .map(|(op, stats)| (op.as_str(), stats))
+            .collect::<Vec<_>>();
+        rows.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+        rows
+    }
+
+    // Share of the passes spent in an operation, in percents
+    pub fn percentage(&self, stats: &OpStats) -> f64 {
+        100. * stats.time.as_secs_f64() / self.total.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = self.ops.keys().map(|op| op.len()).max().unwrap_or(0).max(2);
+        writeln!(
+            f,
+            "{:<width$} {:>10} {:>12} {:>10} {:>12}",
+            "op",
+            "calls",
+            "total ms",
+            "% of step",
+            "MiB",
+            width = width
+        )?;
+        for (op, stats) in self.rows() {
+            writeln!(
+                f,
+                "{:<width$} {:>10} {:>12.3} {:>10.2} {:>12.2}",
+                op,
+                stats.calls,
+                stats.time.as_secs_f64() * 1000.,
+                self.percentage(stats),
+                stats.bytes as f64 / (1024. * 1024.),
+                width = width
+            )?;
+        }
+        write!(
+            f,
+            "{} passes, {:.3} ms",
+            self.passes,
+            self.total.as_secs_f64() * 1000.
+        )
+    }
+}
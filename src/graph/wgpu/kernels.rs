@@ -0,0 +1,285 @@
+use super::super::GraphError;
+use crate::funcs::{Function, LayerNorm, MatMul, MatMulAdd, Softmax};
+use crate::tensor::*;
+use std::any::Any;
+use std::sync::Arc;
+use wgpu::util::DeviceExt;
+
+const WORKGROUP_SIZE: usize = 64;
+const MAX_WORKGROUPS: usize = 65535;
+
+// A wgpu device along with the compiled compute pipelines
+pub struct Kernels {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    matmul: wgpu::ComputePipeline,
+    softmax: wgpu::ComputePipeline,
+    layer_norm: wgpu::ComputePipeline,
+}
+
+impl Kernels {
+    pub fn new() -> Result<Self, GraphError> {
+        let instance = wgpu::Instance::default();
+        // Fall back to a software adapter when no hardware adapter is available
+        let adapter = [false, true]
+            .into_iter()
+            .find_map(|force_fallback_adapter| {
+                pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                    power_preference: wgpu::PowerPreference::HighPerformance,
+                    force_fallback_adapter,
+                    compatible_surface: None,
+                }))
+            })
+            .ok_or_else(|| GraphError::WgpuError("no adapter found".into()))?;
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                required_features: wgpu::Features::empty(),
+                required_limits: adapter.limits(),
+            },
+            None,
+        ))
+        .map_err(|e| GraphError::WgpuError(e.to_string()))?;
+
+        let pipeline = |source: &str| {
+            let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(source.into()),
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: None,
+                layout: None,
+                module: &module,
+                entry_point: "main",
+            })
+        };
+        let matmul = pipeline(include_str!("shaders/matmul.wgsl"));
+        let softmax = pipeline(include_str!("shaders/softmax.wgsl"));
+        let layer_norm = pipeline(include_str!("shaders/layer_norm.wgsl"));
+
+        Ok(Self {
+            device,
+            queue,
+            matmul,
+            softmax,
+            layer_norm,
+        })
+    }
+
+    // Runs a kernel over `works` invocations. Binding 0 holds the parameters, followed by
+    // the inputs and the output.
+    fn dispatch(
+        &self,
+        pipeline: &wgpu::ComputePipeline,
+        params: &[u32],
+        inputs: &[&[f32]],
+        out_len: usize,
+        works: usize,
+    ) -> Vec<f32> {
+        let bytes = |data: &[f32]| {
+            data.iter()
+                .flat_map(|f| f.to_le_bytes())
+                .collect::<Vec<_>>()
+        };
+        let params = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: &params
+                    .iter()
+                    .flat_map(|p| p.to_le_bytes())
+                    .collect::<Vec<_>>(),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+        let inputs = inputs
+            .iter()
+            .map(|inp| {
+                self.device
+                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: None,
+                        contents: &bytes(inp),
+                        usage: wgpu::BufferUsages::STORAGE,
+                    })
+            })
+            .collect::<Vec<_>>();
+        let size = (out_len * std::mem::size_of::<f32>()) as u64;
+        let out = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let entries = std::iter::once(&params)
+            .chain(inputs.iter())
+            .chain(std::iter::once(&out))
+            .enumerate()
+            .map(|(i, b)| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource: b.as_entire_binding(),
+            })
+            .collect::<Vec<_>>();
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        });
+
+        // Workgroups are laid out in two dimensions, so that large tensors do not exceed
+        // the limit of workgroups per dimension
+        let groups = works.div_ceil(WORKGROUP_SIZE).max(1);
+        let groups_x = groups.min(MAX_WORKGROUPS);
+        let groups_y = groups.div_ceil(groups_x);
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(groups_x as u32, groups_y as u32, 1);
+        }
+        encoder.copy_buffer_to_buffer(&out, 0, &staging, 0, size);
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = staging.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |_| {});
+        self.device.poll(wgpu::Maintain::Wait);
+        let result = slice
+            .get_mapped_range()
+            .chunks_exact(std::mem::size_of::<f32>())
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        staging.unmap();
+        result
+    }
+
+    pub fn matmul<A: TensorOps<f32>, B: TensorOps<f32>>(
+        &self,
+        a: &A,
+        b: &B,
+    ) -> Result<Tensor<f32>, TensorError> {
+        if a.dim() < 2 || b.dim() < 2 {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let (m, n) = (a.shape()[a.dim() - 2], a.shape()[a.dim() - 1]);
+        let p = b.shape()[b.dim() - 1];
+        let (a_lead, b_lead) = (&a.shape()[..a.dim() - 2], &b.shape()[..b.dim() - 2]);
+        // The matrices of the smaller operand are broadcast over the bigger one
+        let (long, short) = if a_lead.len() >= b_lead.len() {
+            (a_lead, b_lead)
+        } else {
+            (b_lead, a_lead)
+        };
+        if b.shape()[b.dim() - 2] != n || !long.ends_with(short) {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let a_mats = a_lead.iter().product::<usize>();
+        let b_mats = b_lead.iter().product::<usize>();
+        let mats = a_mats.max(b_mats);
+        let works = mats * m * p;
+        let params = [m, n, p, a_mats, b_mats, works, 0, 0].map(|v| v as u32);
+        let data = self.dispatch(&self.matmul, &params, &[a.blob(), b.blob()], works, works);
+        let mut shape = long.to_vec();
+        shape.extend([m, p]);
+        Tensor::raw(&shape, data)
+    }
+
+    // Runs a kernel that maps each row (Last dimension) of a tensor to a new row
+    fn rows<T: TensorOps<f32>>(
+        &self,
+        pipeline: &wgpu::ComputePipeline,
+        a: &T,
+    ) -> Result<Tensor<f32>, TensorError> {
+        let n = *a.shape().last().ok_or(TensorError::UnexpectedShape)?;
+        if n == 0 {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let works = a.size() / n;
+        let params = [n, works, 0, 0].map(|v| v as u32);
+        let data = self.dispatch(pipeline, &params, &[a.blob()], a.size(), works);
+        Tensor::raw(a.shape(), data)
+    }
+
+    pub fn softmax<T: TensorOps<f32>>(&self, a: &T) -> Result<Tensor<f32>, TensorError> {
+        self.rows(&self.softmax, a)
+    }
+
+    pub fn layer_norm<T: TensorOps<f32>>(&self, a: &T) -> Result<Tensor<f32>, TensorError> {
+        self.rows(&self.layer_norm, a)
+    }
+
+    // Runs the forward pass of a function, on the GPU when there is a kernel for it
+    pub fn run(
+        &self,
+        f: &mut dyn Function,
+        inps: &[&GeneralTensor],
+        training: bool,
+        out: &mut Tensor<f32>,
+    ) -> Result<(), TensorError> {
+        let any = f as &mut dyn Any;
+        *out = if any.is::<MatMul>() {
+            self.matmul(inps[0].as_float()?, inps[1].as_float()?)?
+        } else if any.is::<MatMulAdd>() {
+            let mut product = self.matmul(inps[0].as_float()?, inps[1].as_float()?)?;
+            let bias = inps[2].as_float()?.blob();
+            if product.shape().last() != Some(&bias.len()) {
+                return Err(TensorError::UnexpectedShape);
+            }
+            for row in product.blob_mut().chunks_mut(bias.len()) {
+                for (o, b) in row.iter_mut().zip(bias.iter()) {
+                    *o += b;
+                }
+            }
+            product
+        } else if let Some(softmax) = any.downcast_mut::<Softmax>() {
+            // The output is kept for the backward pass
+            let probs = self.softmax(inps[0].as_float()?)?;
+            softmax.out = Arc::new(probs.clone());
+            probs
+        } else if let Some(layer_norm) = any.downcast_mut::<LayerNorm>() {
+            layer_norm.norm = Arc::new(self.layer_norm(inps[0].as_float()?)?);
+            let scaled = (&layer_norm.norm.view() * inps[1].as_float()?)?;
+            match inps.get(2) {
+                Some(bias) => (&scaled + bias.as_float()?)?,
+                None => scaled,
+            }
+        } else {
+            return f.run_into(inps, training, out);
+        };
+        Ok(())
+    }
+
+    // Runs the backward pass of a function, on the GPU when there is a kernel for it
+    pub fn grad(
+        &self,
+        f: &dyn Function,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        let any = f as &dyn Any;
+        if any.is::<MatMul>() || any.is::<MatMulAdd>() {
+            let (a, b) = (inps[0].as_float()?, inps[1].as_float()?);
+            let mut grads = vec![
+                self.matmul(out_grad, &b.transpose()?)?,
+                self.matmul(&a.transpose()?, out_grad)?,
+            ];
+            if any.is::<MatMulAdd>() {
+                grads.push(out_grad.clone());
+            }
+            Ok(grads)
+        } else {
+            f.grad(inps, out_grad)
+        }
+    }
+}
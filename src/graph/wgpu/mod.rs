@@ -0,0 +1,201 @@
+mod kernels;
+use super::*;
+pub use kernels::Kernels;
+
+// A graph that keeps its tensors on the CPU, but dispatches the heavy functions
+// (MatMul, Softmax and LayerNorm) to WGSL compute kernels through wgpu. Works on
+// Vulkan, Metal, DirectX and OpenGL. Other functions run on the CPU.
+#[derive(Clone)]
+pub struct WgpuGraph {
+    graph: CpuGraph,
+    kernels: Arc<Kernels>,
+}
+
+impl WgpuGraph {
+    pub fn new() -> Result<Self, GraphError> {
+        Ok(Self {
+            graph: CpuGraph::new(),
+            kernels: Arc::new(Kernels::new()?),
+        })
+    }
+}
+
+impl Graph for WgpuGraph {
+    fn alloc(
+        &mut self,
+        t: Tensor<Float>,
+        is_param: bool,
+        name: String,
+    ) -> Result<TensorId, GraphError> {
+        self.graph.alloc(t, is_param, name)
+    }
+    fn alloc_usize(&mut self, t: Tensor<usize>, name: String) -> Result<TensorId, GraphError> {
+        self.graph.alloc_usize(t, name)
+    }
+    fn params(&self) -> &[TensorId] {
+        self.graph.params()
+    }
+    fn load<T: TensorOps<Float>>(
+        &mut self,
+        tensor_id: TensorId,
+        tensor: &T,
+    ) -> Result<(), GraphError> {
+        self.graph.load(tensor_id, tensor)
+    }
+    fn load_usize<T: TensorOps<usize>>(
+        &mut self,
+        tensor_id: TensorId,
+        tensor: &T,
+    ) -> Result<(), GraphError> {
+        self.graph.load_usize(tensor_id, tensor)
+    }
+    fn load_grad<T: TensorOps<Float>>(
+        &mut self,
+        tensor_id: TensorId,
+        tensor: &T,
+    ) -> Result<(), GraphError> {
+        self.graph.load_grad(tensor_id, tensor)
+    }
+    fn zero_grad(&mut self) -> Result<(), GraphError> {
+        self.graph.zero_grad()
+    }
+    fn name_of(&self, id: TensorId) -> Result<&String, GraphError> {
+        self.graph.name_of(id)
+    }
+    fn fetch(&mut self, id: TensorId, grad: bool) -> Result<(), GraphError> {
+        self.graph.fetch(id, grad)
+    }
+    fn get(&self, id: TensorId) -> Result<&GeneralTensor, GraphError> {
+        self.graph.get(id)
+    }
+    fn get_grad(&self, id: TensorId) -> Result<&Tensor<Float>, GraphError> {
+        self.graph.get_grad(id)
+    }
+    fn backward_all(&mut self, id: TensorId, limit: Option<usize>) -> Result<Float, GraphError> {
+        let kernels = self.kernels.clone();
+        self.graph.backward_with(id, limit, |f, inps, out_grad| {
+            Ok(kernels.grad(f, inps, out_grad)?)
+        })
+    }
+    fn forward(&mut self, training: bool) -> Result<(), GraphError> {
+        let kernels = self.kernels.clone();
+        self.graph.forward_with(training, |f, inps, training, out| {
+            Ok(kernels.run(f, inps, training, out)?)
+        })
+    }
+    fn call(
+        &mut self,
+        f: Box<dyn Function>,
+        tensor_ids: &[TensorId],
+    ) -> Result<TensorId, GraphError> {
+        self.graph.call(f, tensor_ids)
+    }
+    fn optimize<O: Optimizer>(
+        &mut self,
+        optimizer: &O,
+        learning_rate: Float,
+    ) -> Result<(), GraphError> {
+        self.graph.optimize(optimizer, learning_rate)
+    }
+    fn optimizer_step(&self) -> usize {
+        self.graph.optimizer_step()
+    }
+    fn set_frozen(&mut self, id: TensorId, frozen: bool) -> Result<(), GraphError> {
+        self.graph.set_frozen(id, frozen)
+    }
+    fn is_frozen(&self, id: TensorId) -> bool {
+        self.graph.is_frozen(id)
+    }
+    fn get_optimizer_state(&self) -> Result<OptimizerState, GraphError> {
+        self.graph.get_optimizer_state()
+    }
+    fn set_optimizer_state(&mut self, state: &OptimizerState) -> Result<(), GraphError> {
+        self.graph.set_optimizer_state(state)
+    }
+    fn fuse(&mut self, keep: &[TensorId]) -> Result<usize, GraphError> {
+        self.graph.fuse(keep)
+    }
+    fn seed(&mut self, seed: u64) {
+        self.graph.seed(seed)
+    }
+    fn to_dot(&self) -> String {
+        self.graph.to_dot()
+    }
+    fn to_onnx(
+        &self,
+        inputs: &[TensorId],
+        outputs: &[(TensorId, &str)],
+    ) -> Result<Vec<u8>, GraphError> {
+        self.graph.to_onnx(inputs, outputs)
+    }
+    fn set_profiling(&mut self, enabled: bool) {
+        self.graph.set_profiling(enabled)
+    }
+    fn share_inputs(&mut self, other: &Self) -> Result<(), GraphError> {
+        self.graph.share_inputs(&other.graph)
+    }
+    fn release_inputs(&mut self) {
+        self.graph.release_inputs()
+    }
+    fn set_precision(&mut self, precision: Precision) -> Result<(), GraphError> {
+        self.graph.set_precision(precision)
+    }
+    fn set_loss_scale(&mut self, scale: Float) {
+        self.graph.set_loss_scale(scale)
+    }
+    fn profile(&self) -> Option<Profile> {
+        self.graph.profile()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::funcs::{LayerNorm, MatMul, Softmax, Transpose};
+
+    // Attention-like block: layer_norm(softmax(h ^ h^T) ^ h), where h = x ^ w
+    fn build<G: Graph>(g: &mut G, inps: &[Tensor<Float>]) -> Result<TensorId, GraphError> {
+        let ids = inps
+            .iter()
+            .map(|t| g.alloc(t.clone(), true, "inp".into()))
+            .collect::<Result<Vec<_>, GraphError>>()?;
+        let h = g.call(MatMul::new(), &[ids[0], ids[1]])?;
+        let h_t = g.call(Transpose::new(), &[h])?;
+        let scores = g.call(MatMul::new(), &[h, h_t])?;
+        let att = g.call(Softmax::new(), &[scores])?;
+        let o = g.call(MatMul::new(), &[att, h])?;
+        g.call(LayerNorm::new(), &[o, ids[2], ids[3]])
+    }
+
+    #[test]
+    fn test_matches_cpu() {
+        let mut rng = rand::thread_rng();
+        let inps = [&[3, 5, 8][..], &[8, 6], &[6], &[6]]
+            .iter()
+            .map(|s| Tensor::<Float>::rand_range(&mut rng, -1., 1., s))
+            .collect::<Vec<_>>();
+
+        let mut cpu = CpuGraph::new();
+        let mut gpu = WgpuGraph::new().unwrap();
+        let cpu_out = build(&mut cpu, &inps).unwrap();
+        let gpu_out = build(&mut gpu, &inps).unwrap();
+        cpu.forward(true).unwrap();
+        gpu.forward(true).unwrap();
+        cpu.backward_all(cpu_out, None).unwrap();
+        gpu.backward_all(gpu_out, None).unwrap();
+
+        let assert_close = |a: &Tensor<Float>, b: &Tensor<Float>| {
+            assert_eq!(a.shape(), b.shape());
+            for (x, y) in a.blob().iter().zip(b.blob().iter()) {
+                assert!((x - y).abs() < 1e-4, "{} != {}", x, y);
+            }
+        };
+        assert_close(
+            cpu.get(cpu_out).unwrap().as_float().unwrap(),
+            gpu.get(gpu_out).unwrap().as_float().unwrap(),
+        );
+        for (c, g) in cpu.params().iter().zip(gpu.params().iter()) {
+            assert_close(cpu.get_grad(*c).unwrap(), gpu.get_grad(*g).unwrap());
+        }
+    }
+}
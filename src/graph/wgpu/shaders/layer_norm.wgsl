@@ -0,0 +1,36 @@
+// Normalizes the last dimension to zero mean and unit variance, one invocation per row.
+// (The coefficients and the bias of the layer-norm are applied on the CPU)
+struct Params {
+    n: u32,
+    works: u32,
+    pad0: u32,
+    pad1: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> a: array<f32>;
+@group(0) @binding(2) var<storage, read_write> out: array<f32>;
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) groups: vec3<u32>) {
+    let wid = gid.x + gid.y * groups.x * 64u;
+    if (wid >= params.works) {
+        return;
+    }
+    let off = wid * params.n;
+    let size_inv = 1.0 / f32(params.n);
+    var avg = 0.0;
+    for (var i = 0u; i < params.n; i = i + 1u) {
+        avg = avg + a[off + i];
+    }
+    avg = avg * size_inv;
+    var var_sum = 0.0;
+    for (var i = 0u; i < params.n; i = i + 1u) {
+        let d = a[off + i] - avg;
+        var_sum = var_sum + d * d;
+    }
+    let var_inv = 1.0 / sqrt(var_sum * size_inv + 1e-5);
+    for (var i = 0u; i < params.n; i = i + 1u) {
+        out[off + i] = (a[off + i] - avg) * var_inv;
+    }
+}
@@ -0,0 +1,36 @@
+// Batched matrix multiplication, one invocation per output element.
+// The operand with fewer matrices is broadcast over the other one.
+struct Params {
+    m: u32,
+    n: u32,
+    p: u32,
+    a_mats: u32,
+    b_mats: u32,
+    works: u32,
+    pad0: u32,
+    pad1: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> a: array<f32>;
+@group(0) @binding(2) var<storage, read> b: array<f32>;
+@group(0) @binding(3) var<storage, read_write> out: array<f32>;
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) groups: vec3<u32>) {
+    let wid = gid.x + gid.y * groups.x * 64u;
+    if (wid >= params.works) {
+        return;
+    }
+    let mp = params.m * params.p;
+    let id = wid / mp;
+    let i = (wid % mp) / params.p;
+    let j = wid % params.p;
+    let a_off = (id % params.a_mats) * params.m * params.n + i * params.n;
+    let b_off = (id % params.b_mats) * params.n * params.p + j;
+    var sum = 0.0;
+    for (var k = 0u; k < params.n; k = k + 1u) {
+        sum = sum + a[a_off + k] * b[b_off + k * params.p];
+    }
+    out[wid] = sum;
+}
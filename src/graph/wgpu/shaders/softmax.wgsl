@@ -0,0 +1,33 @@
+// Softmax over the last dimension, one invocation per row.
+struct Params {
+    n: u32,
+    works: u32,
+    pad0: u32,
+    pad1: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> a: array<f32>;
+@group(0) @binding(2) var<storage, read_write> out: array<f32>;
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) groups: vec3<u32>) {
+    let wid = gid.x + gid.y * groups.x * 64u;
+    if (wid >= params.works) {
+        return;
+    }
+    let off = wid * params.n;
+    var mx = a[off];
+    for (var i = 1u; i < params.n; i = i + 1u) {
+        mx = max(mx, a[off + i]);
+    }
+    var sum = 0.0;
+    for (var i = 0u; i < params.n; i = i + 1u) {
+        let e = exp(a[off + i] - mx);
+        out[off + i] = e;
+        sum = sum + e;
+    }
+    for (var i = 0u; i < params.n; i = i + 1u) {
+        out[off + i] = out[off + i] / sum;
+    }
+}
@@ -1,6 +1,34 @@
+#[cfg(all(feature = "gpu", feature = "f64"))]
+compile_error!("the `gpu` feature does not support `f64` tensors");
+#[cfg(all(feature = "wgpu", feature = "f64"))]
+compile_error!("the `wgpu` feature does not support `f64` tensors");
+#[cfg(all(feature = "cuda", feature = "f64"))]
+compile_error!("the `cuda` feature does not support `f64` tensors");
+
+pub mod callback;
+pub mod chat;
+pub mod checkpoint;
+pub mod compute;
+pub mod dataset;
+pub mod evaluate;
+#[cfg(feature = "cdylib")]
+pub mod ffi;
 pub mod funcs;
+pub mod gguf;
 pub mod gpt;
+pub mod gpt2;
 pub mod graph;
+pub mod metrics;
+pub mod migrate;
+pub mod npy;
 pub mod optimizer;
+pub mod prefix_cache;
+pub mod sampling;
+pub mod scheduler;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod surgery;
 pub mod tensor;
+#[cfg(feature = "tensorboard")]
+pub mod tensorboard;
 pub mod tokenizer;
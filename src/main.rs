@@ -1,22 +1,94 @@
-use femto_gpt::gpt::{TrainingState, GPT};
-use femto_gpt::graph::GraphError;
+use femto_gpt::checkpoint::read_training_state;
+use femto_gpt::compute::{ComputeConfig, Parallelism};
+use femto_gpt::dataset::write_token_file;
+use femto_gpt::evaluate::evaluate_files;
+use femto_gpt::gpt::{
+    split_dataset, Activation, FeedForward, GPTConfig, InitScheme, NanGuard, NormPlacement,
+    PositionalEncoding, Precision, TrainingOptions, GPT,
+};
+use femto_gpt::graph::{CpuGraph, GraphError};
+use femto_gpt::migrate::{migrate, MigrateOptions};
 use femto_gpt::optimizer::AdamW;
-use femto_gpt::tokenizer::{SimpleTokenizer, Tokenizer};
+use femto_gpt::sampling::SamplingParams;
+use femto_gpt::scheduler::Schedule;
+use femto_gpt::surgery::{grow, GrowOptions};
+use femto_gpt::tensor::{Float, TensorOps};
+use femto_gpt::tokenizer::{SavedTokenizer, SimpleTokenizer, Tokenizer};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::fs;
-use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
+use tracing_subscriber::EnvFilter;
 
+// Threads of the subcommands running a model, see `ComputeConfig`
 #[derive(StructOpt, Debug)]
+struct ComputeArgs {
+    #[structopt(long, help = "Number of threads, defaults to the number of cores")]
+    threads: Option<usize>,
+    #[structopt(
+        long,
+        default_value = "both",
+        help = "Split the work between threads across the samples of a batch (batch), within the operations (ops) or both"
+    )]
+    parallelism: Parallelism,
+    #[structopt(long, help = "Pin every thread to its own core (Linux only)")]
+    pin_threads: bool,
+}
+
+impl ComputeArgs {
+    fn apply(&self) -> Result<(), GraphError> {
+        ComputeConfig {
+            threads: self.threads,
+            parallelism: self.parallelism,
+            pin_threads: self.pin_threads,
+        }
+        .apply()
+    }
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "femtogpt", about = "Train and run GPT language-models")]
 enum Cli {
+    #[structopt(about = "Train a model on a text corpus")]
     Train {
         #[structopt(long, default_value = "dataset.txt")]
         dataset: PathBuf,
+        #[structopt(long, help = "JSON file overriding fields of the default model config")]
+        config: Option<PathBuf>,
         #[structopt(long, default_value = "training_state.dat")]
         model: PathBuf,
+        #[structopt(
+            long,
+            help = "Continue training from the checkpoint at --model (With its config, unless --config is given)"
+        )]
+        resume: bool,
+        #[structopt(long, help = "Makes the run reproducible")]
+        seed: Option<u64>,
+        #[structopt(long, default_value = "100000")]
+        steps: usize,
+        #[structopt(long, default_value = "32")]
+        batch_size: usize,
+        #[structopt(
+            long,
+            help = "Save the checkpoint as a directory of shards of at most this many MiB"
+        )]
+        shard_size: Option<usize>,
+        #[structopt(
+            long,
+            help = "Print the time spent in each operation at every checkpoint"
+        )]
+        profile: bool,
+        #[structopt(flatten)]
+        compute: ComputeArgs,
     },
-    Infer {
-        #[structopt(long, default_value = "dataset.txt")]
+    #[structopt(about = "Generate text with a trained model", alias = "infer")]
+    Generate {
+        #[structopt(
+            long,
+            default_value = "dataset.txt",
+            help = "Text the tokenizer is built from, for models saved without their tokenizer"
+        )]
         tokenizer_dataset: PathBuf,
         #[structopt(long, default_value = "training_state.dat")]
         model: PathBuf,
@@ -25,189 +97,602 @@ enum Cli {
         #[structopt(long, default_value = "100")]
         count: usize,
         #[structopt(long, default_value = "0.5")]
-        temperature: f32,
+        temperature: Float,
+        #[structopt(long)]
+        top_k: Option<usize>,
+        #[structopt(long)]
+        top_p: Option<Float>,
+        #[structopt(long, default_value = "1")]
+        repetition_penalty: Float,
+        #[structopt(long)]
+        seed: Option<u64>,
+        #[structopt(flatten)]
+        compute: ComputeArgs,
+    },
+    #[structopt(about = "Compute the loss and bits per character of a model on held-out texts")]
+    Evaluate {
+        #[structopt(
+            long,
+            default_value = "dataset.txt",
+            help = "Text the tokenizer is built from, for models saved without their tokenizer"
+        )]
+        tokenizer_dataset: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(required = true, help = "Text files to evaluate the model on")]
+        files: Vec<PathBuf>,
+        #[structopt(flatten)]
+        compute: ComputeArgs,
+    },
+    #[structopt(about = "Write the attention weights of every head on a text as JSON")]
+    Attention {
+        #[structopt(
+            long,
+            default_value = "dataset.txt",
+            help = "Text the tokenizer is built from, for models saved without their tokenizer"
+        )]
+        tokenizer_dataset: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(
+            long,
+            help = "Text to attend to (At most the context size of the model)"
+        )]
+        text: String,
+        #[structopt(long, default_value = "attention.json")]
+        output: PathBuf,
+    },
+    #[structopt(about = "Print the token ids of a text, or write a corpus as a token file")]
+    Tokenize {
+        #[structopt(long, default_value = "dataset.txt")]
+        dataset: PathBuf,
+        #[structopt(long, help = "Text to tokenize, instead of the whole dataset")]
+        text: Option<String>,
+        #[structopt(long, help = "Token file to write, for memory-mapped datasets")]
+        output: Option<PathBuf>,
+    },
+    #[structopt(about = "Print a summary of a checkpoint")]
+    Info {
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(
+            long,
+            default_value = "32",
+            help = "For estimating the activation memory"
+        )]
+        batch_size: usize,
+    },
+    #[structopt(about = "Convert a checkpoint saved by an earlier version to the current format")]
+    Migrate {
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(
+            long,
+            help = "Where to write the converted checkpoint, instead of --model"
+        )]
+        output: Option<PathBuf>,
+        #[structopt(
+            long,
+            help = "JSON file overriding fields of the default model config, for checkpoints without one"
+        )]
+        config: Option<PathBuf>,
+        #[structopt(
+            long,
+            help = "Dataset of the tokenizer to record in the checkpoint (Required by --config)"
+        )]
+        tokenizer_dataset: Option<PathBuf>,
+        #[structopt(
+            long,
+            help = "Save the checkpoint as a directory of shards of at most this many MiB"
+        )]
+        shard_size: Option<usize>,
+    },
+    #[structopt(
+        about = "Grow a checkpoint into a wider or deeper model computing the same function"
+    )]
+    Grow {
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(long, help = "Where to write the grown checkpoint")]
+        output: PathBuf,
+        #[structopt(
+            long,
+            help = "Insert an identity block before this block (Repeatable, num_layers appends)"
+        )]
+        insert_layer: Vec<usize>,
+        #[structopt(long, help = "New embedding degree, a multiple of the current one")]
+        embedding_degree: Option<usize>,
+        #[structopt(long)]
+        num_heads: Option<usize>,
+        #[structopt(long)]
+        feedforward_degree: Option<usize>,
+        #[structopt(
+            long,
+            default_value = "0.001",
+            help = "Std of the noise breaking the symmetry of the replicated dimensions"
+        )]
+        noise: Float,
     },
+    #[cfg(feature = "serve")]
+    #[structopt(about = "Serve a model through an OpenAI-compatible completions API")]
+    Serve {
+        #[structopt(
+            long,
+            default_value = "dataset.txt",
+            help = "Text the tokenizer is built from, for models saved without their tokenizer"
+        )]
+        tokenizer_dataset: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+        #[structopt(
+            long,
+            default_value = "16",
+            help = "Number of contexts whose attention states are cached across requests (0 disables it)"
+        )]
+        prefix_cache: usize,
+        #[structopt(flatten)]
+        compute: ComputeArgs,
+    },
+}
+
+#[cfg(not(any(feature = "gpu", feature = "wgpu", feature = "cuda")))]
+type DefaultGraph = femto_gpt::graph::CpuGraph;
+#[cfg(all(feature = "wgpu", not(feature = "gpu")))]
+type DefaultGraph = femto_gpt::graph::wgpu::WgpuGraph;
+#[cfg(all(feature = "cuda", not(any(feature = "gpu", feature = "wgpu"))))]
+type DefaultGraph = femto_gpt::graph::cuda::MatMulOffloadGraph;
+#[cfg(feature = "gpu")]
+type DefaultGraph = femto_gpt::graph::gpu::GpuGraph;
+
+const IS_GPU: bool = cfg!(any(feature = "gpu", feature = "wgpu", feature = "cuda"));
+
+fn new_graph() -> Result<DefaultGraph, GraphError> {
+    #[cfg(not(any(feature = "gpu", feature = "wgpu", feature = "cuda")))]
+    return Ok(DefaultGraph::new());
+    #[cfg(any(feature = "gpu", feature = "wgpu", feature = "cuda"))]
+    return DefaultGraph::new();
+}
+
+fn default_config(vocab_size: usize) -> GPTConfig {
+    GPTConfig {
+        vocab_size,
+        num_tokens: 64,
+        embedding_degree: 64,
+        num_layers: 4,
+        num_heads: 4,
+        num_kv_heads: 4, // 1 for multi-query, a divisor of num_heads for grouped-query attention
+        head_size: None, // Defaults to embedding_degree / num_heads
+        dropout: 0.0,
+        positional_encoding: PositionalEncoding::Sinusoidal, // Learned, Sinusoidal, Rope or Alibi
+        activation: Activation::Gelu,                        // Or Relu
+        feedforward: FeedForward::Mlp,                       // Or SwiGlu
+        feedforward_multiplier: 4.0,
+        norm_placement: NormPlacement::Original, // Original, PreNorm or PostNorm
+        final_norm: true,
+        bias: true,                // Set to false for a bias-free model
+        precision: Precision::F32, // F16 or Bf16 to store the activations in half precision
+        lora: None,
+        classifier: None,
+        init: InitScheme::Scaled { std: 0.02 }, // Or Legacy
+        prefix_lm: false,
+    }
+}
+
+// The default config, with the fields given in the JSON file (If any) replaced. The vocab
+// size always comes from the tokenizer.
+fn load_config(path: Option<&Path>, vocab_size: usize) -> Result<GPTConfig, GraphError> {
+    let invalid = |e: serde_json::Error| GraphError::DeserializationError(e.to_string());
+    let mut config = serde_json::to_value(default_config(vocab_size)).map_err(invalid)?;
+    if let Some(path) = path {
+        let overrides: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path)?).map_err(invalid)?;
+        let overrides = overrides.as_object().ok_or_else(|| {
+            GraphError::DeserializationError("the config should be a JSON object".into())
+        })?;
+        for (key, value) in overrides {
+            config[key] = value.clone();
+        }
+    }
+    config["vocab_size"] = vocab_size.into();
+    serde_json::from_value(config).map_err(invalid)
+}
+
+fn new_gpt<R: Rng>(
+    rng: &mut R,
+    config: GPTConfig,
+    batch_size: usize,
+) -> Result<GPT<DefaultGraph>, GraphError> {
+    let mut gpt = GPT::new(
+        rng,
+        new_graph()?,
+        IS_GPU.then_some(batch_size), // Pre-allocate batches only when using GPUs
+        config,
+    )?;
+    gpt.fuse()?;
+    gpt.sync()?;
+    Ok(gpt)
+}
+
+// The model of a checkpoint, with the config it stores
+fn load_gpt(path: &Path, batch_size: usize) -> Result<GPT<DefaultGraph>, GraphError> {
+    let mut gpt = GPT::load_from(new_graph()?, IS_GPU.then_some(batch_size), path)?;
+    gpt.fuse()?;
+    gpt.sync()?;
+    Ok(gpt)
+}
+
+// The tokenizer saved with the model, or the one built from `tokenizer_dataset`
+fn load_tokenizer(
+    gpt: &GPT<DefaultGraph>,
+    tokenizer_dataset: &Path,
+) -> Result<SavedTokenizer, GraphError> {
+    if let Some(tokenizer) = gpt.tokenizer() {
+        return Ok(tokenizer.clone());
+    }
+    let tokenizer = SimpleTokenizer::new(&fs::read_to_string(tokenizer_dataset)?);
+    gpt.check_tokenizer(&tokenizer)?;
+    Ok(SavedTokenizer::Simple(tokenizer))
 }
 
 fn main() -> Result<(), GraphError> {
-    #[cfg(not(feature = "gpu"))]
-    let graph = femto_gpt::graph::CpuGraph::new();
-    #[cfg(not(feature = "gpu"))]
-    let is_gpu = false;
-
-    #[cfg(feature = "gpu")]
-    let graph = femto_gpt::graph::gpu::GpuGraph::new()?;
-    #[cfg(feature = "gpu")]
-    let is_gpu = true;
-
-    let batch_size = 32;
-    let num_tokens = 64;
-    let embedding_degree = 64;
-    let num_layers = 4;
-    let num_heads = 4;
-    let head_size = embedding_degree / num_heads;
-    let dropout = 0.0;
-    assert_eq!(num_heads * head_size, embedding_degree);
-
-    let cli = Cli::from_args();
-    match cli {
-        Cli::Infer {
+    // Progress is logged at the info level, set RUST_LOG to change the verbosity
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    match Cli::from_args() {
+        Cli::Generate {
             tokenizer_dataset,
             model,
             prompt,
             count,
             temperature,
+            top_k,
+            top_p,
+            repetition_penalty,
+            seed,
+            compute,
         } => {
-            let training_state_path = &model.clone();
-
-            let mut rng = rand::thread_rng();
-
-            // Create a unique char-to-int mapping for all unique characters inside our dataset
-            let dataset_char = fs::read_to_string(tokenizer_dataset)
-                .expect("Should have been able to read the file");
-            let tokenizer = SimpleTokenizer::new(&dataset_char);
-
-            assert_eq!(num_heads * head_size, embedding_degree);
-
-            let vocab_size = tokenizer.vocab_size();
-            println!("Vocab-size: {} unique characters", vocab_size);
-            let mut gpt = GPT::new(
-                &mut rng,
-                graph,
-                is_gpu.then(|| batch_size), // Pre-allocate batches only when using GPUs
-                vocab_size,
-                embedding_degree,
-                num_tokens,
-                num_layers,
-                num_heads,
-                head_size,
-                dropout,
-            )?;
-
-            gpt.sync()?;
-
-            let mut ts_file = fs::File::open(&training_state_path).unwrap();
-            let mut bytes = Vec::new();
-            ts_file.read_to_end(&mut bytes).unwrap();
-            let ts: TrainingState = bincode::deserialize(&bytes).unwrap();
-            gpt.set_training_state(ts, true)?;
+            compute.apply()?;
+            let params = SamplingParams {
+                max_tokens: count,
+                temperature,
+                top_k,
+                top_p,
+                repetition_penalty,
+                seed,
+                ..Default::default()
+            };
 
-            println!("Generating text:");
+            let mut gpt = load_gpt(&model, 1)?;
+            let tokenizer = load_tokenizer(&gpt, &tokenizer_dataset)?;
 
             let inference = gpt.infer(
-                &mut rng,
+                &mut rand::thread_rng(),
                 &tokenizer.tokenize(&prompt),
-                count,
-                temperature,
+                &params,
                 |_ch| {},
             )?;
-
-            // Generate 100 character with the currently trained model
             println!("{}", tokenizer.untokenize(&inference));
 
             Ok(())
         }
-        Cli::Train { dataset, model } => {
-            let training_state_path = &model.clone();
+        Cli::Evaluate {
+            tokenizer_dataset,
+            model,
+            files,
+            compute,
+        } => {
+            compute.apply()?;
+            let mut gpt = load_gpt(&model, 1)?;
+            let tokenizer = load_tokenizer(&gpt, &tokenizer_dataset)?;
+            print!("{}", evaluate_files(&mut gpt, &tokenizer, &files)?);
+            Ok(())
+        }
+        Cli::Attention {
+            tokenizer_dataset,
+            model,
+            text,
+            output,
+        } => {
+            let mut gpt = load_gpt(&model, 1)?;
+            let tokenizer = load_tokenizer(&gpt, &tokenizer_dataset)?;
+            let tokens = tokenizer.tokenize(&text);
+            let layers = gpt.attention(&tokens)?;
+            // `layers[l][h][i][j]` is the weight of token j in the attention of head h of
+            // layer l at token i
+            let json = serde_json::json!({
+                "tokens": tokens
+                    .iter()
+                    .map(|t| tokenizer.untokenize(&[*t]))
+                    .collect::<Vec<_>>(),
+                "layers": layers
+                    .iter()
+                    .map(|heads| {
+                        heads
+                            .iter()
+                            .map(|w| w.blob().chunks(tokens.len()).collect::<Vec<_>>())
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>(),
+            });
+            fs::write(&output, json.to_string())?;
+            tracing::info!("Attention weights written to {}", output.display());
+            Ok(())
+        }
+        Cli::Tokenize {
+            dataset,
+            text,
+            output,
+        } => {
+            let dataset_char = fs::read_to_string(dataset)?;
+            let tokenizer = SimpleTokenizer::new(&dataset_char);
+            let tokens = tokenizer.tokenize(text.as_deref().unwrap_or(&dataset_char));
+            match output {
+                Some(output) => {
+                    write_token_file(&output, &tokens)?;
+                    tracing::info!("Wrote {} tokens to {}", tokens.len(), output.display());
+                }
+                None => println!(
+                    "{}",
+                    tokens
+                        .iter()
+                        .map(|t| t.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                ),
+            }
+            Ok(())
+        }
+        #[cfg(feature = "serve")]
+        Cli::Serve {
+            tokenizer_dataset,
+            model,
+            addr,
+            prefix_cache,
+            compute,
+        } => {
+            compute.apply()?;
+            let mut gpt = load_gpt(&model, 1)?;
+            gpt.set_prefix_cache((prefix_cache > 0).then_some(prefix_cache));
+            let tokenizer = load_tokenizer(&gpt, &tokenizer_dataset)?;
+
+            let name = model
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let mut server = femto_gpt::serve::Server::http(&addr, gpt, tokenizer, &name)?;
+            tracing::info!("Serving {} on http://{}", name, addr);
+            server.run()
+        }
+        Cli::Migrate {
+            model,
+            output,
+            config,
+            tokenizer_dataset,
+            shard_size,
+        } => {
+            let tokenizer = match tokenizer_dataset {
+                Some(path) => Some(SimpleTokenizer::new(&fs::read_to_string(path)?)),
+                None => None,
+            };
+            let config = match (config, &tokenizer) {
+                (Some(path), Some(tokenizer)) => {
+                    Some(load_config(Some(&path), tokenizer.vocab_size())?)
+                }
+                (Some(_), None) => {
+                    return Err(GraphError::InvalidConfig(
+                        "--config needs --tokenizer-dataset for the vocab size".into(),
+                    ))
+                }
+                (None, _) => None,
+            };
+            let options = MigrateOptions {
+                config,
+                tokenizer: tokenizer.as_ref().and_then(|t| t.saved()),
+                max_shard_bytes: shard_size.map(|mib| mib * 1024 * 1024),
+            };
+            let output = output.unwrap_or_else(|| model.clone());
+            let state = migrate(&model, &output, &options)?;
+            if state.config.is_none() {
+                tracing::warn!(
+                    "The checkpoint has no config, pass --config to load it with `GPT::load_from`"
+                );
+            }
+            tracing::info!("Migrated {} to {}", model.display(), output.display());
+            Ok(())
+        }
+        Cli::Grow {
+            model,
+            output,
+            insert_layer,
+            embedding_degree,
+            num_heads,
+            feedforward_degree,
+            noise,
+        } => {
+            let options = GrowOptions {
+                insert_layers: insert_layer,
+                embedding_degree,
+                num_heads,
+                feedforward_degree,
+                noise,
+            };
+            let state = grow(&mut rand::thread_rng(), &model, &output, &options)?;
+            let num_params = state.tensors.values().map(|t| t.size()).sum::<usize>();
+            tracing::info!(
+                "Grew {} into {} ({} parameters)",
+                model.display(),
+                output.display(),
+                num_params
+            );
+            Ok(())
+        }
+        Cli::Info { model, batch_size } => {
+            let state = read_training_state(&model)?;
+            let mut names = state.tensors.keys().collect::<Vec<_>>();
+            names.sort();
+            let num_params = state.tensors.values().map(|t| t.size()).sum::<usize>();
+            println!("Checkpoint: {}", model.display());
+            println!("Optimizer step: {}", state.optimizer.step);
+            match &state.manifest {
+                Some(manifest) => println!(
+                    "Manifest: femtoGPT {}, {} checksums, tokenizer {}",
+                    manifest.version,
+                    manifest.checksums.len(),
+                    manifest
+                        .tokenizer
+                        .map(|t| format!("{:016x}", t))
+                        .unwrap_or_else(|| "unknown".into())
+                ),
+                None => println!("Manifest: none"),
+            }
+            if let Some(tokenizer) = &state.tokenizer {
+                println!("Tokenizer: saved, {} tokens", tokenizer.vocab_size());
+            }
+            println!("Parameters: {} ({} tensors)", num_params, names.len());
+            for name in names.iter() {
+                println!("  {} {:?}", name, state.tensors[*name].shape());
+            }
+            if let Some(config) = &state.config {
+                println!("Config: {:#?}", config);
+                let gpt = GPT::from_training_state(CpuGraph::new(), None, state.clone())?;
+                println!("{}", gpt.summary(batch_size)?);
+            }
+            Ok(())
+        }
+        Cli::Train {
+            dataset,
+            config,
+            model,
+            resume,
+            seed,
+            steps,
+            batch_size,
+            shard_size,
+            profile,
+            compute,
+        } => {
+            compute.apply()?;
+            let training_state_path = &model;
+            if training_state_path.exists() && !resume {
+                return Err(GraphError::InvalidConfig(format!(
+                    "{} already exists, pass --resume to continue training it",
+                    training_state_path.display()
+                )));
+            }
 
-            let mut rng = rand::thread_rng();
+            let mut rng = match seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            };
 
             // Create a unique char-to-int mapping for all unique characters inside our dataset
-            let dataset_char =
-                fs::read_to_string(dataset).expect("Should have been able to read the file");
+            let dataset_char = fs::read_to_string(dataset)?;
             let tokenizer = SimpleTokenizer::new(&dataset_char);
 
-            let dataset = tokenizer.tokenize(&dataset_char);
+            let tokens = tokenizer.tokenize(&dataset_char);
+            // The last 5% of the text is held out, to see whether the model overfits
+            let (dataset, validation) = split_dataset(&tokens, 0.05);
 
             let vocab_size = tokenizer.vocab_size();
-            println!("Vocab-size: {} unique characters", vocab_size);
-            let mut gpt = GPT::new(
-                &mut rng,
-                graph,
-                is_gpu.then(|| batch_size), // Pre-allocate batches only when using GPUs
-                vocab_size,
-                embedding_degree,
-                num_tokens,
-                num_layers,
-                num_heads,
-                head_size,
-                dropout,
-            )?;
-
-            gpt.sync()?;
-
-            println!("Number of parameters: {}", gpt.num_params());
-
-            // Load training data from train_data directory (If exists)
-            // If you want to reuse training_data of a smaller model in a bigger model, you may
-            // first start again with a new optimizer by setting load_optimizer=false
-            // WARN: YOU CAN ONLY REUSE THE WEIGHTS OF A MODEL WITH DIFFERENT NUM-LAYERS!
-            // IT'S NOT POSSIBLE TO CHANGE OTHER PROPERTIES ONCE THE MODEL IS TRAINED!
-            if training_state_path.is_file() {
-                let mut ts_file = fs::File::open(&training_state_path).unwrap();
-                let mut bytes = Vec::new();
-                ts_file.read_to_end(&mut bytes).unwrap();
-                let ts: TrainingState = bincode::deserialize(&bytes).unwrap();
-                gpt.set_training_state(ts, true)?;
-            }
-
-            println!();
-            println!(
+            tracing::info!("Vocab-size: {} unique characters", vocab_size);
+            let mut gpt = if resume && config.is_none() {
+                // The checkpoint also restores the RNG of the run
+                load_gpt(training_state_path, batch_size)?
+            } else {
+                let config = load_config(config.as_deref(), vocab_size)?;
+                let mut gpt = new_gpt(&mut rng, config, batch_size)?;
+                if let Some(seed) = seed {
+                    gpt.set_seed(seed);
+                }
+                // If you want to reuse training_data of a smaller model in a bigger model, you
+                // may first start again with a new optimizer by setting load_optimizer=false
+                // WARN: YOU CAN ONLY REUSE THE WEIGHTS OF A MODEL WITH DIFFERENT NUM-LAYERS!
+                // IT'S NOT POSSIBLE TO CHANGE OTHER PROPERTIES ONCE THE MODEL IS TRAINED!
+                if resume {
+                    gpt.load_checkpoint(training_state_path, true)?;
+                }
+                gpt
+            };
+            // Resumed runs have to use the tokenizer of the checkpoint
+            gpt.check_tokenizer(&tokenizer)?;
+            gpt.set_tokenizer(&tokenizer);
+            gpt.set_validation_dataset(validation.to_vec());
+            gpt.set_training_options(TrainingOptions {
+                max_grad_norm: Some(1.),
+                eval_interval: Some(500),
+                eval_batches: Some(20),
+                nan_guard: Some(NanGuard {
+                    lr_backoff: Some(0.5),
+                    recovery_steps: 100,
+                }),
+                ..Default::default()
+            });
+
+            tracing::info!("Number of parameters: {}", gpt.num_params());
+            gpt.set_profiling(profile);
+
+            tracing::info!(
                 "Starting the training loop... (This make take hours to converge! be patient!)"
             );
-            println!();
 
             let base_lr = 0.001;
             let min_lr = 0.00001;
             let warmup_steps = 100;
             let decay_steps = 50000;
 
-            let learning_rate = |step| {
-                if step < warmup_steps {
-                    (base_lr / warmup_steps as f32) * step as f32
-                } else {
-                    // Fancy LR tuning, thanks to https://github.com/cutoken!
-                    f32::max(
-                        min_lr,
-                        base_lr
-                            - (base_lr - min_lr) * (step - warmup_steps) as f32
-                                / decay_steps as f32,
-                    )
-                }
-            };
+            // Fancy LR tuning, thanks to https://github.com/cutoken!
+            // (A resumed run continues with the schedule it was saved with)
+            let learning_rate = gpt.schedule().cloned().unwrap_or(Schedule::Warmup {
+                steps: warmup_steps,
+                then: Box::new(Schedule::Linear {
+                    from: base_lr,
+                    to: min_lr,
+                    steps: decay_steps,
+                }),
+            });
 
             let callback = |gpt: &mut GPT<_>| {
+                // Printed before the sample below, whose passes are profiled as well
+                if let Some(report) = gpt.profile() {
+                    println!("{}", report);
+                }
+
                 let mut rng = rand::thread_rng();
-                let inference_temperature = 0.5; // How creative? 0.0 min 1.0 max
+                let params = SamplingParams {
+                    max_tokens: 100,
+                    temperature: 0.5, // How creative? 0.0 min 1.0 max
+                    ..Default::default()
+                };
 
                 println!("Generating text:");
 
-                let inference = gpt.infer(
-                    &mut rng,
-                    &tokenizer.tokenize("\n"),
-                    100,
-                    inference_temperature,
-                    |_ch| {},
-                )?;
+                let inference =
+                    gpt.infer(&mut rng, &tokenizer.tokenize("\n"), &params, |_ch| {})?;
 
                 // Generate 100 character with the currently trained model before
                 // starting the training loop.
                 println!("{}", tokenizer.untokenize(&inference));
 
-                println!("Saving the model...");
-                gpt.sync().unwrap();
-                let ts = gpt.get_training_state().unwrap();
-                let bytes = bincode::serialize(&ts).unwrap();
-                fs::write(training_state_path, &bytes).expect("Unable to write file");
+                tracing::info!("Saving the model...");
+                match shard_size {
+                    Some(mib) => gpt.save_sharded(training_state_path, mib * 1024 * 1024)?,
+                    None => gpt.save_checkpoint(training_state_path)?,
+                }
 
                 Ok(())
             };
 
             // Training loop!
-            #[cfg(not(feature = "gpu"))]
+            #[cfg(not(any(feature = "gpu", feature = "wgpu", feature = "cuda")))]
             gpt.train_cpu(
-                &dataset,
-                100000,
+                dataset,
+                steps,
                 batch_size,
                 None, // or Some(n), limit backward process to last n computations
                 &AdamW::new(),
@@ -215,10 +700,10 @@ fn main() -> Result<(), GraphError> {
                 callback,
             )?;
 
-            #[cfg(feature = "gpu")]
+            #[cfg(any(feature = "gpu", feature = "wgpu", feature = "cuda"))]
             gpt.train(
-                &dataset,
-                100000,
+                dataset,
+                steps,
                 batch_size,
                 None, // or Some(n), limit backward process to last n computations
                 &AdamW::new(),
@@ -0,0 +1,190 @@
+use crate::callback::{StepInfo, TrainCallback, TrainingStats};
+use crate::gpt::{Evaluation, GPT};
+use crate::graph::{Graph, GraphError};
+use crate::tensor::Float;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::ControlFlow;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricsFormat {
+    Csv,
+    Jsonl,
+}
+
+const COLUMNS: [&str; 8] = [
+    "step",
+    "loss",
+    "learning_rate",
+    "grad_norm",
+    "tokens_per_sec",
+    "wall_time",
+    "validation_loss",
+    "perplexity",
+];
+
+// Training callback recording the metrics of every step (And of every validation) to a
+// CSV or JSON Lines file. Values that are unknown are left empty (`null` in JSON). Nothing
+// is logged, combine it with another callback in a tuple to keep the progress logs.
+pub struct MetricsLogger<W: Write> {
+    writer: W,
+    format: MetricsFormat,
+    header_written: bool,
+}
+
+impl MetricsLogger<BufWriter<File>> {
+    // The format is chosen by the extension of the file (CSV for `.csv`, JSONL otherwise)
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, GraphError> {
+        let format = match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("csv") => MetricsFormat::Csv,
+            _ => MetricsFormat::Jsonl,
+        };
+        Ok(Self::new(BufWriter::new(File::create(path)?), format))
+    }
+}
+
+impl<W: Write> MetricsLogger<W> {
+    pub fn new(writer: W, format: MetricsFormat) -> Self {
+        Self {
+            writer,
+            format,
+            header_written: false,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write(&mut self, values: [Option<Float>; 8]) -> Result<(), GraphError> {
+        match self.format {
+            MetricsFormat::Csv => {
+                if !self.header_written {
+                    writeln!(self.writer, "{}", COLUMNS.join(","))?;
+                    self.header_written = true;
+                }
+                let row = values
+                    .iter()
+                    .map(|v| v.map(|v| v.to_string()).unwrap_or_default())
+                    .collect::<Vec<_>>();
+                writeln!(self.writer, "{}", row.join(","))?;
+            }
+            MetricsFormat::Jsonl => {
+                // Non-finite values can not be represented in JSON
+                let fields = COLUMNS
+                    .iter()
+                    .zip(values.iter())
+                    .filter_map(|(k, v)| v.map(|v| (k, v)))
+                    .map(|(k, v)| match v.is_finite() {
+                        true => format!("\"{}\":{}", k, v),
+                        false => format!("\"{}\":null", k),
+                    })
+                    .collect::<Vec<_>>();
+                writeln!(self.writer, "{{{}}}", fields.join(","))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn step_row(info: &StepInfo) -> [Option<Float>; 8] {
+    [
+        Some(info.step as Float),
+        Some(info.loss),
+        Some(info.learning_rate),
+        info.grad_norm,
+        Some(info.tokens_per_sec()),
+        Some(info.wall_time.as_secs_f64() as Float),
+        None,
+        None,
+    ]
+}
+
+fn eval_row(info: &StepInfo, eval: &Evaluation) -> [Option<Float>; 8] {
+    [
+        Some(info.step as Float),
+        None,
+        None,
+        None,
+        None,
+        Some(info.wall_time.as_secs_f64() as Float),
+        Some(eval.loss),
+        Some(eval.perplexity),
+    ]
+}
+
+impl<G: Graph, W: Write> TrainCallback<G> for MetricsLogger<W> {
+    fn on_step_end(
+        &mut self,
+        _gpt: &mut GPT<G>,
+        info: &StepInfo,
+    ) -> Result<ControlFlow<()>, GraphError> {
+        self.write(step_row(info))?;
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn on_eval(
+        &mut self,
+        _gpt: &mut GPT<G>,
+        info: &StepInfo,
+        eval: &Evaluation,
+    ) -> Result<ControlFlow<()>, GraphError> {
+        self.write(eval_row(info, eval))?;
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn on_checkpoint(&mut self, _gpt: &mut GPT<G>) -> Result<(), GraphError> {
+        Ok(self.writer.flush()?)
+    }
+
+    // The statistics of the tensors do not fit in the columns
+    fn on_stats(
+        &mut self,
+        _gpt: &mut GPT<G>,
+        _info: &StepInfo,
+        _stats: &TrainingStats,
+    ) -> Result<(), GraphError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_metrics_logger() {
+        let info = StepInfo {
+            step: 3,
+            loss: 2.5,
+            learning_rate: 0.5,
+            grad_norm: None,
+            tokens: 100,
+            elapsed: Duration::from_millis(500),
+            wall_time: Duration::from_secs(2),
+        };
+        let eval = Evaluation {
+            loss: 1.,
+            perplexity: Float::INFINITY,
+        };
+        let log = |format| {
+            let mut logger = MetricsLogger::new(Vec::new(), format);
+            logger.write(step_row(&info)).unwrap();
+            logger.write(eval_row(&info, &eval)).unwrap();
+            String::from_utf8(logger.into_inner()).unwrap()
+        };
+        assert_eq!(
+            log(MetricsFormat::Csv),
+            "step,loss,learning_rate,grad_norm,tokens_per_sec,wall_time,validation_loss,perplexity\n\
+             3,2.5,0.5,,200,2,,\n\
+             3,,,,,2,1,inf\n"
+        );
+        assert_eq!(
+            log(MetricsFormat::Jsonl),
+            "{\"step\":3,\"loss\":2.5,\"learning_rate\":0.5,\"tokens_per_sec\":200,\"wall_time\":2}\n\
+             {\"step\":3,\"wall_time\":2,\"validation_loss\":1,\"perplexity\":null}\n"
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use crate::checkpoint::{format_version, read_training_state, save_sharded, Manifest};
+use crate::gpt::{GPTConfig, TrainingState, GPT};
+use crate::graph::{CpuGraph, GraphError};
+use crate::optimizer::OptimizerState;
+use crate::tensor::{Float, Tensor};
+use crate::tokenizer::{SavedTokenizer, Tokenizer};
+use bincode::Options;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Conversion of checkpoints saved by earlier versions of femtoGPT to the current format.
+//
+// Training states start with the version of their format (See `checkpoint::FORMAT_VERSION`),
+// except for those of the first version of femtoGPT: a bincode-encoded struct of the
+// parameters and optimizer state, in f32. A new version of the format comes with the
+// conversion from the previous one.
+
+// Training state of the first version
+type FirstVersionState = (
+    HashMap<String, Tensor<f32>>,
+    (usize, HashMap<String, Tensor<f32>>),
+);
+
+fn cast_all(tensors: HashMap<String, Tensor<f32>>) -> HashMap<String, Tensor<Float>> {
+    tensors.into_iter().map(|(k, t)| (k, t.cast())).collect()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MigrateOptions {
+    // Config of the model, for states saved before checkpoints stored it
+    pub config: Option<GPTConfig>,
+    // Tokenizer saved with the state (And recorded in its manifest), unless it already has one
+    pub tokenizer: Option<SavedTokenizer>,
+    // Writes a sharded checkpoint, see `checkpoint::save_sharded`
+    pub max_shard_bytes: Option<usize>,
+}
+
+// Decodes a training state of the current format or of the first version
+pub fn decode_any_version(bytes: &[u8]) -> Result<TrainingState, GraphError> {
+    if format_version(bytes).is_some() {
+        return TrainingState::from_bytes(bytes);
+    }
+    let (tensors, (step, optimizer)) = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize::<FirstVersionState>(bytes)
+        .map_err(|_| {
+            GraphError::DeserializationError(
+                "not a training state saved by any version of femtoGPT".into(),
+            )
+        })?;
+    Ok(TrainingState {
+        tensors: cast_all(tensors),
+        optimizer: OptimizerState {
+            step,
+            state: cast_all(optimizer),
+        },
+        schedule: None,
+        ema: HashMap::new(),
+        progress: None,
+        config: None,
+        manifest: None,
+        swa: None,
+        tokenizer: None,
+    })
+}
+
+// Reads a checkpoint (Sharded or not) saved by any version of femtoGPT
+pub fn read_any_version<P: AsRef<Path>>(path: P) -> Result<TrainingState, GraphError> {
+    let path = path.as_ref();
+    if path.is_dir() {
+        return read_training_state(path);
+    }
+    let state = decode_any_version(&std::fs::read(path)?)?;
+    if let Some(manifest) = &state.manifest {
+        manifest.verify(&state)?;
+    }
+    Ok(state)
+}
+
+// Rewrites the checkpoint at `src` in the current format at `dst` (Which may be `src`),
+// returning the migrated state
+pub fn migrate<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dst: Q,
+    options: &MigrateOptions,
+) -> Result<TrainingState, GraphError> {
+    let mut state = read_any_version(src)?;
+    match (&state.config, &options.config) {
+        (Some(stored), Some(given)) if stored != given => {
+            return Err(GraphError::InvalidConfig(
+                "the checkpoint already stores another config".into(),
+            ));
+        }
+        (None, Some(given)) => state.config = Some(given.clone()),
+        _ => {}
+    }
+    // The tensors have to fit the config
+    if state.config.is_some() {
+        GPT::from_training_state(CpuGraph::new(), None, state.clone())?;
+    }
+    if state.tokenizer.is_none() {
+        state.tokenizer = options.tokenizer.clone();
+    }
+    let fingerprint = state
+        .manifest
+        .as_ref()
+        .and_then(|m| m.tokenizer)
+        .or(state.tokenizer.as_ref().map(|t| t.fingerprint()));
+    state.manifest = Some(Manifest::new(&state, fingerprint));
+
+    let dst = dst.as_ref();
+    match options.max_shard_bytes {
+        Some(max_shard_bytes) => {
+            if dst.is_file() {
+                std::fs::remove_file(dst)?;
+            }
+            save_sharded(&state, dst, max_shard_bytes)?
+        }
+        None => {
+            if dst.is_dir() {
+                std::fs::remove_dir_all(dst)?;
+            }
+            std::fs::write(dst, state.to_bytes()?)?;
+        }
+    }
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tensor::TensorOps;
+
+    #[test]
+    fn test_decode_any_version() {
+        let tensors = HashMap::from([("w".to_string(), Tensor::constant(&[2, 3], 0.5f32))]);
+        let optimizer = HashMap::from([("w_m".to_string(), Tensor::<f32>::zeros(&[2, 3]))]);
+
+        let first = bincode::serialize(&(&tensors, (7usize, &optimizer))).unwrap();
+        let state = decode_any_version(&first).unwrap();
+        assert_eq!(state.tensors["w"].blob(), &[0.5; 6]);
+        assert_eq!(state.optimizer.step, 7);
+        assert_eq!(state.optimizer.state.len(), 1);
+        assert!(state.ema.is_empty() && state.config.is_none());
+        assert!(decode_any_version(&first[..first.len() - 1]).is_err());
+
+        let current = decode_any_version(&state.to_bytes().unwrap()).unwrap();
+        assert_eq!(current.optimizer.step, 7);
+        let mut later = state.to_bytes().unwrap();
+        later[8] += 1;
+        assert!(decode_any_version(&later).is_err());
+    }
+}
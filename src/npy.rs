@@ -0,0 +1,331 @@
+use crate::graph::GraphError;
+use crate::tensor::{Float, Tensor, TensorOps};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+// NumPy's `.npy` files (https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html)
+// and `.npz` archives of them, for inspecting and editing tensors in Python. Tensors are
+// written as little-endian arrays of `Float`, and float16/32/64 arrays are read back. `.npz`
+// archives are zip files of `.npy` files, written uncompressed as `np.savez` does. (Archives
+// compressed by `np.savez_compressed` can't be read)
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+fn invalid(msg: &str) -> GraphError {
+    GraphError::DeserializationError(format!("npy: {}", msg))
+}
+
+pub fn to_npy(t: &Tensor<Float>) -> Vec<u8> {
+    let shape = match t.shape() {
+        [n] => format!("({},)", n),
+        shape => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '<f{}', 'fortran_order': False, 'shape': {}, }}",
+        std::mem::size_of::<Float>(),
+        shape
+    );
+    // The data is aligned to 64 bytes, the header ends with a newline
+    let len = MAGIC.len() + 4 + header.len() + 1;
+    header.extend(std::iter::repeat_n(' ', len.next_multiple_of(64) - len));
+    header.push('\n');
+
+    let mut out = MAGIC.to_vec();
+    out.extend([1, 0]);
+    out.extend((header.len() as u16).to_le_bytes());
+    out.extend(header.as_bytes());
+    for v in t.blob() {
+        out.extend(v.to_le_bytes());
+    }
+    out
+}
+
+// Value of a key of the header, up to the first of the terminators
+fn header_value<'a>(header: &'a str, key: &str, terminators: &[char]) -> Option<&'a str> {
+    let start = header.find(&format!("'{}':", key))? + key.len() + 3;
+    let value = header[start..].trim_start();
+    let end = value.find(terminators)?;
+    Some(&value[..end])
+}
+
+pub fn from_npy(bytes: &[u8]) -> Result<Tensor<Float>, GraphError> {
+    if bytes.len() < 10 || &bytes[..6] != MAGIC {
+        return Err(invalid("not a .npy file"));
+    }
+    let (header_len, offset) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (
+            u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+            12,
+        ),
+        v => return Err(invalid(&format!("unsupported version {}", v))),
+    };
+    let header = bytes
+        .get(offset..offset + header_len)
+        .and_then(|h| std::str::from_utf8(h).ok())
+        .ok_or_else(|| invalid("truncated header"))?;
+    let data = &bytes[offset + header_len..];
+
+    if header_value(header, "fortran_order", &[',', '}']).map(str::trim) != Some("False") {
+        return Err(invalid("only C-ordered arrays are supported"));
+    }
+    let shape = header_value(header, "shape", &[')'])
+        .and_then(|s| s.strip_prefix('('))
+        .ok_or_else(|| invalid("no shape in the header"))?;
+    let shape = shape
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid("invalid shape"))?;
+    let descr = header_value(header, "descr", &[','])
+        .map(|d| d.trim().trim_matches(|c| c == '\'' || c == '"'))
+        .unwrap_or_default();
+
+    let size = shape.iter().product::<usize>();
+    let values: Vec<Float> = match descr {
+        "<f2" => read_values::<2>(data, size, |b| {
+            half::f16::from_le_bytes([b[0], b[1]]).to_f64() as Float
+        })?,
+        "<f4" => read_values::<4>(data, size, |b| {
+            f32::from_le_bytes(b.try_into().unwrap()) as Float
+        })?,
+        "<f8" => read_values::<8>(data, size, |b| {
+            f64::from_le_bytes(b.try_into().unwrap()) as Float
+        })?,
+        _ => return Err(invalid(&format!("unsupported dtype {}", descr))),
+    };
+    Ok(Tensor::raw(&shape, values)?)
+}
+
+fn read_values<const N: usize>(
+    data: &[u8],
+    size: usize,
+    f: impl Fn(&[u8]) -> Float,
+) -> Result<Vec<Float>, GraphError> {
+    if data.len() != size * N {
+        return Err(invalid(&format!(
+            "{} bytes of data, expected {}",
+            data.len(),
+            size * N
+        )));
+    }
+    Ok(data.chunks(N).map(f).collect())
+}
+
+// CRC-32 of the zip entries
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+// Writes the tensors as a `.npz` archive, in the order of their names
+pub fn write_npz<W: Write>(
+    w: &mut W,
+    tensors: &HashMap<String, Tensor<Float>>,
+) -> Result<(), GraphError> {
+    let mut names = tensors.keys().collect::<Vec<_>>();
+    names.sort();
+    let mut offset = 0usize;
+    let mut central = Vec::new();
+    for name in names.iter() {
+        let data = to_npy(&tensors[*name]);
+        let file_name = format!("{}.npy", name);
+        if offset + data.len() > u32::MAX as usize {
+            return Err(GraphError::InvalidConfig(
+                "npz archives of more than 4 GiB are not supported".into(),
+            ));
+        }
+        // Stored (Uncompressed) entries, dated 1980-01-01
+        let mut fields = Vec::new();
+        fields.extend(20u16.to_le_bytes()); // Version needed to extract
+        fields.extend(0u16.to_le_bytes()); // Flags
+        fields.extend(0u16.to_le_bytes()); // Compression method
+        fields.extend(0u16.to_le_bytes()); // Time
+        fields.extend(0x21u16.to_le_bytes()); // Date
+        fields.extend(crc32(&data).to_le_bytes());
+        fields.extend((data.len() as u32).to_le_bytes());
+        fields.extend((data.len() as u32).to_le_bytes());
+        fields.extend((file_name.len() as u16).to_le_bytes());
+        fields.extend(0u16.to_le_bytes()); // Extra field length
+
+        w.write_all(&0x04034b50u32.to_le_bytes())?;
+        w.write_all(&fields)?;
+        w.write_all(file_name.as_bytes())?;
+        w.write_all(&data)?;
+
+        central.extend(0x02014b50u32.to_le_bytes());
+        central.extend(20u16.to_le_bytes()); // Version made by
+        central.extend(&fields);
+        central.extend([0; 6]); // Comment length, disk number, internal attributes
+        central.extend(0u32.to_le_bytes()); // External attributes
+        central.extend((offset as u32).to_le_bytes());
+        central.extend(file_name.as_bytes());
+        offset += 30 + file_name.len() + data.len();
+    }
+    w.write_all(&central)?;
+    w.write_all(&0x06054b50u32.to_le_bytes())?;
+    w.write_all(&[0; 4])?; // Disk numbers
+    w.write_all(&(names.len() as u16).to_le_bytes())?;
+    w.write_all(&(names.len() as u16).to_le_bytes())?;
+    w.write_all(&(central.len() as u32).to_le_bytes())?;
+    w.write_all(&(offset as u32).to_le_bytes())?;
+    w.write_all(&0u16.to_le_bytes())?; // Comment length
+    Ok(())
+}
+
+pub fn read_npz(bytes: &[u8]) -> Result<HashMap<String, Tensor<Float>>, GraphError> {
+    let u16_at = |i: usize| {
+        bytes
+            .get(i..i + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+    };
+    let u32_at = |i: usize| {
+        bytes
+            .get(i..i + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    };
+    let truncated = || GraphError::DeserializationError("npz: truncated archive".into());
+
+    // The end of central directory record, followed by a comment of at most 64 KiB
+    let eocd = (0..bytes.len().saturating_sub(21))
+        .rev()
+        .take(65536)
+        .find(|i| u32_at(*i) == Some(0x06054b50))
+        .ok_or_else(|| GraphError::DeserializationError("npz: not a zip archive".into()))?;
+    let entries = u16_at(eocd + 10).ok_or_else(truncated)? as usize;
+    let mut entry = u32_at(eocd + 16).ok_or_else(truncated)? as usize;
+
+    let mut tensors = HashMap::new();
+    for _ in 0..entries {
+        if u32_at(entry) != Some(0x02014b50) {
+            return Err(truncated());
+        }
+        let method = u16_at(entry + 10).ok_or_else(truncated)?;
+        let crc = u32_at(entry + 16).ok_or_else(truncated)?;
+        let size = u32_at(entry + 20).ok_or_else(truncated)? as usize;
+        let name_len = u16_at(entry + 28).ok_or_else(truncated)? as usize;
+        let extra_len = u16_at(entry + 30).ok_or_else(truncated)? as usize;
+        let comment_len = u16_at(entry + 32).ok_or_else(truncated)? as usize;
+        let local = u32_at(entry + 42).ok_or_else(truncated)? as usize;
+        let name = bytes
+            .get(entry + 46..entry + 46 + name_len)
+            .ok_or_else(truncated)?;
+        let name = String::from_utf8_lossy(name).to_string();
+        entry += 46 + name_len + extra_len + comment_len;
+
+        if method != 0 {
+            return Err(GraphError::DeserializationError(format!(
+                "npz: {} is compressed, save the archive with np.savez",
+                name
+            )));
+        }
+        let start = local
+            + 30
+            + u16_at(local + 26).ok_or_else(truncated)? as usize
+            + u16_at(local + 28).ok_or_else(truncated)? as usize;
+        let data = bytes.get(start..start + size).ok_or_else(truncated)?;
+        if crc32(data) != crc {
+            return Err(GraphError::DeserializationError(format!(
+                "npz: checksum mismatch of {}",
+                name
+            )));
+        }
+        let name = name.strip_suffix(".npy").unwrap_or(&name).to_string();
+        tensors.insert(name, from_npy(data)?);
+    }
+    Ok(tensors)
+}
+
+pub fn save_npz<P: AsRef<Path>>(
+    path: P,
+    tensors: &HashMap<String, Tensor<Float>>,
+) -> Result<(), GraphError> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    write_npz(&mut file, tensors)?;
+    Ok(file.flush()?)
+}
+
+pub fn load_npz<P: AsRef<Path>>(path: P) -> Result<HashMap<String, Tensor<Float>>, GraphError> {
+    read_npz(&std::fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_npy() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        for shape in [vec![2, 3], vec![4], vec![1, 2, 2]] {
+            let size = shape.iter().product::<usize>();
+            let t = Tensor::raw(&shape, (0..size).map(|i| i as Float * 0.5).collect()).unwrap();
+            let bytes = to_npy(&t);
+            let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+            assert_eq!((10 + header_len) % 64, 0);
+            let read = from_npy(&bytes).unwrap();
+            assert_eq!((read.shape(), read.blob()), (t.shape(), t.blob()));
+        }
+
+        // As written by NumPy, with float64 values
+        let npy = |header: &str| {
+            let mut bytes = MAGIC.to_vec();
+            bytes.extend([1, 0]);
+            bytes.extend((header.len() as u16).to_le_bytes());
+            bytes.extend(header.as_bytes());
+            bytes.extend(1.5f64.to_le_bytes());
+            bytes.extend((-2f64).to_le_bytes());
+            bytes
+        };
+        let bytes = npy("{'descr': '<f8', 'fortran_order': False, 'shape': (2,), }          \n");
+        assert_eq!(from_npy(&bytes).unwrap().blob(), &[1.5, -2.]);
+        assert!(from_npy(&bytes[..bytes.len() - 1]).is_err());
+        let fortran = npy("{'descr': '<f8', 'fortran_order': True, 'shape': (2,), }           \n");
+        assert!(from_npy(&fortran).is_err());
+        assert!(from_npy(b"not numpy").is_err());
+    }
+
+    #[test]
+    fn test_npz() {
+        let tensors = HashMap::from([
+            ("a".to_string(), Tensor::raw(&[2], vec![1., 2.]).unwrap()),
+            ("b_c".to_string(), Tensor::constant(&[3, 2], 0.25)),
+        ]);
+        let mut bytes = Vec::new();
+        write_npz(&mut bytes, &tensors).unwrap();
+        let read = read_npz(&bytes).unwrap();
+        assert_eq!(read.len(), 2);
+        for (name, t) in tensors.iter() {
+            assert_eq!(
+                (read[name].shape(), read[name].blob()),
+                (t.shape(), t.blob())
+            );
+        }
+
+        // Corrupted data, and compressed entries
+        let mut corrupted = bytes.clone();
+        corrupted[100] ^= 1;
+        assert!(read_npz(&corrupted).is_err());
+        let mut compressed = bytes.clone();
+        let eocd = bytes.len() - 22;
+        let central = u32::from_le_bytes(bytes[eocd + 16..eocd + 20].try_into().unwrap());
+        compressed[central as usize + 10] = 8;
+        assert!(read_npz(&compressed).is_err());
+        assert!(read_npz(&bytes[..bytes.len() - 30]).is_err());
+    }
+}
@@ -1,42 +1,113 @@
 use serde::{Deserialize, Serialize};
 
-use crate::tensor::{Tensor, TensorError, TensorOps};
+use crate::compute;
+use crate::tensor::{Float, Tensor, TensorError, TensorMutOps, TensorOps};
+use rand::Rng;
+use rand_distr::{Distribution, Normal};
 use rayon::prelude::*;
 use std::collections::HashMap;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct OptimizerState {
     pub step: usize,
-    pub state: HashMap<String, Tensor<f32>>,
+    pub state: HashMap<String, Tensor<Float>>,
 }
 
 #[cfg(feature = "gpu")]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct GpuOptimizer {
-    pub extra_buffers: HashMap<String, usize>,
+    // State buffers (Names and sizes) of each parameter, passed to the kernel in this
+    // order, after the parameter and its gradient
+    pub extra_buffers: HashMap<String, Vec<(String, usize)>>,
+    // Scalar arguments of each parameter, passed to the kernel after the buffers
+    pub extra_args: HashMap<String, Vec<GpuArg>>,
     pub source_code: String,
     pub kernel_name: String,
 }
 
+#[cfg(feature = "gpu")]
+#[derive(Clone, Debug, PartialEq)]
+pub enum GpuArg {
+    Ulong(u64),
+    Float(f32),
+}
+
+// Hyperparameters of a subset of the parameters, e.g. no weight decay for norms, biases
+// and embeddings. Parameters take the settings of the first group matching their name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParamGroup {
+    // Parameters whose names contain any of these patterns belong to the group
+    pub patterns: Vec<String>,
+    // Multiplies the learning rate
+    pub lr_scale: Float,
+    // Replaces the weight decay of the optimizer
+    pub weight_decay: Option<Float>,
+}
+
+impl ParamGroup {
+    pub fn new(patterns: &[&str]) -> Self {
+        Self {
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            lr_scale: 1.,
+            weight_decay: None,
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.patterns.iter().any(|p| name.contains(p.as_str()))
+    }
+
+    // Learning rate scale and weight decay of a parameter
+    pub fn hyperparams(groups: &[ParamGroup], name: &str, weight_decay: Float) -> (Float, Float) {
+        match groups.iter().find(|g| g.matches(name)) {
+            Some(g) => (g.lr_scale, g.weight_decay.unwrap_or(weight_decay)),
+            None => (1., weight_decay),
+        }
+    }
+
+    // Kernel arguments of each parameter: the learning rate scale and the weight decay
+    #[cfg(feature = "gpu")]
+    fn gpu_args(
+        groups: &[ParamGroup],
+        params: &HashMap<String, Vec<usize>>,
+        weight_decay: Float,
+    ) -> HashMap<String, Vec<GpuArg>> {
+        params
+            .keys()
+            .map(|name| {
+                let (lr_scale, weight_decay) = Self::hyperparams(groups, name, weight_decay);
+                (
+                    name.clone(),
+                    vec![GpuArg::Float(lr_scale), GpuArg::Float(weight_decay)],
+                )
+            })
+            .collect()
+    }
+}
+
 pub trait Optimizer: Clone + Serialize + serde::de::DeserializeOwned {
     fn step(
         &self,
-        params: HashMap<String, (&mut Tensor<f32>, &Tensor<f32>)>,
+        params: HashMap<String, (&mut Tensor<Float>, &Tensor<Float>)>,
         optimizer_state: &mut OptimizerState,
-        learning_rate: f32,
+        learning_rate: Float,
     ) -> Result<(), TensorError>;
 
     #[cfg(feature = "gpu")]
     fn gpu_impl(&self, params: &HashMap<String, Vec<usize>>) -> GpuOptimizer;
 }
 
-const EPSILON: f32 = 1e-8;
-
-#[derive(Clone, Serialize, Deserialize)]
+// Hyperparameters can be customized with struct update syntax, e.g.
+// `AdamW { weight_decay: 0.1, ..AdamW::new() }`
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AdamW {
-    beta1: f32,
-    beta2: f32,
-    weight_decay: f32,
+    pub beta1: Float,
+    pub beta2: Float,
+    pub eps: Float,
+    // Decoupled from the gradient, parameters are shrunk by `learning_rate * weight_decay`
+    // on each step
+    pub weight_decay: Float,
+    pub groups: Vec<ParamGroup>,
 }
 
 impl AdamW {
@@ -44,7 +115,9 @@ impl AdamW {
         Self {
             beta1: 0.9,
             beta2: 0.999,
+            eps: 1e-8,
             weight_decay: 0.01,
+            groups: Vec::new(),
         }
     }
 }
@@ -54,51 +127,56 @@ impl AdamW {
 impl Optimizer for AdamW {
     fn step(
         &self,
-        params: HashMap<String, (&mut Tensor<f32>, &Tensor<f32>)>,
+        params: HashMap<String, (&mut Tensor<Float>, &Tensor<Float>)>,
         optimizer_state: &mut OptimizerState,
-        learning_rate: f32,
+        learning_rate: Float,
     ) -> Result<(), TensorError> {
-        for (name, m, v) in params
-            .into_par_iter()
-            .map(|(name, (param, grad))| {
-                let m_key = format!("{}_m", name);
-                let v_key = format!("{}_v", name);
-                let mut m = optimizer_state
-                    .state
-                    .get(&m_key)
-                    .cloned()
-                    .unwrap_or(Tensor::zeros(param.shape()));
-                let mut v = optimizer_state
-                    .state
-                    .get(&v_key)
-                    .cloned()
-                    .unwrap_or(Tensor::zeros(param.shape()));
-
-                // Weight decay
-                *param =
-                    (&*param - &(&*param * &Tensor::scalar(learning_rate * self.weight_decay))?)?;
-
-                m = (&(&Tensor::scalar(self.beta1) * &m)?
-                    + &(&Tensor::scalar(1. - self.beta1) * grad)?)?;
-                v = (&(&Tensor::scalar(self.beta2) * &v)?
-                    + &(&(&Tensor::scalar(1. - self.beta2) * grad)? * grad)?)?;
-
-                let m_hat = (&m
-                    * &Tensor::scalar(
-                        1. / (1. - self.beta1.powi(optimizer_state.step as i32 + 1)),
-                    ))?;
-                let v_hat = (&v
-                    * &Tensor::scalar(
-                        1. / (1. - self.beta2.powi(optimizer_state.step as i32 + 1)),
-                    ))?;
-
-                let v_hat_sqrt_inv = v_hat.map_values(|f| learning_rate / (f.sqrt() + EPSILON));
-
-                *param = (&*param - &(&m_hat * &v_hat_sqrt_inv)?)?;
-                Ok((name, m, v))
-            })
-            .collect::<Result<Vec<_>, TensorError>>()?
-        {
+        for (name, m, v) in compute::install(|| {
+            params
+                .into_par_iter()
+                .map(|(name, (param, grad))| {
+                    let (lr_scale, weight_decay) =
+                        ParamGroup::hyperparams(&self.groups, &name, self.weight_decay);
+                    let learning_rate = learning_rate * lr_scale;
+                    let m_key = format!("{}_m", name);
+                    let v_key = format!("{}_v", name);
+                    let mut m = optimizer_state
+                        .state
+                        .get(&m_key)
+                        .cloned()
+                        .unwrap_or(Tensor::zeros(param.shape()));
+                    let mut v = optimizer_state
+                        .state
+                        .get(&v_key)
+                        .cloned()
+                        .unwrap_or(Tensor::zeros(param.shape()));
+
+                    // Weight decay
+                    *param =
+                        (&*param - &(&*param * &Tensor::scalar(learning_rate * weight_decay))?)?;
+
+                    m = (&(&Tensor::scalar(self.beta1) * &m)?
+                        + &(&Tensor::scalar(1. - self.beta1) * grad)?)?;
+                    v = (&(&Tensor::scalar(self.beta2) * &v)?
+                        + &(&(&Tensor::scalar(1. - self.beta2) * grad)? * grad)?)?;
+
+                    let m_hat = (&m
+                        * &Tensor::scalar(
+                            1. / (1. - self.beta1.powi(optimizer_state.step as i32 + 1)),
+                        ))?;
+                    let v_hat = (&v
+                        * &Tensor::scalar(
+                            1. / (1. - self.beta2.powi(optimizer_state.step as i32 + 1)),
+                        ))?;
+
+                    let v_hat_sqrt_inv =
+                        v_hat.map_values(|f| learning_rate / (f.sqrt() + self.eps));
+
+                    *param = (&*param - &(&m_hat * &v_hat_sqrt_inv)?)?;
+                    Ok((name, m, v))
+                })
+                .collect::<Result<Vec<_>, TensorError>>()
+        })? {
             let m_key = format!("{}_m", name);
             let v_key = format!("{}_v", name);
             optimizer_state.state.insert(m_key, m);
@@ -110,44 +188,753 @@ impl Optimizer for AdamW {
 
     #[cfg(feature = "gpu")]
     fn gpu_impl(&self, params: &HashMap<String, Vec<usize>>) -> GpuOptimizer {
-        let source_code = "
+        let source_code = format!(
+            "
         __kernel void optimizer(__global float *param,
                                 __global float *grad,
                                 __global float *m,
                                 __global float *v,
+                                float lr_scale,
+                                float weight_decay,
                                 float learning_rate,
                                 ulong step,
-                                ulong n) {
+                                ulong n) {{
             uint id = get_global_id(0);
             param += id;
             grad += id;
             m += id;
             v += id;
-            float beta1 = 0.9;
-            float beta2 = 0.999;
-            float weight_decay = 0.01;
-            if(id < n) {
+            float beta1 = {:?};
+            float beta2 = {:?};
+            float eps = {:?};
+            learning_rate *= lr_scale;
+            if(id < n) {{
                 *param = *param - *param * learning_rate * weight_decay;
                 *m = beta1 * (*m) + (1 - beta1) * (*grad);
                 *v = beta2 * (*v) + (1 - beta2) * (*grad) * (*grad);
                 float m_hat = *m / (1.0 - pow(beta1, step + 1));
                 float v_hat = *v / (1.0 - pow(beta2, step + 1));
-                float v_hat_sqrt_inv = learning_rate / (sqrt(v_hat) + 1e-8);
+                float v_hat_sqrt_inv = learning_rate / (sqrt(v_hat) + eps);
                 *param = *param - m_hat * v_hat_sqrt_inv;
+            }}
+        }}",
+            self.beta1, self.beta2, self.eps
+        );
+        GpuOptimizer {
+            source_code,
+            extra_buffers: params
+                .iter()
+                .map(|(k, v)| {
+                    let sz = v.iter().fold(1, |a, b| a * b);
+                    (
+                        k.clone(),
+                        vec![(k.clone() + "_m", sz), (k.clone() + "_v", sz)],
+                    )
+                })
+                .collect(),
+            extra_args: ParamGroup::gpu_args(&self.groups, params, self.weight_decay),
+            kernel_name: "optimizer".into(),
+        }
+    }
+}
+
+// Stochastic gradient descent, with optional (Nesterov) momentum
+// https://pytorch.org/docs/stable/generated/torch.optim.SGD.html
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Sgd {
+    pub momentum: Float,
+    pub dampening: Float,
+    pub nesterov: bool,
+    // L2 penalty, added to the gradient as `weight_decay * param`
+    pub weight_decay: Float,
+    pub groups: Vec<ParamGroup>,
+}
+
+impl Sgd {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Optimizer for Sgd {
+    fn step(
+        &self,
+        params: HashMap<String, (&mut Tensor<Float>, &Tensor<Float>)>,
+        optimizer_state: &mut OptimizerState,
+        learning_rate: Float,
+    ) -> Result<(), TensorError> {
+        for (name, buf) in compute::install(|| {
+            params
+                .into_par_iter()
+                .map(|(name, (param, grad))| {
+                    let (lr_scale, weight_decay) =
+                        ParamGroup::hyperparams(&self.groups, &name, self.weight_decay);
+                    let learning_rate = learning_rate * lr_scale;
+                    let grad = &(grad + &(&*param * &Tensor::scalar(weight_decay))?)?;
+                    if self.momentum == 0. {
+                        *param = (&*param - &(grad * &Tensor::scalar(learning_rate))?)?;
+                        return Ok((name, None));
+                    }
+                    // The buffer starts from the first gradient, without dampening
+                    let buf = match optimizer_state.state.get(&format!("{}_momentum", name)) {
+                        Some(buf) => {
+                            (&(buf * &Tensor::scalar(self.momentum))?
+                                + &(grad * &Tensor::scalar(1. - self.dampening))?)?
+                        }
+                        None => grad.clone(),
+                    };
+                    let update = if self.nesterov {
+                        (grad + &(&buf * &Tensor::scalar(self.momentum))?)?
+                    } else {
+                        buf.clone()
+                    };
+                    *param = (&*param - &(&update * &Tensor::scalar(learning_rate))?)?;
+                    Ok((name, Some(buf)))
+                })
+                .collect::<Result<Vec<_>, TensorError>>()
+        })? {
+            if let Some(buf) = buf {
+                optimizer_state
+                    .state
+                    .insert(format!("{}_momentum", name), buf);
+            }
+        }
+        optimizer_state.step += 1;
+        Ok(())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, params: &HashMap<String, Vec<usize>>) -> GpuOptimizer {
+        let source_code = if self.momentum == 0. {
+            "
+        __kernel void optimizer(__global float *param,
+                                __global float *grad,
+                                float lr_scale,
+                                float weight_decay,
+                                float learning_rate,
+                                ulong step,
+                                ulong n) {
+            uint id = get_global_id(0);
+            if(id < n) {
+                float g = grad[id] + weight_decay * param[id];
+                param[id] -= learning_rate * lr_scale * g;
             }
         }"
-        .into();
+            .into()
+        } else {
+            format!(
+                "
+        __kernel void optimizer(__global float *param,
+                                __global float *grad,
+                                __global float *buf,
+                                float lr_scale,
+                                float weight_decay,
+                                float learning_rate,
+                                ulong step,
+                                ulong n) {{
+            uint id = get_global_id(0);
+            float momentum = {:?};
+            float dampening = {:?};
+            if(id < n) {{
+                float g = grad[id] + weight_decay * param[id];
+                buf[id] = step == 0 ? g : momentum * buf[id] + (1 - dampening) * g;
+                float update = {};
+                param[id] -= learning_rate * lr_scale * update;
+            }}
+        }}",
+                self.momentum,
+                self.dampening,
+                if self.nesterov {
+                    "g + momentum * buf[id]"
+                } else {
+                    "buf[id]"
+                }
+            )
+        };
         GpuOptimizer {
             source_code,
             extra_buffers: params
                 .iter()
                 .map(|(k, v)| {
-                    let sz = v.iter().fold(1, |a, b| a * b);
-                    vec![(k.clone() + "_m", sz), (k.clone() + "_v", sz)]
+                    let sz = v.iter().product();
+                    let buffers = if self.momentum == 0. {
+                        vec![]
+                    } else {
+                        vec![(k.clone() + "_momentum", sz)]
+                    };
+                    (k.clone(), buffers)
+                })
+                .collect(),
+            extra_args: ParamGroup::gpu_args(&self.groups, params, self.weight_decay),
+            kernel_name: "optimizer".into(),
+        }
+    }
+}
+
+// Adafactor (https://arxiv.org/abs/1804.04235), without momentum. The second moments of
+// a matrix are factored into running averages of its row and column sums, so the state
+// of a r x c matrix takes r + c values instead of r * c. Tensors with more than two
+// dimensions are treated as batches of matrices, vectors as single-row matrices.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Adafactor {
+    // The second moment decay at step t is 1 - t^decay_rate
+    pub decay_rate: Float,
+    pub eps: Float,
+    // Updates with a larger root-mean-square are scaled down
+    pub clip_threshold: Float,
+    pub weight_decay: Float,
+    pub groups: Vec<ParamGroup>,
+}
+
+impl Adafactor {
+    pub fn new() -> Self {
+        Self {
+            decay_rate: -0.8,
+            eps: 1e-30,
+            clip_threshold: 1.,
+            weight_decay: 0.,
+            groups: Vec::new(),
+        }
+    }
+
+    fn rows_cols(shape: &[usize]) -> (usize, usize) {
+        match shape {
+            [] => (1, 1),
+            [n] => (1, *n),
+            [.., r, c] => (*r, *c),
+        }
+    }
+}
+
+impl Default for Adafactor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Optimizer for Adafactor {
+    fn step(
+        &self,
+        params: HashMap<String, (&mut Tensor<Float>, &Tensor<Float>)>,
+        optimizer_state: &mut OptimizerState,
+        learning_rate: Float,
+    ) -> Result<(), TensorError> {
+        let beta2 = 1. - ((optimizer_state.step + 1) as Float).powf(self.decay_rate);
+        for (name, row, col) in compute::install(|| {
+            params
+                .into_par_iter()
+                .map(|(name, (param, grad))| {
+                    let (lr_scale, weight_decay) =
+                        ParamGroup::hyperparams(&self.groups, &name, self.weight_decay);
+                    let learning_rate = learning_rate * lr_scale;
+                    let (rows, cols) = Self::rows_cols(param.shape());
+                    let mats = param.size() / (rows * cols);
+                    let load = |key: String, size: usize| {
+                        optimizer_state
+                            .state
+                            .get(&key)
+                            .filter(|t| t.size() == size)
+                            .map(|t| t.blob().to_vec())
+                            .unwrap_or(vec![0.; size])
+                    };
+                    let mut row = load(format!("{}_row", name), mats * rows);
+                    let mut col = load(format!("{}_col", name), mats * cols);
+
+                    let mut update = vec![0.; param.size()];
+                    for (((g, u), r), c) in grad
+                        .blob()
+                        .chunks(rows * cols)
+                        .zip(update.chunks_mut(rows * cols))
+                        .zip(row.chunks_mut(rows))
+                        .zip(col.chunks_mut(cols))
+                    {
+                        for (i, r) in r.iter_mut().enumerate() {
+                            let sum = (0..cols)
+                                .map(|j| g[i * cols + j].powi(2) + self.eps)
+                                .sum::<Float>();
+                            *r = beta2 * *r + (1. - beta2) * sum / cols as Float;
+                        }
+                        for (j, c) in c.iter_mut().enumerate() {
+                            let sum = (0..rows)
+                                .map(|i| g[i * cols + j].powi(2) + self.eps)
+                                .sum::<Float>();
+                            *c = beta2 * *c + (1. - beta2) * sum / rows as Float;
+                        }
+                        let row_mean = r.iter().sum::<Float>() / rows as Float;
+                        for i in 0..rows {
+                            for j in 0..cols {
+                                u[i * cols + j] = g[i * cols + j] / (r[i] / row_mean * c[j]).sqrt();
+                            }
+                        }
+                    }
+
+                    let rms = (update.iter().map(|u| u * u).sum::<Float>() / update.len() as Float)
+                        .sqrt();
+                    let scale = learning_rate / Float::max(1., rms / self.clip_threshold);
+                    let decay = 1. - learning_rate * weight_decay;
+                    let new_param = param
+                        .blob()
+                        .iter()
+                        .zip(update.iter())
+                        .map(|(p, u)| p * decay - scale * u)
+                        .collect();
+                    *param = Tensor::raw(param.shape(), new_param)?;
+                    Ok((name, row, col))
+                })
+                .collect::<Result<Vec<_>, TensorError>>()
+        })? {
+            let (row_len, col_len) = (row.len(), col.len());
+            optimizer_state
+                .state
+                .insert(format!("{}_row", name), Tensor::raw(&[row_len], row)?);
+            optimizer_state
+                .state
+                .insert(format!("{}_col", name), Tensor::raw(&[col_len], col)?);
+        }
+        optimizer_state.step += 1;
+        Ok(())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, params: &HashMap<String, Vec<usize>>) -> GpuOptimizer {
+        // Factored statistics need reductions over whole rows and columns (And the update
+        // over the whole tensor), so each parameter is updated by a single work-item
+        let source_code = format!(
+            "
+        __kernel void optimizer(__global float *param,
+                                __global float *grad,
+                                __global float *row,
+                                __global float *col,
+                                ulong rows,
+                                ulong cols,
+                                float lr_scale,
+                                float weight_decay,
+                                float learning_rate,
+                                ulong step,
+                                ulong n) {{
+            if(get_global_id(0) != 0) {{
+                return;
+            }}
+            float decay_rate = {:?};
+            float eps = {:?};
+            float clip_threshold = {:?};
+            learning_rate *= lr_scale;
+            float beta2 = 1.0 - pow((float)(step + 1), decay_rate);
+            ulong mats = n / (rows * cols);
+            float sum_sq = 0.0;
+            for(ulong b = 0; b < mats; b++) {{
+                __global float *g = grad + b * rows * cols;
+                __global float *r = row + b * rows;
+                __global float *c = col + b * cols;
+                float row_sum = 0.0;
+                for(ulong i = 0; i < rows; i++) {{
+                    float sum = 0.0;
+                    for(ulong j = 0; j < cols; j++) {{
+                        sum += g[i * cols + j] * g[i * cols + j] + eps;
+                    }}
+                    r[i] = beta2 * r[i] + (1.0 - beta2) * sum / cols;
+                    row_sum += r[i];
+                }}
+                for(ulong j = 0; j < cols; j++) {{
+                    float sum = 0.0;
+                    for(ulong i = 0; i < rows; i++) {{
+                        sum += g[i * cols + j] * g[i * cols + j] + eps;
+                    }}
+                    c[j] = beta2 * c[j] + (1.0 - beta2) * sum / rows;
+                }}
+                float row_mean = row_sum / rows;
+                for(ulong i = 0; i < rows; i++) {{
+                    for(ulong j = 0; j < cols; j++) {{
+                        float u = g[i * cols + j] * rsqrt(r[i] / row_mean * c[j]);
+                        sum_sq += u * u;
+                    }}
+                }}
+            }}
+            float scale = learning_rate / fmax(1.0, sqrt(sum_sq / n) / clip_threshold);
+            for(ulong b = 0; b < mats; b++) {{
+                __global float *p = param + b * rows * cols;
+                __global float *g = grad + b * rows * cols;
+                __global float *r = row + b * rows;
+                __global float *c = col + b * cols;
+                float row_sum = 0.0;
+                for(ulong i = 0; i < rows; i++) {{
+                    row_sum += r[i];
+                }}
+                float row_mean = row_sum / rows;
+                for(ulong i = 0; i < rows; i++) {{
+                    for(ulong j = 0; j < cols; j++) {{
+                        float u = g[i * cols + j] * rsqrt(r[i] / row_mean * c[j]);
+                        p[i * cols + j] = p[i * cols + j] * (1.0 - learning_rate * weight_decay)
+                            - scale * u;
+                    }}
+                }}
+            }}
+        }}",
+            self.decay_rate, self.eps, self.clip_threshold
+        );
+        let shapes = params
+            .iter()
+            .map(|(k, v)| (k, Self::rows_cols(v), v.iter().product::<usize>()));
+        GpuOptimizer {
+            source_code,
+            extra_buffers: shapes
+                .clone()
+                .map(|(k, (rows, cols), size)| {
+                    let mats = size / (rows * cols);
+                    (
+                        k.clone(),
+                        vec![
+                            (k.clone() + "_row", mats * rows),
+                            (k.clone() + "_col", mats * cols),
+                        ],
+                    )
+                })
+                .collect(),
+            extra_args: shapes
+                .map(|(k, (rows, cols), _)| {
+                    let (lr_scale, weight_decay) =
+                        ParamGroup::hyperparams(&self.groups, k, self.weight_decay);
+                    let args = vec![
+                        GpuArg::Ulong(rows as u64),
+                        GpuArg::Ulong(cols as u64),
+                        GpuArg::Float(lr_scale),
+                        GpuArg::Float(weight_decay),
+                    ];
+                    (k.clone(), args)
                 })
-                .flatten()
                 .collect(),
             kernel_name: "optimizer".into(),
         }
     }
 }
+
+// Clamps the gradients to [-max_value, max_value], and then scales them down so that
+// their global L2 norm is at most `max_norm`. Returns the norm before norm clipping.
+pub fn clip_gradients(
+    grads: &mut [Tensor<Float>],
+    max_norm: Option<Float>,
+    max_value: Option<Float>,
+) -> Float {
+    if let Some(max_value) = max_value {
+        for grad in grads.iter_mut() {
+            *grad = grad.map_values(|f| f.clamp(-max_value, max_value));
+        }
+    }
+    let norm = grads
+        .iter()
+        .map(|grad| grad.blob().iter().map(|f| f * f).sum::<Float>())
+        .sum::<Float>()
+        .sqrt();
+    if let Some(max_norm) = max_norm {
+        if norm > max_norm {
+            let scale = max_norm / norm;
+            for grad in grads.iter_mut() {
+                *grad = grad.map_values(|f| f * scale);
+            }
+        }
+    }
+    norm
+}
+
+// Annealed Gaussian noise added to the gradients, whose variance decays as
+// `eta / (1 + step)^gamma` (E.g. eta = 0.01 and gamma = 0.55)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GradNoise {
+    pub eta: Float,
+    pub gamma: Float,
+}
+
+impl GradNoise {
+    pub fn std(&self, step: usize) -> Float {
+        (self.eta / (1. + step as Float).powf(self.gamma)).sqrt()
+    }
+}
+
+pub fn add_gradient_noise<R: Rng>(grads: &mut [Tensor<Float>], std: Float, rng: &mut R) {
+    if std <= 0. {
+        return;
+    }
+    let normal = Normal::new(0., std).unwrap();
+    for grad in grads.iter_mut() {
+        for f in grad.blob_mut() {
+            *f += normal.sample(rng);
+        }
+    }
+}
+
+// Dynamic loss scaling for half-precision training. The backward passes start from a
+// gradient scaled up by `scale`, so that small gradients do not underflow the half-precision
+// gradients of the activations, and the gradients of the parameters are unscaled in full
+// precision. On overflow the step is skipped and the scale is halved, after `growth_interval`
+// successful steps it is doubled again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LossScaler {
+    scale: Float,
+    growth_interval: usize,
+    good_steps: usize,
+}
+
+impl LossScaler {
+    pub fn new() -> Self {
+        Self {
+            scale: 65536.,
+            growth_interval: 2000,
+            good_steps: 0,
+        }
+    }
+    pub fn scale(&self) -> Float {
+        self.scale
+    }
+    // Returns false if the step has to be skipped
+    pub fn update(&mut self, overflow: bool) -> bool {
+        if overflow {
+            self.scale = Float::max(self.scale / 2., 1.);
+            self.good_steps = 0;
+            false
+        } else {
+            self.good_steps += 1;
+            if self.good_steps >= self.growth_interval {
+                self.scale *= 2.;
+                self.good_steps = 0;
+            }
+            true
+        }
+    }
+}
+
+impl Default for LossScaler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Stochastic weight averaging: an equal-weight average of the parameters, sampled every
+// `interval` optimizer steps from step `start` on (E.g. over the last quarter of training)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SwaConfig {
+    pub start: usize,
+    pub interval: usize,
+}
+
+impl SwaConfig {
+    pub fn is_due(&self, step: usize) -> bool {
+        step >= self.start && (step - self.start).is_multiple_of(self.interval)
+    }
+}
+
+// The running average of `SwaConfig`, saved with the training state so that it survives
+// a resume
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SwaState {
+    // Number of samples averaged so far
+    pub count: usize,
+    pub tensors: HashMap<String, Tensor<Float>>,
+}
+
+impl SwaState {
+    // Adds a sample of the parameters to the average
+    pub fn add<'a, I: IntoIterator<Item = (&'a String, &'a Tensor<Float>)>>(
+        &mut self,
+        params: I,
+    ) -> Result<(), TensorError> {
+        let weight = 1. / (self.count + 1) as Float;
+        for (name, param) in params {
+            let avg = match self.tensors.get(name) {
+                Some(avg) => (avg + &(param - avg)?.map_values(|f| f * weight))?,
+                None => param.clone(),
+            };
+            self.tensors.insert(name.clone(), avg);
+        }
+        self.count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adamw_step() {
+        let opt = AdamW {
+            beta1: 0.8,
+            beta2: 0.9,
+            eps: 0.5,
+            weight_decay: 0.1,
+            groups: vec![],
+        };
+        let opt: AdamW = bincode::deserialize(&bincode::serialize(&opt).unwrap()).unwrap();
+        let mut param = Tensor::<Float>::raw(&[2], vec![1., 2.]).unwrap();
+        let grad = Tensor::<Float>::raw(&[2], vec![0., 3.]).unwrap();
+        let mut state = OptimizerState::default();
+        let params = HashMap::from([("p".to_string(), (&mut param, &grad))]);
+        opt.step(params, &mut state, 0.1).unwrap();
+
+        // First step: m_hat = grad, v_hat = grad^2
+        let expected = [1. - 0.01, 2. - 0.02 - 0.1 * 3. / (3. + 0.5)];
+        for (p, e) in param.blob().iter().zip(expected.iter()) {
+            assert!((p - e).abs() < 1e-5);
+        }
+        assert_eq!(state.step, 1);
+        assert!((state.state["p_m"].blob()[1] - 0.6).abs() < 1e-5);
+        assert!((state.state["p_v"].blob()[1] - 0.9).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_param_groups() {
+        let opt = AdamW {
+            groups: vec![
+                ParamGroup {
+                    lr_scale: 0.,
+                    ..ParamGroup::new(&["frozen"])
+                },
+                ParamGroup {
+                    weight_decay: Some(0.),
+                    ..ParamGroup::new(&["bias"])
+                },
+            ],
+            weight_decay: 0.5,
+            ..AdamW::new()
+        };
+        let grad = Tensor::<Float>::raw(&[1], vec![0.]).unwrap();
+        let mut state = OptimizerState::default();
+        let mut frozen = Tensor::<Float>::raw(&[1], vec![1.]).unwrap();
+        let mut bias = frozen.clone();
+        let mut weights = frozen.clone();
+        let params = HashMap::from([
+            ("frozen_weights".to_string(), (&mut frozen, &grad)),
+            ("head_bias".to_string(), (&mut bias, &grad)),
+            ("head_weights".to_string(), (&mut weights, &grad)),
+        ]);
+        opt.step(params, &mut state, 0.1).unwrap();
+
+        // With a zero gradient, only the weight decay moves the parameters
+        assert_eq!(frozen.blob(), [1.]);
+        assert_eq!(bias.blob(), [1.]);
+        assert!((weights.blob()[0] - 0.95).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_sgd_momentum() {
+        let grad = Tensor::<Float>::raw(&[1], vec![2.]).unwrap();
+        for (nesterov, expected) in [(false, [0.8, 0.52]), (true, [0.62, 0.168])] {
+            let opt = Sgd {
+                momentum: 0.9,
+                dampening: 0.5,
+                nesterov,
+                ..Sgd::new()
+            };
+            let mut state = OptimizerState::default();
+            let mut param = Tensor::<Float>::raw(&[1], vec![1.]).unwrap();
+            for e in expected {
+                let params = HashMap::from([("p".to_string(), (&mut param, &grad))]);
+                opt.step(params, &mut state, 0.1).unwrap();
+                assert!((param.blob()[0] - e).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_adafactor() {
+        let mut rng = rand::thread_rng();
+        let opt = Adafactor::new();
+        let mut state = OptimizerState::default();
+        let mut w = Tensor::<Float>::rand_range(&mut rng, -1., 1., &[2, 3, 4]);
+        let mut b = Tensor::<Float>::rand_range(&mut rng, -1., 1., &[4]);
+        let w_grad = Tensor::<Float>::rand_range(&mut rng, -1., 1., &[2, 3, 4]);
+        let b_grad = Tensor::<Float>::rand_range(&mut rng, -1., 1., &[4]);
+        let (w_prev, b_prev) = (w.clone(), b.clone());
+        let params = HashMap::from([
+            ("w".to_string(), (&mut w, &w_grad)),
+            ("b".to_string(), (&mut b, &b_grad)),
+        ]);
+        opt.step(params, &mut state, 0.1).unwrap();
+
+        // State of factored matrices is sub-linear
+        assert_eq!(state.state["w_row"].size(), 2 * 3);
+        assert_eq!(state.state["w_col"].size(), 2 * 4);
+        assert_eq!(state.state["b_row"].size(), 1);
+        assert_eq!(state.state["b_col"].size(), 4);
+
+        // Vectors are not factored, on the first step the update is the sign of the
+        // gradient, scaled so that its RMS is at most clip_threshold
+        for ((p, q), g) in b.blob().iter().zip(b_prev.blob()).zip(b_grad.blob()) {
+            assert!((q - p - 0.1 * g.signum()).abs() < 1e-4);
+        }
+        // Parameters move against their gradients
+        for ((p, q), g) in w.blob().iter().zip(w_prev.blob()).zip(w_grad.blob()) {
+            assert!((q - p) * g > 0.);
+        }
+    }
+
+    #[test]
+    fn test_clip_gradients() {
+        let mut grads = vec![
+            Tensor::<Float>::raw(&[2], vec![3., -10.]).unwrap(),
+            Tensor::<Float>::raw(&[1], vec![0.]).unwrap(),
+        ];
+        // Clamped to [3, -4], with a norm of 5
+        let norm = clip_gradients(&mut grads, Some(1.), Some(4.));
+        assert!((norm - 5.).abs() < 1e-5);
+        assert!((grads[0].blob()[0] - 0.6).abs() < 1e-5);
+        assert!((grads[0].blob()[1] + 0.8).abs() < 1e-5);
+
+        // Gradients within the limits are kept
+        let norm = clip_gradients(&mut grads, Some(1.), None);
+        assert!((norm - 1.).abs() < 1e-5);
+        assert!((grads[0].blob()[0] - 0.6).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_gradient_noise() {
+        let noise = GradNoise {
+            eta: 0.01,
+            gamma: 0.55,
+        };
+        assert!((noise.std(0) - 0.1).abs() < 1e-6);
+        assert!(noise.std(1000) < noise.std(10));
+
+        let mut rng = rand::thread_rng();
+        let mut grads = vec![Tensor::<Float>::zeros(&[100, 100])];
+        add_gradient_noise(&mut grads, 0.1, &mut rng);
+        let std = (grads[0].blob().iter().map(|f| f * f).sum::<Float>() / 10000.).sqrt();
+        assert!((std - 0.1).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_loss_scaler() {
+        let mut scaler = LossScaler::new();
+        assert!(!scaler.update(true) && !scaler.update(true));
+        assert_eq!(scaler.scale(), 16384.);
+        for _ in 0..1999 {
+            assert!(scaler.update(false));
+        }
+        // An overflow restarts the interval
+        assert!(!scaler.update(true));
+        for _ in 0..2000 {
+            scaler.update(false);
+        }
+        assert_eq!(scaler.scale(), 16384.);
+        for _ in 0..20 {
+            scaler.update(true);
+        }
+        assert_eq!(scaler.scale(), 1.);
+    }
+
+    #[test]
+    fn test_swa() {
+        let swa = SwaConfig {
+            start: 10,
+            interval: 5,
+        };
+        assert!(!swa.is_due(5) && swa.is_due(10) && !swa.is_due(12) && swa.is_due(20));
+
+        let mut state = SwaState::default();
+        for v in [1., 2., 6.] {
+            let w = Tensor::<Float>::constant(&[2, 2], v);
+            state.add([(&"w".to_string(), &w)]).unwrap();
+        }
+        assert_eq!(state.count, 3);
+        assert_eq!(state.tensors["w"].blob(), &[3., 3., 3., 3.]);
+    }
+}
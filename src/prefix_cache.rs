@@ -0,0 +1,215 @@
+use crate::graph::GraphError;
+use crate::tensor::{Float, Tensor, TensorOps};
+use std::collections::VecDeque;
+
+// Attention states of the contexts `GPT::infer` ran on, kept across calls (See
+// `GPT::set_prefix_cache`) and evicted least recently used first. Attention is causal, so the
+// attended projections (The keys and values of the layers, named `q` and `v` in `GPT::new`)
+// of the first positions of a context only depend on the tokens before them: a context
+// sharing its first tokens with a cached one only computes its remaining positions. (E.g. a
+// prompt repeated by another request, or the context of the next token of a completion)
+// The projections are taken from the graph of the model after a pass, and loaded into it
+// before the next one, whose rows are put after them. (Models allocated with a batch size
+// have fixed shapes, they do not use the cache)
+
+// Attended projections of every layer and key/value group, as `[len, head_size]` tensors
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AttentionState {
+    pub(crate) len: usize,
+    pub(crate) layers: Vec<(Tensor<Float>, Tensor<Float>)>,
+}
+
+impl AttentionState {
+    fn truncate(&mut self, len: usize) -> Result<(), GraphError> {
+        for (keys, values) in self.layers.iter_mut() {
+            *keys = keys.slice(0..len)?.into();
+            *values = values.slice(0..len)?.into();
+        }
+        self.len = len;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PrefixCache {
+    capacity: usize,
+    // Contexts with the attention states of all their positions, the most recently used last
+    entries: VecDeque<(Vec<usize>, AttentionState)>,
+    // Optimizer step of the parameters the states were computed with
+    step: usize,
+    hits: usize,
+    misses: usize,
+    computed: usize,
+}
+
+impl PrefixCache {
+    // Keeps the attention states of at most `capacity` contexts
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+            step: 0,
+            hits: 0,
+            misses: 0,
+            computed: 0,
+        }
+    }
+
+    // Drops the states computed before the model was trained
+    pub(crate) fn sync(&mut self, step: usize) {
+        if self.step != step {
+            self.entries.clear();
+            self.step = step;
+        }
+    }
+
+    // State of the longest prefix the context shares with a cached one. The last position
+    // is always computed, its logits are not kept.
+    pub(crate) fn lookup(&mut self, context: &[usize]) -> Result<AttentionState, GraphError> {
+        let shared = |tokens: &[usize]| {
+            let len = tokens
+                .iter()
+                .zip(context)
+                .take_while(|(a, b)| a == b)
+                .count();
+            len.min(context.len().saturating_sub(1))
+        };
+        // The most recently used of the longest ones
+        let best = (0..self.entries.len())
+            .max_by_key(|i| shared(&self.entries[*i].0))
+            .filter(|i| shared(&self.entries[*i].0) > 0);
+        let index = match best {
+            Some(index) => index,
+            None => {
+                self.misses += 1;
+                self.computed += context.len();
+                return Ok(AttentionState::default());
+            }
+        };
+        self.hits += 1;
+        let len = shared(&self.entries[index].0);
+        self.computed += context.len() - len;
+        let state = if context.starts_with(&self.entries[index].0) {
+            // The context replaces the entry when inserted, its state can be moved
+            self.entries.remove(index).map(|(_, state)| state)
+        } else {
+            let entry = self.entries.remove(index);
+            let state = entry.as_ref().map(|(_, state)| state.clone());
+            self.entries.extend(entry);
+            state
+        };
+        let mut state = state.unwrap_or_default();
+        state.truncate(len)?;
+        Ok(state)
+    }
+
+    pub(crate) fn insert(&mut self, context: &[usize], state: AttentionState) {
+        if self.capacity == 0 {
+            return;
+        }
+        // The cached prefixes of the context are served by it from now on
+        self.entries
+            .retain(|(tokens, _)| !context.starts_with(tokens));
+        self.entries.push_back((context.to_vec(), state));
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Lookups that reused a cached prefix, and the ones that did not
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    // Positions computed by the lookups (Those of the cached prefixes are not)
+    pub fn computed(&self) -> usize {
+        self.computed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::{
+        Activation, FeedForward, GPTConfig, LoraConfig, NormPlacement, PositionalEncoding, GPT,
+    };
+    use crate::graph::CpuGraph;
+
+    #[test]
+    fn test_prefix_cache() {
+        let configs = [
+            GPTConfig::tiny(),
+            GPTConfig {
+                num_layers: 2,
+                num_kv_heads: 1,
+                positional_encoding: PositionalEncoding::Rope,
+                feedforward: FeedForward::SwiGlu,
+                bias: true,
+                ..GPTConfig::tiny()
+            },
+            GPTConfig {
+                positional_encoding: PositionalEncoding::Alibi,
+                norm_placement: NormPlacement::PostNorm,
+                activation: Activation::Relu,
+                final_norm: false,
+                ..GPTConfig::tiny()
+            },
+            GPTConfig {
+                positional_encoding: PositionalEncoding::Learned,
+                norm_placement: NormPlacement::Original,
+                lora: Some(LoraConfig { rank: 2, alpha: 4. }),
+                ..GPTConfig::tiny()
+            },
+        ];
+        for config in configs {
+            let mut rng = rand::thread_rng();
+            let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, config.clone()).unwrap();
+            // Non-zero adapters and biases
+            let mut state = gpt.get_training_state().unwrap();
+            for t in state.tensors.values_mut() {
+                *t = t.map_values(|f| f + 0.1);
+            }
+            gpt.set_training_state(state, false).unwrap();
+            gpt.set_prefix_cache(Some(2));
+
+            let contexts: [&[usize]; 6] = [
+                &[1, 2],
+                &[1, 2, 3],
+                &[1, 2, 4, 0],
+                &[1, 2, 4, 0],
+                &[3, 1, 2, 4, 0, 1],
+                &[1, 2, 3, 3],
+            ];
+            for context in contexts {
+                let cached = gpt.next_logits(context, 0).unwrap();
+                let logits = gpt.forward(context).unwrap();
+                let expected = logits.get(context.len() - 1).unwrap();
+                for (a, b) in cached.blob().iter().zip(expected.blob()) {
+                    assert!((a - b).abs() < 1e-4, "{:?}: {} != {}", config, a, b);
+                }
+            }
+            // Only the positions after the longest cached prefixes are computed: [1, 2] is
+            // extended twice, [1, 2, 4, 0] recomputes its last position, and the last context
+            // reuses [1, 2] ([1, 2, 3] being evicted by then)
+            let cache = gpt.prefix_cache().unwrap();
+            assert_eq!(cache.computed(), 2 + 1 + 2 + 1 + 6 + 2);
+            assert_eq!((cache.hits(), cache.misses(), cache.len()), (4, 2, 2));
+        }
+    }
+}
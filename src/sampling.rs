@@ -0,0 +1,411 @@
+use crate::graph::GraphError;
+use crate::tensor::{Float, Tensor, TensorOps};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+// How tokens are sampled from the logits of a model, shared by all the generation APIs
+// (`GPT::infer`, the completions server and the C bindings). Missing fields of a deserialized
+// `SamplingParams` take their default value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamplingParams {
+    // Number of tokens to generate (Fewer when a stop token is sampled)
+    pub max_tokens: usize,
+    // Range of the dice thrown against the cumulated probabilities of the tokens, from the
+    // most likely one: 1 samples from the distribution, lower values favor the likely tokens
+    // and 0 always picks the most likely one (See `Temperature`)
+    pub temperature: Float,
+    // Only sample from the `top_k` most likely tokens
+    pub top_k: Option<usize>,
+    // Only sample from the most likely tokens whose probabilities add up to `top_p`
+    pub top_p: Option<Float>,
+    // Divides the (Positive) logits of the tokens already in the context, 1 disables it
+    pub repetition_penalty: Float,
+    // Subtracted from the logits of the tokens already in the context
+    pub presence_penalty: Float,
+    // Subtracted from the logits of the tokens, once per occurrence in the context
+    pub frequency_penalty: Float,
+    // Samples with a RNG seeded with it, instead of the given one
+    pub seed: Option<u64>,
+    // Generation stops when one of them is sampled (It isn't part of the output)
+    pub stop_tokens: Vec<usize>,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            max_tokens: 16,
+            temperature: 1.,
+            top_k: None,
+            top_p: None,
+            repetition_penalty: 1.,
+            presence_penalty: 0.,
+            frequency_penalty: 0.,
+            seed: None,
+            stop_tokens: Vec::new(),
+        }
+    }
+}
+
+impl SamplingParams {
+    pub fn validate(&self) -> Result<(), GraphError> {
+        let invalid = |message: String| Err(GraphError::InvalidConfig(message));
+        if self.temperature.is_nan() || self.temperature < 0. {
+            return invalid(format!("temperature ({}) is negative", self.temperature));
+        }
+        if self.top_k == Some(0) {
+            return invalid("top_k is zero".into());
+        }
+        if let Some(top_p) = self.top_p {
+            if top_p.is_nan() || top_p <= 0. || top_p > 1. {
+                return invalid(format!("top_p ({}) should be in (0, 1]", top_p));
+            }
+        }
+        if self.repetition_penalty.is_nan() || self.repetition_penalty <= 0. {
+            return invalid(format!(
+                "repetition_penalty ({}) should be positive",
+                self.repetition_penalty
+            ));
+        }
+        Ok(())
+    }
+
+    // Built-in processors of the parameters, in the order they run: the penalties, `top_p`,
+    // `top_k` and the temperature
+    pub fn processors(&self) -> Vec<Box<dyn LogitProcessor>> {
+        let mut processors: Vec<Box<dyn LogitProcessor>> = vec![Box::new(Penalties {
+            repetition: self.repetition_penalty,
+            presence: self.presence_penalty,
+            frequency: self.frequency_penalty,
+        })];
+        if let Some(top_p) = self.top_p {
+            processors.push(Box::new(TopP(top_p)));
+        }
+        if let Some(top_k) = self.top_k {
+            processors.push(Box::new(TopK(top_k)));
+        }
+        processors.push(Box::new(Temperature(self.temperature)));
+        processors
+    }
+
+    // Picks the next token given its `logits` and the tokens of the context
+    pub fn select<R: Rng>(
+        &self,
+        rng: &mut R,
+        logits: &[Float],
+        context: &[usize],
+    ) -> Result<usize, GraphError> {
+        self.select_with(rng, logits, context, &self.processors())
+    }
+
+    // Same as `select`, with the logits going through `processors` (E.g. the ones of
+    // `processors` with custom ones inserted) instead of the built-in ones
+    pub fn select_with<R: Rng>(
+        &self,
+        rng: &mut R,
+        logits: &[Float],
+        context: &[usize],
+        processors: &[Box<dyn LogitProcessor>],
+    ) -> Result<usize, GraphError> {
+        let mut logits = logits.to_vec();
+        for processor in processors {
+            processor.process(context, &mut logits)?;
+        }
+
+        let dice = rng.gen_range(0.0..1.);
+        let mut accum = 0.;
+        let mut last = None;
+        for (id, p) in softmax(&logits).into_iter().enumerate() {
+            if p > 0. {
+                accum += p;
+                last = Some(id);
+                if dice < accum {
+                    return Ok(id);
+                }
+            }
+        }
+        // (The probabilities may add up to slightly less than 1)
+        if let Some(id) = last {
+            return Ok(id);
+        }
+        // No logits, or non-finite ones
+        Err(GraphError::EmptyDistribution)
+    }
+}
+
+fn softmax(logits: &[Float]) -> Vec<Float> {
+    let max = logits.iter().cloned().fold(Float::NEG_INFINITY, Float::max);
+    let probs = logits.iter().map(|l| (l - max).exp()).collect::<Vec<_>>();
+    let total = probs.iter().sum::<Float>();
+    probs.into_iter().map(|p| p / total).collect()
+}
+
+// A step of the sampling pipeline (See `SamplingParams::select_with`), modifying the logits of
+// the next token given the tokens of the context. Tokens whose logits are set to -inf are not
+// sampled. Closures taking the context and the logits are processors too, e.g. for only
+// allowing the tokens a grammar accepts.
+pub trait LogitProcessor {
+    fn process(&self, context: &[usize], logits: &mut [Float]) -> Result<(), GraphError>;
+}
+
+impl<F: Fn(&[usize], &mut [Float]) -> Result<(), GraphError>> LogitProcessor for F {
+    fn process(&self, context: &[usize], logits: &mut [Float]) -> Result<(), GraphError> {
+        self(context, logits)
+    }
+}
+
+// Penalties of the tokens already in the context, see `SamplingParams`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Penalties {
+    pub repetition: Float,
+    pub presence: Float,
+    pub frequency: Float,
+}
+
+impl LogitProcessor for Penalties {
+    fn process(&self, context: &[usize], logits: &mut [Float]) -> Result<(), GraphError> {
+        let mut counts = HashMap::<usize, usize>::new();
+        for t in context {
+            *counts.entry(*t).or_default() += 1;
+        }
+        for (t, count) in counts {
+            if let Some(logit) = logits.get_mut(t) {
+                if *logit > 0. {
+                    *logit /= self.repetition;
+                } else {
+                    *logit *= self.repetition;
+                }
+                *logit -= self.presence + self.frequency * count as Float;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Keeps the `k` most likely tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TopK(pub usize);
+
+impl LogitProcessor for TopK {
+    fn process(&self, _context: &[usize], logits: &mut [Float]) -> Result<(), GraphError> {
+        if self.0 < logits.len() {
+            let (_, ids) = Tensor::raw(&[logits.len()], logits.to_vec())?.topk(self.0)?;
+            mask_all_but(logits, ids.blob());
+        }
+        Ok(())
+    }
+}
+
+// Keeps the most likely tokens whose probabilities add up to `p` (At least one)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TopP(pub Float);
+
+impl LogitProcessor for TopP {
+    fn process(&self, _context: &[usize], logits: &mut [Float]) -> Result<(), GraphError> {
+        let probs = Tensor::raw(&[logits.len()], softmax(logits))?;
+        let (probs, ids) = probs.sort_desc()?;
+        let mut accum = 0.;
+        let kept = probs
+            .blob()
+            .iter()
+            .take_while(|p| {
+                let is_kept = accum < self.0;
+                accum += *p;
+                is_kept
+            })
+            .count();
+        mask_all_but(logits, &ids.blob()[..kept.max(1).min(logits.len())]);
+        Ok(())
+    }
+}
+
+// Keeps the most likely tokens whose probabilities add up to the temperature, the last one
+// only for the part within it. The tokens are then sampled as if a dice was thrown in
+// [0, temperature) against their cumulated probabilities, from the most likely one. A
+// temperature of 0 keeps the most likely token, and of 1 (Or more) all of them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Temperature(pub Float);
+
+impl LogitProcessor for Temperature {
+    fn process(&self, context: &[usize], logits: &mut [Float]) -> Result<(), GraphError> {
+        if self.0 >= 1. {
+            return Ok(());
+        }
+        if self.0 <= 0. {
+            return TopK(1).process(context, logits);
+        }
+        let probs = Tensor::raw(&[logits.len()], softmax(logits))?;
+        let (probs, ids) = probs.sort_desc()?;
+        let mut kept = vec![0.; logits.len()];
+        let mut accum = 0.;
+        for (id, p) in ids.blob().iter().zip(probs.blob()) {
+            if accum >= self.0 {
+                break;
+            }
+            kept[*id] = p.min(self.0 - accum);
+            accum += p;
+        }
+        for (logit, p) in logits.iter_mut().zip(kept) {
+            *logit = p.ln();
+        }
+        Ok(())
+    }
+}
+
+// Tokens that are never sampled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BanTokens(pub Vec<usize>);
+
+impl LogitProcessor for BanTokens {
+    fn process(&self, _context: &[usize], logits: &mut [Float]) -> Result<(), GraphError> {
+        for t in self.0.iter() {
+            if let Some(logit) = logits.get_mut(*t) {
+                *logit = Float::NEG_INFINITY;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn mask_all_but(logits: &mut [Float], kept: &[usize]) {
+    let kept = kept.iter().collect::<HashSet<_>>();
+    for (t, logit) in logits.iter_mut().enumerate() {
+        if !kept.contains(&t) {
+            *logit = Float::NEG_INFINITY;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_select() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let logits = [1., 3., 2., 0.];
+        let greedy = SamplingParams {
+            temperature: 0.,
+            ..Default::default()
+        };
+        assert_eq!(greedy.select(&mut rng, &logits, &[]).unwrap(), 1);
+
+        // The penalties push the most likely token below the second one
+        let penalized = SamplingParams {
+            repetition_penalty: 2.,
+            ..greedy.clone()
+        };
+        assert_eq!(penalized.select(&mut rng, &logits, &[1]).unwrap(), 2);
+        let penalized = SamplingParams {
+            frequency_penalty: 0.6,
+            ..greedy.clone()
+        };
+        assert_eq!(penalized.select(&mut rng, &logits, &[1]).unwrap(), 1);
+        assert_eq!(penalized.select(&mut rng, &logits, &[1, 1]).unwrap(), 2);
+
+        let top_k = SamplingParams {
+            top_k: Some(2),
+            ..Default::default()
+        };
+        let top_p = SamplingParams {
+            top_p: Some(0.5),
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            assert!([1, 2].contains(&top_k.select(&mut rng, &logits, &[]).unwrap()));
+            assert_eq!(top_p.select(&mut rng, &logits, &[]).unwrap(), 1);
+        }
+
+        assert!(SamplingParams::default().validate().is_ok());
+        for invalid in [
+            SamplingParams {
+                top_p: Some(0.),
+                ..Default::default()
+            },
+            SamplingParams {
+                top_k: Some(0),
+                ..Default::default()
+            },
+            SamplingParams {
+                temperature: Float::NAN,
+                ..Default::default()
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_logit_processors() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let logits = [1., 3., 2., 0.];
+        let mut processed = logits;
+        TopK(2).process(&[], &mut processed).unwrap();
+        assert_eq!(
+            processed,
+            [Float::NEG_INFINITY, 3., 2., Float::NEG_INFINITY]
+        );
+        let mut processed = logits;
+        TopP(0.8).process(&[], &mut processed).unwrap();
+        assert_eq!(
+            processed,
+            [Float::NEG_INFINITY, 3., 2., Float::NEG_INFINITY]
+        );
+        // The most likely token has a probability of 0.644, the second one is kept for the
+        // remaining 0.156 of the dice range
+        let mut processed = logits;
+        Temperature(0.8).process(&[], &mut processed).unwrap();
+        let probs = softmax(&processed);
+        assert_eq!((probs[0], probs[3]), (0., 0.));
+        assert!((probs[1] - 0.644 / 0.8).abs() < 1e-3);
+        assert!((probs[2] - 0.156 / 0.8).abs() < 1e-3);
+
+        // The temperature is a processor like the others, it can be left out
+        let greedy = SamplingParams {
+            temperature: 0.,
+            ..Default::default()
+        };
+        let mut processors = greedy.processors();
+        assert_eq!(
+            greedy
+                .select_with(&mut rng, &logits, &[], &processors)
+                .unwrap(),
+            1
+        );
+        processors.pop();
+        let sampled = (0..100)
+            .map(|_| {
+                greedy
+                    .select_with(&mut rng, &logits, &[], &processors)
+                    .unwrap()
+            })
+            .collect::<HashSet<_>>();
+        assert!(sampled.len() > 1);
+
+        // Custom processors run along the built-in ones, in order
+        let params = SamplingParams::default();
+        let mut processors = params.processors();
+        processors.push(Box::new(BanTokens(vec![1])));
+        let only_even = |_: &[usize], logits: &mut [Float]| {
+            for logit in logits.iter_mut().skip(1).step_by(2) {
+                *logit = Float::NEG_INFINITY;
+            }
+            Ok(())
+        };
+        processors.push(Box::new(only_even));
+        for _ in 0..100 {
+            let t = params
+                .select_with(&mut rng, &logits, &[], &processors)
+                .unwrap();
+            assert!([0, 2].contains(&t));
+        }
+        processors.push(Box::new(BanTokens(vec![0, 2])));
+        assert!(matches!(
+            params.select_with(&mut rng, &logits, &[], &processors),
+            Err(GraphError::EmptyDistribution)
+        ));
+    }
+}
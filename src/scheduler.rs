@@ -0,0 +1,182 @@
+use crate::tensor::Float;
+use serde::{Deserialize, Serialize};
+
+// Learning rate as a function of the optimizer step. Implemented by closures and by
+// `Schedule`, which can also be saved with the training state.
+pub trait LearningRate {
+    fn learning_rate(&self, step: usize) -> Float;
+    fn schedule(&self) -> Option<Schedule> {
+        None
+    }
+}
+
+impl<F: Fn(usize) -> Float> LearningRate for F {
+    fn learning_rate(&self, step: usize) -> Float {
+        self(step)
+    }
+}
+
+// Number of sequences per optimizer step as a function of the step, e.g. to start training
+// with small batches and ramp up. Implemented by constants, closures and `Schedule` (Rounded).
+pub trait BatchSize {
+    fn batch_size(&self, step: usize) -> usize;
+}
+
+impl BatchSize for usize {
+    fn batch_size(&self, _step: usize) -> usize {
+        *self
+    }
+}
+
+impl<F: Fn(usize) -> usize> BatchSize for F {
+    fn batch_size(&self, step: usize) -> usize {
+        self(step)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Schedule {
+    Constant(Float),
+    // Linear interpolation from `from` to `to` in `steps` steps, `to` afterwards
+    Linear {
+        from: Float,
+        to: Float,
+        steps: usize,
+    },
+    // Half a cosine wave from `max` to `min` in `steps` steps, `min` afterwards
+    Cosine {
+        max: Float,
+        min: Float,
+        steps: usize,
+    },
+    // `lr`, multiplied by `gamma` every `every` steps
+    StepDecay {
+        lr: Float,
+        gamma: Float,
+        every: usize,
+    },
+    // Increases linearly from 0 to the initial rate of `then` in `steps` steps, and
+    // continues with `then` (Which starts counting its steps after the warmup)
+    Warmup {
+        steps: usize,
+        then: Box<Schedule>,
+    },
+}
+
+impl Schedule {
+    pub fn constant_with_warmup(lr: Float, warmup_steps: usize) -> Self {
+        Schedule::Warmup {
+            steps: warmup_steps,
+            then: Box::new(Schedule::Constant(lr)),
+        }
+    }
+
+    // Cosine decay from `max` to `min`, reaching `min` after `total_steps` (Including the
+    // warmup)
+    pub fn cosine_with_warmup(
+        max: Float,
+        min: Float,
+        warmup_steps: usize,
+        total_steps: usize,
+    ) -> Self {
+        Schedule::Warmup {
+            steps: warmup_steps,
+            then: Box::new(Schedule::Cosine {
+                max,
+                min,
+                steps: total_steps.saturating_sub(warmup_steps),
+            }),
+        }
+    }
+
+    pub fn at(&self, step: usize) -> Float {
+        match self {
+            Schedule::Constant(lr) => *lr,
+            Schedule::Linear { from, to, steps } => {
+                let progress = Float::min(1., step as Float / usize::max(*steps, 1) as Float);
+                from + (to - from) * progress
+            }
+            Schedule::Cosine { max, min, steps } => {
+                let progress = Float::min(1., step as Float / usize::max(*steps, 1) as Float);
+                min + (max - min) * 0.5 * (1. + (std::f64::consts::PI as Float * progress).cos())
+            }
+            Schedule::StepDecay { lr, gamma, every } => {
+                lr * gamma.powi((step / usize::max(*every, 1)) as i32)
+            }
+            Schedule::Warmup { steps, then } => {
+                if step < *steps {
+                    then.at(0) * step as Float / *steps as Float
+                } else {
+                    then.at(step - steps)
+                }
+            }
+        }
+    }
+}
+
+impl LearningRate for Schedule {
+    fn learning_rate(&self, step: usize) -> Float {
+        self.at(step)
+    }
+    fn schedule(&self) -> Option<Schedule> {
+        Some(self.clone())
+    }
+}
+
+impl BatchSize for Schedule {
+    fn batch_size(&self, step: usize) -> usize {
+        self.at(step).round().max(0.) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedules() {
+        let close = |a: Float, b: Float| (a - b).abs() < 1e-6;
+        let warmup = Schedule::constant_with_warmup(0.1, 10);
+        assert!(close(warmup.at(0), 0.));
+        assert!(close(warmup.at(5), 0.05));
+        assert!(close(warmup.at(10), 0.1));
+        assert!(close(warmup.at(1000), 0.1));
+
+        let cosine = Schedule::cosine_with_warmup(1., 0.1, 10, 110);
+        assert!(close(cosine.at(10), 1.));
+        assert!(close(cosine.at(60), 0.55));
+        assert!(close(cosine.at(110), 0.1));
+        assert!(close(cosine.at(500), 0.1));
+
+        let step = Schedule::StepDecay {
+            lr: 1.,
+            gamma: 0.5,
+            every: 10,
+        };
+        assert!(close(step.at(9), 1.));
+        assert!(close(step.at(25), 0.25));
+
+        let linear = Schedule::Linear {
+            from: 1.,
+            to: 0.,
+            steps: 4,
+        };
+        assert!(close(linear.at(1), 0.75));
+        assert!(close(linear.at(8), 0.));
+
+        let restored: Schedule =
+            bincode::deserialize(&bincode::serialize(&cosine).unwrap()).unwrap();
+        assert_eq!(restored, cosine);
+        assert!(close((|step: usize| step as Float).learning_rate(3), 3.));
+
+        let ramp_up = Schedule::Linear {
+            from: 4.,
+            to: 32.,
+            steps: 10,
+        };
+        assert_eq!(ramp_up.batch_size(0), 4);
+        assert_eq!(ramp_up.batch_size(5), 18);
+        assert_eq!(ramp_up.batch_size(100), 32);
+        assert_eq!(8.batch_size(100), 8);
+    }
+}
@@ -0,0 +1,410 @@
+use crate::gpt::GPT;
+use crate::graph::{Graph, GraphError};
+use crate::sampling::SamplingParams;
+use crate::tokenizer::Tokenizer;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response};
+
+// HTTP server exposing a model through the OpenAI completions API
+// (https://platform.openai.com/docs/api-reference/completions), so that existing clients
+// can query it. Requests are handled one at a time, on the thread calling `run`.
+
+#[derive(Deserialize, Debug)]
+struct CompletionRequest {
+    prompt: String,
+    // `max_tokens`, `temperature`, `top_p`, `seed`... share the names of the OpenAI API
+    #[serde(flatten)]
+    sampling: SamplingParams,
+    #[serde(default)]
+    stream: bool,
+    // Generation ends once the text contains one of them, the completion ending before it
+    #[serde(default)]
+    stop: Option<StopSequences>,
+}
+
+// The `stop` field of the API, a single sequence or several
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl CompletionRequest {
+    fn stop_sequences(&self) -> Vec<String> {
+        let stop = match &self.stop {
+            Some(StopSequences::One(s)) => vec![s.clone()],
+            Some(StopSequences::Many(s)) => s.clone(),
+            None => Vec::new(),
+        };
+        stop.into_iter().filter(|s| !s.is_empty()).collect()
+    }
+}
+
+// Byte offset of the first stop sequence of the text
+fn find_stop(text: &str, stop: &[String]) -> Option<usize> {
+    stop.iter().filter_map(|s| text.find(s.as_str())).min()
+}
+
+// Length of the start of the text that can be streamed, its end being held back while it
+// may be the beginning of a stop sequence
+fn streamable_len(text: &str, stop: &[String]) -> usize {
+    let held = stop
+        .iter()
+        .flat_map(|s| {
+            (1..s.len()).filter(move |k| s.is_char_boundary(*k) && text.ends_with(&s[..*k]))
+        })
+        .max()
+        .unwrap_or(0);
+    text.len() - held
+}
+
+// Generation ends early when a stop token is sampled or a stop sequence is generated
+fn finish_reason(num_tokens: usize, sampling: &SamplingParams, stopped: bool) -> &'static str {
+    if stopped || num_tokens < sampling.max_tokens {
+        "stop"
+    } else {
+        "length"
+    }
+}
+
+fn json_response(status: u16, body: &Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+}
+
+fn error_body(message: &str) -> Value {
+    json!({"error": {"message": message, "type": "invalid_request_error"}})
+}
+
+// Writes one server-sent event as a chunk of a chunked response
+fn send_event<W: Write + ?Sized>(writer: &mut W, data: &str) -> std::io::Result<()> {
+    let event = format!("data: {}\n\n", data);
+    write!(writer, "{:x}\r\n{}\r\n", event.len(), event)?;
+    writer.flush()
+}
+
+pub struct Server<G: Graph, T: Tokenizer> {
+    server: tiny_http::Server,
+    gpt: GPT<G>,
+    tokenizer: T,
+    model: String,
+    num_completions: usize,
+}
+
+impl<G: Graph, T: Tokenizer> Server<G, T> {
+    // `model` is the name reported to the clients
+    pub fn http<A: ToSocketAddrs>(
+        addr: A,
+        gpt: GPT<G>,
+        tokenizer: T,
+        model: &str,
+    ) -> Result<Self, GraphError> {
+        Ok(Self {
+            server: tiny_http::Server::http(addr).map_err(std::io::Error::other)?,
+            gpt,
+            tokenizer,
+            model: model.into(),
+            num_completions: 0,
+        })
+    }
+
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    pub fn run(&mut self) -> Result<(), GraphError> {
+        loop {
+            let request = self.server.recv()?;
+            if let Err(e) = self.handle(request) {
+                tracing::warn!("failed to respond: {}", e);
+            }
+        }
+    }
+
+    fn handle(&mut self, mut request: Request) -> std::io::Result<()> {
+        let path = request.url().split('?').next().unwrap_or_default();
+        match (request.method(), path) {
+            (Method::Get, "/v1/models") => {
+                let body = json!({
+                    "object": "list",
+                    "data": [{"id": self.model, "object": "model", "owned_by": "femtogpt"}],
+                });
+                request.respond(json_response(200, &body))
+            }
+            (Method::Post, "/v1/completions") => {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body)?;
+                match serde_json::from_str::<CompletionRequest>(&body) {
+                    Ok(completion) => self.complete(request, completion),
+                    Err(e) => request.respond(json_response(400, &error_body(&e.to_string()))),
+                }
+            }
+            _ => request.respond(json_response(404, &error_body("unknown endpoint"))),
+        }
+    }
+
+    fn complete(&mut self, request: Request, completion: CompletionRequest) -> std::io::Result<()> {
+        let prompt = self.tokenizer.tokenize(&completion.prompt);
+        if prompt.is_empty() {
+            return request.respond(json_response(400, &error_body("the prompt is empty")));
+        }
+        if let Err(e) = completion.sampling.validate() {
+            return request.respond(json_response(400, &error_body(&e.to_string())));
+        }
+        // Unless the request has a seed
+        let mut rng = StdRng::from_entropy();
+        self.num_completions += 1;
+        let id = format!("cmpl-{}", self.num_completions);
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let choice = |text: &str, finish_reason: Option<&str>| {
+            json!({
+                "id": id,
+                "object": "text_completion",
+                "created": created,
+                "model": self.model,
+                "choices": [{
+                    "text": text,
+                    "index": 0,
+                    "logprobs": null,
+                    "finish_reason": finish_reason,
+                }],
+            })
+        };
+
+        let stop = completion.stop_sequences();
+        // The callback is called on the prompt tokens first
+        let mut seen = 0;
+        let mut generated = Vec::new();
+        let mut stopped = false;
+
+        if !completion.stream {
+            let tokens = self
+                .gpt
+                .infer_until(&mut rng, &prompt, &completion.sampling, |token| {
+                    seen += 1;
+                    if seen <= prompt.len() {
+                        return true;
+                    }
+                    generated.push(token);
+                    stopped = find_stop(&self.tokenizer.untokenize(&generated), &stop).is_some();
+                    !stopped
+                });
+            let tokens = match tokens {
+                Ok(tokens) => tokens,
+                Err(e) => {
+                    return request.respond(json_response(500, &error_body(&e.to_string())));
+                }
+            };
+            let mut text = self.tokenizer.untokenize(&generated);
+            text.truncate(find_stop(&text, &stop).unwrap_or(text.len()));
+            let mut body = choice(
+                &text,
+                Some(finish_reason(
+                    generated.len(),
+                    &completion.sampling,
+                    stopped,
+                )),
+            );
+            body["usage"] = json!({
+                "prompt_tokens": prompt.len(),
+                "completion_tokens": generated.len(),
+                "total_tokens": tokens.len(),
+            });
+            return request.respond(json_response(200, &body));
+        }
+
+        // tiny_http buffers the responses it encodes, so the events are written as chunks by
+        // hand, in order to reach the client as soon as the tokens are sampled
+        let mut writer = request.into_writer();
+        writer.write_all(
+            concat!(
+                "HTTP/1.1 200 OK\r\n",
+                "Content-Type: text/event-stream\r\n",
+                "Cache-Control: no-cache\r\n",
+                "Transfer-Encoding: chunked\r\n\r\n",
+            )
+            .as_bytes(),
+        )?;
+        let mut result = Ok(());
+        // Bytes of the text sent so far
+        let mut sent = 0;
+        let tokens = self
+            .gpt
+            .infer_until(&mut rng, &prompt, &completion.sampling, |token| {
+                seen += 1;
+                if seen <= prompt.len() {
+                    return true;
+                }
+                generated.push(token);
+                let text = self.tokenizer.untokenize(&generated);
+                let end = match find_stop(&text, &stop) {
+                    Some(end) => {
+                        stopped = true;
+                        end
+                    }
+                    None => streamable_len(&text, &stop),
+                };
+                if let Some(chunk) = text.get(sent..end).filter(|c| !c.is_empty()) {
+                    if result.is_ok() {
+                        result = send_event(&mut *writer, &choice(chunk, None).to_string());
+                    }
+                    sent = end;
+                }
+                !stopped && result.is_ok()
+            });
+        result?;
+        match tokens {
+            Ok(_) => {
+                // The end of the text held back by a stop sequence that was not completed
+                let text = self.tokenizer.untokenize(&generated);
+                if let Some(chunk) = text.get(sent..).filter(|c| !c.is_empty() && !stopped) {
+                    send_event(&mut *writer, &choice(chunk, None).to_string())?;
+                }
+                let reason = finish_reason(generated.len(), &completion.sampling, stopped);
+                send_event(&mut *writer, &choice("", Some(reason)).to_string())?
+            }
+            Err(e) => send_event(&mut *writer, &error_body(&e.to_string()).to_string())?,
+        }
+        send_event(&mut *writer, "[DONE]")?;
+        writer.write_all(b"0\r\n\r\n")?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::*;
+    use crate::graph::CpuGraph;
+    use crate::tokenizer::SimpleTokenizer;
+    use std::io::Read;
+    use std::net::TcpStream;
+
+    fn post(addr: SocketAddr, path: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            concat!(
+                "POST {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n",
+                "Content-Length: {}\r\n\r\n{}",
+            ),
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn body(response: &str) -> Value {
+        serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_completions() {
+        let (send, recv) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let tokenizer = SimpleTokenizer::new("abcde");
+            let mut rng = StdRng::seed_from_u64(0);
+            let config = GPTConfig {
+                vocab_size: tokenizer.vocab_size(),
+                num_tokens: 4,
+                bias: true,
+                ..GPTConfig::tiny()
+            };
+            let gpt = GPT::new(&mut rng, CpuGraph::new(), None, config).unwrap();
+            let mut server = Server::http("127.0.0.1:0", gpt, tokenizer, "tiny").unwrap();
+            send.send(server.addr().unwrap()).unwrap();
+            server.run().unwrap();
+        });
+        let addr = recv.recv().unwrap();
+
+        let request = r#"{"prompt": "ab", "max_tokens": 6, "temperature": 0}"#;
+        let response = post(addr, "/v1/completions", request);
+        assert!(response.starts_with("HTTP/1.1 200"));
+        let completion = body(&response);
+        let text = completion["choices"][0]["text"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(text.chars().count(), 6);
+        assert_eq!(completion["choices"][0]["finish_reason"], "length");
+        assert_eq!(completion["usage"]["total_tokens"], 8);
+
+        // The streamed tokens add up to the same completion
+        let request = r#"{"prompt": "ab", "max_tokens": 6, "temperature": 0, "stream": true}"#;
+        let response = post(addr, "/v1/completions", request);
+        assert!(response.contains("text/event-stream"));
+        let events = response
+            .lines()
+            .filter_map(|l| l.strip_prefix("data: "))
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 8);
+        assert_eq!(events[7], "[DONE]");
+        let streamed = events[..7]
+            .iter()
+            .map(|e| serde_json::from_str::<Value>(e).unwrap()["choices"][0]["text"].clone())
+            .map(|t| t.as_str().unwrap().to_string())
+            .collect::<String>();
+        assert_eq!(streamed, text);
+
+        // The completion ends before the first stop sequence, also when streamed (The text
+        // that may start one is held back)
+        assert_eq!(streamable_len("abc", &["cd".into(), "x".into()]), 2);
+        let stop = text[2..4].to_string();
+        let expected = &text[..text.find(&stop).unwrap()];
+        for stream in [false, true] {
+            let request = format!(
+                r#"{{"prompt": "ab", "max_tokens": 6, "temperature": 0, "stream": {}, "stop": ["x", "{}"]}}"#,
+                stream, stop
+            );
+            let response = post(addr, "/v1/completions", &request);
+            let events = response
+                .lines()
+                .filter_map(|l| l.strip_prefix("data: "))
+                .filter(|e| *e != "[DONE]")
+                .map(|e| serde_json::from_str::<Value>(e).unwrap())
+                .collect::<Vec<_>>();
+            let completion = if stream {
+                events
+            } else {
+                vec![body(&response)]
+            };
+            let choices = completion.iter().map(|c| &c["choices"][0]);
+            let streamed = choices
+                .map(|c| c["text"].as_str().unwrap())
+                .collect::<String>();
+            assert_eq!(streamed, expected);
+            let last = &completion[completion.len() - 1];
+            assert_eq!(last["choices"][0]["finish_reason"], "stop");
+        }
+        let request = r#"{"prompt": "ab", "max_tokens": 6, "temperature": 0, "stop": "x"}"#;
+        let completion = body(&post(addr, "/v1/completions", request));
+        assert_eq!(completion["choices"][0]["text"], text);
+        assert_eq!(completion["choices"][0]["finish_reason"], "length");
+
+        // Every token is a stop token
+        let request = r#"{"prompt": "ab", "max_tokens": 6, "stop_tokens": [0, 1, 2, 3, 4]}"#;
+        let completion = body(&post(addr, "/v1/completions", request));
+        assert_eq!(completion["choices"][0]["text"], "");
+        assert_eq!(completion["choices"][0]["finish_reason"], "stop");
+
+        assert!(post(addr, "/v1/completions", "{}").starts_with("HTTP/1.1 400"));
+        let request = r#"{"prompt": "ab", "top_p": 2}"#;
+        assert!(post(addr, "/v1/completions", request).starts_with("HTTP/1.1 400"));
+        assert!(post(addr, "/v1/chat", "{}").starts_with("HTTP/1.1 404"));
+    }
+}
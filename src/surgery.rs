@@ -0,0 +1,403 @@
+use crate::checkpoint::{read_training_state, Manifest};
+use crate::gpt::{layer_of, GPTConfig, NormPlacement, PositionalEncoding, TrainingState, GPT};
+use crate::graph::{CpuGraph, GraphError};
+use crate::tensor::{Float, Tensor, TensorOps};
+use crate::tokenizer::Tokenizer;
+use rand::Rng;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Growing a trained model into a larger one, so that training can proceed in stages from small
+// to large. The grown model computes the same function as the original one (Up to rounding):
+//
+// - Inserted blocks are identities: the output projections of their attention and feed-forward
+//   layers are zero, so they add nothing to the residual stream. (Pre-norm models only, the
+//   other placements normalize the residual stream within the blocks)
+// - The residual stream is widened by a whole factor, every dimension being replicated as in
+//   Net2Net. Norms see the same mean and variance, and the weights reading the stream are split
+//   among the copies.
+// - New heads and feed-forward units are initialized as in a new model, except for their
+//   output weights which are zero.
+//
+// The optimizer state, moving averages and training progress are not carried over.
+
+#[derive(Debug, Clone, Default)]
+pub struct GrowOptions {
+    // Blocks of the original model before which an identity block is inserted, once per
+    // occurrence (`num_layers` appends one after the last block)
+    pub insert_layers: Vec<usize>,
+    // Width of the residual stream, a multiple of the original one
+    pub embedding_degree: Option<usize>,
+    // Heads share their query/value projections in the same groups as in the original model,
+    // so the number of groups grows accordingly
+    pub num_heads: Option<usize>,
+    pub feedforward_degree: Option<usize>,
+    // Std of the noise added to the weights reading the replicated dimensions, which would
+    // otherwise stay tied during training. It sums to zero across the copies, so the function
+    // is still preserved.
+    pub noise: Float,
+}
+
+// How a dimension of a parameter grows. Rows are the inputs of the parameters and columns their
+// outputs (Vectors are a single row).
+#[derive(Debug, Clone, Copy)]
+enum Axis {
+    Unchanged,
+    // Dimension of the residual stream: the inputs are split among the copies, the outputs
+    // replicated
+    Residual,
+    // The first `n` units are the original ones. The new inputs have zero weights, the new
+    // outputs the weights of a new model.
+    Units(usize),
+}
+
+impl Axis {
+    // Original index of the `i`-th index of the grown dimension, `None` for a new unit
+    fn source(self, i: usize, len: usize) -> Option<usize> {
+        match self {
+            Axis::Unchanged => Some(i),
+            Axis::Residual => Some(i % len),
+            Axis::Units(n) => (i < n).then_some(i),
+        }
+    }
+}
+
+// Replaces the block of a parameter name, see `layer_of`
+fn with_layer(name: &str, layer: usize) -> String {
+    let mut replaced = false;
+    name.split('_')
+        .map(|t| match t.parse::<usize>() {
+            Ok(_) if !replaced => {
+                replaced = true;
+                layer.to_string()
+            }
+            _ => t.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+// How the rows and columns of the parameter grow
+fn axes(name: &str, attention_degree: usize, feedforward_degree: usize) -> Option<(Axis, Axis)> {
+    let kind = name.split('_').next()?;
+    let is_bias = name.ends_with("_bias");
+    Some(match kind {
+        "token" | "pos" | "norm" | "atten" => (Axis::Unchanged, Axis::Residual),
+        "head" if name.starts_with("head_norm") => (Axis::Unchanged, Axis::Residual),
+        "head" | "classifier" if is_bias => (Axis::Unchanged, Axis::Unchanged),
+        "head" | "classifier" => (Axis::Residual, Axis::Unchanged),
+        "proj" | "feedforward2" if is_bias => (Axis::Unchanged, Axis::Residual),
+        "proj" => (Axis::Units(attention_degree), Axis::Residual),
+        "feedforward2" => (Axis::Units(feedforward_degree), Axis::Residual),
+        "feedforward1" | "feedforward3" if is_bias => {
+            (Axis::Unchanged, Axis::Units(feedforward_degree))
+        }
+        "feedforward1" | "feedforward3" => (Axis::Residual, Axis::Units(feedforward_degree)),
+        _ => return None,
+    })
+}
+
+fn matrix_shape(t: &Tensor<Float>) -> (usize, usize) {
+    match t.shape() {
+        [cols] => (1, *cols),
+        shape => (shape[0], shape[1]),
+    }
+}
+
+// The original parameter, grown to the shape of the parameter of a new model
+fn grow_tensor<R: Rng>(
+    rng: &mut R,
+    old: &Tensor<Float>,
+    fresh: &Tensor<Float>,
+    (rows, cols): (Axis, Axis),
+    noise: Float,
+) -> Result<Tensor<Float>, GraphError> {
+    let (old_rows, old_cols) = matrix_shape(old);
+    let (new_rows, new_cols) = matrix_shape(fresh);
+    let copies = match rows {
+        Axis::Residual => new_rows / old_rows,
+        _ => 1,
+    };
+    // Noise of the copies of a row, minus its mean
+    let mut noises = vec![0.; new_rows * new_cols];
+    if copies > 1 && noise > 0. {
+        noises = Tensor::<Float>::rand_normal(rng, noise, &[new_rows, new_cols])
+            .blob()
+            .to_vec();
+        for i in 0..old_rows * new_cols {
+            let copies_of = |c: usize| (i / new_cols + c * old_rows) * new_cols + i % new_cols;
+            let mean = (0..copies).map(|c| noises[copies_of(c)]).sum::<Float>() / copies as Float;
+            for c in 0..copies {
+                noises[copies_of(c)] -= mean;
+            }
+        }
+    }
+    let mut values = Vec::with_capacity(new_rows * new_cols);
+    for r in 0..new_rows {
+        for c in 0..new_cols {
+            let i = r * new_cols + c;
+            values.push(match (rows.source(r, old_rows), cols.source(c, old_cols)) {
+                (None, _) => 0.,
+                (_, None) => fresh.blob()[i],
+                (Some(or), Some(oc)) => {
+                    old.blob()[or * old_cols + oc] / copies as Float + noises[i]
+                }
+            });
+        }
+    }
+    Ok(Tensor::raw(fresh.shape(), values)?)
+}
+
+// Config of the grown model, with the blocks of the original model each block of the grown one
+// comes from (`None` for the inserted ones)
+fn grown_config(
+    config: &GPTConfig,
+    options: &GrowOptions,
+) -> Result<(GPTConfig, Vec<Option<usize>>), GraphError> {
+    let err = |msg: String| Err(GraphError::InvalidConfig(format!("model surgery: {}", msg)));
+    if config.lora.is_some() {
+        return err("merge the LoRA adapters first".into());
+    }
+    let mut layers = Vec::new();
+    for l in 0..=config.num_layers {
+        for _ in options.insert_layers.iter().filter(|i| **i == l) {
+            layers.push(None);
+        }
+        if l < config.num_layers {
+            layers.push(Some(l));
+        }
+    }
+    if let Some(l) = options
+        .insert_layers
+        .iter()
+        .find(|l| **l > config.num_layers)
+    {
+        return err(format!(
+            "can not insert a block before block {} of {}",
+            l, config.num_layers
+        ));
+    }
+    if !options.insert_layers.is_empty() && config.norm_placement != NormPlacement::PreNorm {
+        return err("blocks can only be inserted into pre-norm models".into());
+    }
+
+    let embedding_degree = options.embedding_degree.unwrap_or(config.embedding_degree);
+    if embedding_degree < config.embedding_degree
+        || !embedding_degree.is_multiple_of(config.embedding_degree)
+    {
+        return err(format!(
+            "embedding_degree ({}) should be a multiple of {}",
+            embedding_degree, config.embedding_degree
+        ));
+    }
+    if embedding_degree != config.embedding_degree
+        && config.positional_encoding == PositionalEncoding::Sinusoidal
+    {
+        return err("sinusoidal positional encodings depend on embedding_degree".into());
+    }
+
+    let num_heads = options.num_heads.unwrap_or(config.num_heads);
+    let group_size = config.num_heads / config.num_kv_heads;
+    if num_heads < config.num_heads || !num_heads.is_multiple_of(group_size) {
+        return err(format!(
+            "num_heads ({}) should be at least {} and a multiple of {}",
+            num_heads, config.num_heads, group_size
+        ));
+    }
+    if num_heads != config.num_heads && config.positional_encoding == PositionalEncoding::Alibi {
+        return err("ALiBi slopes depend on num_heads".into());
+    }
+
+    let feedforward_degree = options
+        .feedforward_degree
+        .unwrap_or(config.feedforward_degree());
+    if feedforward_degree < config.feedforward_degree() {
+        return err(format!(
+            "feedforward_degree ({}) should be at least {}",
+            feedforward_degree,
+            config.feedforward_degree()
+        ));
+    }
+
+    let head_size = config.head_size()?;
+    let mut grown = GPTConfig {
+        num_layers: layers.len(),
+        embedding_degree,
+        num_heads,
+        num_kv_heads: num_heads / group_size,
+        feedforward_multiplier: feedforward_degree as Float / embedding_degree as Float,
+        ..config.clone()
+    };
+    if grown.head_size().ok() != Some(head_size) {
+        grown.head_size = Some(head_size);
+    }
+    if grown.feedforward_degree() != feedforward_degree {
+        return err(format!(
+            "feedforward_degree ({}) can not be expressed as a multiplier",
+            feedforward_degree
+        ));
+    }
+    Ok((grown, layers))
+}
+
+// The training state of the grown model, see `GrowOptions`
+pub fn grow_state<R: Rng>(
+    rng: &mut R,
+    state: &TrainingState,
+    options: &GrowOptions,
+) -> Result<TrainingState, GraphError> {
+    // The tensors have to fit the config
+    GPT::from_training_state(CpuGraph::new(), None, state.clone())?;
+    let config = state.config.clone().ok_or_else(|| {
+        GraphError::InvalidConfig("the checkpoint does not store its config".into())
+    })?;
+    let (grown, layers) = grown_config(&config, options)?;
+    let fresh = GPT::new(rng, CpuGraph::new(), None, grown.clone())?.get_training_state()?;
+
+    let attention_degree = config.num_heads * config.head_size()?;
+    let mut tensors = HashMap::new();
+    for (name, fresh) in fresh.tensors {
+        let source = match layer_of(&name) {
+            Some(l) => layers[l].map(|l| with_layer(&name, l)),
+            None => Some(name.clone()),
+        };
+        let tensor = match source.as_ref().map(|s| state.tensors.get(s)) {
+            // The output projections of the inserted blocks
+            None if name.starts_with("proj_") || name.starts_with("feedforward2_") => {
+                Tensor::zeros(fresh.shape())
+            }
+            Some(Some(old)) => {
+                let axes = axes(&name, attention_degree, config.feedforward_degree())
+                    .ok_or_else(|| GraphError::UnexpectedTensor(name.clone()))?;
+                grow_tensor(rng, old, &fresh, axes, options.noise)?
+            }
+            // Parameters of the inserted blocks and of the new heads
+            _ => fresh,
+        };
+        tensors.insert(name, tensor);
+    }
+
+    let mut grown_state = TrainingState {
+        tensors,
+        optimizer: Default::default(),
+        schedule: None,
+        ema: HashMap::new(),
+        progress: None,
+        config: Some(grown),
+        manifest: None,
+        swa: None,
+        tokenizer: state.tokenizer.clone(),
+    };
+    let fingerprint = state
+        .manifest
+        .as_ref()
+        .and_then(|m| m.tokenizer)
+        .or(state.tokenizer.as_ref().map(|t| t.fingerprint()));
+    grown_state.manifest = Some(Manifest::new(&grown_state, fingerprint));
+    Ok(grown_state)
+}
+
+// Grows the checkpoint at `src` into a new checkpoint at `dst`, returning the grown state
+pub fn grow<R: Rng, P: AsRef<Path>, Q: AsRef<Path>>(
+    rng: &mut R,
+    src: P,
+    dst: Q,
+    options: &GrowOptions,
+) -> Result<TrainingState, GraphError> {
+    let state = grow_state(rng, &read_training_state(src)?, options)?;
+    std::fs::write(dst, state.to_bytes()?)?;
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::{FeedForward, InitScheme};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn config(norm_placement: NormPlacement, feedforward: FeedForward) -> GPTConfig {
+        GPTConfig {
+            num_layers: 2,
+            num_heads: 4,
+            positional_encoding: PositionalEncoding::Rope,
+            feedforward,
+            norm_placement,
+            bias: true,
+            init: InitScheme::Scaled { std: 0.5 },
+            ..GPTConfig::tiny()
+        }
+    }
+
+    #[test]
+    fn test_grow() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let options = GrowOptions {
+            insert_layers: vec![0, 2, 2],
+            embedding_degree: Some(16),
+            num_heads: Some(6),
+            feedforward_degree: Some(20),
+            noise: 0.01,
+        };
+        for feedforward in [FeedForward::Mlp, FeedForward::SwiGlu] {
+            let config = config(NormPlacement::PreNorm, feedforward);
+            let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, config).unwrap();
+            let state = gpt.get_training_state().unwrap();
+            let grown = grow_state(&mut rng, &state, &options).unwrap();
+            let grown_config = grown.config.clone().unwrap();
+            assert_eq!(grown_config.num_layers, 5);
+            assert_eq!(grown_config.num_kv_heads, 3);
+            assert_eq!(grown_config.head_size().unwrap(), 2);
+            assert_eq!(grown_config.feedforward_degree(), 20);
+
+            let mut grown = GPT::from_training_state(CpuGraph::new(), None, grown).unwrap();
+            let context = [1, 2, 3, 4];
+            let logits = gpt.forward(&context).unwrap();
+            let grown_logits = grown.forward(&context).unwrap();
+            for (a, b) in logits.blob().iter().zip(grown_logits.blob().iter()) {
+                assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_grow_errors() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let state = |config: GPTConfig| {
+            let gpt = GPT::new(&mut StdRng::seed_from_u64(0), CpuGraph::new(), None, config);
+            gpt.unwrap().get_training_state().unwrap()
+        };
+        let pre_norm = state(config(NormPlacement::PreNorm, FeedForward::Mlp));
+        let post_norm = state(config(NormPlacement::PostNorm, FeedForward::Mlp));
+        let insert = GrowOptions {
+            insert_layers: vec![1],
+            ..Default::default()
+        };
+        assert!(grow_state(&mut rng, &post_norm, &insert).is_err());
+        for options in [
+            GrowOptions {
+                insert_layers: vec![3],
+                ..Default::default()
+            },
+            GrowOptions {
+                embedding_degree: Some(12),
+                ..Default::default()
+            },
+            GrowOptions {
+                num_heads: Some(5),
+                ..Default::default()
+            },
+            GrowOptions {
+                feedforward_degree: Some(8),
+                ..Default::default()
+            },
+        ] {
+            assert!(grow_state(&mut rng, &pre_norm, &options).is_err());
+        }
+        assert_eq!(with_layer("head_2_0_q", 5), "head_5_0_q");
+        assert_eq!(
+            with_layer("feedforward1_2_weights", 0),
+            "feedforward1_0_weights"
+        );
+    }
+}
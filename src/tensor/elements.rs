@@ -12,6 +12,15 @@ impl TensorElement for f32 {
     }
 }
 
+impl TensorElement for f64 {
+    fn zero() -> Self {
+        0.
+    }
+    fn one() -> Self {
+        1.
+    }
+}
+
 impl TensorElement for usize {
     fn zero() -> Self {
         0
@@ -20,3 +29,78 @@ impl TensorElement for usize {
         1
     }
 }
+
+impl TensorElement for half::f16 {
+    fn zero() -> Self {
+        half::f16::ZERO
+    }
+    fn one() -> Self {
+        half::f16::ONE
+    }
+}
+
+impl TensorElement for half::bf16 {
+    fn zero() -> Self {
+        half::bf16::ZERO
+    }
+    fn one() -> Self {
+        half::bf16::ONE
+    }
+}
+
+// Floating point types that tensors can be stored as and converted between
+pub trait FloatElement: TensorElement {
+    fn from_f64(v: f64) -> Self;
+    fn to_f64(self) -> f64;
+}
+
+impl FloatElement for f32 {
+    fn from_f64(v: f64) -> Self {
+        v as f32
+    }
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl FloatElement for f64 {
+    fn from_f64(v: f64) -> Self {
+        v
+    }
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+impl FloatElement for half::f16 {
+    fn from_f64(v: f64) -> Self {
+        half::f16::from_f64(v)
+    }
+    fn to_f64(self) -> f64 {
+        half::f16::to_f64(self)
+    }
+}
+
+impl FloatElement for half::bf16 {
+    fn from_f64(v: f64) -> Self {
+        half::bf16::from_f64(v)
+    }
+    fn to_f64(self) -> f64 {
+        half::bf16::to_f64(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tensor::*;
+
+    #[test]
+    fn test_cast() {
+        let t = Tensor::<f32>::raw(&[3], vec![1., 1.0001, 100000.]).unwrap();
+        let f16 = t.cast::<half::f16>().cast::<f32>();
+        assert_eq!(f16.blob(), &[1., 1., f32::INFINITY]);
+        let bf16 = t.cast::<half::bf16>().cast::<f32>();
+        assert_eq!(bf16.blob(), &[1., 1., 99840.]);
+        assert_eq!(t.cast::<f64>().cast::<f32>().blob(), t.blob());
+    }
+}
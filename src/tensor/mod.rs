@@ -2,6 +2,7 @@ mod elements;
 mod error;
 mod helper;
 mod ops;
+pub mod simd;
 mod view;
 pub use elements::*;
 pub use error::*;
@@ -12,8 +13,15 @@ pub use view::*;
 use rand::prelude::*;
 use rand_distr::Normal;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::ops::*;
 
+// Element type of float tensors, f64 when built with the `f64` feature
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(feature = "f64")]
+pub type Float = f64;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Tensor<V: TensorElement> {
     blob: Vec<V>,
@@ -22,8 +30,11 @@ pub struct Tensor<V: TensorElement> {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum GeneralTensor {
-    Float(Tensor<f32>),
+    Float(Tensor<Float>),
     Usize(Tensor<usize>),
+    // Float tensors stored in half precision, see `Precision`
+    F16(Tensor<half::f16>),
+    Bf16(Tensor<half::bf16>),
 }
 
 impl GeneralTensor {
@@ -31,15 +42,32 @@ impl GeneralTensor {
         match self {
             GeneralTensor::Float(t) => t.size(),
             GeneralTensor::Usize(t) => t.size(),
+            GeneralTensor::F16(t) => t.size(),
+            GeneralTensor::Bf16(t) => t.size(),
         }
     }
     pub fn shape(&self) -> &[usize] {
         match self {
             GeneralTensor::Float(t) => t.shape(),
             GeneralTensor::Usize(t) => t.shape(),
+            GeneralTensor::F16(t) => t.shape(),
+            GeneralTensor::Bf16(t) => t.shape(),
+        }
+    }
+    // Whether the tensor holds floats, in any precision
+    pub fn is_float(&self) -> bool {
+        !matches!(self, GeneralTensor::Usize(_))
+    }
+    // The values of a float tensor as `Float`s, converted when stored in half precision
+    pub fn to_float(&self) -> Result<Cow<'_, Tensor<Float>>, TensorError> {
+        match self {
+            GeneralTensor::Float(t) => Ok(Cow::Borrowed(t)),
+            GeneralTensor::F16(t) => Ok(Cow::Owned(t.cast())),
+            GeneralTensor::Bf16(t) => Ok(Cow::Owned(t.cast())),
+            GeneralTensor::Usize(_) => Err(TensorError::UnexpectedType),
         }
     }
-    pub fn as_float(&self) -> Result<&Tensor<f32>, TensorError> {
+    pub fn as_float(&self) -> Result<&Tensor<Float>, TensorError> {
         match self {
             GeneralTensor::Float(t) => Ok(t),
             _ => Err(TensorError::UnexpectedType),
@@ -51,7 +79,7 @@ impl GeneralTensor {
             _ => Err(TensorError::UnexpectedType),
         }
     }
-    pub fn as_float_mut(&mut self) -> Result<&mut Tensor<f32>, TensorError> {
+    pub fn as_float_mut(&mut self) -> Result<&mut Tensor<Float>, TensorError> {
         match self {
             GeneralTensor::Float(t) => Ok(t),
             _ => Err(TensorError::UnexpectedType),
@@ -65,6 +93,28 @@ impl GeneralTensor {
     }
 }
 
+// Precision float tensors are stored in. With half precision (F16 or Bf16), the graph stores
+// the outputs of the computations and their gradients as half-precision tensors, and the
+// computations see half-precision copies of the parameters, which are kept in full precision
+// for the optimizer. (See `Graph::set_precision`)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+pub enum Precision {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+}
+
+impl Precision {
+    pub fn store(&self, t: &Tensor<Float>) -> GeneralTensor {
+        match self {
+            Precision::F32 => GeneralTensor::Float(t.clone()),
+            Precision::F16 => GeneralTensor::F16(t.cast()),
+            Precision::Bf16 => GeneralTensor::Bf16(t.cast()),
+        }
+    }
+}
+
 impl<V: TensorElement> Tensor<V> {
     pub fn raw(shape: &[usize], blob: Vec<V>) -> Result<Self, TensorError> {
         let sz = shape.iter().fold(1, |c, s| c * s);
@@ -76,6 +126,15 @@ impl<V: TensorElement> Tensor<V> {
             shape: shape.to_vec(),
         })
     }
+    // Gives the tensor another shape, reusing its blob when it is large enough. The values
+    // are left unspecified, for writing the output of an operation into.
+    pub fn resize(&mut self, shape: &[usize]) -> &mut [V] {
+        self.blob.resize(shape.iter().product(), V::zero());
+        self.shape.clear();
+        self.shape.extend_from_slice(shape);
+        &mut self.blob
+    }
+
     pub fn scalar(v: V) -> Self {
         Tensor {
             blob: vec![v],
@@ -91,17 +150,25 @@ impl<V: TensorElement> Tensor<V> {
     pub fn zeros(shape: &[usize]) -> Self {
         Self::constant(shape, V::zero())
     }
-    pub fn rand_range<R: Rng>(r: &mut R, start: f32, end: f32, shape: &[usize]) -> Tensor<f32> {
-        Tensor::<f32> {
+    pub fn rand_range<R: Rng>(
+        r: &mut R,
+        start: Float,
+        end: Float,
+        shape: &[usize],
+    ) -> Tensor<Float> {
+        Tensor::<Float> {
             blob: (0..shape.iter().fold(1, |curr, s| curr * s))
                 .map(|_| r.gen_range(start..end))
                 .collect(),
             shape: shape.to_vec(),
         }
     }
-    pub fn rand<R: Rng>(r: &mut R, shape: &[usize]) -> Tensor<f32> {
-        let normal = Normal::new(0.0, 0.02).unwrap();
-        Tensor::<f32> {
+    pub fn rand<R: Rng>(r: &mut R, shape: &[usize]) -> Tensor<Float> {
+        Self::rand_normal(r, 0.02, shape)
+    }
+    pub fn rand_normal<R: Rng>(r: &mut R, std: Float, shape: &[usize]) -> Tensor<Float> {
+        let normal = Normal::new(0.0, std).unwrap();
+        Tensor::<Float> {
             blob: (0..shape.iter().fold(1, |curr, s| curr * s))
                 .map(|_| normal.sample(r))
                 .collect(),
@@ -127,6 +194,49 @@ pub trait TensorMutOps<V: TensorElement>: TensorOps<V> {
         self.blob_mut().clone_from_slice(t.blob());
         Ok(())
     }
+
+    // In-place variants of the elementwise operations, which don't allocate a new tensor.
+    // Adds `other` (Broadcast to the shape of this tensor) to the elements
+    fn add_assign<T: TensorOps<V>>(&mut self, other: &T) -> Result<(), TensorError>
+    where
+        V: AddAssign,
+    {
+        if self.shape() == other.shape() {
+            for (a, b) in self.blob_mut().iter_mut().zip(other.blob()) {
+                *a += *b;
+            }
+            return Ok(());
+        }
+        let shape = self.shape().to_vec();
+        if broadcast_shape(&shape, other.shape())? != shape {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let other_blob = other.blob();
+        for (a, i) in self
+            .blob_mut()
+            .iter_mut()
+            .zip(broadcast_indices(other.shape(), &shape))
+        {
+            *a += other_blob[i];
+        }
+        Ok(())
+    }
+    fn mul_scalar_assign(&mut self, c: V)
+    where
+        V: MulAssign,
+    {
+        self.blob_mut().iter_mut().for_each(|v| *v *= c);
+    }
+    fn relu_inplace(&mut self)
+    where
+        V: PartialOrd,
+    {
+        for v in self.blob_mut().iter_mut() {
+            if *v < V::zero() {
+                *v = V::zero();
+            }
+        }
+    }
     fn get_mut(&mut self, ind: usize) -> Result<TensorMutView<V>, TensorError> {
         if ind >= self.len() {
             return Err(TensorError::InvalidIndex);
@@ -140,9 +250,19 @@ pub trait TensorMutOps<V: TensorElement>: TensorOps<V> {
     }
 }
 
-impl Tensor<f32> {
-    pub fn mean(&self) -> f32 {
-        self.blob().iter().cloned().sum::<f32>() / self.size() as f32
+impl Tensor<Float> {
+    pub fn mean(&self) -> Float {
+        self.blob().iter().cloned().sum::<Float>() / self.size() as Float
+    }
+}
+
+impl<V: FloatElement> Tensor<V> {
+    // Converts the elements to another floating point type (E.g. f32 <-> f16)
+    pub fn cast<W: FloatElement>(&self) -> Tensor<W> {
+        Tensor {
+            blob: self.blob.iter().map(|v| W::from_f64(v.to_f64())).collect(),
+            shape: self.shape.clone(),
+        }
     }
 }
 
@@ -232,6 +352,30 @@ pub trait TensorOps<V: TensorElement>: Sized + Into<Tensor<V>> + Send + Sync {
         }
     }
 
+    fn strided(&self) -> StridedView<'_, V> {
+        StridedView {
+            mirror: self.tensor(),
+            offset: self.offset(),
+            shape: self.shape().to_vec(),
+            strides: contiguous_strides(self.shape()),
+        }
+    }
+
+    // The items (Along the first dimension) whose index is in `range`, without copying them
+    fn slice(&self, range: Range<usize>) -> Result<TensorView<'_, V>, TensorError> {
+        if self.dim() == 0 || range.start > range.end || range.end > self.len() {
+            return Err(TensorError::InvalidIndex);
+        }
+        let sub_size = self.size() / self.len().max(1);
+        let mut shape = self.shape().to_vec();
+        shape[0] = range.len();
+        Ok(TensorView {
+            offset: self.offset() + sub_size * range.start,
+            shape,
+            mirror: self.tensor(),
+        })
+    }
+
     fn get(&self, ind: usize) -> Result<TensorView<V>, TensorError> {
         if ind >= self.len() {
             return Err(TensorError::InvalidIndex);
@@ -244,6 +388,64 @@ pub trait TensorOps<V: TensorElement>: Sized + Into<Tensor<V>> + Send + Sync {
         })
     }
 
+    // Index of the largest value of every row (The last dimension), ties going to the first
+    // one. NaNs are larger than any number.
+    fn argmax(&self) -> Result<Tensor<usize>, TensorError>
+    where
+        V: FloatElement,
+    {
+        self.map(1, |row| {
+            let row = row.blob();
+            let best = (1..row.len()).fold(0, |best, i| {
+                if row[i].to_f64().total_cmp(&row[best].to_f64()).is_gt() {
+                    i
+                } else {
+                    best
+                }
+            });
+            match row.is_empty() {
+                true => Err(TensorError::UnexpectedShape),
+                false => Ok(Tensor::scalar(best)),
+            }
+        })
+    }
+
+    // The `k` largest values of every row and their indices, in decreasing order (Ties in the
+    // order of the indices). Rows shorter than `k` are sorted whole.
+    fn topk(&self, k: usize) -> Result<(Tensor<V>, Tensor<usize>), TensorError>
+    where
+        V: FloatElement,
+    {
+        let rows = self.keep_right(1)?;
+        let k = k.min(rows.shape()[1]);
+        let mut values = Vec::with_capacity(rows.len() * k);
+        let mut indices = Vec::with_capacity(rows.len() * k);
+        for row in rows.inners() {
+            let row = row.blob();
+            let descending =
+                |a: &usize, b: &usize| row[*b].to_f64().total_cmp(&row[*a].to_f64()).then(a.cmp(b));
+            let mut order = (0..row.len()).collect::<Vec<_>>();
+            if k > 0 && k < row.len() {
+                order.select_nth_unstable_by(k - 1, descending);
+            }
+            order.truncate(k);
+            order.sort_unstable_by(descending);
+            values.extend(order.iter().map(|i| row[*i]));
+            indices.extend(order);
+        }
+        let mut shape = self.shape().to_vec();
+        *shape.last_mut().unwrap() = k;
+        Ok((Tensor::raw(&shape, values)?, Tensor::raw(&shape, indices)?))
+    }
+
+    // Every row sorted in decreasing order, with the original indices of the values
+    fn sort_desc(&self) -> Result<(Tensor<V>, Tensor<usize>), TensorError>
+    where
+        V: FloatElement,
+    {
+        self.topk(self.shape().last().copied().unwrap_or(0))
+    }
+
     fn transpose(&self) -> Result<Tensor<V>, TensorError> {
         self.map(2, |m| {
             let d0 = m.shape()[0];
@@ -287,3 +489,140 @@ impl<V: TensorElement> TensorOps<V> for Tensor<V> {
         &self.blob
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topk() {
+        let t = Tensor::<f32>::raw(&[2, 4], vec![1., 3., 2., 3., -1., f32::NAN, 0., 5.]).unwrap();
+        let argmax = t.argmax().unwrap();
+        assert_eq!((argmax.shape(), argmax.blob()), (&[2][..], &[1, 1][..]));
+        assert_eq!(t.get(0).unwrap().argmax().unwrap().scalar().unwrap(), 1);
+
+        let (values, indices) = t.topk(2).unwrap();
+        assert_eq!(values.shape(), &[2, 2]);
+        assert_eq!(indices.blob(), &[1, 3, 1, 3]);
+        assert_eq!(values.get(0).unwrap().blob(), &[3., 3.]);
+        let (values, indices) = t.get(0).unwrap().sort_desc().unwrap();
+        assert_eq!(values.blob(), &[3., 3., 2., 1.]);
+        assert_eq!(indices.blob(), &[1, 3, 2, 0]);
+        assert_eq!(t.topk(10).unwrap().1.shape(), &[2, 4]);
+        assert_eq!(t.topk(0).unwrap().1.shape(), &[2, 0]);
+        assert!(Tensor::<f32>::scalar(1.).argmax().is_err());
+    }
+
+    #[test]
+    fn test_broadcast() {
+        assert_eq!(broadcast_shape(&[2, 3, 4], &[4]).unwrap(), [2, 3, 4]);
+        assert_eq!(broadcast_shape(&[2, 1, 4], &[3, 1]).unwrap(), [2, 3, 4]);
+        assert!(broadcast_shape(&[2, 3], &[2]).is_err());
+
+        let a = Tensor::<f32>::raw(&[2, 1, 3], vec![1., 2., 3., 4., 5., 6.]).unwrap();
+        let b = Tensor::<f32>::raw(&[2, 1], vec![10., 20.]).unwrap();
+        let sum = (&a.view() + &b.view()).unwrap();
+        assert_eq!(sum.shape(), &[2, 2, 3]);
+        assert_eq!(
+            sum.blob(),
+            &[11., 12., 13., 21., 22., 23., 14., 15., 16., 24., 25., 26.]
+        );
+        assert_eq!((&b.view() - &a.view()).unwrap().blob()[..3], [9., 8., 7.]);
+        assert!((&a.view() * &Tensor::<f32>::zeros(&[2]).view()).is_err());
+
+        // The gradients of the copies are summed
+        assert_eq!(unbroadcast(&sum, &[2, 1]).unwrap().blob(), &[81., 141.]);
+        assert_eq!(unbroadcast(&sum, &[3]).unwrap().blob(), &[70., 74., 78.]);
+        assert!(unbroadcast(&sum, &[2]).is_err());
+    }
+
+    #[test]
+    fn test_inplace() {
+        let mut t = Tensor::<f32>::raw(&[2, 3], vec![1., -2., 3., -4., 5., -6.]).unwrap();
+        t.add_assign(&Tensor::constant(&[2, 3], 1.)).unwrap();
+        assert_eq!(t.blob(), &[2., -1., 4., -3., 6., -5.]);
+        t.add_assign(&Tensor::raw(&[2, 1], vec![10., 20.]).unwrap())
+            .unwrap();
+        assert_eq!(t.blob(), &[12., 9., 14., 17., 26., 15.]);
+        assert!(t.add_assign(&Tensor::zeros(&[3, 3])).is_err());
+        t.mul_scalar_assign(-0.5);
+        t.get_mut(1).unwrap().relu_inplace();
+        assert_eq!(t.blob(), &[-6., -4.5, -7., 0., 0., 0.]);
+    }
+
+    #[test]
+    fn test_strided_view() {
+        let t = Tensor::<f32>::raw(&[2, 3, 4], (0..24).map(|i| i as f32).collect()).unwrap();
+        let view = t.strided();
+        assert!(view.is_contiguous());
+        assert_eq!(view.contiguous().unwrap().blob(), t.blob());
+
+        let t_t = view.t().unwrap();
+        assert_eq!(t_t.shape(), &[2, 4, 3]);
+        assert!(!t_t.is_contiguous() && t_t.contiguous().is_none());
+        assert_eq!(
+            Tensor::from(t_t.clone()).blob(),
+            t.transpose().unwrap().blob()
+        );
+        assert_eq!(
+            t_t.get(1).unwrap().iter().take(3).collect::<Vec<_>>(),
+            [12., 16., 20.]
+        );
+
+        let sliced = view.slice(2, 1..3).unwrap().slice(1, 2..3).unwrap();
+        assert_eq!(sliced.shape(), &[2, 1, 2]);
+        assert_eq!(sliced.iter().collect::<Vec<_>>(), [9., 10., 21., 22.]);
+        assert!(view.slice(0, 1..3).is_err());
+        assert_eq!(t.slice(1..2).unwrap().blob(), &t.blob()[12..]);
+
+        // Leading dimensions are merged only when contiguous
+        assert_eq!(view.keep_right(1).unwrap().shape(), &[6, 4]);
+        assert!(t_t.transpose(0, 1).unwrap().keep_right(1).is_err());
+        let merged = sliced.keep_right(2).unwrap();
+        assert_eq!(
+            (merged.shape(), merged.strides()),
+            (&[2, 1, 2][..], &[12, 4, 1][..])
+        );
+
+        // Products with transposed operands read them in place
+        let a = Tensor::<f32>::raw(&[2, 3, 2], (0..12).map(|i| i as f32).collect()).unwrap();
+        let b = Tensor::<f32>::raw(&[3, 4], (0..12).map(|i| i as f32 - 5.).collect()).unwrap();
+        let expected = (&a.transpose().unwrap() ^ &b).unwrap();
+        let product = matmul(&a.strided().t().unwrap(), &b.strided()).unwrap();
+        assert_eq!(product.shape(), &[2, 2, 4]);
+        assert_eq!(product.blob(), expected.blob());
+        assert!(matmul(&a.strided(), &b.strided()).is_err());
+    }
+
+    #[test]
+    fn test_parallel_matmul() {
+        // Big enough to be split between threads, with tiles spanning two matrices
+        let (m, n, p) = (37, 150, 20);
+        assert!(2 * m * n * p >= PARALLEL_THRESHOLD);
+        let a = Tensor::<f32>::raw(&[2, m, n], (0..2 * m * n).map(|i| (i % 7) as f32).collect())
+            .unwrap();
+        let b =
+            Tensor::<f32>::raw(&[n, p], (0..n * p).map(|i| (i % 5) as f32 - 2.).collect()).unwrap();
+        let mut expected = vec![0.; 2 * m * p];
+        for batch in 0..2 {
+            for i in 0..m {
+                for k in 0..n {
+                    for j in 0..p {
+                        expected[(batch * m + i) * p + j] +=
+                            a.blob()[(batch * m + i) * n + k] * b.blob()[k * p + j];
+                    }
+                }
+            }
+        }
+        let product = (&a ^ &b).unwrap();
+        assert_eq!(product.shape(), &[2, m, p]);
+        assert_eq!(product.blob(), &expected[..]);
+        let b_t = Tensor::from(b.strided().t().unwrap());
+        let product = matmul(&a.strided(), &b_t.strided().t().unwrap()).unwrap();
+        assert_eq!(product.blob(), &expected[..]);
+
+        let mut rows = vec![0; 1000];
+        for_each_row(&mut rows, 10, PARALLEL_THRESHOLD, |i, row| row.fill(i));
+        assert_eq!(rows[995], 99);
+    }
+}
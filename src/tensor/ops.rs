@@ -1,4 +1,78 @@
 use super::*;
+use rayon::prelude::*;
+
+// Operations split their rows between the threads of the rayon pool when they have at least
+// this many multiply-adds (Or similar) to compute, smaller ones run on the calling thread
+pub const PARALLEL_THRESHOLD: usize = 1 << 16;
+
+// Calls `f` with the index and the contents of every row of `row_len` elements of `blob`, in
+// parallel when the rows cost (Roughly `cost` operations each) `PARALLEL_THRESHOLD` in total,
+// unless the parallelism of the `ComputeConfig` excludes the operations
+pub fn for_each_row<V: Send, F: Fn(usize, &mut [V]) + Sync + Send>(
+    blob: &mut [V],
+    row_len: usize,
+    cost: usize,
+    f: F,
+) {
+    let row_len = row_len.max(1);
+    if blob.len() / row_len * cost >= PARALLEL_THRESHOLD
+        && crate::compute::parallelism().within_ops()
+    {
+        crate::compute::install(|| {
+            blob.par_chunks_mut(row_len)
+                .enumerate()
+                .for_each(|(i, row)| f(i, row))
+        });
+    } else {
+        blob.chunks_mut(row_len)
+            .enumerate()
+            .for_each(|(i, row)| f(i, row));
+    }
+}
+
+// Shape of the result of an elementwise operation on tensors of shapes `a` and `b`, following
+// the NumPy broadcasting rules: the shapes are aligned on their last dimension, and the
+// dimensions of size 1 (Or missing) of a tensor are repeated along those of the other one.
+pub fn broadcast_shape(a: &[usize], b: &[usize]) -> Result<Vec<usize>, TensorError> {
+    let dim = a.len().max(b.len());
+    let size = |shape: &[usize], d: usize| {
+        (d + shape.len())
+            .checked_sub(dim)
+            .map(|d| shape[d])
+            .unwrap_or(1)
+    };
+    (0..dim)
+        .map(|d| match (size(a, d), size(b, d)) {
+            (a, b) if a == b || b == 1 => Ok(a),
+            (1, b) => Ok(b),
+            _ => Err(TensorError::UnexpectedShape),
+        })
+        .collect()
+}
+
+// Index of every element of a tensor of shape `out` in a tensor of shape `shape` broadcast
+// to it
+pub(super) fn broadcast_indices(shape: &[usize], out: &[usize]) -> impl Iterator<Item = usize> {
+    let mut strides = vec![0; out.len()];
+    let mut stride = 1;
+    for (d, size) in shape.iter().enumerate().rev() {
+        let d = d + out.len() - shape.len();
+        if *size != 1 {
+            strides[d] = stride;
+        }
+        stride *= size;
+    }
+    let out = out.to_vec();
+    let size = out.iter().product::<usize>();
+    (0..size).map(move |mut i| {
+        let mut index = 0;
+        for (size, stride) in out.iter().zip(strides.iter()).rev() {
+            index += (i % size) * stride;
+            i /= size;
+        }
+        index
+    })
+}
 
 pub fn binary<
     'a,
@@ -12,25 +86,160 @@ pub fn binary<
     b: &T2,
     f: F,
 ) -> Result<Tensor<W>, TensorError> {
-    let (a, b, rev) = if a.dim() > b.dim() {
-        (a.view(), b.view(), false)
+    // When the shape of a tensor ends with the shape of the other one, the smaller one is
+    // simply repeated
+    let (big, small) = if a.dim() > b.dim() {
+        (a.shape(), b.shape())
     } else {
-        (b.view(), a.view(), true)
+        (b.shape(), a.shape())
+    };
+    if big.ends_with(small) {
+        let (a, b, rev) = if a.dim() > b.dim() {
+            (a.view(), b.view(), false)
+        } else {
+            (b.view(), a.view(), true)
+        };
+        return a.map(b.dim(), |a| {
+            let (a, b) = if rev { (&b, &a) } else { (&a, &b) };
+            Tensor::raw(
+                a.shape(),
+                a.blob()
+                    .iter()
+                    .zip(b.blob().iter())
+                    .map(|(a, b)| f(*a, *b))
+                    .collect(),
+            )
+        });
+    }
+    let shape = broadcast_shape(a.shape(), b.shape())?;
+    let (a_blob, b_blob) = (a.blob(), b.blob());
+    Tensor::raw(
+        &shape,
+        broadcast_indices(a.shape(), &shape)
+            .zip(broadcast_indices(b.shape(), &shape))
+            .map(|(i, j)| f(a_blob[i], b_blob[j]))
+            .collect(),
+    )
+}
+
+// Gradient of a tensor of shape `shape` broadcast in an elementwise operation, given the
+// gradient of its result: the sum of the gradients of all the copies of each element
+pub fn unbroadcast<V: TensorElement + AddAssign, T: TensorOps<V>>(
+    grad: &T,
+    shape: &[usize],
+) -> Result<Tensor<V>, TensorError> {
+    if grad.shape() == shape {
+        return Ok(grad.view().into());
+    }
+    if broadcast_shape(shape, grad.shape())? != grad.shape() {
+        return Err(TensorError::UnexpectedShape);
+    }
+    let mut out = Tensor::zeros(shape);
+    let out_blob = out.blob_mut();
+    for (i, g) in broadcast_indices(shape, grad.shape()).zip(grad.blob()) {
+        out_blob[i] += *g;
+    }
+    Ok(out)
+}
+
+// Product of the matrices (The last two dimensions) of two views, the leading dimensions of
+// the smaller one being repeated over those of the other one as with `^`. Operands are read
+// through their strides, e.g. `matmul(&a.strided().t()?, &b.strided())` computes `a^T b`
+// without copying `a`.
+pub fn matmul<
+    V: TensorElement + std::ops::Mul<Output = V> + std::ops::Add<Output = V> + std::ops::AddAssign,
+>(
+    a: &StridedView<V>,
+    b: &StridedView<V>,
+) -> Result<Tensor<V>, TensorError> {
+    let mut out = Tensor {
+        blob: Vec::new(),
+        shape: Vec::new(),
     };
-    a.map(b.dim(), |a| {
-        if a.shape() != b.shape() {
-            return Err(TensorError::UnexpectedShape);
+    matmul_into(a, b, &mut out)?;
+    Ok(out)
+}
+
+// Rows of the product computed by a task, and rows of `b` applied to them at once, so that a
+// tile of `b` stays in cache while it is accumulated into every row of the task
+const ROW_TILE: usize = 16;
+const INNER_TILE: usize = 128;
+
+// Same as `matmul`, writing the product into `out` (See `Tensor::resize`). Big products are
+// computed in parallel, by tiles of rows, see `for_each_row`.
+pub fn matmul_into<
+    V: TensorElement + std::ops::Mul<Output = V> + std::ops::Add<Output = V> + std::ops::AddAssign,
+>(
+    a: &StridedView<V>,
+    b: &StridedView<V>,
+    out: &mut Tensor<V>,
+) -> Result<(), TensorError> {
+    if a.dim() < 2 || b.dim() < 2 {
+        return Err(TensorError::UnexpectedShape);
+    }
+    let (m, n) = (a.shape()[a.dim() - 2], a.shape()[a.dim() - 1]);
+    let p = b.shape()[b.dim() - 1];
+    let (a_batch, b_batch) = (&a.shape()[..a.dim() - 2], &b.shape()[..b.dim() - 2]);
+    if b.shape()[b.dim() - 2] != n || !(a_batch.ends_with(b_batch) || b_batch.ends_with(a_batch)) {
+        return Err(TensorError::UnexpectedShape);
+    }
+    // Offsets of the matrices of each operand
+    let matrices = |v: &StridedView<V>| {
+        let batch = v.dim() - 2;
+        StridedView {
+            mirror: v.mirror,
+            offset: v.offset,
+            shape: v.shape[..batch].to_vec(),
+            strides: v.strides[..batch].to_vec(),
         }
-        let (a, b) = if rev { (&b, &a) } else { (&a, &b) };
-        Tensor::raw(
-            a.shape(),
-            a.blob()
-                .iter()
-                .zip(b.blob().iter())
-                .map(|(a, b)| f(*a, *b))
-                .collect(),
-        )
-    })
+        .offsets()
+        .collect::<Vec<_>>()
+    };
+    let (a_offsets, b_offsets) = (matrices(a), matrices(b));
+    let (sa0, sa1) = (a.strides()[a.dim() - 2], a.strides()[a.dim() - 1]);
+    let (sb0, sb1) = (b.strides()[b.dim() - 2], b.strides()[b.dim() - 1]);
+    let (a_blob, b_blob) = (a.mirror.blob(), b.mirror.blob());
+
+    let mut shape = if a_batch.len() >= b_batch.len() {
+        a_batch
+    } else {
+        b_batch
+    }
+    .to_vec();
+    shape.extend([m, p]);
+    let result = out.resize(&shape);
+    result.fill(V::zero());
+    if m == 0 || p == 0 {
+        return Ok(());
+    }
+    // The k-th product of every element is accumulated in order, whatever the tiling, so
+    // that the result does not depend on the number of threads
+    let tile_len = ROW_TILE.min(m) * p;
+    for_each_row(result, tile_len, tile_len * n, |tile, rows| {
+        let first = tile * (tile_len / p);
+        for k0 in (0..n).step_by(INNER_TILE) {
+            for (r, out) in rows.chunks_mut(p).enumerate() {
+                let (batch, i) = ((first + r) / m, (first + r) % m);
+                let a_i = a_offsets[batch % a_offsets.len()] + i * sa0;
+                let b_offset = b_offsets[batch % b_offsets.len()];
+                for k in k0..(k0 + INNER_TILE).min(n) {
+                    let a_ik = a_blob[a_i + k * sa1];
+                    let b_k = b_offset + k * sb0;
+                    if sb1 == 1 {
+                        // Rows of `b` are contiguous
+                        for (o, b) in out.iter_mut().zip(&b_blob[b_k..b_k + p]) {
+                            *o += a_ik * *b;
+                        }
+                    } else {
+                        for (j, o) in out.iter_mut().enumerate() {
+                            *o += a_ik * b_blob[b_k + j * sb1];
+                        }
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
 }
 
 impl<'a, V: TensorElement + std::ops::Add<Output = V>> Add for &TensorView<'a, V> {
@@ -58,48 +267,6 @@ impl<
 {
     type Output = Result<Tensor<V>, TensorError>;
     fn bitxor(self, other: &TensorView<V>) -> Self::Output {
-        let (a, b, rev) = if self.dim() > other.dim() {
-            (self.view(), other.view(), false)
-        } else {
-            (other.view(), self.view(), true)
-        };
-        a.map(b.dim(), |a| {
-            if a.shape()[..a.dim() - 2] != b.shape()[..b.dim() - 2] {
-                return Err(TensorError::UnexpectedShape);
-            }
-            let (a, b) = if rev { (&b, &a) } else { (&a, &b) };
-            if a.shape()[a.dim() - 1] != b.shape()[b.dim() - 2] {
-                return Err(TensorError::UnexpectedShape);
-            }
-            let data = a
-                .keep_right(2)?
-                .inners()
-                .iter()
-                .zip(b.keep_right(2)?.inners().iter())
-                .map(|(a, b)| {
-                    let m = a.shape()[0];
-                    let n = a.shape()[1];
-                    let p = b.shape()[1];
-                    let mut result = vec![
-                            <<V as std::ops::Mul>::Output as std::ops::Add>::Output::zero();
-                            m * p
-                        ];
-                    let a_blob = a.blob();
-                    let b_blob = b.blob();
-                    for i in 0..m {
-                        for k in 0..n {
-                            for j in 0..p {
-                                result[i * p + j] += a_blob[i * n + k] * b_blob[k * p + j];
-                            }
-                        }
-                    }
-                    result
-                })
-                .flatten()
-                .collect();
-            let mut final_shape = a.shape().to_vec();
-            final_shape[a.dim() - 1] = b.shape()[b.dim() - 1];
-            Tensor::raw(&final_shape, data)
-        })
+        matmul(&self.strided(), &other.strided())
     }
 }
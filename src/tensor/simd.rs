@@ -0,0 +1,216 @@
+use super::Float;
+
+// Vectorized inner loops of the hot CPU functions. With the `simd` feature, AVX is used
+// on x86_64 machines that support it (Detected at runtime), otherwise (Or with the `f64`
+// feature) the plain loops are used. Transcendental functions (exp, tanh) stay scalar.
+
+macro_rules! dispatch {
+    ($call:expr) => {
+        #[cfg(all(feature = "simd", target_arch = "x86_64", not(feature = "f64")))]
+        if is_x86_feature_detected!("avx") {
+            return unsafe { $call };
+        }
+    };
+}
+
+// out = a + b
+pub fn add(a: &[Float], b: &[Float], out: &mut [Float]) {
+    dispatch!(avx::add(a, b, out));
+    for ((o, a), b) in out.iter_mut().zip(a.iter()).zip(b.iter()) {
+        *o = a + b;
+    }
+}
+
+// out = max(a, slope * a)
+pub fn leaky_relu(a: &[Float], slope: Float, out: &mut [Float]) {
+    dispatch!(avx::leaky_relu(a, slope, out));
+    for (o, a) in out.iter_mut().zip(a.iter()) {
+        *o = if *a > 0. { *a } else { slope * a };
+    }
+}
+
+// out = (a - sub) * mul
+pub fn sub_mul(a: &[Float], sub: Float, mul: Float, out: &mut [Float]) {
+    dispatch!(avx::sub_mul(a, sub, mul, out));
+    for (o, a) in out.iter_mut().zip(a.iter()) {
+        *o = (a - sub) * mul;
+    }
+}
+
+// a *= c
+pub fn scale(a: &mut [Float], c: Float) {
+    dispatch!(avx::scale(a, c));
+    for a in a.iter_mut() {
+        *a *= c;
+    }
+}
+
+pub fn max(a: &[Float]) -> Float {
+    dispatch!(avx::max(a));
+    a.iter().fold(Float::NEG_INFINITY, |a, b| Float::max(a, *b))
+}
+
+pub fn sum(a: &[Float]) -> Float {
+    dispatch!(avx::sum(a));
+    a.iter().sum()
+}
+
+// Sum of (a - m)^2
+pub fn sum_sq_diff(a: &[Float], m: Float) -> Float {
+    dispatch!(avx::sum_sq_diff(a, m));
+    a.iter().map(|f| (f - m).powi(2)).sum()
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64", not(feature = "f64")))]
+mod avx {
+    use std::arch::x86_64::*;
+
+    const LANES: usize = 8;
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn add(a: &[f32], b: &[f32], out: &mut [f32]) {
+        let n = out.len().min(a.len()).min(b.len());
+        let body = n - n % LANES;
+        for i in (0..body).step_by(LANES) {
+            let v = _mm256_add_ps(
+                _mm256_loadu_ps(a.as_ptr().add(i)),
+                _mm256_loadu_ps(b.as_ptr().add(i)),
+            );
+            _mm256_storeu_ps(out.as_mut_ptr().add(i), v);
+        }
+        for i in body..n {
+            out[i] = a[i] + b[i];
+        }
+    }
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn leaky_relu(a: &[f32], slope: f32, out: &mut [f32]) {
+        let n = out.len().min(a.len());
+        let body = n - n % LANES;
+        let s = _mm256_set1_ps(slope);
+        for i in (0..body).step_by(LANES) {
+            let v = _mm256_loadu_ps(a.as_ptr().add(i));
+            let v = _mm256_max_ps(v, _mm256_mul_ps(v, s));
+            _mm256_storeu_ps(out.as_mut_ptr().add(i), v);
+        }
+        for i in body..n {
+            out[i] = if a[i] > 0. { a[i] } else { slope * a[i] };
+        }
+    }
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn sub_mul(a: &[f32], sub: f32, mul: f32, out: &mut [f32]) {
+        let n = out.len().min(a.len());
+        let body = n - n % LANES;
+        let s = _mm256_set1_ps(sub);
+        let m = _mm256_set1_ps(mul);
+        for i in (0..body).step_by(LANES) {
+            let v = _mm256_mul_ps(_mm256_sub_ps(_mm256_loadu_ps(a.as_ptr().add(i)), s), m);
+            _mm256_storeu_ps(out.as_mut_ptr().add(i), v);
+        }
+        for i in body..n {
+            out[i] = (a[i] - sub) * mul;
+        }
+    }
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn scale(a: &mut [f32], c: f32) {
+        let n = a.len();
+        let body = n - n % LANES;
+        let c8 = _mm256_set1_ps(c);
+        for i in (0..body).step_by(LANES) {
+            let v = _mm256_mul_ps(_mm256_loadu_ps(a.as_ptr().add(i)), c8);
+            _mm256_storeu_ps(a.as_mut_ptr().add(i), v);
+        }
+        for v in a[body..].iter_mut() {
+            *v *= c;
+        }
+    }
+
+    #[target_feature(enable = "avx")]
+    unsafe fn lanes(v: __m256) -> [f32; LANES] {
+        let mut out = [0.; LANES];
+        _mm256_storeu_ps(out.as_mut_ptr(), v);
+        out
+    }
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn max(a: &[f32]) -> f32 {
+        let n = a.len();
+        let body = n - n % LANES;
+        let mut acc = _mm256_set1_ps(f32::NEG_INFINITY);
+        for i in (0..body).step_by(LANES) {
+            acc = _mm256_max_ps(acc, _mm256_loadu_ps(a.as_ptr().add(i)));
+        }
+        lanes(acc)
+            .iter()
+            .chain(a[body..].iter())
+            .fold(f32::NEG_INFINITY, |a, b| f32::max(a, *b))
+    }
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn sum(a: &[f32]) -> f32 {
+        let n = a.len();
+        let body = n - n % LANES;
+        let mut acc = _mm256_setzero_ps();
+        for i in (0..body).step_by(LANES) {
+            acc = _mm256_add_ps(acc, _mm256_loadu_ps(a.as_ptr().add(i)));
+        }
+        lanes(acc).iter().chain(a[body..].iter()).sum()
+    }
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn sum_sq_diff(a: &[f32], m: f32) -> f32 {
+        let n = a.len();
+        let body = n - n % LANES;
+        let m8 = _mm256_set1_ps(m);
+        let mut acc = _mm256_setzero_ps();
+        for i in (0..body).step_by(LANES) {
+            let d = _mm256_sub_ps(_mm256_loadu_ps(a.as_ptr().add(i)), m8);
+            acc = _mm256_add_ps(acc, _mm256_mul_ps(d, d));
+        }
+        lanes(acc).iter().sum::<f32>() + a[body..].iter().map(|f| (f - m).powi(2)).sum::<f32>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_scalar() {
+        // Odd length, so that both the vectorized body and the remainder are covered
+        let a = (0..19)
+            .map(|i| (i as Float - 9.) * 0.37)
+            .collect::<Vec<_>>();
+        let b = (0..19)
+            .map(|i| (i as Float * 1.3).sin())
+            .collect::<Vec<_>>();
+        let mut out = vec![0.; 19];
+
+        add(&a, &b, &mut out);
+        for i in 0..19 {
+            assert_eq!(out[i], a[i] + b[i]);
+        }
+        leaky_relu(&a, 0.01, &mut out);
+        for i in 0..19 {
+            assert_eq!(out[i], if a[i] > 0. { a[i] } else { 0.01 * a[i] });
+        }
+        sub_mul(&a, 0.5, 2., &mut out);
+        for i in 0..19 {
+            assert_eq!(out[i], (a[i] - 0.5) * 2.);
+        }
+        let mut scaled = a.clone();
+        scale(&mut scaled, 3.);
+        for i in 0..19 {
+            assert_eq!(scaled[i], a[i] * 3.);
+        }
+        assert_eq!(
+            max(&b),
+            b.iter().cloned().fold(Float::NEG_INFINITY, Float::max)
+        );
+        assert!((sum(&a) - a.iter().sum::<Float>()).abs() < 1e-4);
+        let sq = a.iter().map(|f| (f - 0.3).powi(2)).sum::<Float>();
+        assert!((sum_sq_diff(&a, 0.3) - sq).abs() < 1e-3);
+    }
+}
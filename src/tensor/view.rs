@@ -69,3 +69,152 @@ impl<V: TensorElement> TensorOps<V> for TensorView<'_, V> {
         &self.mirror.blob[self.offset..self.offset + self.size()]
     }
 }
+
+// View of a tensor through arbitrary strides, so that slices and transpositions of it don't
+// copy its blob. Unlike `TensorView`, its elements aren't necessarily contiguous, they are
+// read with `iter` (Or `contiguous` when they happen to be).
+#[derive(Debug, Clone)]
+pub struct StridedView<'a, V: TensorElement> {
+    pub(super) mirror: &'a Tensor<V>,
+    pub(super) offset: usize,
+    pub(super) shape: Vec<usize>,
+    pub(super) strides: Vec<usize>,
+}
+
+// Strides of a contiguous tensor of the given shape
+pub(super) fn contiguous_strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for d in (0..shape.len().saturating_sub(1)).rev() {
+        strides[d] = strides[d + 1] * shape[d + 1];
+    }
+    strides
+}
+
+impl<'a, V: TensorElement> StridedView<'a, V> {
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+    pub fn dim(&self) -> usize {
+        self.shape.len()
+    }
+    pub fn size(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn is_contiguous(&self) -> bool {
+        self.shape
+            .iter()
+            .zip(self.strides.iter().zip(contiguous_strides(&self.shape)))
+            .all(|(size, (stride, expected))| *size == 1 || *stride == expected)
+    }
+
+    // The same elements as a `TensorView`, if they are contiguous
+    pub fn contiguous(&self) -> Option<TensorView<'a, V>> {
+        self.is_contiguous().then(|| TensorView {
+            mirror: self.mirror,
+            offset: self.offset,
+            shape: self.shape.clone(),
+        })
+    }
+
+    // Positions in the blob of the elements of the view, in order
+    pub fn offsets(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.size()).map(move |mut i| {
+            let mut offset = self.offset;
+            for (size, stride) in self.shape.iter().zip(self.strides.iter()).rev() {
+                offset += (i % size) * stride;
+                i /= size;
+            }
+            offset
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = V> + '_ {
+        self.offsets().map(|i| self.mirror.blob[i])
+    }
+
+    pub fn get(&self, ind: usize) -> Result<Self, TensorError> {
+        if self.dim() == 0 || ind >= self.shape[0] {
+            return Err(TensorError::InvalidIndex);
+        }
+        Ok(Self {
+            mirror: self.mirror,
+            offset: self.offset + ind * self.strides[0],
+            shape: self.shape[1..].to_vec(),
+            strides: self.strides[1..].to_vec(),
+        })
+    }
+
+    // The elements of the view whose index along `dim` is in `range`
+    pub fn slice(&self, dim: usize, range: Range<usize>) -> Result<Self, TensorError> {
+        if dim >= self.dim() || range.start > range.end || range.end > self.shape[dim] {
+            return Err(TensorError::InvalidIndex);
+        }
+        let mut view = self.clone();
+        view.offset += range.start * self.strides[dim];
+        view.shape[dim] = range.len();
+        Ok(view)
+    }
+
+    // Swaps two dimensions of the view
+    pub fn transpose(&self, dim0: usize, dim1: usize) -> Result<Self, TensorError> {
+        if dim0 >= self.dim() || dim1 >= self.dim() {
+            return Err(TensorError::InvalidIndex);
+        }
+        let mut view = self.clone();
+        view.shape.swap(dim0, dim1);
+        view.strides.swap(dim0, dim1);
+        Ok(view)
+    }
+
+    // Swaps the last two dimensions, transposing the matrices of the view
+    pub fn t(&self) -> Result<Self, TensorError> {
+        if self.dim() < 2 {
+            return Err(TensorError::UnexpectedShape);
+        }
+        self.transpose(self.dim() - 2, self.dim() - 1)
+    }
+
+    // Merges the leading dimensions into one, as `TensorOps::keep_right` does. Fails when
+    // they can't be merged without copying.
+    pub fn keep_right(&self, dims: usize) -> Result<Self, TensorError> {
+        if self.dim() < dims {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let lead = self.dim() - dims;
+        let mut view = Self {
+            mirror: self.mirror,
+            offset: self.offset,
+            shape: self.shape[lead..].to_vec(),
+            strides: self.strides[lead..].to_vec(),
+        };
+        let (mut size, mut stride) = (1, 0);
+        for d in (0..lead).rev() {
+            if self.shape[d] == 1 {
+                continue;
+            }
+            if size > 1 && self.strides[d] != stride * size {
+                return Err(TensorError::UnexpectedShape);
+            }
+            if size == 1 {
+                stride = self.strides[d];
+            }
+            size *= self.shape[d];
+        }
+        view.shape.insert(0, size);
+        view.strides.insert(0, stride);
+        Ok(view)
+    }
+}
+
+impl<V: TensorElement> From<StridedView<'_, V>> for Tensor<V> {
+    fn from(view: StridedView<'_, V>) -> Tensor<V> {
+        Tensor {
+            blob: view.iter().collect(),
+            shape: view.shape().to_vec(),
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use crate::callback::{StepInfo, TrainCallback, TrainingStats};
+use crate::gpt::{Evaluation, GPT};
+use crate::graph::{Graph, GraphError};
+use crate::tensor::{Float, FloatElement, TensorOps};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Writer of TensorBoard event files (https://github.com/tensorflow/tensorboard). Events are
+// `Event` protobufs, stored as TFRecords.
+
+const NUM_BUCKETS: usize = 30;
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f63b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn masked_crc(data: &[u8]) -> u32 {
+    let crc = crc32c(data);
+    crc.rotate_right(15).wrapping_add(0xa282ead8)
+}
+
+fn varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn field(out: &mut Vec<u8>, field: u64, wire_type: u64) {
+    varint(out, (field << 3) | wire_type);
+}
+
+fn double(out: &mut Vec<u8>, f: u64, v: f64) {
+    field(out, f, 1);
+    out.extend(v.to_le_bytes());
+}
+
+fn bytes(out: &mut Vec<u8>, f: u64, b: &[u8]) {
+    field(out, f, 2);
+    varint(out, b.len() as u64);
+    out.extend(b);
+}
+
+fn packed_doubles(out: &mut Vec<u8>, f: u64, vs: &[f64]) {
+    let b = vs.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+    bytes(out, f, &b);
+}
+
+// `HistogramProto` with equally sized buckets between the minimum and the maximum
+fn histogram(values: &[Float]) -> Vec<u8> {
+    let values = values
+        .iter()
+        .map(|v| v.to_f64())
+        .filter(|v| v.is_finite())
+        .collect::<Vec<_>>();
+    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let mut h = Vec::new();
+    if values.is_empty() {
+        return h;
+    }
+    let width = (max - min) / NUM_BUCKETS as f64;
+    let mut counts = vec![0.; NUM_BUCKETS];
+    for v in values.iter() {
+        let i = if width > 0. {
+            ((v - min) / width) as usize
+        } else {
+            0
+        };
+        counts[usize::min(i, NUM_BUCKETS - 1)] += 1.;
+    }
+    let limits = (1..=NUM_BUCKETS)
+        .map(|i| min + width * i as f64)
+        .collect::<Vec<_>>();
+    double(&mut h, 1, min);
+    double(&mut h, 2, max);
+    double(&mut h, 3, values.len() as f64);
+    double(&mut h, 4, values.iter().sum());
+    double(&mut h, 5, values.iter().map(|v| v * v).sum());
+    packed_doubles(&mut h, 6, &limits);
+    packed_doubles(&mut h, 7, &counts);
+    h
+}
+
+// Logs the loss, learning rate, gradient norm and throughput of every step, the
+// validation results, and on checkpoints the histograms of the parameters whose names
+// contain any of the given patterns
+pub struct TensorBoardWriter {
+    file: BufWriter<File>,
+    histograms: Vec<String>,
+}
+
+impl TensorBoardWriter {
+    // Creates a new event file in `log_dir`
+    pub fn create<P: AsRef<Path>>(log_dir: P) -> Result<Self, GraphError> {
+        std::fs::create_dir_all(&log_dir)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = log_dir
+            .as_ref()
+            .join(format!("events.out.tfevents.{}.femtogpt", now));
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            histograms: Vec::new(),
+        };
+        let mut event = Vec::new();
+        bytes(&mut event, 3, b"brain.Event:2");
+        writer.event(0, event)?;
+        Ok(writer)
+    }
+
+    pub fn with_histograms(mut self, patterns: &[&str]) -> Self {
+        self.histograms = patterns.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    fn event(&mut self, step: usize, mut body: Vec<u8>) -> Result<(), GraphError> {
+        let wall_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut event = Vec::new();
+        double(&mut event, 1, wall_time);
+        field(&mut event, 2, 0);
+        varint(&mut event, step as u64);
+        event.append(&mut body);
+
+        let len = (event.len() as u64).to_le_bytes();
+        self.file.write_all(&len)?;
+        self.file.write_all(&masked_crc(&len).to_le_bytes())?;
+        self.file.write_all(&event)?;
+        self.file.write_all(&masked_crc(&event).to_le_bytes())?;
+        Ok(())
+    }
+
+    fn summary(&mut self, step: usize, tag: &str, value: Vec<u8>) -> Result<(), GraphError> {
+        let mut v = Vec::new();
+        bytes(&mut v, 1, tag.as_bytes());
+        v.extend(value);
+        let mut summary = Vec::new();
+        bytes(&mut summary, 1, &v);
+        let mut event = Vec::new();
+        bytes(&mut event, 5, &summary);
+        self.event(step, event)
+    }
+
+    pub fn add_scalar(&mut self, tag: &str, value: Float, step: usize) -> Result<(), GraphError> {
+        let mut v = Vec::new();
+        field(&mut v, 2, 5);
+        v.extend(f32::from_f64(value.to_f64()).to_le_bytes());
+        self.summary(step, tag, v)
+    }
+
+    pub fn add_histogram(
+        &mut self,
+        tag: &str,
+        values: &[Float],
+        step: usize,
+    ) -> Result<(), GraphError> {
+        let mut v = Vec::new();
+        bytes(&mut v, 5, &histogram(values));
+        self.summary(step, tag, v)
+    }
+
+    pub fn flush(&mut self) -> Result<(), GraphError> {
+        Ok(self.file.flush()?)
+    }
+}
+
+impl<G: Graph> TrainCallback<G> for TensorBoardWriter {
+    fn on_step_end(
+        &mut self,
+        _gpt: &mut GPT<G>,
+        info: &StepInfo,
+    ) -> Result<ControlFlow<()>, GraphError> {
+        self.add_scalar("train/loss", info.loss, info.step)?;
+        self.add_scalar("train/learning_rate", info.learning_rate, info.step)?;
+        if let Some(grad_norm) = info.grad_norm {
+            self.add_scalar("train/grad_norm", grad_norm, info.step)?;
+        }
+        self.add_scalar("train/tokens_per_sec", info.tokens_per_sec(), info.step)?;
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn on_eval(
+        &mut self,
+        _gpt: &mut GPT<G>,
+        info: &StepInfo,
+        eval: &Evaluation,
+    ) -> Result<ControlFlow<()>, GraphError> {
+        self.add_scalar("validation/loss", eval.loss, info.step)?;
+        self.add_scalar("validation/perplexity", eval.perplexity, info.step)?;
+        self.flush()?;
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn on_stats(
+        &mut self,
+        _gpt: &mut GPT<G>,
+        info: &StepInfo,
+        stats: &TrainingStats,
+    ) -> Result<(), GraphError> {
+        for s in stats.gradients.iter() {
+            self.add_scalar(&format!("grad_norm/{}", s.name), s.norm, info.step)?;
+        }
+        for s in stats.activations.iter() {
+            self.add_scalar(&format!("activations/{}/mean", s.name), s.mean, info.step)?;
+            let tag = format!("activations/{}/variance", s.name);
+            self.add_scalar(&tag, s.variance, info.step)?;
+        }
+        for s in stats.non_finite() {
+            let tag = format!("non_finite/{}", s.name);
+            self.add_scalar(&tag, s.non_finite as Float, info.step)?;
+        }
+        Ok(())
+    }
+
+    fn on_checkpoint(&mut self, gpt: &mut GPT<G>) -> Result<(), GraphError> {
+        if !self.histograms.is_empty() {
+            let state = gpt.get_training_state()?;
+            let step = state.optimizer.step;
+            let mut names = state
+                .tensors
+                .keys()
+                .filter(|name| self.histograms.iter().any(|p| name.contains(p.as_str())))
+                .cloned()
+                .collect::<Vec<_>>();
+            names.sort();
+            for name in names {
+                let tag = format!("params/{}", name);
+                self.add_histogram(&tag, state.tensors[&name].blob(), step)?;
+            }
+        }
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_file() {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+
+        let dir = std::env::temp_dir().join(format!("femto_gpt_tb_{}", std::process::id()));
+        let mut writer = TensorBoardWriter::create(&dir).unwrap();
+        writer.add_scalar("loss", 1.5, 7).unwrap();
+        writer.add_histogram("w", &[0., 1., 2., 3.], 7).unwrap();
+        writer.flush().unwrap();
+        let path = std::fs::read_dir(&dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let data = std::fs::read(path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Three records, each framed with its length and checksums
+        let mut records = Vec::new();
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let len = u64::from_le_bytes(rest[..8].try_into().unwrap()) as usize;
+            let len_crc = u32::from_le_bytes(rest[8..12].try_into().unwrap());
+            assert_eq!(len_crc, masked_crc(&rest[..8]));
+            let record = &rest[12..12 + len];
+            let crc = u32::from_le_bytes(rest[12 + len..16 + len].try_into().unwrap());
+            assert_eq!(crc, masked_crc(record));
+            records.push(record.to_vec());
+            rest = &rest[16 + len..];
+        }
+        assert_eq!(records.len(), 3);
+        assert!(records[0].ends_with(b"brain.Event:2"));
+        // wall_time, step 7, then the summary ending with the float value
+        assert_eq!(records[1][9..11], [0x10, 7]);
+        assert!(records[1].ends_with(&1.5f32.to_le_bytes()));
+    }
+}
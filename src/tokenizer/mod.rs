@@ -4,8 +4,80 @@ pub use simple::*;
 mod sentencepiece;
 pub use sentencepiece::*;
 
+use serde::{Deserialize, Serialize};
+
 pub trait Tokenizer {
     fn vocab_size(&self) -> usize;
     fn tokenize(&self, string: &str) -> Vec<usize>;
     fn untokenize(&self, tokens: &[usize]) -> String;
+
+    // The tokenizer as saved in the checkpoints of a model, if it can be
+    fn saved(&self) -> Option<SavedTokenizer> {
+        None
+    }
+
+    // Hash of the text of every token, identifying the vocabulary
+    fn fingerprint(&self) -> u64 {
+        let texts = (0..self.vocab_size()).map(|t| self.untokenize(&[t]) + "\0");
+        crate::checkpoint::fnv1a(texts.flat_map(String::into_bytes))
+    }
+}
+
+// Tokenizers saved with a model, see `GPT::set_tokenizer`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SavedTokenizer {
+    Simple(SimpleTokenizer),
+    SentencePiece(SentencePieceTokenizer),
+}
+
+impl Tokenizer for SavedTokenizer {
+    fn vocab_size(&self) -> usize {
+        match self {
+            SavedTokenizer::Simple(t) => t.vocab_size(),
+            SavedTokenizer::SentencePiece(t) => t.vocab_size(),
+        }
+    }
+    fn tokenize(&self, string: &str) -> Vec<usize> {
+        match self {
+            SavedTokenizer::Simple(t) => t.tokenize(string),
+            SavedTokenizer::SentencePiece(t) => t.tokenize(string),
+        }
+    }
+    fn untokenize(&self, tokens: &[usize]) -> String {
+        match self {
+            SavedTokenizer::Simple(t) => t.untokenize(tokens),
+            SavedTokenizer::SentencePiece(t) => t.untokenize(tokens),
+        }
+    }
+    fn saved(&self) -> Option<SavedTokenizer> {
+        Some(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_saved_tokenizer() {
+        let pieces = ["<unk>", "\u{2581}a", "b", "\u{2581}ab"]
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (p.to_string(), -(i as f32)))
+            .collect::<Vec<_>>();
+        let tokenizers = [
+            SimpleTokenizer::new("ba ").saved().unwrap(),
+            SentencePieceTokenizer::from(pieces).saved().unwrap(),
+        ];
+        for tokenizer in tokenizers {
+            let bytes = bincode::serialize(&tokenizer).unwrap();
+            let restored: SavedTokenizer = bincode::deserialize(&bytes).unwrap();
+            assert_eq!(restored.fingerprint(), tokenizer.fingerprint());
+            assert_eq!(restored.tokenize("ab a"), tokenizer.tokenize("ab a"));
+            assert_eq!(
+                restored.untokenize(&restored.tokenize("ab a")).trim(),
+                "ab a"
+            );
+        }
+    }
 }
@@ -11,9 +11,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::Tokenizer;
+use super::{SavedTokenizer, Tokenizer};
 
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io;
@@ -28,6 +29,7 @@ struct Node {
     start: usize,
 }
 
+#[derive(Debug, Clone)]
 struct DagNode {
     text: String,
     len: usize,
@@ -51,9 +53,13 @@ impl DagNode {
     }
 }
 
+// Saved as its pieces and their scores
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "Vec<(String, f32)>", into = "Vec<(String, f32)>")]
 pub struct SentencePieceTokenizer {
     root: DagNode,
     vocab: Vec<String>,
+    scores: Vec<f32>,
 }
 
 impl SentencePieceTokenizer {
@@ -61,6 +67,7 @@ impl SentencePieceTokenizer {
         let mut model = SentencePieceTokenizer {
             root: DagNode::new("".to_string()),
             vocab: Default::default(),
+            scores: Default::default(),
         };
 
         let f = File::open(vocab_file)?;
@@ -82,8 +89,18 @@ impl SentencePieceTokenizer {
         Ok(model)
     }
 
+    // Pieces of the vocabulary and their scores, ordered by id
+    pub fn pieces(&self) -> Vec<(String, f32)> {
+        self.vocab
+            .iter()
+            .cloned()
+            .zip(self.scores.iter().cloned())
+            .collect()
+    }
+
     fn insert(&mut self, word: &str, score: f32, index: usize) {
         self.vocab.insert(index as usize, word.into());
+        self.scores.insert(index, score);
         let char_count = word.chars().count();
         let mut node = &mut self.root;
 
@@ -173,29 +190,51 @@ impl SentencePieceTokenizer {
     }
 }
 
+impl From<Vec<(String, f32)>> for SentencePieceTokenizer {
+    fn from(pieces: Vec<(String, f32)>) -> Self {
+        let mut model = SentencePieceTokenizer {
+            root: DagNode::new("".to_string()),
+            vocab: Default::default(),
+            scores: Default::default(),
+        };
+        for (idx, (token, score)) in pieces.iter().enumerate() {
+            model.insert(token, *score, idx);
+        }
+        model
+    }
+}
+
+impl From<SentencePieceTokenizer> for Vec<(String, f32)> {
+    fn from(tokenizer: SentencePieceTokenizer) -> Self {
+        tokenizer.pieces()
+    }
+}
+
 impl Tokenizer for SentencePieceTokenizer {
     fn vocab_size(&self) -> usize {
         self.vocab.len()
     }
     fn tokenize(&self, text: &str) -> Vec<usize> {
         let lines = text.split("\n").collect::<Vec<_>>();
-        let chunk_size = std::cmp::max(1, lines.len() / rayon::current_num_threads());
-
-        lines
-            .par_chunks(chunk_size)
-            .map(|lines| {
-                let mut tokens = Vec::new();
-                for line in lines.iter() {
-                    let text =
-                        (String::from(" ") + line).replace(' ', &PREFIXED_UNDERSCORE.to_string());
-                    let text = text.as_str();
-                    let output = self.decode_forward_dag(text);
-                    tokens.extend(self.decode_backward(output));
-                }
-                tokens
-            })
-            .flatten()
-            .collect::<Vec<_>>()
+        let chunk_size = std::cmp::max(1, lines.len() / crate::compute::num_threads());
+
+        crate::compute::install(|| {
+            lines
+                .par_chunks(chunk_size)
+                .map(|lines| {
+                    let mut tokens = Vec::new();
+                    for line in lines.iter() {
+                        let text = (String::from(" ") + line)
+                            .replace(' ', &PREFIXED_UNDERSCORE.to_string());
+                        let text = text.as_str();
+                        let output = self.decode_forward_dag(text);
+                        tokens.extend(self.decode_backward(output));
+                    }
+                    tokens
+                })
+                .flatten()
+                .collect::<Vec<_>>()
+        })
     }
     fn untokenize(&self, tokens: &[usize]) -> String {
         let mut out = String::new();
@@ -204,4 +243,7 @@ impl Tokenizer for SentencePieceTokenizer {
         }
         out.replace(PREFIXED_UNDERSCORE, " ")
     }
+    fn saved(&self) -> Option<SavedTokenizer> {
+        Some(SavedTokenizer::SentencePiece(self.clone()))
+    }
 }
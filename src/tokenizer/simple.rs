@@ -1,6 +1,10 @@
-use super::Tokenizer;
+use super::{SavedTokenizer, Tokenizer};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 
+// Saved as its characters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "Vec<char>", into = "Vec<char>")]
 pub struct SimpleTokenizer {
     vocab_size: usize,
     ch_to_int: HashMap<char, usize>,
@@ -15,6 +19,17 @@ impl SimpleTokenizer {
             .into_iter()
             .collect::<Vec<_>>();
         chars.sort();
+        Self::from(chars)
+    }
+
+    // Characters of the vocabulary, ordered by id
+    pub fn chars(&self) -> Vec<char> {
+        (0..self.vocab_size).map(|i| self.int_to_ch[&i]).collect()
+    }
+}
+
+impl From<Vec<char>> for SimpleTokenizer {
+    fn from(chars: Vec<char>) -> Self {
         let int_to_ch = chars
             .iter()
             .enumerate()
@@ -33,6 +48,12 @@ impl SimpleTokenizer {
     }
 }
 
+impl From<SimpleTokenizer> for Vec<char> {
+    fn from(tokenizer: SimpleTokenizer) -> Self {
+        tokenizer.chars()
+    }
+}
+
 impl Tokenizer for SimpleTokenizer {
     fn vocab_size(&self) -> usize {
         self.vocab_size
@@ -49,4 +70,7 @@ impl Tokenizer for SimpleTokenizer {
             .map(|tkn| self.int_to_ch.get(tkn).unwrap().clone())
             .collect()
     }
+    fn saved(&self) -> Option<SavedTokenizer> {
+        Some(SavedTokenizer::Simple(self.clone()))
+    }
 }
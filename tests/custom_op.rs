@@ -0,0 +1,56 @@
+use femto_gpt::funcs::Function;
+use femto_gpt::graph::{CpuGraph, Graph};
+use femto_gpt::tensor::*;
+
+// A custom operation, defined outside of the crate: f(x) = x^2
+#[derive(Debug, Clone)]
+struct Square;
+
+impl Function for Square {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<Float>, TensorError> {
+        Ok(inps[0].as_float()?.map_values(|f| f * f))
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<Float>,
+    ) -> Result<Vec<Tensor<Float>>, TensorError> {
+        let doubled = inps[0].as_float()?.map_values(|f| 2. * f);
+        Ok(vec![(out_grad * &doubled)?])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+}
+
+#[test]
+fn test_custom_op() {
+    let mut g = CpuGraph::new();
+    let x = g
+        .alloc(
+            Tensor::raw(&[2, 2], vec![1., -2., 3., 0.5]).unwrap(),
+            true,
+            "x".into(),
+        )
+        .unwrap();
+    let y = g.call(Box::new(Square), &[x]).unwrap();
+    g.forward(true).unwrap();
+    assert_eq!(
+        g.get(y).unwrap().as_float().unwrap().blob(),
+        &[1., 4., 9., 0.25]
+    );
+
+    // backward_all() starts from the gradient of the mean of the output
+    g.zero_grad().unwrap();
+    g.backward_all(y, None).unwrap();
+    assert_eq!(g.get_grad(x).unwrap().blob(), &[0.5, -1., 1.5, 0.25]);
+    assert_eq!(Square.name(), "Square");
+
+    // Without OpenCL kernels, GPU graphs report an error instead of running it
+    #[cfg(feature = "gpu")]
+    assert!(Square.gpu_impl(y, &[vec![2, 2]]).is_none());
+}
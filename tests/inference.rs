@@ -0,0 +1,377 @@
+use femto_gpt::evaluate::evaluate_files;
+use femto_gpt::gpt::*;
+use femto_gpt::graph::*;
+use femto_gpt::optimizer::AdamW;
+use femto_gpt::sampling::SamplingParams;
+use femto_gpt::tensor::{Float, TensorOps};
+use femto_gpt::tokenizer::{SimpleTokenizer, Tokenizer};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+fn cfg(positional_encoding: PositionalEncoding) -> GPTConfig {
+    GPTConfig {
+        num_layers: 2,
+        positional_encoding,
+        ..GPTConfig::tiny()
+    }
+}
+
+#[test]
+fn test_short_context() {
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    for pe in [
+        PositionalEncoding::Learned,
+        PositionalEncoding::Sinusoidal,
+        PositionalEncoding::Rope,
+        PositionalEncoding::Alibi,
+    ] {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, cfg(pe)).unwrap();
+        gpt.train_cpu(&data, 10, 2, None, &AdamW::new(), |_| 0.01, ())
+            .unwrap();
+
+        // Models with a batch size run on full-width, padded contexts
+        let mut padded = GPT::new(&mut rng, CpuGraph::new(), Some(1), cfg(pe)).unwrap();
+        padded
+            .set_training_state(gpt.get_training_state().unwrap(), false)
+            .unwrap();
+
+        let short = gpt.forward(&[1, 4, 4]).unwrap();
+        assert_eq!(short.shape(), &[3, 5]);
+        let full = padded.forward(&[1, 4, 4]).unwrap();
+        for (a, b) in short.blob().iter().zip(full.blob().iter()) {
+            assert!((a - b).abs() < 1e-4, "{:?}: {} != {}", pe, a, b);
+        }
+
+        // Training still sees full contexts afterwards
+        gpt.train_cpu(&data, 1, 2, None, &AdamW::new(), |_| 0.01, ())
+            .unwrap();
+        assert_eq!(gpt.forward(&data[..6]).unwrap().shape(), &[6, 5]);
+        assert!(gpt.forward(&[]).is_err());
+        assert!(gpt.forward(&data[..7]).is_err());
+    }
+}
+
+#[test]
+fn test_sampling_errors() {
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut gpt = GPT::new(
+        &mut rng,
+        CpuGraph::new(),
+        None,
+        cfg(PositionalEncoding::Sinusoidal),
+    )
+    .unwrap();
+
+    // A zero temperature always picks the most likely token
+    let greedy = SamplingParams {
+        max_tokens: 8,
+        temperature: 0.,
+        ..Default::default()
+    };
+    let a = gpt.infer(&mut rng, &[1, 2], &greedy, |_| {}).unwrap();
+    let b = gpt.infer(&mut rng, &[1, 2], &greedy, |_| {}).unwrap();
+    assert_eq!(a, b);
+
+    // Generation stops before the stop tokens, and seeded params ignore the given RNG
+    let stopped = SamplingParams {
+        stop_tokens: vec![a[4]],
+        ..greedy.clone()
+    };
+    let c = gpt.infer(&mut rng, &[1, 2], &stopped, |_| {}).unwrap();
+    assert_eq!(c, a[..a[2..].iter().position(|t| *t == a[4]).unwrap() + 2]);
+    let seeded = SamplingParams {
+        seed: Some(3),
+        ..Default::default()
+    };
+    let mut other_rng = StdRng::seed_from_u64(7);
+    assert_eq!(
+        gpt.infer(&mut rng, &[1, 2], &seeded, |_| {}).unwrap(),
+        gpt.infer(&mut other_rng, &[1, 2], &seeded, |_| {}).unwrap()
+    );
+
+    // The cached attention states give the same completions, and are dropped with the
+    // parameters
+    gpt.set_prefix_cache(Some(4));
+    assert_eq!(gpt.infer(&mut rng, &[1, 2], &greedy, |_| {}).unwrap(), a);
+    let cache = gpt.prefix_cache().unwrap();
+    let (hits, misses, computed) = (cache.hits(), cache.misses(), cache.computed());
+    assert_eq!(gpt.infer(&mut rng, &[1, 2], &greedy, |_| {}).unwrap(), a);
+    // Every token of the repeated completion only computes its last position
+    let cache = gpt.prefix_cache().unwrap();
+    assert_eq!(
+        (cache.hits(), cache.misses(), cache.computed()),
+        (hits + 8, misses, computed + 8)
+    );
+
+    let mut state = gpt.get_training_state().unwrap();
+    for t in state.tensors.values_mut() {
+        *t = t.map_values(|_| Float::NAN);
+    }
+    gpt.set_training_state(state, false).unwrap();
+    assert!(matches!(
+        gpt.infer(&mut rng, &[1, 2], &SamplingParams::default(), |_| {}),
+        Err(GraphError::EmptyDistribution)
+    ));
+}
+
+#[test]
+fn test_hidden_states() {
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut gpt = GPT::new(
+        &mut rng,
+        CpuGraph::new(),
+        None,
+        cfg(PositionalEncoding::Rope),
+    )
+    .unwrap();
+    let hidden = gpt.hidden_states(&[1, 2, 3]).unwrap();
+    assert_eq!(hidden.shape(), &[3, 8]);
+
+    // Positions only attend to the ones before them
+    let first = gpt.hidden_states(&[1]).unwrap();
+    for (a, b) in first.blob().iter().zip(hidden.blob().iter()) {
+        assert!((a - b).abs() < 1e-5);
+    }
+
+    let last = gpt.embed(&[1, 2, 3], Pooling::Last).unwrap();
+    assert_eq!(last, hidden.get(2).unwrap().blob());
+    let mean = gpt.embed(&[1, 2, 3], Pooling::Mean).unwrap();
+    for (i, m) in mean.iter().enumerate() {
+        let expected = (0..3).map(|p| hidden.blob()[p * 8 + i]).sum::<Float>() / 3.;
+        assert!((m - expected).abs() < 1e-5);
+    }
+
+    // Fusing keeps the hidden states
+    gpt.fuse().unwrap();
+    let fused = gpt.hidden_states(&[1, 2, 3]).unwrap();
+    for (a, b) in fused.blob().iter().zip(hidden.blob().iter()) {
+        assert!((a - b).abs() < 1e-4);
+    }
+}
+
+#[test]
+fn test_attention() {
+    let mut rng = StdRng::seed_from_u64(42);
+    for batch_size in [None, Some(1)] {
+        let mut gpt = GPT::new(
+            &mut rng,
+            CpuGraph::new(),
+            batch_size,
+            cfg(PositionalEncoding::Sinusoidal),
+        )
+        .unwrap();
+        let layers = gpt.attention(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(layers.len(), 2);
+        for heads in layers.iter() {
+            assert_eq!(heads.len(), 2);
+            for weights in heads.iter() {
+                assert_eq!(weights.shape(), &[4, 4]);
+                // Rows are distributions over the positions up to their own
+                for i in 0..4 {
+                    let row = weights.get(i).unwrap();
+                    assert!((row.blob().iter().sum::<Float>() - 1.).abs() < 1e-5);
+                    assert!(row.blob()[i + 1..].iter().all(|w| *w == 0.));
+                }
+            }
+        }
+
+        // The fused softmax is tapped as well
+        gpt.fuse().unwrap();
+        let fused = gpt.attention(&[1, 2, 3, 4]).unwrap();
+        for (a, b) in fused.concat().iter().zip(layers.concat().iter()) {
+            for (a, b) in a.blob().iter().zip(b.blob().iter()) {
+                assert!((a - b).abs() < 1e-5);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_score() {
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut gpt = GPT::new(
+        &mut rng,
+        CpuGraph::new(),
+        None,
+        cfg(PositionalEncoding::Rope),
+    )
+    .unwrap();
+    let text: Vec<usize> = (0..20).map(|i| (i * i) % 5).collect();
+    let scores = gpt.score(&text).unwrap();
+    assert_eq!(scores.len(), 19);
+    assert!(scores.iter().all(|s| *s < 0.));
+
+    // Within the context, the log-probabilities are those of the logits
+    let logits = gpt.forward(&text[..2]).unwrap();
+    let logits = logits.get(0).unwrap();
+    let log_sum = logits.blob().iter().map(|l| l.exp()).sum::<Float>().ln();
+    assert!((scores[0] - (logits.blob()[text[1]] - log_sum)).abs() < 1e-5);
+    assert_eq!(scores[..5], gpt.score(&text[..6]).unwrap());
+
+    let mean = scores.iter().sum::<Float>() / 19.;
+    assert!((gpt.perplexity(&text).unwrap() - (-mean).exp()).abs() < 1e-4);
+    assert!(gpt.perplexity(&text[..1]).is_err());
+    assert!(gpt.score(&[1, 5]).is_err());
+}
+
+#[test]
+fn test_summary() {
+    let mut rng = StdRng::seed_from_u64(42);
+    let gpt = GPT::new(
+        &mut rng,
+        CpuGraph::new(),
+        None,
+        cfg(PositionalEncoding::Learned),
+    )
+    .unwrap();
+    let summary = gpt.summary(4).unwrap();
+
+    assert_eq!(summary.embeddings, 5 * 8 + 6 * 8);
+    let layer = LayerSummary {
+        attention: 3 * 2 * 8 * 4 + 8 * 8,
+        feedforward: 2 * 8 * 16,
+        norms: 2 * 8,
+    };
+    assert_eq!(summary.layers, vec![layer.clone(), layer]);
+    assert_eq!(summary.head, 8 + 8 * 5);
+    assert_eq!(summary.total, gpt.num_params());
+    assert_eq!(summary.total, 88 + 2 * 528 + 48);
+
+    // Projections and attention over the 6 positions of each of the 2 layers
+    let matmul_params = 2 * (3 * 2 * 8 * 4 + 8 * 8 + 2 * 8 * 16) + 8 * 5;
+    assert_eq!(summary.flops_per_token, 2 * matmul_params + 2 * 4 * 6 * 8);
+    assert_eq!(
+        gpt.summary(8).unwrap().activation_bytes,
+        2 * summary.activation_bytes
+    );
+}
+
+#[test]
+fn test_evaluate_files() {
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut gpt = GPT::new(
+        &mut rng,
+        CpuGraph::new(),
+        None,
+        cfg(PositionalEncoding::Rope),
+    )
+    .unwrap();
+    let tokenizer = SimpleTokenizer::new("abcde");
+    let dir = std::env::temp_dir().join(format!("femtogpt-evaluate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let short = dir.join("short.txt");
+    let long = dir.join("long.txt");
+    std::fs::write(&short, "abcab").unwrap();
+    std::fs::write(&long, "edcbaabcdeabcdeedcba").unwrap();
+
+    let report = evaluate_files(&mut gpt, &tokenizer, &[&short, &long]).unwrap();
+    assert_eq!(report.files.len(), 2);
+    let eval = &report.files[1];
+    assert_eq!((eval.num_tokens, eval.num_chars), (19, 19));
+    let perplexity = gpt.perplexity(&tokenizer.tokenize("edcbaabcdeabcdeedcba"));
+    assert!((eval.perplexity() - perplexity.unwrap()).abs() < 1e-3);
+    // One token per character
+    let bits = eval.loss() / std::f64::consts::LN_2 as Float;
+    assert!((eval.bits_per_char() - bits).abs() < 1e-4);
+
+    let total = report.total();
+    assert_eq!(total.num_tokens, 23);
+    assert!((total.nll - report.files[0].nll - eval.nll).abs() < 1e-9);
+    assert_eq!(report.to_string().lines().count(), 3);
+
+    std::fs::write(&short, "a").unwrap();
+    assert!(evaluate_files(&mut gpt, &tokenizer, &[&short]).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_generate_chat() {
+    use femto_gpt::chat::{generate_chat, ChatTemplate, Message, Role};
+
+    let tokenizer = SimpleTokenizer::new("abcd\n");
+    let eot = tokenizer.tokenize("\n")[0];
+    let template = ChatTemplate {
+        system: "".into(),
+        user: "a".into(),
+        assistant: "b".into(),
+        prefix: Vec::new(),
+        end_of_turn: eot,
+    };
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut gpt = GPT::new(
+        &mut rng,
+        CpuGraph::new(),
+        None,
+        cfg(PositionalEncoding::Sinusoidal),
+    )
+    .unwrap();
+    let messages = [Message::new(Role::User, "cd")];
+    let prompt = template.prompt(&tokenizer, &messages).unwrap();
+    assert_eq!(tokenizer.untokenize(&prompt), "acd\nb");
+    let params = SamplingParams {
+        max_tokens: 20,
+        ..Default::default()
+    };
+    for _ in 0..5 {
+        let answer = generate_chat(
+            &mut gpt, &mut rng, &tokenizer, &template, &messages, &params,
+        )
+        .unwrap();
+        assert_eq!(answer.role, Role::Assistant);
+        // Generation stops at the end of the turn, which is not part of the answer
+        assert!(answer.content.len() <= 20 && !answer.content.contains('\n'));
+    }
+}
+
+#[test]
+fn test_prefix_lm() {
+    let config = GPTConfig {
+        prefix_lm: true,
+        ..cfg(PositionalEncoding::Rope)
+    };
+    for batch_size in [None, Some(1)] {
+        let mut gpt = GPT::new(
+            &mut StdRng::seed_from_u64(42),
+            CpuGraph::new(),
+            batch_size,
+            config.clone(),
+        )
+        .unwrap();
+        let mut causal = GPT::new(
+            &mut StdRng::seed_from_u64(42),
+            CpuGraph::new(),
+            batch_size,
+            cfg(PositionalEncoding::Rope),
+        )
+        .unwrap();
+        let context = [1, 2, 3, 4];
+        // Without a prefix (Or with a prefix of a single token), the model is causal
+        let logits = causal.forward(&context).unwrap();
+        assert_eq!(gpt.forward(&context).unwrap().blob(), logits.blob());
+        let first = gpt.forward_with_prefix(&context, 1).unwrap();
+        assert_eq!(first.blob(), logits.blob());
+
+        // The tokens of the prefix see the ones after them, the tokens after the prefix
+        // still only see the ones before them
+        let prefixed = gpt.forward_with_prefix(&context, 2).unwrap();
+        assert_ne!(
+            prefixed.get(0).unwrap().blob(),
+            logits.get(0).unwrap().blob()
+        );
+        let extended = gpt.forward_with_prefix(&[1, 2, 3, 0], 2).unwrap();
+        assert_eq!(
+            prefixed.get(2).unwrap().blob(),
+            extended.get(2).unwrap().blob()
+        );
+
+        let params = SamplingParams {
+            max_tokens: 10,
+            ..Default::default()
+        };
+        let tokens = gpt
+            .infer(&mut StdRng::seed_from_u64(0), &context, &params, |_| {})
+            .unwrap();
+        assert_eq!(tokens.len(), 14);
+    }
+}
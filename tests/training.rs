@@ -0,0 +1,1262 @@
+use femto_gpt::dataset::{Batch, Dataset, SftDataset};
+use femto_gpt::gpt::*;
+use femto_gpt::graph::*;
+use femto_gpt::optimizer::AdamW;
+use femto_gpt::tensor::{Float, Tensor, TensorOps};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+
+#[test]
+fn test_early_stopping() {
+    let mut rng = rand::thread_rng();
+    let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+    let data: Vec<usize> = (0..100).map(|i| i % 5).collect();
+    let (train, validation) = split_dataset(&data, 0.5);
+    assert_eq!(validation.len(), 50);
+    gpt.set_validation_dataset(validation.to_vec());
+    gpt.set_training_options(TrainingOptions {
+        eval_interval: Some(3),
+        early_stopping: Some(EarlyStopping {
+            patience: 1,
+            // No evaluation after the first one counts as an improvement
+            min_delta: 1e9,
+        }),
+        ..Default::default()
+    });
+    let summary = gpt
+        .train_cpu(train, 100, 2, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+    assert!(summary.stopped_early);
+    assert_eq!(summary.steps, 6);
+    assert_eq!(summary.best_step, Some(3));
+    assert!(summary.best_state.is_some());
+    assert_eq!(summary.evaluations.len(), 2);
+    assert_eq!(summary.evaluations[0].0, 3);
+
+    // The best loss is the loss of the best state
+    let best_loss = summary.best_validation_loss.unwrap();
+    gpt.set_training_state(summary.best_state.unwrap(), false)
+        .unwrap();
+    let eval = gpt.evaluate(validation, None).unwrap();
+    assert!((eval.loss - best_loss).abs() < 1e-5);
+    assert!((eval.perplexity - best_loss.exp()).abs() < 1e-3);
+}
+
+#[test]
+fn test_checkpoint_rotation() {
+    let dir = std::env::temp_dir().join(format!("femto_gpt_checkpoints_{}", std::process::id()));
+    let mut rng = rand::thread_rng();
+    let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+    let data: Vec<usize> = (0..100).map(|i| i % 5).collect();
+    gpt.set_validation_dataset(data.clone());
+    gpt.set_training_options(TrainingOptions {
+        eval_interval: Some(3),
+        checkpoint: Some(CheckpointConfig {
+            dir: dir.clone(),
+            interval: 2,
+            keep_last: Some(2),
+            save_best: true,
+        }),
+        ..Default::default()
+    });
+    gpt.train_cpu(&data, 7, 2, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+
+    // Saved after steps 1, 3, 5 and 7
+    let mut files = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(
+        files,
+        ["best.dat", "step_00000005.dat", "step_00000007.dat"]
+    );
+    gpt.load_checkpoint(dir.join("best.dat"), true).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_resume() {
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let mut rng = rand::thread_rng();
+    let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+    gpt.train_cpu(&data, 3, 3, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+    let state = gpt.get_training_state().unwrap();
+    let first = gpt
+        .train_cpu(&data, 3, 3, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+
+    // A new model continues exactly where the first one stopped
+    let mut resumed = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+    resumed.set_training_state(state, true).unwrap();
+    let second = resumed
+        .train_cpu(&data, 3, 3, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+    assert_eq!(first.loss, second.loss);
+    let (a, b) = (
+        gpt.get_training_state().unwrap(),
+        resumed.get_training_state().unwrap(),
+    );
+    for (name, t) in a.tensors {
+        assert_eq!(t.blob(), b.tensors[&name].blob(), "{}", name);
+    }
+}
+
+#[test]
+fn test_load_from() {
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let config = GPTConfig {
+        num_layers: 1,
+        positional_encoding: PositionalEncoding::Rope,
+        ..GPTConfig::tiny()
+    };
+    let mut gpt = GPT::new(&mut rand::thread_rng(), CpuGraph::new(), None, config).unwrap();
+    gpt.train_cpu(&data, 3, 3, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+    let path = std::env::temp_dir().join(format!("femto_gpt_load_from_{}", std::process::id()));
+    gpt.save_checkpoint(&path).unwrap();
+
+    // The architecture comes from the checkpoint
+    let mut loaded = GPT::load_from(CpuGraph::new(), None, &path).unwrap();
+    assert_eq!(loaded.config().num_layers, 1);
+    assert_eq!(
+        loaded.forward(&[1, 2, 3]).unwrap().blob(),
+        gpt.forward(&[1, 2, 3]).unwrap().blob()
+    );
+
+    let mut state = gpt.get_training_state().unwrap();
+    let load = |state: &TrainingState| {
+        std::fs::write(&path, state.to_bytes().unwrap()).unwrap();
+        GPT::load_from(CpuGraph::new(), None, &path).map(|_| ())
+    };
+    // Edited tensors don't match the checksums of the manifest
+    let mut corrupt = state.clone();
+    corrupt
+        .tensors
+        .insert("token_embedding".into(), Tensor::zeros(&[5, 8]));
+    assert!(matches!(
+        load(&corrupt),
+        Err(GraphError::CorruptCheckpoint(_))
+    ));
+    state.manifest = None;
+    let mut missing = state.clone();
+    missing.tensors.remove("head_map_weights");
+    assert!(matches!(
+        load(&missing),
+        Err(GraphError::MissingTensor(name)) if name == "head_map_weights"
+    ));
+    let mut unexpected = state.clone();
+    unexpected
+        .tensors
+        .insert("norm_9_coeff".into(), Tensor::zeros(&[8]));
+    assert!(matches!(
+        load(&unexpected),
+        Err(GraphError::UnexpectedTensor(name)) if name == "norm_9_coeff"
+    ));
+    let mut wrong_shape = state.clone();
+    wrong_shape.config = Some(GPTConfig {
+        embedding_degree: 12,
+        ..state.config.clone().unwrap()
+    });
+    assert!(matches!(
+        load(&wrong_shape),
+        Err(GraphError::ShapeMismatch { .. })
+    ));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_npz() {
+    use femto_gpt::npy::{load_npz, save_npz};
+
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+    let path = std::env::temp_dir().join(format!("femto_gpt_npz_{}.npz", std::process::id()));
+    gpt.export_npz(&path).unwrap();
+    let mut tensors = load_npz(&path).unwrap();
+    let state = gpt.get_training_state().unwrap();
+    assert_eq!(tensors.len(), state.tensors.len());
+    for (name, t) in state.tensors.iter() {
+        assert_eq!(tensors[name].blob(), t.blob(), "{}", name);
+    }
+
+    // Edited weights are loaded back, the ones missing from the archive are kept
+    let logits = gpt.forward(&[1, 2, 3]).unwrap();
+    let edited = tensors["head_map_weights"].map_values(|v| v * 2.);
+    tensors.retain(|name, _| name == "head_map_weights");
+    tensors.insert("head_map_weights".into(), edited.clone());
+    save_npz(&path, &tensors).unwrap();
+    gpt.import_npz(&path).unwrap();
+    let state = gpt.get_training_state().unwrap();
+    assert_eq!(state.tensors["head_map_weights"].blob(), edited.blob());
+    assert_ne!(gpt.forward(&[1, 2, 3]).unwrap().blob(), logits.blob());
+
+    tensors.insert("norm_9_coeff".into(), Tensor::zeros(&[8]));
+    save_npz(&path, &tensors).unwrap();
+    assert!(matches!(
+        gpt.import_npz(&path),
+        Err(GraphError::UnexpectedTensor(name)) if name == "norm_9_coeff"
+    ));
+    tensors.remove("norm_9_coeff");
+    tensors.insert("head_map_weights".into(), Tensor::zeros(&[5, 8]));
+    save_npz(&path, &tensors).unwrap();
+    assert!(matches!(
+        gpt.import_npz(&path),
+        Err(GraphError::ShapeMismatch { .. })
+    ));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_sharded_checkpoint() {
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let mut rng = rand::thread_rng();
+    let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+    gpt.train_cpu(&data, 3, 3, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+    let dir = std::env::temp_dir().join(format!("femto_gpt_sharded_gpt_{}", std::process::id()));
+    gpt.save_sharded(&dir, 1024).unwrap();
+    assert!(
+        femto_gpt::checkpoint::ShardedCheckpoint::open(&dir)
+            .unwrap()
+            .num_shards()
+            > 1
+    );
+
+    // Resuming from the shards continues exactly like the original model
+    let mut resumed = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+    resumed.load_checkpoint(&dir, true).unwrap();
+    let first = gpt
+        .train_cpu(&data, 2, 3, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+    let second = resumed
+        .train_cpu(&data, 2, 3, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+    assert_eq!(first.loss, second.loss);
+
+    let mut loaded = GPT::load_from(CpuGraph::new(), None, &dir).unwrap();
+    resumed.save_sharded(&dir, 1024).unwrap();
+    let mut loaded_again = GPT::load_from(CpuGraph::new(), None, &dir).unwrap();
+    assert_ne!(
+        loaded.forward(&[1, 2]).unwrap().blob(),
+        loaded_again.forward(&[1, 2]).unwrap().blob()
+    );
+    assert_eq!(
+        loaded_again.forward(&[1, 2]).unwrap().blob(),
+        gpt.forward(&[1, 2]).unwrap().blob()
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_tokenizer_fingerprint() {
+    use femto_gpt::tokenizer::SimpleTokenizer;
+    let tokenizer = SimpleTokenizer::new("abcde");
+    let mut gpt = GPT::new(
+        &mut rand::thread_rng(),
+        CpuGraph::new(),
+        None,
+        GPTConfig::tiny(),
+    )
+    .unwrap();
+    gpt.set_tokenizer(&tokenizer);
+    let path = std::env::temp_dir().join(format!("femto_gpt_tokenizer_{}", std::process::id()));
+    gpt.save_checkpoint(&path).unwrap();
+    let loaded = GPT::load_from(CpuGraph::new(), None, &path).unwrap();
+    loaded.check_tokenizer(&tokenizer).unwrap();
+    assert!(matches!(
+        loaded.check_tokenizer(&SimpleTokenizer::new("abcdf")),
+        Err(GraphError::TokenizerMismatch)
+    ));
+    // The tokenizer itself is saved too, sharded or not
+    assert_eq!(loaded.encode("bad").unwrap(), [1, 0, 3]);
+    assert_eq!(loaded.decode(&[4, 0]).unwrap(), "ea");
+    let dir = path.with_extension("shards");
+    gpt.save_sharded(&dir, 1024).unwrap();
+    let loaded = GPT::load_from(CpuGraph::new(), None, &dir).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(loaded.encode("bad").unwrap(), [1, 0, 3]);
+    let untrained = GPT::new(
+        &mut rand::thread_rng(),
+        CpuGraph::new(),
+        None,
+        GPTConfig::tiny(),
+    )
+    .unwrap();
+    assert!(untrained.encode("bad").is_err());
+
+    // Flipping a byte of a tensor is caught by its checksum
+    let mut bytes = std::fs::read(&path).unwrap();
+    let state = gpt.get_training_state().unwrap();
+    let blob = state.tensors["token_embedding"].blob();
+    let needle = blob
+        .iter()
+        .take(4)
+        .flat_map(|v| v.to_le_bytes())
+        .collect::<Vec<_>>();
+    let at = bytes
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .unwrap();
+    bytes[at] ^= 1;
+    std::fs::write(&path, bytes).unwrap();
+    assert!(matches!(
+        GPT::load_from(CpuGraph::new(), None, &path),
+        Err(GraphError::CorruptCheckpoint(_))
+    ));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_migrate() {
+    use femto_gpt::migrate::{migrate, MigrateOptions};
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let mut gpt = GPT::new(
+        &mut rand::thread_rng(),
+        CpuGraph::new(),
+        None,
+        GPTConfig::tiny(),
+    )
+    .unwrap();
+    gpt.train_cpu(&data, 3, 3, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+    let state = gpt.get_training_state().unwrap();
+    // Checkpoints of the first version, the parameters and optimizer state (In f32)
+    let f32s = |tensors: &HashMap<String, Tensor<Float>>| {
+        tensors
+            .iter()
+            .map(|(k, t)| (k.clone(), t.cast::<f32>()))
+            .collect::<HashMap<_, _>>()
+    };
+    let old = bincode::serialize(&(
+        f32s(&state.tensors),
+        (state.optimizer.step, f32s(&state.optimizer.state)),
+    ))
+    .unwrap();
+    let path = std::env::temp_dir().join(format!("femto_gpt_migrate_{}", std::process::id()));
+    std::fs::write(&path, old).unwrap();
+    assert!(matches!(
+        GPT::load_from(CpuGraph::new(), None, &path),
+        Err(GraphError::DeserializationError(_))
+    ));
+
+    let wrong = MigrateOptions {
+        config: Some(GPTConfig {
+            num_layers: 2,
+            ..GPTConfig::tiny()
+        }),
+        ..Default::default()
+    };
+    assert!(migrate(&path, &path, &wrong).is_err());
+    let options = MigrateOptions {
+        config: Some(GPTConfig::tiny()),
+        max_shard_bytes: Some(1024),
+        ..Default::default()
+    };
+    migrate(&path, &path, &options).unwrap();
+    assert!(path.is_dir());
+    let mut loaded = GPT::load_from(CpuGraph::new(), None, &path).unwrap();
+    // (Up to the rounding of the parameters to f32)
+    let logits = gpt.forward(&[1, 2, 3]).unwrap();
+    for (a, b) in loaded
+        .forward(&[1, 2, 3])
+        .unwrap()
+        .blob()
+        .iter()
+        .zip(logits.blob())
+    {
+        assert!((a - b).abs() < 1e-5);
+    }
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_init_scheme() {
+    let std_of = |t: &Tensor<Float>| {
+        let n = t.size() as Float;
+        (t.blob().iter().map(|v| v * v).sum::<Float>() / n).sqrt()
+    };
+    let config = GPTConfig {
+        embedding_degree: 32,
+        num_layers: 8,
+        feedforward_multiplier: 4.,
+        bias: true,
+        ..GPTConfig::tiny()
+    };
+    let gpt = GPT::new(
+        &mut rand::thread_rng(),
+        CpuGraph::new(),
+        None,
+        config.clone(),
+    )
+    .unwrap();
+    let tensors = gpt.get_training_state().unwrap().tensors;
+    assert!(tensors["norm_0_coeff"].blob().iter().all(|v| *v == 1.));
+    assert!(tensors["feedforward1_0_bias"]
+        .blob()
+        .iter()
+        .all(|v| *v == 0.));
+    // Residual projections are scaled down by 1/sqrt(2 * 8)
+    let std = std_of(&tensors["feedforward1_0_weights"]);
+    let residual_std = std_of(&tensors["feedforward2_0_weights"]);
+    assert!((std - 0.02).abs() < 0.002, "{}", std);
+    assert!((residual_std - 0.005).abs() < 0.001, "{}", residual_std);
+
+    let legacy = GPTConfig {
+        init: InitScheme::Legacy,
+        ..config
+    };
+    let gpt = GPT::new(&mut rand::thread_rng(), CpuGraph::new(), None, legacy).unwrap();
+    let tensors = gpt.get_training_state().unwrap().tensors;
+    assert!(std_of(&tensors["norm_0_coeff"]) < 0.1);
+    assert!((std_of(&tensors["feedforward2_0_weights"]) - 0.02).abs() < 0.002);
+}
+
+#[test]
+fn test_shape_mismatch() {
+    let mut gpt = GPT::new(
+        &mut rand::thread_rng(),
+        CpuGraph::new(),
+        None,
+        GPTConfig::tiny(),
+    )
+    .unwrap();
+    let state = gpt.get_training_state().unwrap();
+    let mut bad = state.clone();
+    for t in bad.tensors.values_mut() {
+        *t = t.map_values(|_| 1.);
+    }
+    bad.tensors
+        .insert("token_embedding".into(), Tensor::zeros(&[4, 8]));
+    assert!(matches!(
+        gpt.set_training_state(bad, false),
+        Err(GraphError::ShapeMismatch { name, .. }) if name == "token_embedding"
+    ));
+    // Nothing was loaded
+    let after = gpt.get_training_state().unwrap();
+    for (name, t) in state.tensors.iter() {
+        assert_eq!(t.blob(), after.tensors[name].blob(), "{}", name);
+    }
+}
+
+#[test]
+fn test_deterministic() {
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let run = || {
+        let mut rng = StdRng::seed_from_u64(42);
+        let config = GPTConfig {
+            dropout: 0.2,
+            ..GPTConfig::tiny()
+        };
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, config).unwrap();
+        gpt.set_seed(7);
+        let summary = gpt
+            .train_cpu(&data, 3, 3, None, &AdamW::new(), |_| 0.01, ())
+            .unwrap();
+        (summary.loss, gpt.get_training_state().unwrap().tensors)
+    };
+    let (loss, tensors) = run();
+    let (other_loss, other_tensors) = run();
+    assert_eq!(loss, other_loss);
+    for (name, t) in tensors {
+        assert_eq!(t.blob(), other_tensors[&name].blob(), "{}", name);
+    }
+}
+
+#[test]
+fn test_prefetch() {
+    use femto_gpt::dataset::PrefetchConfig;
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let run = |batch_size: Option<usize>, prefetch: Option<PrefetchConfig>| {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), batch_size, GPTConfig::tiny()).unwrap();
+        gpt.set_seed(7);
+        gpt.set_training_options(TrainingOptions {
+            prefetch,
+            ..Default::default()
+        });
+        match batch_size {
+            Some(batch_size) => gpt.train(&data, 3, batch_size, None, &AdamW::new(), |_| 0.01, ()),
+            None => gpt.train_cpu(&data, 3, 3, None, &AdamW::new(), |_| 0.01, ()),
+        }
+        .unwrap()
+        .loss
+    };
+    // The batches don't depend on the number of workers
+    for batch_size in [None, Some(2)] {
+        let loss = run(
+            batch_size,
+            Some(PrefetchConfig {
+                workers: 1,
+                depth: 1,
+            }),
+        );
+        let other = run(
+            batch_size,
+            Some(PrefetchConfig {
+                workers: 3,
+                depth: 8,
+            }),
+        );
+        assert_eq!(loss, other);
+        assert_ne!(loss, run(batch_size, None));
+    }
+}
+
+#[test]
+fn test_dropout_schedule() {
+    use femto_gpt::scheduler::Schedule;
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let run = |dropout: Option<DropoutConfig>| {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+        gpt.set_seed(7);
+        gpt.set_training_options(TrainingOptions {
+            dropout,
+            ..Default::default()
+        });
+        gpt.train_cpu(&data, 3, 3, None, &AdamW::new(), |_| 0.01, ())
+            .map(|summary| summary.loss)
+    };
+    let loss = run(None).unwrap();
+    let attention = DropoutConfig {
+        attention: Some(0.5),
+        ..Default::default()
+    };
+    assert_ne!(run(Some(attention.clone())).unwrap(), loss);
+    // Scheduled down to zero from the first step
+    let phased_out = DropoutConfig {
+        schedule: Some(Schedule::Constant(0.)),
+        ..attention
+    };
+    assert_eq!(run(Some(phased_out)).unwrap(), loss);
+    let invalid = DropoutConfig {
+        embedding: Some(1.),
+        ..Default::default()
+    };
+    assert!(matches!(
+        run(Some(invalid)),
+        Err(GraphError::InvalidConfig(_))
+    ));
+}
+
+#[test]
+fn test_batch_size_schedule() {
+    use femto_gpt::callback::{StepInfo, TrainCallback};
+    use femto_gpt::scheduler::Schedule;
+    use std::ops::ControlFlow;
+
+    // Records the number of sequences of each step
+    struct Sizes<'a>(&'a mut Vec<usize>);
+    impl<G: Graph> TrainCallback<G> for Sizes<'_> {
+        fn on_step_end(
+            &mut self,
+            _gpt: &mut GPT<G>,
+            info: &StepInfo,
+        ) -> Result<ControlFlow<()>, GraphError> {
+            self.0.push(info.tokens / GPTConfig::tiny().num_tokens);
+            Ok(ControlFlow::Continue(()))
+        }
+    }
+
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let mut rng = rand::thread_rng();
+    let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+    let mut sizes = Vec::new();
+    let callback = Sizes(&mut sizes);
+    gpt.train_cpu(
+        &data,
+        4,
+        |step| step + 1,
+        None,
+        &AdamW::new(),
+        |_| 0.01,
+        callback,
+    )
+    .unwrap();
+    assert_eq!(sizes, [1, 2, 3, 4]);
+
+    // Models with a batch size process larger batches in chunks
+    let mut gpt = GPT::new(&mut rng, CpuGraph::new(), Some(2), GPTConfig::tiny()).unwrap();
+    let ramp_up = Schedule::Linear {
+        from: 2.,
+        to: 6.,
+        steps: 2,
+    };
+    let mut sizes = Vec::new();
+    let callback = Sizes(&mut sizes);
+    let summary = gpt
+        .train(&data, 3, ramp_up, None, &AdamW::new(), |_| 0.01, callback)
+        .unwrap();
+    assert_eq!(sizes, [2, 4, 6]);
+    assert!(summary.loss.is_finite());
+    assert!(matches!(
+        gpt.train(&data, 1, 3, None, &AdamW::new(), |_| 0.01, ()),
+        Err(GraphError::InvalidConfig(_))
+    ));
+}
+
+#[test]
+fn test_monitor() {
+    use femto_gpt::callback::{StepInfo, TensorStats, TrainCallback, TrainingStats};
+
+    // Records the monitored steps with their statistics
+    struct Monitor<'a>(&'a mut Vec<(usize, TrainingStats)>);
+    impl<G: Graph> TrainCallback<G> for Monitor<'_> {
+        fn on_stats(
+            &mut self,
+            _gpt: &mut GPT<G>,
+            info: &StepInfo,
+            stats: &TrainingStats,
+        ) -> Result<(), GraphError> {
+            self.0.push((info.step, stats.clone()));
+            Ok(())
+        }
+    }
+
+    let stats = TensorStats::new("t".into(), &[1., 3., Float::NAN, Float::INFINITY]);
+    assert_eq!((stats.mean, stats.variance, stats.non_finite), (2., 1., 2));
+    assert!((stats.norm - (10 as Float).sqrt()).abs() < 1e-5);
+
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let mut rng = rand::thread_rng();
+    for batch_size in [None, Some(2)] {
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), batch_size, GPTConfig::tiny()).unwrap();
+        gpt.set_training_options(TrainingOptions {
+            monitor_interval: Some(2),
+            ..Default::default()
+        });
+        let mut monitored = Vec::new();
+        let callback = Monitor(&mut monitored);
+        match batch_size {
+            None => gpt.train_cpu(&data, 5, 2, None, &AdamW::new(), |_| 0.01, callback),
+            Some(_) => gpt.train(&data, 5, 2, None, &AdamW::new(), |_| 0.01, callback),
+        }
+        .unwrap();
+        assert_eq!(
+            monitored.iter().map(|(step, _)| *step).collect::<Vec<_>>(),
+            [2, 4]
+        );
+        let stats = &monitored[0].1;
+        assert_eq!(
+            stats.gradients.len(),
+            gpt.get_training_state().unwrap().tensors.len()
+        );
+        assert!(stats.gradients.iter().any(|g| g.norm > 0.));
+        let names = stats
+            .activations
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["block_0", "hidden", "logits"]);
+        assert_eq!(stats.non_finite().count(), 0);
+    }
+}
+
+#[test]
+fn test_nan_guard() {
+    use femto_gpt::callback::{StepInfo, TrainCallback};
+    use std::ops::ControlFlow;
+
+    // Records the learning rates of the steps taken
+    struct Rates<'a>(&'a mut Vec<Float>);
+    impl<G: Graph> TrainCallback<G> for Rates<'_> {
+        fn on_step_end(
+            &mut self,
+            _gpt: &mut GPT<G>,
+            info: &StepInfo,
+        ) -> Result<ControlFlow<()>, GraphError> {
+            self.0.push(info.learning_rate);
+            Ok(ControlFlow::Continue(()))
+        }
+    }
+
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let mut rng = rand::thread_rng();
+    for batch_size in [None, Some(2)] {
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), batch_size, GPTConfig::tiny()).unwrap();
+        let guard = NanGuard {
+            lr_backoff: Some(0.5),
+            recovery_steps: 2,
+        };
+        // A NaN z-loss coefficient poisons the loss and every gradient
+        gpt.set_training_options(TrainingOptions {
+            z_loss: Some(Float::NAN),
+            nan_guard: Some(guard),
+            ..Default::default()
+        });
+        let before = gpt.get_training_state().unwrap();
+        let mut rates = Vec::new();
+        let summary = match batch_size {
+            None => gpt.train_cpu(
+                &data,
+                3,
+                2,
+                None,
+                &AdamW::new(),
+                |_| 0.08,
+                Rates(&mut rates),
+            ),
+            Some(_) => gpt.train(
+                &data,
+                3,
+                2,
+                None,
+                &AdamW::new(),
+                |_| 0.08,
+                Rates(&mut rates),
+            ),
+        }
+        .unwrap();
+        assert_eq!((summary.steps, summary.skipped_steps), (0, 3));
+        assert!(rates.is_empty());
+        let after = gpt.get_training_state().unwrap();
+        for (name, tensor) in before.tensors.iter() {
+            assert_eq!(tensor.blob(), after.tensors[name].blob());
+        }
+
+        // The learning rate is reduced for the steps following the skipped ones
+        gpt.set_training_options(TrainingOptions {
+            nan_guard: Some(guard),
+            ..Default::default()
+        });
+        let summary = match batch_size {
+            None => gpt.train_cpu(
+                &data,
+                3,
+                2,
+                None,
+                &AdamW::new(),
+                |_| 0.08,
+                Rates(&mut rates),
+            ),
+            Some(_) => gpt.train(
+                &data,
+                3,
+                2,
+                None,
+                &AdamW::new(),
+                |_| 0.08,
+                Rates(&mut rates),
+            ),
+        }
+        .unwrap();
+        assert_eq!((summary.steps, summary.skipped_steps), (3, 0));
+        assert_eq!(rates, [0.01, 0.01, 0.08]);
+        assert!(summary.loss.is_finite());
+    }
+}
+
+#[test]
+fn test_spike_rollback() {
+    use femto_gpt::callback::{StepInfo, TrainCallback};
+    use std::ops::ControlFlow;
+
+    struct Rates<'a>(&'a mut Vec<Float>);
+    impl<G: Graph> TrainCallback<G> for Rates<'_> {
+        fn on_step_end(
+            &mut self,
+            _gpt: &mut GPT<G>,
+            info: &StepInfo,
+        ) -> Result<ControlFlow<()>, GraphError> {
+            self.0.push(info.learning_rate);
+            Ok(ControlFlow::Continue(()))
+        }
+    }
+
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let dir = std::env::temp_dir().join(format!("femto_gpt_rollback_{}", std::process::id()));
+    let mut rng = rand::thread_rng();
+    for (batch_size, dir) in [(None, None), (Some(2), Some(dir.clone()))] {
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), batch_size, GPTConfig::tiny()).unwrap();
+        // Every loss after the first two is a spike, rolling back to the initial state
+        let rollback = SpikeRollback {
+            threshold: 0.5,
+            ema_decay: 0.9,
+            warmup_steps: 2,
+            interval: 100,
+            lr_factor: 0.5,
+            recovery_steps: 2,
+            dir,
+        };
+        gpt.set_training_options(TrainingOptions {
+            spike_rollback: Some(rollback.clone()),
+            ..Default::default()
+        });
+        let before = gpt.get_training_state().unwrap();
+        let mut rates = Vec::new();
+        let summary = match batch_size {
+            None => gpt.train_cpu(
+                &data,
+                5,
+                2,
+                None,
+                &AdamW::new(),
+                |_| 0.08,
+                Rates(&mut rates),
+            ),
+            Some(_) => gpt.train(
+                &data,
+                5,
+                2,
+                None,
+                &AdamW::new(),
+                |_| 0.08,
+                Rates(&mut rates),
+            ),
+        }
+        .unwrap();
+        assert_eq!((summary.steps, summary.rollbacks), (2, 3));
+        assert_eq!(rates, [0.08, 0.08]);
+        let after = gpt.get_training_state().unwrap();
+        assert_eq!(after.optimizer.step, 0);
+        for (name, tensor) in before.tensors.iter() {
+            assert_eq!(tensor.blob(), after.tensors[name].blob());
+        }
+
+        // The learning rate is reduced for the steps following the rollbacks
+        gpt.set_training_options(TrainingOptions {
+            spike_rollback: Some(SpikeRollback {
+                threshold: 1e9,
+                ..rollback
+            }),
+            ..Default::default()
+        });
+        rates.clear();
+        let summary = match batch_size {
+            None => gpt.train_cpu(
+                &data,
+                3,
+                2,
+                None,
+                &AdamW::new(),
+                |_| 0.08,
+                Rates(&mut rates),
+            ),
+            Some(_) => gpt.train(
+                &data,
+                3,
+                2,
+                None,
+                &AdamW::new(),
+                |_| 0.08,
+                Rates(&mut rates),
+            ),
+        }
+        .unwrap();
+        assert_eq!((summary.steps, summary.rollbacks), (3, 0));
+        assert_eq!(rates, [0.01, 0.01, 0.08]);
+    }
+    assert!(dir.join("last_good.dat").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_dpo() {
+    use femto_gpt::dataset::PreferencePair;
+
+    let pairs = vec![
+        PreferencePair {
+            prompt: vec![1, 2],
+            chosen: vec![3, 4, 0],
+            rejected: vec![4, 3, 0],
+        },
+        PreferencePair {
+            prompt: vec![2, 1, 3, 4],
+            chosen: vec![1, 1, 1, 0],
+            rejected: vec![2, 2, 0],
+        },
+    ];
+    let margin =
+        |reference: &[(Float, Float)]| -> Float { reference.iter().map(|(c, r)| c - r).sum() };
+    let mut rng = rand::thread_rng();
+    for batch_size in [None, Some(2)] {
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), batch_size, GPTConfig::tiny()).unwrap();
+        let reference = gpt.dpo_reference(&pairs).unwrap();
+        assert!(reference.iter().all(|(c, r)| *c < 0. && *r < 0.));
+        let bad = gpt.train_dpo(
+            &pairs,
+            &reference[..1],
+            0.1,
+            1,
+            2,
+            &AdamW::new(),
+            |_| 0.01,
+            (),
+        );
+        assert!(bad.is_err());
+
+        let summary = gpt
+            .train_dpo(&pairs, &reference, 0.5, 20, 3, &AdamW::new(), |_| 0.01, ())
+            .unwrap();
+        assert_eq!(summary.steps, 20);
+        // The loss starts at ln(2), and the chosen completions become relatively more likely
+        assert!(summary.loss < (2 as Float).ln());
+        let trained = gpt.dpo_reference(&pairs).unwrap();
+        assert!(margin(&trained) > margin(&reference));
+    }
+}
+
+#[test]
+fn test_swa() {
+    use femto_gpt::optimizer::SwaConfig;
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let mut rng = rand::thread_rng();
+    let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+    assert!(gpt.swap_swa().is_err());
+    gpt.set_training_options(TrainingOptions {
+        swa: Some(SwaConfig {
+            start: 2,
+            interval: 2,
+        }),
+        ..Default::default()
+    });
+    gpt.train_cpu(&data, 5, 3, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+    // Sampled after steps 2 and 4
+    let state = gpt.get_training_state().unwrap();
+    assert_eq!(state.swa.as_ref().unwrap().count, 2);
+
+    // The average survives a resume from a sharded checkpoint
+    let dir = std::env::temp_dir().join(format!("femto_gpt_swa_{}", std::process::id()));
+    gpt.save_sharded(&dir, 1024).unwrap();
+    let mut resumed = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+    resumed.load_checkpoint(&dir, true).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let swa = resumed.get_training_state().unwrap().swa.unwrap();
+    assert_eq!(swa.count, 2);
+    for (name, t) in state.swa.unwrap().tensors {
+        assert_eq!(t.blob(), swa.tensors[&name].blob(), "{}", name);
+    }
+
+    let trained = gpt.forward(&[1, 2]).unwrap();
+    gpt.swap_swa().unwrap();
+    let averaged = gpt.forward(&[1, 2]).unwrap();
+    assert_ne!(trained.blob(), averaged.blob());
+    resumed.swap_swa().unwrap();
+    assert_eq!(resumed.forward(&[1, 2]).unwrap().blob(), averaged.blob());
+    gpt.swap_swa().unwrap();
+    assert_eq!(gpt.forward(&[1, 2]).unwrap().blob(), trained.blob());
+}
+
+#[test]
+fn test_sft_loss_mask() {
+    // Only the completion counts, so the padding (Which comes after it) can't change the loss
+    let loss = |pad: usize| {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+        let dataset = SftDataset::new(&[(vec![1, 2, 3], vec![4])], pad);
+        gpt.train_cpu(&dataset, 1, 2, None, &AdamW::new(), |_| 0., ())
+            .unwrap()
+            .loss
+    };
+    assert_eq!(loss(0), loss(1));
+
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+    let unmasked = |pad: usize| vec![1, 2, 3, 4, pad, pad, pad];
+    let a = gpt.evaluate(&unmasked(0), None).unwrap().loss;
+    let b = gpt.evaluate(&unmasked(1), None).unwrap().loss;
+    assert_ne!(a, b);
+}
+
+// A single sequence, padded on the left with the given token
+struct LeftPadded(usize);
+
+impl Dataset for LeftPadded {
+    fn len(&self) -> usize {
+        4
+    }
+    fn read(&self, start: usize, len: usize) -> Result<Vec<usize>, GraphError> {
+        Ok([1, 2, 3, 4][start..start + len].to_vec())
+    }
+    fn sample<R: Rng>(
+        &self,
+        _rng: &mut R,
+        batch_size: usize,
+        context_size: usize,
+    ) -> Result<Batch, GraphError> {
+        let pads = context_size - 3;
+        let row = |tokens: &[usize]| {
+            let mut row = vec![self.0; pads];
+            row.extend(tokens);
+            row.repeat(batch_size)
+        };
+        let mut mask = vec![0.; pads];
+        mask.extend([1., 1., 1.]);
+        let shape = [batch_size, context_size];
+        Ok(Batch {
+            xs: Tensor::raw(&shape, row(&[1, 2, 3]))?,
+            ys: Tensor::raw(&shape, row(&[2, 3, 4]))?,
+            weights: None,
+            attention_mask: Some(Tensor::raw(&shape, mask.repeat(batch_size))?),
+            prefix: None,
+        })
+    }
+}
+
+#[test]
+fn test_attention_mask() {
+    // The real tokens come after the padding, so they only ignore it if it's masked. The
+    // embeddings of the pads get no gradient, so training ends with the same weights.
+    let train = |pad: usize| {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+        gpt.train_cpu(&LeftPadded(pad), 1, 2, None, &AdamW::new(), |_| 0.01, ())
+            .unwrap();
+        gpt.get_training_state().unwrap().tensors
+    };
+    let a = train(0);
+    for pad in [1, 4] {
+        let b = train(pad);
+        for (name, t) in a.iter() {
+            assert_eq!(t.blob(), b[name].blob(), "{}", name);
+        }
+    }
+}
+
+#[test]
+fn test_lora() {
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let mut base = GPT::new(
+        &mut rand::thread_rng(),
+        CpuGraph::new(),
+        None,
+        GPTConfig::tiny(),
+    )
+    .unwrap();
+    let base_state = base.get_training_state().unwrap();
+    let base_loss = base.evaluate(&data, None).unwrap().loss;
+
+    let lora_cfg = GPTConfig {
+        lora: Some(LoraConfig { rank: 2, alpha: 4. }),
+        classifier: None,
+        ..GPTConfig::tiny()
+    };
+    let new_lora = || {
+        let mut gpt = GPT::new(
+            &mut rand::thread_rng(),
+            CpuGraph::new(),
+            None,
+            lora_cfg.clone(),
+        )
+        .unwrap();
+        gpt.set_training_state(base_state.clone(), false).unwrap();
+        gpt
+    };
+
+    // Adapters start as no-ops, and only they are trained
+    let mut gpt = new_lora();
+    assert_eq!(gpt.evaluate(&data, None).unwrap().loss, base_loss);
+    gpt.train_cpu(&data, 5, 2, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+    let state = gpt.get_training_state().unwrap();
+    for (name, t) in base_state.tensors.iter() {
+        assert_eq!(t.blob(), state.tensors[name].blob(), "{}", name);
+    }
+    assert!(state
+        .tensors
+        .iter()
+        .any(|(name, t)| name.ends_with("_lora_b") && t.blob().iter().any(|f| *f != 0.)));
+    let loss = gpt.evaluate(&data, None).unwrap().loss;
+
+    let path = std::env::temp_dir().join(format!("femto_gpt_lora_{}", std::process::id()));
+    gpt.save_adapters(&path).unwrap();
+    let mut loaded = new_lora();
+    loaded.load_adapters(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.evaluate(&data, None).unwrap().loss, loss);
+
+    // Merged into the base weights, the adapters are not needed anymore
+    gpt.merge_adapters().unwrap();
+    assert!((gpt.evaluate(&data, None).unwrap().loss - loss).abs() < 1e-4);
+    let mut merged = GPT::new(
+        &mut rand::thread_rng(),
+        CpuGraph::new(),
+        None,
+        GPTConfig::tiny(),
+    )
+    .unwrap();
+    merged
+        .set_training_state(gpt.get_training_state().unwrap(), false)
+        .unwrap();
+    assert!((merged.evaluate(&data, None).unwrap().loss - loss).abs() < 1e-4);
+}
+
+#[test]
+fn test_freeze_layers() {
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let config = GPTConfig {
+        num_layers: 2,
+        ..GPTConfig::tiny()
+    };
+    let mut gpt = GPT::new(&mut rand::thread_rng(), CpuGraph::new(), None, config).unwrap();
+    assert!(gpt.freeze_layers(0..1).unwrap() > 0);
+    assert_eq!(gpt.freeze(&["token_embedding"]).unwrap(), 1);
+    let before = gpt.get_training_state().unwrap().tensors;
+    gpt.train_cpu(&data, 3, 2, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+    let after = gpt.get_training_state().unwrap().tensors;
+    for (name, t) in before {
+        let layer = name
+            .split('_')
+            .skip(1)
+            .find_map(|t| t.parse::<usize>().ok());
+        let frozen = name == "token_embedding" || layer == Some(0);
+        assert_eq!(t.blob() == after[&name].blob(), frozen, "{}", name);
+    }
+}
+
+#[test]
+fn test_z_loss() {
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let loss = |z_loss: Option<Float>| {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+        gpt.set_seed(7);
+        gpt.set_training_options(TrainingOptions {
+            z_loss,
+            ..Default::default()
+        });
+        let summary = gpt
+            .train_cpu(&data, 1, 2, None, &AdamW::new(), |_| 0., ())
+            .unwrap();
+        (summary.loss, gpt.evaluate(&data, None).unwrap().loss)
+    };
+    let (train, eval) = loss(None);
+    let (train_z, eval_z) = loss(Some(0.1));
+    // The logits are close to zero, so log-sum-exp is close to ln(vocab_size)
+    let expected = 0.1 * (5 as Float).ln().powi(2);
+    assert!((train_z - train - expected).abs() < 1e-2);
+    assert_eq!(eval, eval_z);
+}
+
+#[test]
+fn test_classifier() {
+    let mut rng = StdRng::seed_from_u64(42);
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let mut pretrained = GPT::new(&mut rng, CpuGraph::new(), None, GPTConfig::tiny()).unwrap();
+    pretrained
+        .train_cpu(&data, 3, 2, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+
+    let config = GPTConfig {
+        classifier: Some(ClassifierConfig {
+            num_classes: 2,
+            pooling: Pooling::Mean,
+        }),
+        ..GPTConfig::tiny()
+    };
+    let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, config).unwrap();
+    gpt.set_training_state(pretrained.get_training_state().unwrap(), false)
+        .unwrap();
+    let before = gpt.get_training_state().unwrap().tensors;
+
+    // Sequences of 1s and 3s are of the first class, sequences of 2s and 4s of the second one
+    let examples = (1..=8)
+        .map(|len| (vec![len % 4 + 1; len], (len + 1) % 2))
+        .collect::<Vec<_>>();
+    let summary = gpt
+        .train_classifier(&examples, 100, 4, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+    assert_eq!(summary.steps, 100);
+    for (tokens, label) in examples.iter() {
+        let logits = gpt.classify(tokens).unwrap();
+        assert_eq!(logits.len(), 2);
+        assert!(logits[*label] > logits[1 - *label], "{:?}", tokens);
+    }
+    let after = gpt.get_training_state().unwrap().tensors;
+    assert_eq!(
+        before["head_map_weights"].blob(),
+        after["head_map_weights"].blob()
+    );
+
+    // Language modeling still works on the fine-tuned model
+    gpt.train_cpu(&data, 1, 2, None, &AdamW::new(), |_| 0.01, ())
+        .unwrap();
+    assert!(gpt
+        .train_classifier(&[(vec![1], 2)], 1, 1, &AdamW::new(), |_| 0.01, ())
+        .is_err());
+}
+
+#[test]
+fn test_prefix_lm() {
+    // The prompt is the prefix of the examples of an `SftDataset`, which only makes a
+    // difference for prompts of more than a token, on models of more than a layer. (The first
+    // layer of the completion tokens sees the same prompt either way) The weights are large
+    // enough for the difference to show in the loss.
+    let loss = |prefix_lm: bool, prompt: Vec<usize>| {
+        let mut rng = StdRng::seed_from_u64(42);
+        let config = GPTConfig {
+            prefix_lm,
+            num_layers: 2,
+            init: InitScheme::Scaled { std: 0.5 },
+            ..GPTConfig::tiny()
+        };
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, config).unwrap();
+        let dataset = SftDataset::new(&[(prompt, vec![4, 1])], 0);
+        gpt.train_cpu(&dataset, 1, 2, None, &AdamW::new(), |_| 0.01, ())
+            .unwrap()
+            .loss
+    };
+    assert_eq!(loss(true, vec![1]), loss(false, vec![1]));
+    assert_ne!(loss(true, vec![1, 2, 3]), loss(false, vec![1, 2, 3]));
+}
+
+#[test]
+fn test_half_precision() {
+    use femto_gpt::dataset::PreferencePair;
+
+    let data: Vec<usize> = (0..100).map(|i| (i * i) % 5).collect();
+    let config = GPTConfig {
+        classifier: Some(ClassifierConfig {
+            num_classes: 2,
+            pooling: Pooling::Mean,
+        }),
+        ..GPTConfig::tiny()
+    };
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut full = GPT::new(&mut rng, CpuGraph::new(), None, config.clone()).unwrap();
+    for precision in [Precision::F16, Precision::Bf16] {
+        let config = GPTConfig {
+            precision,
+            ..config.clone()
+        };
+        let mut gpt = GPT::new(&mut rng, CpuGraph::new(), None, config).unwrap();
+        gpt.set_training_state(full.get_training_state().unwrap(), false)
+            .unwrap();
+        let before = gpt.evaluate(&data, None).unwrap().loss;
+        // Inference in half precision is close to the full-precision one
+        let context = [1, 2, 3, 4];
+        let logits = gpt.forward(&context).unwrap();
+        let expected = full.forward(&context).unwrap();
+        for (a, b) in logits.blob().iter().zip(expected.blob()) {
+            assert!((a - b).abs() < 0.05, "{:?}: {} != {}", precision, a, b);
+        }
+
+        // Every training loop runs in half precision, the steps overflowing it are skipped
+        let pairs = vec![PreferencePair {
+            prompt: vec![1, 2],
+            chosen: vec![3, 4, 0],
+            rejected: vec![4, 3, 0],
+        }];
+        let reference = gpt.dpo_reference(&pairs).unwrap();
+        let examples = vec![(vec![1, 3], 0), (vec![2, 4, 2], 1)];
+        let summaries = [
+            gpt.train_cpu(&data, 30, 3, None, &AdamW::new(), |_| 0.01, ()),
+            gpt.train(&data, 5, 3, None, &AdamW::new(), |_| 0.01, ()),
+            gpt.train_classifier(&examples, 5, 2, &AdamW::new(), |_| 0.01, ()),
+            gpt.train_dpo(&pairs, &reference, 0.5, 5, 1, &AdamW::new(), |_| 0.01, ()),
+        ];
+        for (summary, num_batches) in summaries.into_iter().zip([30, 5, 5, 5]) {
+            let summary = summary.unwrap();
+            assert_eq!(summary.steps + summary.skipped_steps, num_batches);
+            assert!(summary.steps > 0 && summary.loss.is_finite());
+        }
+        let after = gpt.evaluate(&data, None).unwrap().loss;
+        assert!(after < before, "{:?}: {} >= {}", precision, after, before);
+    }
+}